@@ -9,7 +9,7 @@ extern crate panic_halt;
 extern crate stm32g0xx_hal as hal;
 
 use cortex_m_semihosting::hprintln;
-use hal::analog::adc::{OversamplingRatio, Precision, SampleTime, VBat};
+use hal::analog::adc::{OversamplingRatio, Precision, SampleTime, VBat, VTemp};
 use hal::prelude::*;
 use hal::stm32;
 use rt::entry;
@@ -36,11 +36,13 @@ fn main() -> ! {
     let mut adc_pin = gpioa.pa0.into_analog();
 
     let mut vbat = VBat::new();
-    vbat.enable(&mut adc);
+    let mut vtemp = VTemp::new();
+    vtemp.enable(&mut adc);
 
     loop {
         let u_mv = adc.read_voltage(&mut adc_pin).expect("adc read failed");
-        let u_bat = adc.read_voltage(&mut vbat).expect("adc read failed");
-        hprintln!("VBat: {}mV | PA0: {}mV", u_bat * 3, u_mv).unwrap();
+        let u_bat = adc.read_vbat(&mut vbat).expect("adc read failed");
+        let temp = adc.read_temperature(&mut vtemp).expect("adc read failed");
+        hprintln!("VBat: {}mV | PA0: {}mV | {}°C", u_bat, u_mv, temp).unwrap();
     }
 }
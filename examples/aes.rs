@@ -0,0 +1,49 @@
+#![deny(warnings)]
+#![deny(unsafe_code)]
+#![no_main]
+#![no_std]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate panic_halt;
+extern crate stm32g0xx_hal as hal;
+
+use cortex_m_semihosting::hprintln;
+use hal::aes::Mode;
+use hal::prelude::*;
+use hal::stm32;
+use rt::entry;
+
+#[entry]
+fn main() -> ! {
+    let dp = stm32::Peripherals::take().expect("cannot take peripherals");
+    let mut rcc = dp.RCC.constrain();
+
+    let mut aes = dp.AES.constrain(&mut rcc);
+    let key = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f,
+    ];
+    aes.set_key(&key).unwrap();
+
+    // FIPS-197 example vector, the ciphertext is 69c4e0d86a7b0430d8cdb78070b4c55a
+    let mut block = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ];
+    aes.encrypt(Mode::Ecb, &mut block).unwrap();
+    hprintln!("ecb: {:02x?}", block).unwrap();
+    aes.decrypt(Mode::Ecb, &mut block).unwrap();
+    hprintln!("decrypted: {:02x?}", block).unwrap();
+
+    let iv = [0x42; 12];
+    let mut message = *b"The quick brown fox jumps over the lazy dog";
+    let tag = aes.encrypt_gcm(&iv, b"header", &mut message).unwrap();
+    hprintln!("gcm tag: {:02x?}", tag).unwrap();
+    match aes.decrypt_gcm(&iv, b"header", &mut message, &tag) {
+        Ok(()) => hprintln!("authentic").unwrap(),
+        Err(err) => hprintln!("error: {:?}", err).unwrap(),
+    }
+
+    loop {}
+}
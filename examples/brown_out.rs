@@ -0,0 +1,37 @@
+#![deny(warnings)]
+#![deny(unsafe_code)]
+#![no_main]
+#![no_std]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate panic_halt;
+extern crate stm32g0xx_hal as hal;
+
+use hal::flash::BrownOutLevel;
+use hal::power::PvdLevel;
+use hal::prelude::*;
+use hal::stm32;
+use rt::entry;
+
+#[entry]
+fn main() -> ! {
+    let dp = stm32::Peripherals::take().expect("cannot take peripherals");
+    let mut rcc = dp.RCC.constrain();
+
+    // Hold the application until VDD is high enough for flash writes
+    let mut pwr = dp.PWR.constrain(&mut rcc);
+    pwr.wait_for_vdd(PvdLevel::Level4);
+
+    // Keep the device in reset below ~2.6 V, programming the option bytes resets the device
+    if let Ok(mut flash) = dp.FLASH.unlock() {
+        flash.raise_brown_out_level(BrownOutLevel::Level3).unwrap();
+        flash.lock();
+    }
+
+    let gpioa = dp.GPIOA.split(&mut rcc);
+    let mut led = gpioa.pa5.into_push_pull_output();
+    loop {
+        led.set_state(pwr.is_vdd_low().into()).unwrap();
+    }
+}
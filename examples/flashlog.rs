@@ -0,0 +1,39 @@
+#![deny(warnings)]
+#![deny(unsafe_code)]
+#![no_main]
+#![no_std]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate panic_halt;
+extern crate stm32g0xx_hal as hal;
+
+use cortex_m_semihosting::hprintln;
+use hal::flash::{FlashExt, FlashPage, FlashRegion};
+use hal::flashlog::FlashLog;
+use hal::stm32;
+use rt::entry;
+
+#[allow(clippy::empty_loop)]
+#[entry]
+fn main() -> ! {
+    let dp = stm32::Peripherals::take().expect("cannot take peripherals");
+
+    // Pages 12 and 13, which have to be left out of the FLASH region of memory.x
+    let mut log = FlashLog::open(FlashRegion::new(FlashPage(12), 2));
+    for record in log.records() {
+        hprintln!("record: {:02x?}", record).ok();
+    }
+
+    let boots = log.records().count() as u32;
+    match dp.FLASH.unlock() {
+        Ok(mut flash) => {
+            log.append(&mut flash, &boots.to_le_bytes())
+                .expect("cannot append the record");
+            flash.lock();
+        }
+        Err(_) => hprintln!("Cannot unlock flash").unwrap(),
+    }
+
+    loop {}
+}
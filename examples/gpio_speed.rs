@@ -0,0 +1,78 @@
+// Measures the cost of the GPIO writes with the SysTick counter
+#![deny(warnings)]
+#![no_main]
+#![no_std]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate panic_semihosting;
+extern crate stm32g0xx_hal as hal;
+
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::SYST;
+use cortex_m_semihosting::hprintln;
+use hal::hal::digital::v2::PinState;
+use hal::prelude::*;
+use hal::rcc::Config;
+use hal::stm32;
+use rt::entry;
+
+const RUNS: u32 = 100;
+
+/// Core clock cycles of `f`, SysTick counts down
+fn cycles(mut f: impl FnMut()) -> u32 {
+    let start = SYST::get_current();
+    f();
+    start.wrapping_sub(SYST::get_current()) & 0x00ff_ffff
+}
+
+#[entry]
+fn main() -> ! {
+    let dp = stm32::Peripherals::take().expect("cannot take peripherals");
+    let cp = cortex_m::Peripherals::take().expect("cannot take core peripherals");
+    let mut rcc = dp.RCC.freeze(Config::pll());
+
+    let mut syst = cp.SYST;
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(0x00ff_ffff);
+    syst.clear_current();
+    syst.enable_counter();
+
+    let gpioa = dp.GPIOA.split(&mut rcc);
+    let mut port = gpioa.port;
+    let mut pin = gpioa.pa5.into_push_pull_output();
+    let _pa6 = gpioa.pa6.into_push_pull_output();
+
+    let empty = cycles(|| {
+        for _ in 0..RUNS {
+            cortex_m::asm::nop();
+        }
+    });
+    let toggle = cycles(|| {
+        for _ in 0..RUNS {
+            pin.toggle().unwrap();
+        }
+    });
+    let set_state = cycles(|| {
+        for _ in 0..RUNS {
+            pin.set_state(PinState::High).unwrap();
+        }
+    });
+    let write_pins = cycles(|| {
+        for _ in 0..RUNS {
+            port.write_pins(1 << 5, 1 << 6);
+        }
+    });
+
+    // Cycles per operation, in hundredths, without the loop overhead
+    let per_op = |total: u32| total.saturating_sub(empty) * 100 / RUNS;
+    hprintln!(
+        "toggle: {} | set_state: {} | write_pins: {}",
+        per_op(toggle),
+        per_op(set_state),
+        per_op(write_pins)
+    )
+    .unwrap();
+
+    loop {}
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![deny(warnings)]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate panic_halt;
+extern crate rtic;
+extern crate stm32g0xx_hal as hal;
+
+use cortex_m_semihosting::hprintln;
+use hal::gpio::gpioa;
+use hal::prelude::*;
+use hal::stm32;
+use hal::timer::keypad::{Config, KeyEvent, Keypad};
+use rtic::app;
+
+const KEYS: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+
+type Keys = Keypad<gpioa::Port, gpioa::PortInput, stm32::TIM14, 4, 4, 8>;
+
+#[app(device = hal::stm32, peripherals = true)]
+mod app {
+    use super::*;
+
+    #[shared]
+    struct Shared {
+        keypad: Keys,
+    }
+
+    #[local]
+    struct Local {}
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let mut rcc = ctx.device.RCC.constrain();
+        let gpioa = ctx.device.GPIOA.split(&mut rcc);
+
+        // Rows on PA0 to PA3, columns on PA4 to PA7
+        gpioa.pa0.into_open_drain_output();
+        gpioa.pa1.into_open_drain_output();
+        gpioa.pa2.into_open_drain_output();
+        gpioa.pa3.into_open_drain_output();
+        gpioa.pa4.into_pull_up_input();
+        gpioa.pa5.into_pull_up_input();
+        gpioa.pa6.into_pull_up_input();
+        gpioa.pa7.into_pull_up_input();
+
+        let cols = gpioa.port.input();
+        let keypad = Keypad::new(
+            gpioa.port,
+            [1 << 0, 1 << 1, 1 << 2, 1 << 3],
+            cols,
+            [1 << 4, 1 << 5, 1 << 6, 1 << 7],
+            ctx.device.TIM14,
+            Config::default(),
+            &mut rcc,
+        );
+
+        (Shared { keypad }, Local {}, init::Monotonics())
+    }
+
+    #[idle(shared = [keypad])]
+    fn idle(mut ctx: idle::Context) -> ! {
+        loop {
+            match ctx.shared.keypad.lock(|keypad| keypad.next_event()) {
+                Some(KeyEvent::Pressed(key)) => {
+                    hprintln!("{}", KEYS[key.row as usize][key.col as usize]).unwrap()
+                }
+                Some(KeyEvent::Released(_)) => {}
+                None => cortex_m::asm::wfi(),
+            }
+        }
+    }
+
+    #[task(binds = TIM14, shared = [keypad])]
+    fn scan(mut ctx: scan::Context) {
+        ctx.shared.keypad.lock(|keypad| keypad.on_interrupt());
+    }
+}
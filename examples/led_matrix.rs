@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![deny(warnings)]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate panic_halt;
+extern crate rtic;
+extern crate stm32g0xx_hal as hal;
+
+use hal::gpio::{gpioa, gpiob};
+use hal::prelude::*;
+use hal::stm32;
+use hal::timer::matrix::{Config, MatrixScan};
+use rtic::app;
+
+/// Segments a to g of the digits 0 to 9, on PA0 to PA6
+const DIGITS: [u16; 10] = [0x3f, 0x06, 0x5b, 0x4f, 0x66, 0x6d, 0x7d, 0x07, 0x7f, 0x6f];
+
+type Display = MatrixScan<gpiob::Port, gpioa::Port, stm32::TIM14, 4>;
+
+#[app(device = hal::stm32, peripherals = true)]
+mod app {
+    use super::*;
+
+    #[shared]
+    struct Shared {
+        display: Display,
+    }
+
+    #[local]
+    struct Local {}
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let mut rcc = ctx.device.RCC.constrain();
+        let gpioa = ctx.device.GPIOA.split(&mut rcc);
+        let gpiob = ctx.device.GPIOB.split(&mut rcc);
+
+        // Segments of a common cathode display, commons of the 4 digits on PB0 to PB3
+        // through NPN low-side drivers
+        gpioa.pa0.into_push_pull_output();
+        gpioa.pa1.into_push_pull_output();
+        gpioa.pa2.into_push_pull_output();
+        gpioa.pa3.into_push_pull_output();
+        gpioa.pa4.into_push_pull_output();
+        gpioa.pa5.into_push_pull_output();
+        gpioa.pa6.into_push_pull_output();
+        gpiob.pb0.into_push_pull_output();
+        gpiob.pb1.into_push_pull_output();
+        gpiob.pb2.into_push_pull_output();
+        gpiob.pb3.into_push_pull_output();
+
+        let display = MatrixScan::new(
+            gpiob.port,
+            [1 << 0, 1 << 1, 1 << 2, 1 << 3],
+            gpioa.port,
+            0x7f,
+            ctx.device.TIM14,
+            Config::new(200.Hz()),
+            &mut rcc,
+        );
+
+        (Shared { display }, Local {}, init::Monotonics())
+    }
+
+    #[idle(shared = [display])]
+    fn idle(mut ctx: idle::Context) -> ! {
+        let mut count = 0u32;
+        loop {
+            ctx.shared.display.lock(|display| {
+                if !display.is_presenting() {
+                    let back = display.back_mut();
+                    for (i, digit) in back.iter_mut().rev().enumerate() {
+                        *digit = DIGITS[(count / 10u32.pow(i as u32) % 10) as usize];
+                    }
+                    display.present();
+                    count = (count + 1) % 10_000;
+                }
+            });
+            cortex_m::asm::delay(1_600_000);
+        }
+    }
+
+    #[task(binds = TIM14, shared = [display])]
+    fn scan(mut ctx: scan::Context) {
+        ctx.shared.display.lock(|display| display.on_interrupt());
+    }
+}
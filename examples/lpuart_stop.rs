@@ -0,0 +1,57 @@
+// Echoes the bytes received on the LPUART while sleeping in Stop mode
+#![deny(warnings)]
+#![no_main]
+#![no_std]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate panic_halt;
+extern crate stm32g0xx_hal as hal;
+
+use cortex_m::peripheral::NVIC;
+use hal::exti::Event;
+use hal::power::{LowPowerMode, PowerMode};
+use hal::prelude::*;
+use hal::rcc::{self, Prescaler};
+use hal::serial::{self, BasicConfig, ClockSource, WakeupEvent};
+use hal::stm32::{self, Interrupt};
+use nb::block;
+use rt::entry;
+
+#[entry]
+fn main() -> ! {
+    let dp = stm32::Peripherals::take().expect("cannot take peripherals");
+    let mut cp = cortex_m::Peripherals::take().expect("cannot take core peripherals");
+    let mut rcc = dp.RCC.freeze(rcc::Config::hsi(Prescaler::Div16));
+    let gpioa = dp.GPIOA.split(&mut rcc);
+
+    // HSI16 keeps clocking the LPUART in Stop mode
+    let config = BasicConfig::default()
+        .baudrate(9600.bps())
+        .clock_source(ClockSource::HSI16);
+    let mut lpuart = dp
+        .LPUART
+        .usart(gpioa.pa2, gpioa.pa3, config, &mut rcc)
+        .unwrap();
+    lpuart
+        .enable_wakeup_from_stop(WakeupEvent::RxNotEmpty)
+        .unwrap();
+
+    let exti = dp.EXTI;
+    exti.wakeup(Event::LPUART1);
+
+    let mut power = dp.PWR.constrain(&mut rcc);
+    power.set_mode(PowerMode::LowPower(LowPowerMode::StopMode2));
+    cp.SCB.set_sleepdeep();
+
+    // The pending interrupt wakes the core up without being taken
+    unsafe { NVIC::unmask(Interrupt::USART3_USART4_LPUART1) };
+    cortex_m::interrupt::free(|_| loop {
+        cortex_m::asm::wfi();
+        lpuart.unpend(serial::Event::WUF);
+        if let Ok(byte) = lpuart.read() {
+            block!(lpuart.write(byte)).unwrap();
+            block!(lpuart.flush()).unwrap();
+        }
+    })
+}
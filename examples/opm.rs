@@ -52,9 +52,9 @@ mod app {
 
         let max_delay = opm_ch2.get_max_delay();
 
-        opm_ch2.set_delay(max_delay / 2);
-        opm_ch3.set_delay(max_delay / 4);
-        opm_ch4.set_delay(max_delay / 8);
+        opm_ch2.try_set_delay(max_delay / 2).unwrap();
+        opm_ch3.try_set_delay(max_delay / 4).unwrap();
+        opm_ch4.try_set_delay(max_delay / 8).unwrap();
 
         opm_ch1.enable();
         opm_ch2.enable();
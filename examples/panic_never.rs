@@ -0,0 +1,58 @@
+//! Link check of the `panic-never` feature
+//!
+//! The `panic-never` crate makes the link fail if a panic is reachable, this example
+//! only builds if the fallible configuration paths and the DMA buffer checks leave none:
+//!
+//! ```
+//! cargo build --release --example panic_never --features stm32g081,rt,panic-never
+//! ```
+#![deny(warnings)]
+#![no_main]
+#![no_std]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate panic_never;
+extern crate stm32g0xx_hal as hal;
+
+use hal::dma::Transfer;
+use hal::prelude::*;
+use hal::rcc::Config;
+use hal::stm32;
+use hal::timer::opm::{Opm, OpmConfig};
+use rt::entry;
+
+#[entry]
+fn main() -> ! {
+    let (dp, cp) = match (stm32::Peripherals::take(), cortex_m::Peripherals::take()) {
+        (Some(dp), Some(cp)) => (dp, cp),
+        _ => loop {},
+    };
+    let mut rcc = match dp.RCC.constrain().try_freeze(Config::pll()) {
+        Ok(rcc) => rcc,
+        Err(_) => loop {},
+    };
+
+    let mut timer = cp.SYST.timer(&mut rcc);
+    if timer.try_start(100.millis()).is_err() {
+        loop {}
+    }
+
+    if let Ok(mut opm) = Opm::new(dp.TIM3, OpmConfig::new(10.micros()), &mut rcc) {
+        opm.generate();
+    }
+
+    // The lengths of the arrays are known, the buffer check is done at build time
+    let dma = dp.DMA.split(&mut rcc, dp.DMAMUX);
+    let src = cortex_m::singleton!(: [u32; 16] = [0x55; 16]);
+    let dst = cortex_m::singleton!(: [u32; 16] = [0; 16]);
+    if let (Some(src), Some(dst)) = (src, dst) {
+        let transfer = Transfer::memory_to_memory(dma.ch1, &*src, dst);
+        while !transfer.is_done() {}
+        let _ = transfer.abort();
+    }
+
+    loop {
+        let _ = hal::block!(timer.wait());
+    }
+}
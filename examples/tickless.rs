@@ -0,0 +1,37 @@
+#![deny(warnings)]
+#![deny(unsafe_code)]
+#![no_main]
+#![no_std]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate nb;
+extern crate panic_halt;
+extern crate stm32g0xx_hal as hal;
+
+use hal::prelude::*;
+use hal::stm32;
+use hal::tickless::Tickless;
+use rt::entry;
+
+#[entry]
+fn main() -> ! {
+    let dp = stm32::Peripherals::take().expect("cannot take peripherals");
+    let cp = cortex_m::Peripherals::take().expect("cannot take core peripherals");
+    let mut rcc = dp.RCC.constrain();
+
+    let gpioa = dp.GPIOA.split(&mut rcc);
+    let mut led = gpioa.pa5.into_push_pull_output();
+
+    // The TIM17 interrupt stays masked in the NVIC, it only wakes up the core
+    let mut timer = dp.TIM17.timer(&mut rcc);
+    timer.start(500.millis());
+    timer.listen();
+
+    let mut idle = Tickless::new(cp.SCB);
+    loop {
+        idle.idle_until(stm32::Interrupt::TIM17);
+        timer.clear_irq();
+        led.toggle().unwrap();
+    }
+}
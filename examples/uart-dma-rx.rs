@@ -0,0 +1,53 @@
+#![no_main]
+#![no_std]
+
+extern crate cortex_m_rt as rt;
+extern crate panic_halt;
+extern crate stm32g0xx_hal as hal;
+
+use core::ptr::addr_of_mut;
+
+use hal::prelude::*;
+use hal::serial::*;
+use hal::stm32;
+use heapless::spsc::Queue;
+
+use rt::entry;
+
+static mut RX_BUFFER: [u8; 64] = [0; 64];
+static mut QUEUE: Queue<Chunk<16>, 8> = Queue::new();
+
+#[entry]
+fn main() -> ! {
+    let dp = stm32::Peripherals::take().expect("cannot take peripherals");
+    let mut rcc = dp.RCC.constrain();
+    let gpioa = dp.GPIOA.split(&mut rcc);
+
+    let usart2 = dp
+        .USART2
+        .usart(
+            gpioa.pa2,
+            gpioa.pa3,
+            FullConfig::default().baudrate(115200.bps()),
+            &mut rcc,
+        )
+        .unwrap();
+
+    let (mut tx, rx) = usart2.split();
+    let dma = dp.DMA.split(&mut rcc, dp.DMAMUX);
+
+    let (mut producer, mut consumer) = unsafe { (*addr_of_mut!(QUEUE)).split() };
+    let mut rx = rx.read_circular(dma.ch1, unsafe { &mut *addr_of_mut!(RX_BUFFER) });
+
+    loop {
+        // Usually called from the USART2 and DMA_CHANNEL1 interrupt handlers
+        rx.on_interrupt(&mut producer).ok();
+
+        // Echo the received chunks
+        while let Some(chunk) = consumer.dequeue() {
+            for byte in &chunk {
+                nb::block!(tx.write(*byte)).ok();
+            }
+        }
+    }
+}
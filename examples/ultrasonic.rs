@@ -0,0 +1,39 @@
+#![deny(warnings)]
+#![deny(unsafe_code)]
+#![no_main]
+#![no_std]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate panic_halt;
+extern crate stm32g0xx_hal as hal;
+
+use cortex_m_semihosting::hprintln;
+use hal::prelude::*;
+use hal::stm32;
+use hal::timer::ultrasonic::Ultrasonic;
+use rt::entry;
+
+#[entry]
+fn main() -> ! {
+    let dp = stm32::Peripherals::take().expect("cannot take peripherals");
+    let mut rcc = dp.RCC.constrain();
+    let gpioa = dp.GPIOA.split(&mut rcc);
+
+    // 10 µs trigger pulse on PA6
+    let opm = dp.TIM3.opm(10.micros(), &mut rcc);
+    let mut trigger = opm.bind_pin(gpioa.pa6);
+    trigger.enable();
+
+    // Echo input on PA8
+    let mut ranger = Ultrasonic::tim1(dp.TIM1, trigger, gpioa.pa8, &mut rcc);
+    let mut delay = dp.TIM16.delay(&mut rcc);
+
+    loop {
+        match ranger.measure() {
+            Ok(distance) => hprintln!("distance: {} mm", distance).unwrap(),
+            Err(_) => hprintln!("out of range").unwrap(),
+        }
+        delay.delay(100.millis());
+    }
+}
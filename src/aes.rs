@@ -0,0 +1,653 @@
+//! # AES hardware accelerator
+//!
+//! Encrypts and decrypts with 128 or 256-bit keys, in the ECB, CBC, CTR and GCM modes. The
+//! data is processed in 16-byte blocks, moved by the CPU with [`Aes::encrypt`],
+//! [`Aes::decrypt`] and the GCM methods, or by the DMA with [`Aes::encrypt_dma`] and
+//! [`Aes::decrypt_dma`].
+//!
+//! The key is kept by the driver and loaded for each operation, as the ECB and CBC
+//! decryptions overwrite the key registers with the derived decryption key.
+//!
+//! With the `rustcrypto` feature, [`Aes`] implements the `cipher` block traits with
+//! the ECB mode, for the RustCrypto mode crates, and the `aead` `AeadMutInPlace` trait
+//! with the GCM mode.
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use crate::dma::{Channel, Direction, Event, ReadBuffer, WordSize, WriteBuffer};
+use crate::dmamux::DmaMuxIndex;
+use crate::rcc::{Enable, Rcc, Reset};
+use crate::stm32::AES;
+
+/// Size of a block, in bytes
+pub const BLOCK_SIZE: usize = 16;
+
+/// AES_CR MODE: encryption
+const MODE_ENCRYPT: u8 = 0b00;
+/// AES_CR MODE: derivation of the decryption key
+const MODE_KEY_DERIVATION: u8 = 0b01;
+/// AES_CR MODE: decryption
+const MODE_DECRYPT: u8 = 0b10;
+
+/// AES_CR CHMOD values
+const CHMOD_ECB: u8 = 0b000;
+const CHMOD_CBC: u8 = 0b001;
+const CHMOD_CTR: u8 = 0b010;
+const CHMOD_GCM: u8 = 0b011;
+
+/// AES_CR GCMPH values
+const GCM_INIT: u8 = 0b00;
+const GCM_HEADER: u8 = 0b01;
+const GCM_PAYLOAD: u8 = 0b10;
+const GCM_FINAL: u8 = 0b11;
+
+/// AES_CR DATATYPE: the bytes of the data words are swapped, so that the words read from
+/// memory in little-endian give the blocks in byte order
+const DATATYPE_BYTES: u8 = 0b10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The key is neither 16 nor 32 bytes long
+    KeySize,
+    /// The ECB or CBC data is not a whole number of blocks
+    Length,
+    /// Register accessed during a computation (AES_SR RDERR or WRERR)
+    Sequence,
+    /// The GCM tag does not match, the data is not authentic
+    Authentication,
+}
+
+/// Chaining mode of [`Aes::encrypt`] and [`Aes::decrypt`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Each block on its own
+    Ecb,
+    /// Each block is combined with the previous ciphertext block, the first one with `iv`
+    Cbc { iv: [u8; BLOCK_SIZE] },
+    /// The data is XORed with the encryption of counter blocks, starting with `iv` and
+    /// incremented in the last 32 bits, so it can have any length
+    Ctr { iv: [u8; BLOCK_SIZE] },
+}
+
+impl Mode {
+    fn chmod(&self) -> u8 {
+        match self {
+            Mode::Ecb => CHMOD_ECB,
+            Mode::Cbc { .. } => CHMOD_CBC,
+            Mode::Ctr { .. } => CHMOD_CTR,
+        }
+    }
+
+    fn iv(&self) -> Option<&[u8; BLOCK_SIZE]> {
+        match self {
+            Mode::Ecb => None,
+            Mode::Cbc { iv } | Mode::Ctr { iv } => Some(iv),
+        }
+    }
+}
+
+pub trait AesExt {
+    fn constrain(self, rcc: &mut Rcc) -> Aes;
+}
+
+impl AesExt for AES {
+    fn constrain(self, rcc: &mut Rcc) -> Aes {
+        AES::enable(rcc);
+        AES::reset(rcc);
+        Aes {
+            rb: self,
+            key: [0; 8],
+            key256: false,
+        }
+    }
+}
+
+/// AES peripheral, with its key
+pub struct Aes {
+    rb: AES,
+    /// Key words, the most significant first
+    key: [u32; 8],
+    key256: bool,
+}
+
+impl Aes {
+    /// Sets the key of the next operations, 16 bytes for AES-128 or 32 bytes for AES-256
+    pub fn set_key(&mut self, key: &[u8]) -> Result<(), Error> {
+        if key.len() != 16 && key.len() != 32 {
+            return Err(Error::KeySize);
+        }
+        self.key = [0; 8];
+        for (word, bytes) in self.key.iter_mut().zip(key.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        self.key256 = key.len() == 32;
+        Ok(())
+    }
+
+    /// Encrypts `data` in place
+    ///
+    /// Fails with `Error::Length` if the data of the ECB and CBC modes is not a whole number
+    /// of blocks.
+    pub fn encrypt(&mut self, mode: Mode, data: &mut [u8]) -> Result<(), Error> {
+        self.process(mode, false, data)
+    }
+
+    /// Decrypts `data` in place, see [`encrypt`](Self::encrypt)
+    pub fn decrypt(&mut self, mode: Mode, data: &mut [u8]) -> Result<(), Error> {
+        self.process(mode, true, data)
+    }
+
+    /// Encrypts `data` in place in the GCM mode, and returns the tag authenticating it
+    /// with `aad`, the additional data sent in clear
+    ///
+    /// A given key must never be used twice with the same `iv`.
+    pub fn encrypt_gcm(
+        &mut self,
+        iv: &[u8; 12],
+        aad: &[u8],
+        data: &mut [u8],
+    ) -> Result<[u8; BLOCK_SIZE], Error> {
+        self.gcm(iv, aad, data, false)
+    }
+
+    /// Decrypts `data` in place in the GCM mode, checking it and `aad` against `tag`
+    ///
+    /// Fails with `Error::Authentication` on a tag mismatch, `data` is then zeroed.
+    pub fn decrypt_gcm(
+        &mut self,
+        iv: &[u8; 12],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; BLOCK_SIZE],
+    ) -> Result<(), Error> {
+        let computed = self.gcm(iv, aad, data, true)?;
+        // Constant time comparison
+        let diff = computed
+            .iter()
+            .zip(tag.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        if diff != 0 {
+            data.iter_mut().for_each(|byte| *byte = 0);
+            return Err(Error::Authentication);
+        }
+        Ok(())
+    }
+
+    /// Encrypts `input` into `output` with the DMA, `in_ch` feeds the input blocks and
+    /// `out_ch` reads back the output blocks
+    ///
+    /// Each word of the buffers holds 4 bytes of data in memory order, as given by
+    /// `u32::from_le_bytes`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffers are not a whole number of blocks, do not have the same
+    /// length, or are longer than 65535 words.
+    pub fn encrypt_dma<INCH, OUTCH, IN, OUT>(
+        self,
+        mode: Mode,
+        in_ch: INCH,
+        out_ch: OUTCH,
+        input: IN,
+        output: OUT,
+    ) -> AesTransfer<INCH, OUTCH, IN, OUT>
+    where
+        INCH: Channel,
+        OUTCH: Channel,
+        IN: ReadBuffer<Word = u32>,
+        OUT: WriteBuffer<Word = u32>,
+    {
+        self.start_dma(mode, false, in_ch, out_ch, input, output)
+    }
+
+    /// Decrypts `input` into `output` with the DMA, see [`encrypt_dma`](Self::encrypt_dma)
+    pub fn decrypt_dma<INCH, OUTCH, IN, OUT>(
+        self,
+        mode: Mode,
+        in_ch: INCH,
+        out_ch: OUTCH,
+        input: IN,
+        output: OUT,
+    ) -> AesTransfer<INCH, OUTCH, IN, OUT>
+    where
+        INCH: Channel,
+        OUTCH: Channel,
+        IN: ReadBuffer<Word = u32>,
+        OUT: WriteBuffer<Word = u32>,
+    {
+        self.start_dma(mode, true, in_ch, out_ch, input, output)
+    }
+
+    /// Clears the key and releases the peripheral
+    pub fn release(mut self) -> AES {
+        self.disable();
+        self.key = [0; 8];
+        self.load_key();
+        self.rb
+    }
+
+    fn process(&mut self, mode: Mode, decrypt: bool, data: &mut [u8]) -> Result<(), Error> {
+        if mode.chmod() != CHMOD_CTR && !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(Error::Length);
+        }
+        let res = self
+            .prepare(mode, decrypt)
+            .and_then(|_| self.payload(data, false));
+        self.disable();
+        res
+    }
+
+    fn gcm(
+        &mut self,
+        iv: &[u8; 12],
+        aad: &[u8],
+        data: &mut [u8],
+        decrypt: bool,
+    ) -> Result<[u8; BLOCK_SIZE], Error> {
+        let mut counter = [0; BLOCK_SIZE];
+        counter[..12].copy_from_slice(iv);
+        // The payload starts at counter 2, counter 1 encrypts the tag
+        counter[15] = 2;
+        self.configure(CHMOD_GCM, if decrypt { MODE_DECRYPT } else { MODE_ENCRYPT });
+        self.load_iv(&counter);
+        let res = self.gcm_phases(aad, data);
+        self.disable();
+        res
+    }
+
+    fn gcm_phases(&mut self, aad: &[u8], data: &mut [u8]) -> Result<[u8; BLOCK_SIZE], Error> {
+        // Computes the hash subkey
+        self.set_phase(GCM_INIT);
+        self.wait()?;
+        if !aad.is_empty() {
+            self.set_phase(GCM_HEADER);
+            for chunk in aad.chunks(BLOCK_SIZE) {
+                let mut block = [0; BLOCK_SIZE];
+                block[..chunk.len()].copy_from_slice(chunk);
+                self.write_block(&block);
+                self.wait()?;
+            }
+        }
+        if !data.is_empty() {
+            self.set_phase(GCM_PAYLOAD);
+            self.payload(data, true)?;
+        }
+        // The final phase is an encryption in both directions. The init phase clears EN
+        // when done, so it is still off without header and payload
+        self.rb.cr.modify(|_, w| unsafe {
+            w.gcmph()
+                .bits(GCM_FINAL)
+                .mode()
+                .bits(MODE_ENCRYPT)
+                .en()
+                .set_bit()
+        });
+        let mut lengths = [0; BLOCK_SIZE];
+        lengths[..8].copy_from_slice(&(aad.len() as u64 * 8).to_be_bytes());
+        lengths[8..].copy_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+        self.write_block(&lengths);
+        self.wait()?;
+        Ok(self.read_block())
+    }
+
+    fn start_dma<INCH, OUTCH, IN, OUT>(
+        mut self,
+        mode: Mode,
+        decrypt: bool,
+        mut in_ch: INCH,
+        mut out_ch: OUTCH,
+        input: IN,
+        mut output: OUT,
+    ) -> AesTransfer<INCH, OUTCH, IN, OUT>
+    where
+        INCH: Channel,
+        OUTCH: Channel,
+        IN: ReadBuffer<Word = u32>,
+        OUT: WriteBuffer<Word = u32>,
+    {
+        let (in_ptr, len) = unsafe { input.read_buffer() };
+        let (out_ptr, out_len) = unsafe { output.write_buffer() };
+        assert!(len == out_len, "buffers of different lengths");
+        hal_assert!(len > 0 && len <= u16::MAX as usize);
+        hal_assert!(len.is_multiple_of(BLOCK_SIZE / 4));
+
+        let derived = self.prepare(mode, decrypt);
+        hal_assert!(derived.is_ok(), "AES key derivation failed");
+        let dinr = &self.rb.dinr as *const _ as u32;
+        let doutr = &self.rb.doutr as *const _ as u32;
+        setup(
+            &mut out_ch,
+            doutr,
+            Direction::FromPeripheral,
+            out_ptr as u32,
+            len,
+            DmaMuxIndex::AES_OUT,
+        );
+        setup(
+            &mut in_ch,
+            dinr,
+            Direction::FromMemory,
+            in_ptr as u32,
+            len,
+            DmaMuxIndex::AES_IN,
+        );
+        // The input is written before the DMA starts reading it
+        compiler_fence(Ordering::Release);
+        out_ch.enable();
+        in_ch.enable();
+        self.rb
+            .cr
+            .modify(|_, w| w.dmainen().set_bit().dmaouten().set_bit().en().set_bit());
+        AesTransfer {
+            aes: self,
+            in_ch,
+            out_ch,
+            input,
+            output,
+        }
+    }
+
+    /// Loads the key and the IV of `mode`, deriving the decryption key for ECB and CBC
+    fn prepare(&mut self, mode: Mode, decrypt: bool) -> Result<(), Error> {
+        let chmod = mode.chmod();
+        let derive = decrypt && chmod != CHMOD_CTR;
+        if derive {
+            self.configure(chmod, MODE_KEY_DERIVATION);
+            self.rb.cr.modify(|_, w| w.en().set_bit());
+            self.wait()?;
+            self.rb
+                .cr
+                .modify(|_, w| unsafe { w.en().clear_bit().mode().bits(MODE_DECRYPT) });
+        } else {
+            self.configure(chmod, if decrypt { MODE_DECRYPT } else { MODE_ENCRYPT });
+        }
+        if let Some(iv) = mode.iv() {
+            self.load_iv(iv);
+        }
+        self.rb.cr.modify(|_, w| w.en().set_bit());
+        Ok(())
+    }
+
+    /// Processes `data` in place while enabled, the last block may be partial
+    fn payload(&mut self, data: &mut [u8], gcm: bool) -> Result<(), Error> {
+        for chunk in data.chunks_mut(BLOCK_SIZE) {
+            let mut block = [0; BLOCK_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            if gcm && chunk.len() < BLOCK_SIZE {
+                // The padding bytes are left out of the tag
+                let padding = (BLOCK_SIZE - chunk.len()) as u8;
+                self.rb.cr.modify(|_, w| unsafe { w.npblb().bits(padding) });
+            }
+            self.write_block(&block);
+            self.wait()?;
+            let out = self.read_block();
+            chunk.copy_from_slice(&out[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    /// Writes the configuration and the key, the peripheral is disabled
+    fn configure(&mut self, chmod: u8, mode: u8) {
+        self.rb.cr.write(|w| unsafe {
+            w.datatype()
+                .bits(DATATYPE_BYTES)
+                .mode()
+                .bits(mode)
+                .chmod10()
+                .bits(chmod & 0b11)
+                .chmod2()
+                .bit(chmod & 0b100 != 0)
+                .keysize()
+                .bit(self.key256)
+                .ccfc()
+                .set_bit()
+                .errc()
+                .set_bit()
+        });
+        self.load_key();
+    }
+
+    fn load_key(&mut self) {
+        let rb = &self.rb;
+        let key = &self.key;
+        // KEYR0 holds the least significant word of the key
+        let low = if self.key256 { 4 } else { 0 };
+        unsafe {
+            rb.keyr3.write(|w| w.bits(key[low]));
+            rb.keyr2.write(|w| w.bits(key[low + 1]));
+            rb.keyr1.write(|w| w.bits(key[low + 2]));
+            rb.keyr0.write(|w| w.bits(key[low + 3]));
+            if self.key256 {
+                rb.keyr7.write(|w| w.bits(key[0]));
+                rb.keyr6.write(|w| w.bits(key[1]));
+                rb.keyr5.write(|w| w.bits(key[2]));
+                rb.keyr4.write(|w| w.bits(key[3]));
+            }
+        }
+    }
+
+    fn load_iv(&mut self, iv: &[u8; BLOCK_SIZE]) {
+        let word = |i: usize| u32::from_be_bytes([iv[i], iv[i + 1], iv[i + 2], iv[i + 3]]);
+        let rb = &self.rb;
+        unsafe {
+            rb.ivr3.write(|w| w.bits(word(0)));
+            rb.ivr2.write(|w| w.bits(word(4)));
+            rb.ivr1.write(|w| w.bits(word(8)));
+            rb.ivr0.write(|w| w.bits(word(12)));
+        }
+    }
+
+    fn set_phase(&mut self, phase: u8) {
+        self.rb
+            .cr
+            .modify(|_, w| unsafe { w.gcmph().bits(phase).en().set_bit() });
+    }
+
+    fn write_block(&mut self, block: &[u8; BLOCK_SIZE]) {
+        for bytes in block.chunks_exact(4) {
+            let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            self.rb.dinr.write(|w| unsafe { w.bits(word) });
+        }
+    }
+
+    fn read_block(&mut self) -> [u8; BLOCK_SIZE] {
+        let mut block = [0; BLOCK_SIZE];
+        for bytes in block.chunks_exact_mut(4) {
+            bytes.copy_from_slice(&self.rb.doutr.read().bits().to_le_bytes());
+        }
+        block
+    }
+
+    /// Waits for the end of the computation and clears the flag
+    fn wait(&mut self) -> Result<(), Error> {
+        loop {
+            let sr = self.rb.sr.read();
+            if sr.rderr().bit_is_set() || sr.wrerr().bit_is_set() {
+                self.rb.cr.modify(|_, w| w.errc().set_bit());
+                return Err(Error::Sequence);
+            }
+            if sr.ccf().bit_is_set() {
+                self.rb.cr.modify(|_, w| w.ccfc().set_bit());
+                return Ok(());
+            }
+        }
+    }
+
+    fn disable(&mut self) {
+        self.rb.cr.modify(|_, w| {
+            w.en()
+                .clear_bit()
+                .dmainen()
+                .clear_bit()
+                .dmaouten()
+                .clear_bit()
+        });
+    }
+}
+
+/// AES DMA transfer in progress
+///
+/// Dropping the transfer does not stop it, use [`AesTransfer::abort`].
+pub struct AesTransfer<INCH, OUTCH, IN, OUT> {
+    aes: Aes,
+    in_ch: INCH,
+    out_ch: OUTCH,
+    input: IN,
+    output: OUT,
+}
+
+impl<INCH, OUTCH, IN, OUT> AesTransfer<INCH, OUTCH, IN, OUT>
+where
+    INCH: Channel,
+    OUTCH: Channel,
+{
+    /// Returns `true` once the last output block has been read, or the DMA failed
+    pub fn is_done(&self) -> bool {
+        self.out_ch.event_occurred(Event::TransferComplete) || self.is_error()
+    }
+
+    /// Enables the interrupt of the output channel for the given event
+    pub fn listen(&mut self, event: Event) {
+        self.out_ch.listen(event);
+    }
+
+    /// Disables the interrupt of the output channel for the given event
+    pub fn unlisten(&mut self, event: Event) {
+        self.out_ch.unlisten(event);
+    }
+
+    /// Waits for the end of the transfer and releases the resources
+    ///
+    /// The error is returned with the resources if the DMA hit a bus error.
+    #[allow(clippy::type_complexity)]
+    pub fn wait(self) -> Result<(Aes, INCH, OUTCH, IN, OUT), (Aes, INCH, OUTCH, IN, OUT)> {
+        while !self.is_done() {}
+        let failed = self.is_error();
+        let parts = self.abort();
+        if failed {
+            Err(parts)
+        } else {
+            Ok(parts)
+        }
+    }
+
+    /// Stops the transfer and releases the resources
+    pub fn abort(mut self) -> (Aes, INCH, OUTCH, IN, OUT) {
+        self.in_ch.disable();
+        self.out_ch.disable();
+        self.aes.disable();
+        // The buffer accesses of the DMA are done before the buffers are handed back
+        compiler_fence(Ordering::Acquire);
+        (self.aes, self.in_ch, self.out_ch, self.input, self.output)
+    }
+
+    fn is_error(&self) -> bool {
+        self.in_ch.event_occurred(Event::TransferError)
+            || self.out_ch.event_occurred(Event::TransferError)
+    }
+}
+
+/// Sets up a channel for `len` words between a data register and `mem`
+fn setup<CH: Channel>(
+    ch: &mut CH,
+    reg: u32,
+    dir: Direction,
+    mem: u32,
+    len: usize,
+    mux: DmaMuxIndex,
+) {
+    ch.disable();
+    ch.clear_event(Event::Any);
+    ch.set_direction(dir);
+    ch.set_word_size(WordSize::BITS32);
+    ch.set_peripheral_address(reg, false);
+    ch.set_memory_address(mem, true);
+    ch.set_transfer_length(len as u16);
+    ch.set_circular_mode(false);
+    ch.select_peripheral(mux);
+}
+
+#[cfg(feature = "rustcrypto")]
+mod rustcrypto {
+    use aead::consts::{U0, U1, U12, U16};
+    use aead::{AeadCore, AeadMutInPlace, Nonce, Tag};
+    use cipher::inout::InOut;
+    use cipher::{
+        Block, BlockBackend, BlockClosure, BlockDecryptMut, BlockEncryptMut, BlockSizeUser,
+        ParBlocksSizeUser,
+    };
+
+    use super::{Aes, Mode};
+
+    /// Processes the blocks with the configured peripheral
+    struct Backend<'a>(&'a mut Aes);
+
+    impl BlockSizeUser for Backend<'_> {
+        type BlockSize = U16;
+    }
+
+    impl ParBlocksSizeUser for Backend<'_> {
+        type ParBlocksSize = U1;
+    }
+
+    impl BlockBackend for Backend<'_> {
+        fn proc_block(&mut self, mut block: InOut<'_, '_, Block<Self>>) {
+            let mut data = [0; 16];
+            data.copy_from_slice(block.get_in());
+            let res = self.0.payload(&mut data, false);
+            hal_assert!(res.is_ok());
+            block.get_out().copy_from_slice(&data);
+        }
+    }
+
+    impl BlockSizeUser for Aes {
+        type BlockSize = U16;
+    }
+
+    impl BlockEncryptMut for Aes {
+        fn encrypt_with_backend_mut(&mut self, f: impl BlockClosure<BlockSize = U16>) {
+            let res = self.prepare(Mode::Ecb, false);
+            hal_assert!(res.is_ok());
+            f.call(&mut Backend(self));
+            self.disable();
+        }
+    }
+
+    impl BlockDecryptMut for Aes {
+        fn decrypt_with_backend_mut(&mut self, f: impl BlockClosure<BlockSize = U16>) {
+            let res = self.prepare(Mode::Ecb, true);
+            hal_assert!(res.is_ok());
+            f.call(&mut Backend(self));
+            self.disable();
+        }
+    }
+
+    impl AeadCore for Aes {
+        type NonceSize = U12;
+        type TagSize = U16;
+        type CiphertextOverhead = U0;
+    }
+
+    impl AeadMutInPlace for Aes {
+        fn encrypt_in_place_detached(
+            &mut self,
+            nonce: &Nonce<Self>,
+            associated_data: &[u8],
+            buffer: &mut [u8],
+        ) -> aead::Result<Tag<Self>> {
+            self.encrypt_gcm(&(*nonce).into(), associated_data, buffer)
+                .map(Tag::<Self>::from)
+                .map_err(|_| aead::Error)
+        }
+
+        fn decrypt_in_place_detached(
+            &mut self,
+            nonce: &Nonce<Self>,
+            associated_data: &[u8],
+            buffer: &mut [u8],
+            tag: &Tag<Self>,
+        ) -> aead::Result<()> {
+            self.decrypt_gcm(&(*nonce).into(), associated_data, buffer, &(*tag).into())
+                .map_err(|_| aead::Error)
+        }
+    }
+}
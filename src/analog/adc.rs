@@ -1,11 +1,23 @@
 //! # Analog to Digital converter
 use core::ptr;
 
+use crate::debug::{AdcRegisters, Dump};
 use crate::gpio::*;
 use crate::rcc::{Enable, Rcc};
 use crate::stm32::ADC;
 use hal::adc::{Channel, OneShot};
 
+/// VREFINT_CAL: VREFINT conversion result at 30 °C and VDDA = 3.0 V (DS12766 3.13.2)
+const VREFINT_CAL: *const u16 = 0x1FFF_75AA as *const u16;
+/// TS_CAL1: temperature sensor conversion result at 30 °C and VDDA = 3.0 V
+const TS_CAL1: *const u16 = 0x1FFF_75A8 as *const u16;
+/// TS_CAL2: temperature sensor conversion result at 130 °C and VDDA = 3.0 V
+const TS_CAL2: *const u16 = 0x1FFF_75CA as *const u16;
+/// VDDA during the factory calibration, in millivolts
+const CAL_VDDA_MV: u32 = 3_000;
+const TS_CAL1_TEMP: i32 = 30;
+const TS_CAL2_TEMP: i32 = 130;
+
 /// ADC Result Alignment
 #[derive(PartialEq)]
 pub enum Align {
@@ -100,13 +112,42 @@ pub enum InjTrigSource {
     TRG_7 = 0b111, // EXTI11
 }
 
+/// ADC analog watchdog
+///
+/// AWD1 watches a single channel, AWD2 and AWD3 can watch any set of channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watchdog {
+    AWD1,
+    AWD2,
+    AWD3,
+}
+
 /// Analog to Digital converter interface
 pub struct Adc {
-    rb: ADC,
+    pub(crate) rb: ADC,
     sample_time: SampleTime,
     align: Align,
     precision: Precision,
     vdda_mv: Option<u32>,
+    /// CHSELR value of the programmed scan sequence (CHSELRMOD = 1)
+    pub(crate) sequence: u32,
+    pub(crate) sequence_len: u8,
+}
+
+impl Dump for Adc {
+    type Registers = AdcRegisters;
+
+    fn dump(&self) -> AdcRegisters {
+        AdcRegisters {
+            isr: self.rb.isr.read().bits(),
+            ier: self.rb.ier.read().bits(),
+            cr: self.rb.cr.read().bits(),
+            cfgr1: self.rb.cfgr1.read().bits(),
+            cfgr2: self.rb.cfgr2.read().bits(),
+            smpr: self.rb.smpr.read().bits(),
+            chselr: self.rb.chselr().read().bits(),
+        }
+    }
 }
 
 /// Contains the calibration factors for the ADC which can be reused with [`Adc::set_calibration()`]
@@ -126,6 +167,8 @@ impl Adc {
             align: Align::Right,
             precision: Precision::B_12,
             vdda_mv: None,
+            sequence: 0xffff_ffff,
+            sequence_len: 0,
         }
     }
 
@@ -232,29 +275,9 @@ impl Adc {
         &mut self,
         pin: &mut PIN,
     ) -> nb::Result<u16, ()> {
-        let vdda_mv = if let Some(vdda_mv) = self.vdda_mv {
-            vdda_mv
-        } else {
-            let mut vref = VRef::new();
-            let vref_val: u32 = if vref.enabled(self) {
-                self.read(&mut vref)?
-            } else {
-                vref.enable(self);
-                let vref_val = self.read(&mut vref)?;
-                vref.disable(self);
-                vref_val
-            };
-
-            let vref_cal: u32 = unsafe {
-                // DS12766 3.13.2
-                ptr::read_volatile(0x1FFF_75AA as *const u16) as u32
-            };
-
-            // RM0454 14.9 Calculating the actual VDDA voltage using the internal reference voltage
-            // V_DDA = 3 V x VREFINT_CAL / VREFINT_DATA
-            let vdda_mv = vref_cal * 3_000_u32 / vref_val;
-            self.vdda_mv = Some(vdda_mv);
-            vdda_mv
+        let vdda_mv = match self.vdda_mv {
+            Some(vdda_mv) => vdda_mv,
+            None => self.read_vdda()?,
         };
 
         self.read(pin).map(|raw: u32| {
@@ -263,23 +286,233 @@ impl Adc {
         })
     }
 
+    /// Measures VDDA with the internal voltage reference, in millivolts
+    ///
+    /// The result is also cached for the conversions of [`Self::read_voltage()`].
+    pub fn read_vdda(&mut self) -> nb::Result<u32, ()> {
+        let mut vref = VRef::new();
+        let raw = if vref.enabled(self) {
+            self.read_internal(&mut vref)?
+        } else {
+            vref.enable(self);
+            let raw = self.read_internal(&mut vref);
+            vref.disable(self);
+            raw?
+        };
+        let vdda_mv = VRef::vdda_mv(raw);
+        self.vdda_mv = Some(vdda_mv);
+        Ok(vdda_mv)
+    }
+
+    /// Measures the junction temperature, in degrees Celsius
+    ///
+    /// The sensor is enabled for the conversion if needed. It needs its start-up time
+    /// (tSTART in the datasheet) before the first accurate conversion, so keep it enabled
+    /// with [`VTemp::enable()`] when reading it often.
+    pub fn read_temperature(&mut self, temp: &mut VTemp) -> nb::Result<i16, ()> {
+        let vdda_mv = match self.vdda_mv {
+            Some(vdda_mv) => vdda_mv,
+            None => self.read_vdda()?,
+        };
+        let raw = if temp.enabled(self) {
+            self.read_internal(temp)?
+        } else {
+            temp.enable(self);
+            let raw = self.read_internal(temp);
+            temp.disable(self);
+            raw?
+        };
+        Ok(VTemp::celsius(raw, vdda_mv))
+    }
+
+    /// Measures the VBAT pin voltage, in millivolts
+    ///
+    /// The VBAT bridge drains the battery while enabled: unless it was already enabled, it
+    /// is only enabled for the conversion.
+    pub fn read_vbat(&mut self, vbat: &mut VBat) -> nb::Result<u32, ()> {
+        let vdda_mv = match self.vdda_mv {
+            Some(vdda_mv) => vdda_mv,
+            None => self.read_vdda()?,
+        };
+        let raw = if vbat.enabled(self) {
+            self.read_internal(vbat)?
+        } else {
+            vbat.enable(self);
+            let raw = self.read_internal(vbat);
+            vbat.disable(self);
+            raw?
+        };
+        Ok(VBat::millivolts(raw, vdda_mv))
+    }
+
+    /// Converts an internal channel as a right aligned 12 bit result, with the longest
+    /// sampling time
+    ///
+    /// The internal channels need several microseconds of sampling (tS_temp, tS_vrefint and
+    /// tS_vbat in the datasheet), which 160.5 cycles cover at the usual ADC clocks. The
+    /// oversampling settings still apply, their shift must keep 12 bit results.
+    fn read_internal<PIN: Channel<Adc, ID = u8>>(&mut self, pin: &mut PIN) -> nb::Result<u16, ()> {
+        let sample_time = core::mem::replace(&mut self.sample_time, SampleTime::T_160);
+        let precision = core::mem::replace(&mut self.precision, Precision::B_12);
+        let align = core::mem::replace(&mut self.align, Align::Right);
+        let raw = self.read(pin);
+        self.sample_time = sample_time;
+        self.precision = precision;
+        self.align = align;
+        raw
+    }
+
     pub fn release(self) -> ADC {
         self.rb
     }
 
-    fn power_up(&mut self) {
+    /// Gives scoped access to the peripheral registers
+    ///
+    /// # Safety
+    ///
+    /// The driver keeps relying on its own setup, `f` must leave the peripheral clock,
+    /// the voltage regulator and the configuration registers as it found them.
+    pub unsafe fn with_registers<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&ADC) -> R,
+    {
+        f(&self.rb)
+    }
+
+    /// Applies the resolution, alignment and sampling time, and selects a single channel
+    ///
+    /// ADEN must be 0.
+    pub(crate) fn setup_channel(&mut self, channel: u8) {
+        self.rb.cfgr1.modify(|_, w| unsafe {
+            w.res()
+                .bits(self.precision as u8)
+                .align()
+                .bit(self.align == Align::Left)
+        });
+        self.rb
+            .smpr
+            .modify(|_, w| unsafe { w.smp1().bits(self.sample_time as u8) });
+        self.rb
+            .chselr()
+            .modify(|_, w| unsafe { w.chsel().bits(1 << channel) });
+    }
+
+    pub(crate) fn power_up(&mut self) {
         self.rb.isr.modify(|_, w| w.adrdy().set_bit());
         self.rb.cr.modify(|_, w| w.aden().set_bit());
         while self.rb.isr.read().adrdy().bit_is_clear() {}
     }
 
-    fn power_down(&mut self) {
+    pub(crate) fn power_down(&mut self) {
         self.rb.cr.modify(|_, w| w.addis().set_bit());
         self.rb.isr.modify(|_, w| w.adrdy().set_bit());
         while self.rb.cr.read().aden().bit_is_set() {}
     }
 }
 
+impl Adc {
+    /// Flags the conversions of `pin` outside of `[low, high]` with the analog watchdog
+    ///
+    /// Thresholds are 12-bit values, whatever the resolution and alignment of the
+    /// results. The watchdog only sees the conversions done by the ADC, e.g. of a timer
+    /// triggered sequence, so it can check a battery voltage while the core sleeps. AWD2
+    /// and AWD3 keep watching their other channels.
+    ///
+    /// ADSTART must be 0.
+    pub fn enable_watchdog<PIN: Channel<Adc, ID = u8>>(
+        &mut self,
+        watchdog: Watchdog,
+        _pin: &PIN,
+        low: u16,
+        high: u16,
+    ) {
+        hal_assert!(low <= high && high <= 0xfff);
+        let channel = PIN::channel();
+        match watchdog {
+            Watchdog::AWD1 => {
+                self.rb
+                    .awd1tr
+                    .write(|w| unsafe { w.lt1().bits(low).ht1().bits(high) });
+                self.rb.cfgr1.modify(|_, w| unsafe {
+                    w.awd1sgl()
+                        .set_bit()
+                        .awdch1ch()
+                        .bits(channel)
+                        .awd1en()
+                        .set_bit()
+                });
+            }
+            Watchdog::AWD2 => {
+                self.rb
+                    .awd2tr
+                    .write(|w| unsafe { w.lt2().bits(low).ht2().bits(high) });
+                self.rb
+                    .awd2cr
+                    .modify(|r, w| unsafe { w.awd2ch().bits(r.awd2ch().bits() | 1 << channel) });
+            }
+            Watchdog::AWD3 => {
+                self.rb
+                    .awd3tr
+                    .write(|w| unsafe { w.lt3().bits(low).ht3().bits(high) });
+                self.rb
+                    .awd3cr
+                    .modify(|r, w| unsafe { w.awd3ch().bits(r.awd3ch().bits() | 1 << channel) });
+            }
+        }
+    }
+
+    /// Stops a watchdog, for all its channels
+    ///
+    /// ADSTART must be 0.
+    pub fn disable_watchdog(&mut self, watchdog: Watchdog) {
+        match watchdog {
+            Watchdog::AWD1 => self
+                .rb
+                .cfgr1
+                .modify(|_, w| w.awd1en().clear_bit().awd1sgl().clear_bit()),
+            Watchdog::AWD2 => self.rb.awd2cr.reset(),
+            Watchdog::AWD3 => self.rb.awd3cr.reset(),
+        }
+    }
+
+    /// Enables raising the `ADC` interrupt when the watchdog flags a conversion
+    pub fn listen_watchdog(&mut self, watchdog: Watchdog) {
+        self.rb.ier.modify(|_, w| match watchdog {
+            Watchdog::AWD1 => w.awd1ie().set_bit(),
+            Watchdog::AWD2 => w.awd2ie().set_bit(),
+            Watchdog::AWD3 => w.awd3ie().set_bit(),
+        });
+    }
+
+    /// Disables the watchdog interrupt
+    pub fn unlisten_watchdog(&mut self, watchdog: Watchdog) {
+        self.rb.ier.modify(|_, w| match watchdog {
+            Watchdog::AWD1 => w.awd1ie().clear_bit(),
+            Watchdog::AWD2 => w.awd2ie().clear_bit(),
+            Watchdog::AWD3 => w.awd3ie().clear_bit(),
+        });
+    }
+
+    /// Returns true if a conversion was out of the watchdog window since the last clear
+    pub fn is_watchdog_triggered(&self, watchdog: Watchdog) -> bool {
+        let isr = self.rb.isr.read();
+        match watchdog {
+            Watchdog::AWD1 => isr.awd1().bit_is_set(),
+            Watchdog::AWD2 => isr.awd2().bit_is_set(),
+            Watchdog::AWD3 => isr.awd3().bit_is_set(),
+        }
+    }
+
+    /// Clears the watchdog flag
+    pub fn clear_watchdog(&mut self, watchdog: Watchdog) {
+        self.rb.isr.write(|w| match watchdog {
+            Watchdog::AWD1 => w.awd1().set_bit(),
+            Watchdog::AWD2 => w.awd2().set_bit(),
+            Watchdog::AWD3 => w.awd3().set_bit(),
+        });
+    }
+}
+
 pub trait AdcExt {
     fn constrain(self, rcc: &mut Rcc) -> Adc;
 }
@@ -434,6 +667,41 @@ macro_rules! int_adc {
     };
 }
 
+impl VTemp {
+    /// Converts a 12 bit conversion result to degrees Celsius, using the factory
+    /// calibration of the sensor at 30 °C and 130 °C
+    pub fn celsius(raw: u16, vdda_mv: u32) -> i16 {
+        let (cal1, cal2) = unsafe { (ptr::read_volatile(TS_CAL1), ptr::read_volatile(TS_CAL2)) };
+        // RM0444 15.10 Temperature sensor: the calibration values are taken at 3.0 V
+        let raw = (raw as u32 * vdda_mv / CAL_VDDA_MV) as i32;
+        let (cal1, cal2) = (cal1 as i32, cal2 as i32);
+        let temp = (raw - cal1) * (TS_CAL2_TEMP - TS_CAL1_TEMP) / (cal2 - cal1).max(1);
+        (temp + TS_CAL1_TEMP) as i16
+    }
+}
+
+impl VRef {
+    /// Factory VREFINT conversion result at VDDA = 3.0 V
+    pub fn calibration() -> u16 {
+        unsafe { ptr::read_volatile(VREFINT_CAL) }
+    }
+
+    /// Computes VDDA in millivolts from a 12 bit VREFINT conversion result
+    pub fn vdda_mv(raw: u16) -> u32 {
+        // RM0454 14.9 Calculating the actual VDDA voltage using the internal reference voltage
+        // V_DDA = 3 V x VREFINT_CAL / VREFINT_DATA
+        Self::calibration() as u32 * CAL_VDDA_MV / (raw as u32).max(1)
+    }
+}
+
+impl VBat {
+    /// Converts a 12 bit conversion result to the VBAT voltage in millivolts, the channel
+    /// is VBAT divided by 3
+    pub fn millivolts(raw: u16, vdda_mv: u32) -> u32 {
+        (raw as u32 * vdda_mv * 3) >> 12
+    }
+}
+
 int_adc! {
     VTemp: (12, tsen),
     VRef: (13, vrefen),
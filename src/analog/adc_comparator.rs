@@ -0,0 +1,174 @@
+//! # ADC based comparator
+//!
+//! Software comparator for parts without COMP peripheral (or when both are in use): the ADC
+//! converts a channel continuously and the analog watchdog 1 detects threshold crossings.
+//! The thresholds are swapped on every crossing to implement the hysteresis.
+use core::convert::Infallible;
+
+use crate::analog::adc::Adc;
+use crate::analog::ComparatorOutput;
+use hal::adc::Channel;
+use hal::digital::v2::OutputPin;
+
+/// A filler type for when no GPIO output is used
+pub struct NoOutput;
+
+impl OutputPin for NoOutput {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// Software comparator built on continuous ADC conversions and the analog watchdog
+///
+/// Thresholds are raw 12 bits conversion results.
+pub struct AdcComparator<PIN, OUT> {
+    adc: Adc,
+    pin: PIN,
+    out: OUT,
+    threshold: u16,
+    hysteresis: u16,
+    state: bool,
+}
+
+impl<PIN> AdcComparator<PIN, NoOutput>
+where
+    PIN: Channel<Adc, ID = u8>,
+{
+    /// Starts comparing `pin` against `threshold`
+    ///
+    /// The output goes high above `threshold` and back low below `threshold - hysteresis`.
+    pub fn new(mut adc: Adc, pin: PIN, threshold: u16, hysteresis: u16) -> Self {
+        hal_assert!(threshold <= 0xfff && hysteresis <= threshold);
+
+        adc.rb.cfgr1.modify(|_, w| unsafe {
+            w.res()
+                .bits(0b00)
+                .align()
+                .clear_bit()
+                .cont()
+                .set_bit()
+                .ovrmod()
+                .set_bit()
+                .awd1sgl()
+                .set_bit()
+                .awdch1ch()
+                .bits(PIN::channel())
+                .awd1en()
+                .set_bit()
+        });
+        adc.power_up();
+        adc.rb
+            .chselr()
+            .modify(|_, w| unsafe { w.chsel().bits(1 << PIN::channel()) });
+
+        let mut comp = AdcComparator {
+            adc,
+            pin,
+            out: NoOutput,
+            threshold,
+            hysteresis,
+            state: false,
+        };
+        comp.set_window();
+        comp.adc.rb.isr.write(|w| w.awd1().set_bit());
+        comp.adc.rb.cr.modify(|_, w| w.adstart().set_bit());
+        comp
+    }
+
+    /// Drives a GPIO pin with the output of the comparator, updated by [`AdcComparator::update`]
+    pub fn output_pin<OUT: OutputPin>(self, mut out: OUT) -> AdcComparator<PIN, OUT> {
+        if self.state {
+            out.set_high().ok();
+        } else {
+            out.set_low().ok();
+        }
+        AdcComparator {
+            adc: self.adc,
+            pin: self.pin,
+            out,
+            threshold: self.threshold,
+            hysteresis: self.hysteresis,
+            state: self.state,
+        }
+    }
+}
+
+impl<PIN, OUT> AdcComparator<PIN, OUT>
+where
+    OUT: OutputPin,
+{
+    /// Enables raising the `ADC` interrupt when the output changes
+    pub fn listen(&mut self) {
+        self.adc.rb.ier.modify(|_, w| w.awd1ie().set_bit());
+    }
+
+    /// Disables the output change interrupt
+    pub fn unlisten(&mut self) {
+        self.adc.rb.ier.modify(|_, w| w.awd1ie().clear_bit());
+    }
+
+    /// Processes a threshold crossing and returns the output state
+    ///
+    /// Has to be called from the `ADC` interrupt handler or polled.
+    pub fn update(&mut self) -> bool {
+        if self.adc.rb.isr.read().awd1().bit_is_set() {
+            self.state = !self.state;
+
+            self.adc.rb.cr.modify(|_, w| w.adstp().set_bit());
+            while self.adc.rb.cr.read().adstart().bit_is_set() {}
+            self.set_window();
+            self.adc.rb.isr.write(|w| w.awd1().set_bit());
+            self.adc.rb.cr.modify(|_, w| w.adstart().set_bit());
+
+            if self.state {
+                self.out.set_high().ok();
+            } else {
+                self.out.set_low().ok();
+            }
+        }
+        self.state
+    }
+
+    /// Stops the conversions and releases the ADC, input pin and output pin
+    pub fn release(mut self) -> (Adc, PIN, OUT) {
+        self.unlisten();
+        self.adc.rb.cr.modify(|_, w| w.adstp().set_bit());
+        while self.adc.rb.cr.read().adstart().bit_is_set() {}
+        self.adc.rb.cfgr1.modify(|_, w| {
+            w.cont()
+                .clear_bit()
+                .awd1en()
+                .clear_bit()
+                .awd1sgl()
+                .clear_bit()
+        });
+        self.adc.power_down();
+        (self.adc, self.pin, self.out)
+    }
+
+    fn set_window(&mut self) {
+        // The watchdog flags conversions outside of [LT, HT]
+        let (low, high) = if self.state {
+            (self.threshold - self.hysteresis, 0xfff)
+        } else {
+            (0, self.threshold)
+        };
+        self.adc
+            .rb
+            .awd1tr
+            .write(|w| unsafe { w.lt1().bits(low).ht1().bits(high) });
+    }
+}
+
+impl<PIN, OUT> ComparatorOutput for AdcComparator<PIN, OUT> {
+    fn output(&self) -> bool {
+        self.state
+    }
+}
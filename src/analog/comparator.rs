@@ -2,7 +2,7 @@
 
 use core::marker::PhantomData;
 
-use crate::analog::dac;
+use crate::analog::{dac, ComparatorOutput};
 use crate::exti::{Event as ExtiEvent, ExtiExt};
 use crate::gpio::*;
 use crate::rcc::{Clocks, Rcc};
@@ -321,6 +321,12 @@ macro_rules! impl_comparator {
             }
         }
 
+        impl ComparatorOutput for Comparator<$COMP, Enabled> {
+            fn output(&self) -> bool {
+                Comparator::<$COMP, Enabled>::output(self)
+            }
+        }
+
         impl<ED> Comparator<$COMP, ED> {
             /// Disables raising interrupts for the output signal
             pub fn unlisten(&self, exti: &EXTI) {
@@ -410,7 +416,7 @@ macro_rules! impl_window_comparator {
             }
 
             /// Enables raising the `ADC_COMP` interrupt at the specified signal edge
-            pub fn listen(&self, edge: SignalEdge, exti: &mut EXTI) {
+            pub fn listen(&self, edge: SignalEdge, exti: &EXTI) {
                 self.upper.listen(edge, exti)
             }
         }
@@ -444,7 +450,7 @@ macro_rules! impl_window_comparator {
             }
 
             /// Disables raising interrupts for the output signal
-            pub fn unlisten(&self, exti: &mut EXTI) {
+            pub fn unlisten(&self, exti: &EXTI) {
                 self.upper.unlisten(exti)
             }
 
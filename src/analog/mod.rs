@@ -1,5 +1,21 @@
 pub mod adc;
+pub mod adc_comparator;
 #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
 pub mod comparator;
 #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
 pub mod dac;
+pub mod mux_scan;
+#[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+pub mod passthrough;
+pub mod recorder;
+pub mod sequence;
+
+/// Comparator output state
+///
+/// Implemented by the COMP peripheral driver and by the ADC based
+/// [`AdcComparator`](adc_comparator::AdcComparator), so code can be shared between parts
+/// with and without comparators.
+pub trait ComparatorOutput {
+    /// Returns the value of the output of the comparator
+    fn output(&self) -> bool;
+}
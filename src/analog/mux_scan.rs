@@ -0,0 +1,108 @@
+//! # Analog multiplexer scanner
+//!
+//! Cycles the select lines of an external analog multiplexer (e.g. a 74HC4051 in front of
+//! a thermistor or keypad array) and converts its output with the ADC once it has settled,
+//! so many sensors can be read through a single ADC pin.
+use core::convert::Infallible;
+
+use crate::analog::adc::Adc;
+use crate::time::MicroSecond;
+use hal::adc::{Channel, OneShot};
+use hal::digital::v2::OutputPin;
+use hal::timer::CountDown;
+
+/// Conversion result of one multiplexer input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample<L> {
+    /// Label of the input
+    pub label: L,
+    /// Raw conversion result
+    pub value: u16,
+}
+
+/// Scanner of the `N` inputs of a multiplexer with `B` select lines
+///
+/// The select lines are given LSB first, input `i` is selected by driving `i` on them and
+/// is reported with `labels[i]`.
+pub struct MuxScan<S, PIN, TIM, L, const B: usize, const N: usize> {
+    select: [S; B],
+    pin: PIN,
+    tim: TIM,
+    labels: [L; N],
+    settle: MicroSecond,
+    input: usize,
+}
+
+impl<S, PIN, TIM, L, const B: usize, const N: usize> MuxScan<S, PIN, TIM, L, B, N>
+where
+    S: OutputPin<Error = Infallible>,
+    PIN: Channel<Adc, ID = u8>,
+    TIM: CountDown<Time = MicroSecond>,
+    L: Copy,
+{
+    /// Selects the first input and starts its settle time
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no labels, or more than the select lines can address.
+    pub fn new(select: [S; B], pin: PIN, tim: TIM, labels: [L; N], settle: MicroSecond) -> Self {
+        hal_assert!(N > 0 && N <= 1 << B);
+        let mut scan = MuxScan {
+            select,
+            pin,
+            tim,
+            labels,
+            settle,
+            input: 0,
+        };
+        scan.select(0);
+        scan
+    }
+
+    /// Changes the time waited after switching inputs, from the next switch on
+    pub fn set_settle_time(&mut self, settle: MicroSecond) {
+        self.settle = settle;
+    }
+
+    /// Converts the current input once it has settled and switches to the next one
+    ///
+    /// Returns `WouldBlock` while the multiplexer output is settling.
+    pub fn poll(&mut self, adc: &mut Adc) -> nb::Result<Sample<L>, ()> {
+        match self.tim.wait() {
+            Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+            Err(nb::Error::Other(_)) | Ok(()) => {}
+        }
+        let value = adc.read(&mut self.pin)?;
+        let label = self.labels[self.input];
+        self.select((self.input + 1) % N);
+        Ok(Sample { label, value })
+    }
+
+    /// Converts all the inputs, starting with the current one, indexed like the labels
+    #[allow(clippy::result_unit_err)]
+    pub fn scan(&mut self, adc: &mut Adc) -> Result<[u16; N], ()> {
+        let mut values = [0; N];
+        for _ in 0..N {
+            let input = self.input;
+            values[input] = nb::block!(self.poll(adc))?.value;
+        }
+        Ok(values)
+    }
+
+    /// Releases the select lines, the ADC pin and the timer
+    pub fn release(self) -> ([S; B], PIN, TIM) {
+        (self.select, self.pin, self.tim)
+    }
+
+    fn select(&mut self, input: usize) {
+        for (bit, line) in self.select.iter_mut().enumerate() {
+            if input & (1 << bit) != 0 {
+                line.set_high().ok();
+            } else {
+                line.set_low().ok();
+            }
+        }
+        self.input = input;
+        self.tim.start(self.settle);
+    }
+}
@@ -0,0 +1,306 @@
+//! # ADC to DAC pipeline
+//!
+//! Samples one ADC channel and plays the processed samples on a DAC channel at the same
+//! rate. The update event of a timer triggers both the ADC conversions and the DAC
+//! conversions, and two circular DMA transfers move the samples between the peripherals
+//! and a pair of double buffers. The application only runs the transform, on one half of
+//! the buffers while the DMA works on the other half, which is enough for simple audio
+//! effects, filters and loopback tests.
+//!
+//! The output lags the input by the length of the buffers.
+use crate::analog::adc::Adc;
+use crate::analog::dac::{Channel1, Channel2};
+use crate::analog::recorder::TriggerTimer;
+use crate::dma::{self, Channel as DmaChannel};
+use crate::dmamux::DmaMuxIndex;
+use crate::hal::adc::Channel;
+use crate::rcc::Rcc;
+use crate::stm32::*;
+use crate::time::Hertz;
+use crate::timer::Period;
+
+/// Timer able to trigger the DAC conversions on its update event, as well as the ADC ones
+pub trait DacTrigger: TriggerTimer {
+    /// DAC TSELx value of the timer trigger output
+    const TSEL: u8;
+}
+
+impl DacTrigger for TIM1 {
+    const TSEL: u8 = 0b0001;
+}
+
+impl DacTrigger for TIM2 {
+    const TSEL: u8 = 0b0010;
+}
+
+impl DacTrigger for TIM3 {
+    const TSEL: u8 = 0b0011;
+}
+
+impl DacTrigger for TIM6 {
+    const TSEL: u8 = 0b0101;
+}
+
+/// DAC channel fed by the DMA
+pub trait DacDma {
+    /// DMAMUX request of the channel
+    const DMAMUX: DmaMuxIndex;
+
+    /// Address of the 12-bit right aligned data holding register
+    fn data_register(&self) -> u32;
+
+    /// Starts the conversions on the `tsel` trigger, each one requesting the next sample
+    fn enable_dma(&mut self, tsel: u8);
+
+    /// Goes back to the software driven conversions
+    fn disable_dma(&mut self);
+}
+
+macro_rules! dac_dma {
+    ($($CX:ident: ($dmamux:ident, $en:ident, $ten:ident, $tsel:ident, $dmaen:ident, $dhr:ident),)+) => {
+        $(
+            impl<ED> DacDma for $CX<ED> {
+                const DMAMUX: DmaMuxIndex = DmaMuxIndex::$dmamux;
+
+                fn data_register(&self) -> u32 {
+                    let dac = unsafe { &(*DAC::ptr()) };
+                    &dac.$dhr as *const _ as u32
+                }
+
+                fn enable_dma(&mut self, tsel: u8) {
+                    let dac = unsafe { &(*DAC::ptr()) };
+                    // The trigger can only be changed while the channel is disabled
+                    dac.dac_cr.modify(|_, w| w.$en().clear_bit());
+                    dac.dac_cr.modify(|_, w| unsafe {
+                        w.$tsel().bits(tsel).$ten().set_bit().$dmaen().set_bit()
+                    });
+                    dac.dac_cr.modify(|_, w| w.$en().set_bit());
+                }
+
+                fn disable_dma(&mut self) {
+                    let dac = unsafe { &(*DAC::ptr()) };
+                    dac.dac_cr.modify(|_, w| w.$en().clear_bit());
+                    dac.dac_cr
+                        .modify(|_, w| w.$ten().clear_bit().$dmaen().clear_bit());
+                    dac.dac_cr.modify(|_, w| w.$en().set_bit());
+                }
+            }
+        )+
+    }
+}
+
+dac_dma! {
+    Channel1: (DAC_Channel1, en1, ten1, tsel1, dmaen1, dac_dhr12r1),
+    Channel2: (DAC_Channel2, en2, ten2, tsel2, dmaen2, dac_dhr12r2),
+}
+
+/// Pipeline in progress
+pub struct Pipeline<TIM, DAC, INCH, OUTCH, IN = &'static mut [u16], OUT = &'static mut [u16]> {
+    adc: Adc,
+    tim: TIM,
+    dac: DAC,
+    in_ch: INCH,
+    out_ch: OUTCH,
+    input: IN,
+    output: OUT,
+    in_ptr: *const u16,
+    out_ptr: *mut u16,
+    len: usize,
+}
+
+impl Adc {
+    /// Samples `pin` at `rate` and plays the samples processed by [`Pipeline::process`]
+    /// on `dac`
+    ///
+    /// The samples are 12-bit right aligned values on both ends, the ADC has to be set
+    /// up accordingly. `output` is played from the start while the first half of `input`
+    /// is being filled, so it should hold the idle level of the output.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffers have different lengths, or a length that is odd, less than
+    /// 2 or above 65534.
+    #[allow(clippy::too_many_arguments)]
+    pub fn pipeline<PIN, TIM, DAC, INCH, OUTCH, IN, OUT>(
+        mut self,
+        _pin: &mut PIN,
+        tim: TIM,
+        rate: Hertz,
+        mut dac: DAC,
+        mut in_ch: INCH,
+        mut out_ch: OUTCH,
+        mut input: IN,
+        mut output: OUT,
+        rcc: &mut Rcc,
+    ) -> Pipeline<TIM, DAC, INCH, OUTCH, IN, OUT>
+    where
+        PIN: Channel<Adc, ID = u8>,
+        TIM: DacTrigger,
+        DAC: DacDma,
+        INCH: DmaChannel,
+        OUTCH: DmaChannel,
+        IN: dma::WriteBuffer<Word = u16>,
+        OUT: dma::WriteBuffer<Word = u16>,
+    {
+        let (in_ptr, len) = unsafe { input.write_buffer() };
+        let (out_ptr, out_len) = unsafe { output.write_buffer() };
+        assert!(len == out_len, "buffers of different lengths");
+        hal_assert!(len >= 2 && len.is_multiple_of(2) && len < u16::MAX as usize);
+
+        TIM::enable(rcc);
+        TIM::reset(rcc);
+        Period::for_rate::<TIM>(rcc.clocks.apb_tim_clk, rate).apply::<TIM>();
+        TIM::trigger_on_update();
+
+        self.setup_channel(PIN::channel());
+        self.rb.cfgr1.modify(|_, w| unsafe {
+            w.cont()
+                .clear_bit()
+                .exten()
+                .bits(0b01)
+                .extsel()
+                .bits(TIM::EXTSEL)
+                .dmacfg()
+                .set_bit()
+                .dmaen()
+                .set_bit()
+        });
+
+        in_ch.disable();
+        in_ch.set_word_size(dma::WordSize::BITS16);
+        in_ch.set_direction(dma::Direction::FromPeripheral);
+        in_ch.set_circular_mode(true);
+        in_ch.set_peripheral_address(&self.rb.dr as *const _ as u32, false);
+        in_ch.set_memory_address(in_ptr as u32, true);
+        in_ch.set_transfer_length(len as u16);
+        in_ch.select_peripheral(DmaMuxIndex::ADC);
+
+        out_ch.disable();
+        out_ch.set_word_size(dma::WordSize::BITS16);
+        out_ch.set_direction(dma::Direction::FromMemory);
+        out_ch.set_circular_mode(true);
+        out_ch.set_peripheral_address(dac.data_register(), false);
+        out_ch.set_memory_address(out_ptr as u32, true);
+        out_ch.set_transfer_length(len as u16);
+        out_ch.select_peripheral(DAC::DMAMUX);
+
+        in_ch.enable();
+        out_ch.enable();
+        dac.enable_dma(TIM::TSEL);
+
+        self.power_up();
+        self.rb.isr.write(|w| w.ovr().set_bit());
+        self.rb.cr.modify(|_, w| w.adstart().set_bit());
+        TIM::start(false);
+
+        Pipeline {
+            adc: self,
+            tim,
+            dac,
+            in_ch,
+            out_ch,
+            input,
+            output,
+            in_ptr,
+            out_ptr,
+            len,
+        }
+    }
+}
+
+impl<TIM, DAC, INCH, OUTCH, IN, OUT> Pipeline<TIM, DAC, INCH, OUTCH, IN, OUT>
+where
+    TIM: DacTrigger,
+    DAC: DacDma,
+    INCH: DmaChannel,
+    OUTCH: DmaChannel,
+{
+    /// Runs `transform` on the half of the buffers just filled by the ADC, if any
+    ///
+    /// `transform` gets the input samples and fills the output samples of the same
+    /// index. It has to return before the ADC has filled the other half of `input`, the
+    /// call is meant for the interrupt handler of the ADC DMA channel, see
+    /// [`Self::listen`]. Returns `false` if no half was ready.
+    pub fn process<F>(&mut self, mut transform: F) -> bool
+    where
+        F: FnMut(&[u16], &mut [u16]),
+    {
+        let mut ready = false;
+        if self.in_ch.event_occurred(dma::Event::HalfTransfer) {
+            self.in_ch.clear_event(dma::Event::HalfTransfer);
+            self.transform_half(0, &mut transform);
+            ready = true;
+        }
+        if self.in_ch.event_occurred(dma::Event::TransferComplete) {
+            self.in_ch.clear_event(dma::Event::TransferComplete);
+            self.transform_half(self.len / 2, &mut transform);
+            ready = true;
+        }
+        ready
+    }
+
+    /// Runs `transform` on the half of the buffers starting at `offset`
+    fn transform_half<F>(&mut self, offset: usize, transform: &mut F)
+    where
+        F: FnMut(&[u16], &mut [u16]),
+    {
+        let half = self.len / 2;
+        // NOTE(unsafe) the buffers are owned by the pipeline, the DMA only accesses the
+        // other halves while the transform runs
+        let (input, output) = unsafe {
+            (
+                core::slice::from_raw_parts(self.in_ptr.add(offset), half),
+                core::slice::from_raw_parts_mut(self.out_ptr.add(offset), half),
+            )
+        };
+        transform(input, output);
+    }
+
+    /// Enables the interrupts of the ADC DMA channel on each filled half
+    pub fn listen(&mut self) {
+        self.in_ch.listen(dma::Event::HalfTransfer);
+        self.in_ch.listen(dma::Event::TransferComplete);
+    }
+
+    /// Disables the interrupts of the ADC DMA channel
+    pub fn unlisten(&mut self) {
+        self.in_ch.unlisten(dma::Event::HalfTransfer);
+        self.in_ch.unlisten(dma::Event::TransferComplete);
+    }
+
+    /// Returns true if a conversion result was lost, because the DMA was too slow
+    pub fn is_overrun(&self) -> bool {
+        self.adc.rb.isr.read().ovr().bit_is_set()
+    }
+
+    /// Stops the pipeline and returns the resources
+    #[allow(clippy::type_complexity)]
+    pub fn release(mut self) -> (Adc, TIM, DAC, INCH, OUTCH, IN, OUT) {
+        TIM::stop();
+        self.adc.rb.cr.modify(|_, w| w.adstp().set_bit());
+        while self.adc.rb.cr.read().adstart().bit_is_set() {}
+        self.adc.power_down();
+        self.adc.rb.cfgr1.modify(|_, w| unsafe {
+            w.exten()
+                .bits(0b00)
+                .dmacfg()
+                .clear_bit()
+                .dmaen()
+                .clear_bit()
+        });
+        self.dac.disable_dma();
+        self.in_ch.disable();
+        self.out_ch.disable();
+        self.in_ch.clear_event(dma::Event::Any);
+        self.out_ch.clear_event(dma::Event::Any);
+        (
+            self.adc,
+            self.tim,
+            self.dac,
+            self.in_ch,
+            self.out_ch,
+            self.input,
+            self.output,
+        )
+    }
+}
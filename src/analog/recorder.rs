@@ -0,0 +1,164 @@
+//! # Single-shot ADC recorder
+//!
+//! Fills a buffer with samples of one ADC channel taken at a fixed rate: the update event
+//! of a timer triggers the conversions through TRGO, and a DMA channel moves the results
+//! to memory. Useful to capture the step response of a control loop.
+use crate::analog::adc::Adc;
+use crate::dma::{self, Channel as DmaChannel};
+use crate::dmamux::DmaMuxIndex;
+use crate::hal::adc::Channel;
+use crate::rcc::Rcc;
+use crate::stm32::*;
+use crate::time::Hertz;
+use crate::timer::{Instance, Period};
+
+/// Timer able to trigger ADC conversions on its update event
+pub trait TriggerTimer: Instance {
+    /// ADC EXTSEL value of the timer trigger output
+    const EXTSEL: u8;
+
+    /// Routes the update event to the trigger outputs used by the ADC and the DAC
+    fn trigger_on_update();
+}
+
+macro_rules! trigger_timers {
+    ($($TIM:ident: ($extsel:expr, $($mms:ident),+),)+) => {
+        $(
+            impl TriggerTimer for $TIM {
+                const EXTSEL: u8 = $extsel;
+
+                fn trigger_on_update() {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    $(tim.cr2.modify(|_, w| unsafe { w.$mms().bits(0b010) });)+
+                }
+            }
+        )+
+    }
+}
+
+trigger_timers! {
+    // The ADC is triggered by TRGO2 of TIM1, the DAC by TRGO
+    TIM1: (0b000, mms2, mms),
+    TIM3: (0b011, mms),
+}
+
+#[cfg(feature = "stm32g0x1")]
+trigger_timers! {
+    TIM2: (0b010, mms),
+}
+
+#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
+trigger_timers! {
+    TIM6: (0b101, mms),
+}
+
+/// Recording in progress
+pub struct Recorder<TIM, CH, BUF = &'static mut [u16]> {
+    adc: Adc,
+    tim: TIM,
+    ch: CH,
+    buf: BUF,
+    len: usize,
+}
+
+impl Adc {
+    /// Records `buf.len()` samples of `pin` at `rate`
+    ///
+    /// The recording starts immediately, use [`Recorder::is_done`] or the DMA transfer
+    /// complete interrupt to know when the buffer is full.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record<PIN, TIM, CH, BUF>(
+        mut self,
+        _pin: &mut PIN,
+        tim: TIM,
+        rate: Hertz,
+        ch: CH,
+        mut buf: BUF,
+        rcc: &mut Rcc,
+    ) -> Recorder<TIM, CH, BUF>
+    where
+        PIN: Channel<Adc, ID = u8>,
+        TIM: TriggerTimer,
+        CH: DmaChannel,
+        BUF: dma::WriteBuffer<Word = u16>,
+    {
+        let (ptr, len) = unsafe { buf.write_buffer() };
+        hal_assert!(len > 0 && len <= u16::MAX as usize);
+
+        TIM::enable(rcc);
+        TIM::reset(rcc);
+        Period::for_rate::<TIM>(rcc.clocks.apb_tim_clk, rate).apply::<TIM>();
+        TIM::trigger_on_update();
+
+        self.setup_channel(PIN::channel());
+        self.rb.cfgr1.modify(|_, w| unsafe {
+            w.cont()
+                .clear_bit()
+                .exten()
+                .bits(0b01)
+                .extsel()
+                .bits(TIM::EXTSEL)
+                .dmacfg()
+                .clear_bit()
+                .dmaen()
+                .set_bit()
+        });
+
+        let mut ch = ch;
+        ch.disable();
+        ch.set_word_size(dma::WordSize::BITS16);
+        ch.set_direction(dma::Direction::FromPeripheral);
+        ch.set_circular_mode(false);
+        ch.set_peripheral_address(&self.rb.dr as *const _ as u32, false);
+        ch.set_memory_address(ptr as u32, true);
+        ch.set_transfer_length(len as u16);
+        ch.select_peripheral(DmaMuxIndex::ADC);
+        ch.enable();
+
+        self.power_up();
+        self.rb.isr.write(|w| w.ovr().set_bit());
+        self.rb.cr.modify(|_, w| w.adstart().set_bit());
+        TIM::start(false);
+
+        Recorder {
+            adc: self,
+            tim,
+            ch,
+            buf,
+            len,
+        }
+    }
+}
+
+impl<TIM: TriggerTimer, CH: DmaChannel, BUF> Recorder<TIM, CH, BUF> {
+    /// Number of samples recorded so far
+    pub fn recorded(&self) -> usize {
+        self.len - self.ch.get_remaining_transfers() as usize
+    }
+
+    /// Returns true once the buffer is full
+    pub fn is_done(&self) -> bool {
+        self.ch.event_occurred(dma::Event::TransferComplete)
+    }
+
+    /// Returns true if a conversion result was lost, because the DMA was too slow
+    pub fn is_overrun(&self) -> bool {
+        self.adc.rb.isr.read().ovr().bit_is_set()
+    }
+
+    /// Stops the recording and returns the resources, the buffer holds
+    /// [`recorded`](Recorder::recorded) samples
+    pub fn release(mut self) -> (Adc, TIM, CH, BUF) {
+        TIM::stop();
+        self.adc.rb.cr.modify(|_, w| w.adstp().set_bit());
+        while self.adc.rb.cr.read().adstart().bit_is_set() {}
+        self.adc.power_down();
+        self.adc
+            .rb
+            .cfgr1
+            .modify(|_, w| unsafe { w.exten().bits(0b00).dmaen().clear_bit() });
+        self.ch.disable();
+        self.ch.clear_event(dma::Event::Any);
+        (self.adc, self.tim, self.ch, self.buf)
+    }
+}
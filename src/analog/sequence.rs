@@ -0,0 +1,278 @@
+//! # ADC scan sequences
+//!
+//! Converts up to eight channels in a programmed order (fully configurable sequencer,
+//! CHSELRMOD = 1) and lets a DMA channel store one result per channel, so several analog
+//! inputs are sampled without any CPU involvement. A scan runs on request, back to back,
+//! or on each update event of a timer to sample all the channels at a fixed rate.
+use crate::analog::adc::Adc;
+use crate::analog::recorder::TriggerTimer;
+use crate::dma::{self, Channel as DmaChannel};
+use crate::dmamux::DmaMuxIndex;
+use crate::rcc::Rcc;
+use crate::time::Hertz;
+use crate::timer::Period;
+
+/// Maximum number of conversions in a sequence
+pub const MAX_SEQUENCE_LEN: usize = 8;
+/// Highest channel reachable by the sequencer, 15 ends the sequence
+const SEQUENCE_CHANNEL_MAX: u8 = 14;
+
+/// Start of the scans of a sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanMode {
+    /// One scan per [`Sequence::trigger`], the buffer is filled once
+    Single,
+    /// Scans back to back, the buffer is used as a ring
+    Continuous,
+}
+
+/// Sequence conversions in progress
+///
+/// `TIM` is the timer triggering the scans, or `()` for software started scans.
+pub struct Sequence<CH, TIM, BUF = &'static mut [u16]> {
+    adc: Adc,
+    ch: CH,
+    tim: TIM,
+    buf: BUF,
+    ptr: *const u16,
+    len: usize,
+}
+
+impl Adc {
+    /// Programs the conversion order of the scans, by ADC channel number (as given by
+    /// `Channel::channel()`)
+    ///
+    /// The same channel may appear several times. The resolution, alignment and sampling
+    /// time in effect when the sequence is started apply to all the conversions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the sequence is empty, longer than [`MAX_SEQUENCE_LEN`], or uses a channel
+    /// above 14, which the sequencer cannot select.
+    pub fn configure_sequence(&mut self, channels: &[u8]) {
+        hal_assert!(!channels.is_empty() && channels.len() <= MAX_SEQUENCE_LEN);
+        let mut sequence = 0xffff_ffff;
+        for (i, &channel) in channels.iter().enumerate() {
+            hal_assert!(channel <= SEQUENCE_CHANNEL_MAX);
+            let offset = 4 * i;
+            sequence = sequence & !(0xf << offset) | (channel as u32) << offset;
+        }
+        self.sequence = sequence;
+        self.sequence_len = channels.len() as u8;
+    }
+
+    /// Starts converting the sequence into `buf` with DMA
+    ///
+    /// In [`ScanMode::Single`] the first scan starts immediately and fills the start of
+    /// `buf`, each [`Sequence::trigger`] fills the next part. In [`ScanMode::Continuous`]
+    /// the buffer is overwritten in a loop.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no sequence is configured, or if the length of `buf` is not a multiple of
+    /// the sequence length.
+    pub fn start_sequence_dma<CH, BUF>(
+        mut self,
+        ch: CH,
+        mut buf: BUF,
+        mode: ScanMode,
+    ) -> Sequence<CH, (), BUF>
+    where
+        CH: DmaChannel,
+        BUF: dma::WriteBuffer<Word = u16>,
+    {
+        let continuous = mode == ScanMode::Continuous;
+        let (ptr, len) = unsafe { buf.write_buffer() };
+        let ch = self.setup_sequence(ch, ptr, len, continuous);
+        self.rb
+            .cfgr1
+            .modify(|_, w| unsafe { w.cont().bit(continuous).exten().bits(0b00) });
+        self.rb.cr.modify(|_, w| w.adstart().set_bit());
+        Sequence {
+            adc: self,
+            ch,
+            tim: (),
+            buf,
+            ptr,
+            len,
+        }
+    }
+
+    /// Starts converting the sequence `rate` times per second into `buf`, used as a ring
+    ///
+    /// The update event of `tim` starts each scan through TRGO.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no sequence is configured, or if the length of `buf` is not a multiple of
+    /// the sequence length.
+    pub fn start_sequence_timed<TIM, CH, BUF>(
+        mut self,
+        tim: TIM,
+        rate: Hertz,
+        ch: CH,
+        mut buf: BUF,
+        rcc: &mut Rcc,
+    ) -> Sequence<CH, TIM, BUF>
+    where
+        TIM: TriggerTimer,
+        CH: DmaChannel,
+        BUF: dma::WriteBuffer<Word = u16>,
+    {
+        TIM::enable(rcc);
+        TIM::reset(rcc);
+        Period::for_rate::<TIM>(rcc.clocks.apb_tim_clk, rate).apply::<TIM>();
+        TIM::trigger_on_update();
+
+        let (ptr, len) = unsafe { buf.write_buffer() };
+        let ch = self.setup_sequence(ch, ptr, len, true);
+        self.rb.cfgr1.modify(|_, w| unsafe {
+            w.cont()
+                .clear_bit()
+                .exten()
+                .bits(0b01)
+                .extsel()
+                .bits(TIM::EXTSEL)
+        });
+        self.rb.cr.modify(|_, w| w.adstart().set_bit());
+        TIM::start(false);
+        Sequence {
+            adc: self,
+            ch,
+            tim,
+            buf,
+            ptr,
+            len,
+        }
+    }
+
+    fn setup_sequence<CH: DmaChannel>(
+        &mut self,
+        mut ch: CH,
+        buf: *mut u16,
+        buf_len: usize,
+        circular: bool,
+    ) -> CH {
+        let len = self.sequence_len as usize;
+        hal_assert!(len > 0, "no sequence configured");
+        hal_assert!(buf_len > 0 && buf_len.is_multiple_of(len) && buf_len <= u16::MAX as usize);
+
+        // Resolution, alignment and sampling time, the channel selection is overwritten below
+        self.setup_channel(0);
+        self.rb.cfgr1.modify(|_, w| {
+            w.chselrmod()
+                .set_bit()
+                .dmacfg()
+                .bit(circular)
+                .dmaen()
+                .set_bit()
+        });
+        self.rb.isr.write(|w| w.ccrdy().set_bit());
+        self.rb
+            .chselr_1()
+            .write(|w| unsafe { w.bits(self.sequence) });
+        while self.rb.isr.read().ccrdy().bit_is_clear() {}
+
+        ch.disable();
+        ch.set_word_size(dma::WordSize::BITS16);
+        ch.set_direction(dma::Direction::FromPeripheral);
+        ch.set_circular_mode(circular);
+        ch.set_peripheral_address(&self.rb.dr as *const _ as u32, false);
+        ch.set_memory_address(buf as u32, true);
+        ch.set_transfer_length(buf_len as u16);
+        ch.select_peripheral(DmaMuxIndex::ADC);
+        ch.enable();
+
+        self.power_up();
+        self.rb.isr.write(|w| w.ovr().set_bit().eos().set_bit());
+        ch
+    }
+}
+
+impl<CH: DmaChannel, TIM, BUF> Sequence<CH, TIM, BUF> {
+    /// Number of conversions per scan
+    pub fn sequence_len(&self) -> usize {
+        self.adc.sequence_len as usize
+    }
+
+    /// Number of results written to the buffer since its start, or since the last wrap
+    pub fn position(&self) -> usize {
+        self.len - self.ch.get_remaining_transfers() as usize
+    }
+
+    /// Copies the results of the last complete scan into `out`, in sequence order
+    ///
+    /// Returns `false` if no scan has completed yet. With fast scans the DMA may overwrite
+    /// the results while they are copied, the scan rate has to leave time for the copy.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `out` is shorter than the sequence.
+    pub fn last_scan(&self, out: &mut [u16]) -> bool {
+        let len = self.sequence_len();
+        let scans = self.position() / len;
+        let wrapped = self.ch.event_occurred(dma::Event::TransferComplete);
+        let start = match scans {
+            0 if !wrapped => return false,
+            0 => self.len - len,
+            n => (n - 1) * len,
+        };
+        // NOTE(unsafe) the buffer is owned by the sequence, only the DMA writes to it
+        let scan = unsafe { core::slice::from_raw_parts(self.ptr.add(start), len) };
+        out[..len].copy_from_slice(scan);
+        true
+    }
+
+    /// Starts the next scan of a [`ScanMode::Single`] sequence
+    pub fn trigger(&mut self) {
+        self.adc.rb.cr.modify(|_, w| w.adstart().set_bit());
+    }
+
+    /// Returns true once the end of the buffer has been reached
+    pub fn is_done(&self) -> bool {
+        self.ch.event_occurred(dma::Event::TransferComplete)
+    }
+
+    /// Returns true if a conversion result was lost, because the DMA was too slow
+    pub fn is_overrun(&self) -> bool {
+        self.adc.rb.isr.read().ovr().bit_is_set()
+    }
+
+    fn stop(&mut self) {
+        self.adc.rb.cr.modify(|_, w| w.adstp().set_bit());
+        while self.adc.rb.cr.read().adstart().bit_is_set() {}
+        self.adc.power_down();
+        self.adc.rb.cfgr1.modify(|_, w| unsafe {
+            w.cont()
+                .clear_bit()
+                .exten()
+                .bits(0b00)
+                .chselrmod()
+                .clear_bit()
+                .dmacfg()
+                .clear_bit()
+                .dmaen()
+                .clear_bit()
+        });
+        self.ch.disable();
+        self.ch.clear_event(dma::Event::Any);
+    }
+}
+
+impl<CH: DmaChannel, BUF> Sequence<CH, (), BUF> {
+    /// Stops the conversions and returns the resources, the sequence stays configured
+    pub fn release(mut self) -> (Adc, CH, BUF) {
+        self.stop();
+        (self.adc, self.ch, self.buf)
+    }
+}
+
+impl<CH: DmaChannel, TIM: TriggerTimer, BUF> Sequence<CH, TIM, BUF> {
+    /// Stops the timer and the conversions and returns the resources, the sequence stays
+    /// configured
+    pub fn release(mut self) -> (Adc, TIM, CH, BUF) {
+        TIM::stop();
+        self.stop();
+        (self.adc, self.tim, self.ch, self.buf)
+    }
+}
@@ -0,0 +1,344 @@
+//! # Bit-banged I2C and SPI masters
+//!
+//! Software implementations of the I2C and SPI master traits on any GPIO pins, for when
+//! the hardware instances are all taken or the pins have no I2C/SPI alternate function.
+//! The bit timing comes from a delay provider, so the actual bus frequency is lower than
+//! requested because of the GPIO and function call overhead.
+use core::convert::Infallible;
+use hal::blocking::delay::DelayUs;
+use hal::blocking::i2c::{Read, Write, WriteRead};
+use hal::digital::v2::{InputPin, OutputPin};
+use hal::spi::{FullDuplex, Mode, Phase, Polarity};
+
+use crate::i2c::Error;
+use crate::time::Hertz;
+
+/// Number of half periods a slave can stretch the clock before the transfer is aborted
+const STRETCH_TIMEOUT: u32 = 1_000;
+
+/// Software I2C master
+///
+/// SDA and SCL have to be open drain outputs, with pull-ups on the bus (or the internal
+/// ones, see [`with_pull`](crate::gpio::gpioa::PA0::with_pull)). Clock stretching by the
+/// slaves is supported.
+pub struct SoftI2c<SDA, SCL, DELAY> {
+    sda: SDA,
+    scl: SCL,
+    delay: DELAY,
+    half_period_us: u32,
+}
+
+impl<SDA, SCL, DELAY> SoftI2c<SDA, SCL, DELAY>
+where
+    SDA: OutputPin<Error = Infallible> + InputPin<Error = Infallible>,
+    SCL: OutputPin<Error = Infallible> + InputPin<Error = Infallible>,
+    DELAY: DelayUs<u32>,
+{
+    /// Creates the master and releases the bus, `freq` is at most 500 kHz
+    pub fn new(mut sda: SDA, mut scl: SCL, delay: DELAY, freq: Hertz) -> Self {
+        hal_assert!(freq.raw() > 0);
+        sda.set_high().ok();
+        scl.set_high().ok();
+        SoftI2c {
+            sda,
+            scl,
+            delay,
+            half_period_us: (500_000 / freq.raw()).max(1),
+        }
+    }
+
+    /// Releases the pins and the delay provider
+    pub fn release(self) -> (SDA, SCL, DELAY) {
+        (self.sda, self.scl, self.delay)
+    }
+
+    fn wait(&mut self) {
+        self.delay.delay_us(self.half_period_us);
+    }
+
+    fn scl_high(&mut self) -> Result<(), Error> {
+        self.scl.set_high().ok();
+        let mut timeout = STRETCH_TIMEOUT;
+        while !self.scl.is_high().unwrap_or(true) {
+            timeout -= 1;
+            if timeout == 0 {
+                return Err(Error::BusError);
+            }
+            self.wait();
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        // Also used for repeated starts, SCL may be low
+        self.sda.set_high().ok();
+        self.wait();
+        self.scl_high()?;
+        if self.sda.is_low().unwrap_or(false) {
+            return Err(Error::ArbitrationLost);
+        }
+        self.wait();
+        self.sda.set_low().ok();
+        self.wait();
+        self.scl.set_low().ok();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        self.sda.set_low().ok();
+        self.wait();
+        self.scl_high()?;
+        self.wait();
+        self.sda.set_high().ok();
+        self.wait();
+        Ok(())
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), Error> {
+        if bit {
+            self.sda.set_high().ok();
+        } else {
+            self.sda.set_low().ok();
+        }
+        self.wait();
+        self.scl_high()?;
+        if bit && self.sda.is_low().unwrap_or(false) {
+            return Err(Error::ArbitrationLost);
+        }
+        self.wait();
+        self.scl.set_low().ok();
+        Ok(())
+    }
+
+    fn read_bit(&mut self) -> Result<bool, Error> {
+        self.sda.set_high().ok();
+        self.wait();
+        self.scl_high()?;
+        let bit = self.sda.is_high().unwrap_or(false);
+        self.wait();
+        self.scl.set_low().ok();
+        Ok(bit)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Error> {
+        for i in (0..8).rev() {
+            self.write_bit(byte & (1 << i) != 0)?;
+        }
+        if self.read_bit()? {
+            return Err(Error::Nack);
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self, ack: bool) -> Result<u8, Error> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = (byte << 1) | self.read_bit()? as u8;
+        }
+        self.write_bit(!ack)?;
+        Ok(byte)
+    }
+
+    fn write_bytes(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.write_byte(addr << 1)?;
+        for byte in bytes {
+            self.write_byte(*byte)?;
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.write_byte((addr << 1) | 1)?;
+        let last = buffer.len().saturating_sub(1);
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read_byte(i != last)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a stop condition if the transfer failed, unless the bus was lost
+    fn finish(&mut self, result: Result<(), Error>) -> Result<(), Error> {
+        match result {
+            Err(Error::ArbitrationLost) => result,
+            Err(err) => {
+                self.stop().ok();
+                Err(err)
+            }
+            Ok(()) => self.stop(),
+        }
+    }
+}
+
+impl<SDA, SCL, DELAY> Write for SoftI2c<SDA, SCL, DELAY>
+where
+    SDA: OutputPin<Error = Infallible> + InputPin<Error = Infallible>,
+    SCL: OutputPin<Error = Infallible> + InputPin<Error = Infallible>,
+    DELAY: DelayUs<u32>,
+{
+    type Error = Error;
+
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        let result = self.start().and_then(|_| self.write_bytes(addr, bytes));
+        self.finish(result)
+    }
+}
+
+impl<SDA, SCL, DELAY> Read for SoftI2c<SDA, SCL, DELAY>
+where
+    SDA: OutputPin<Error = Infallible> + InputPin<Error = Infallible>,
+    SCL: OutputPin<Error = Infallible> + InputPin<Error = Infallible>,
+    DELAY: DelayUs<u32>,
+{
+    type Error = Error;
+
+    fn read(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let result = self.start().and_then(|_| self.read_bytes(addr, buffer));
+        self.finish(result)
+    }
+}
+
+impl<SDA, SCL, DELAY> WriteRead for SoftI2c<SDA, SCL, DELAY>
+where
+    SDA: OutputPin<Error = Infallible> + InputPin<Error = Infallible>,
+    SCL: OutputPin<Error = Infallible> + InputPin<Error = Infallible>,
+    DELAY: DelayUs<u32>,
+{
+    type Error = Error;
+
+    fn write_read(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let result = self
+            .start()
+            .and_then(|_| self.write_bytes(addr, bytes))
+            .and_then(|_| self.start())
+            .and_then(|_| self.read_bytes(addr, buffer));
+        self.finish(result)
+    }
+}
+
+/// Software SPI master, MSB first
+///
+/// SCK and MOSI are push-pull outputs and MISO an input. The chip select is left to the
+/// application.
+pub struct SoftSpi<SCK, MISO, MOSI, DELAY> {
+    sck: SCK,
+    miso: MISO,
+    mosi: MOSI,
+    delay: DELAY,
+    mode: Mode,
+    half_period_us: u32,
+    received: Option<u8>,
+}
+
+impl<SCK, MISO, MOSI, DELAY> SoftSpi<SCK, MISO, MOSI, DELAY>
+where
+    SCK: OutputPin<Error = Infallible>,
+    MISO: InputPin<Error = Infallible>,
+    MOSI: OutputPin<Error = Infallible>,
+    DELAY: DelayUs<u32>,
+{
+    /// Creates the master and drives the clock to its idle level, `freq` is at most 500 kHz
+    pub fn new(sck: SCK, miso: MISO, mosi: MOSI, delay: DELAY, mode: Mode, freq: Hertz) -> Self {
+        hal_assert!(freq.raw() > 0);
+        let mut spi = SoftSpi {
+            sck,
+            miso,
+            mosi,
+            delay,
+            mode,
+            half_period_us: (500_000 / freq.raw()).max(1),
+            received: None,
+        };
+        spi.set_sck(false);
+        spi
+    }
+
+    /// Releases the pins and the delay provider
+    pub fn release(self) -> (SCK, MISO, MOSI, DELAY) {
+        (self.sck, self.miso, self.mosi, self.delay)
+    }
+
+    /// Drives the clock to its idle level, or to the active one
+    fn set_sck(&mut self, active: bool) {
+        let idle_high = self.mode.polarity == Polarity::IdleHigh;
+        if active != idle_high {
+            self.sck.set_high().ok();
+        } else {
+            self.sck.set_low().ok();
+        }
+    }
+
+    fn set_mosi(&mut self, bit: bool) {
+        if bit {
+            self.mosi.set_high().ok();
+        } else {
+            self.mosi.set_low().ok();
+        }
+    }
+
+    /// Exchanges a byte
+    pub fn transfer_byte(&mut self, byte: u8) -> u8 {
+        let mut received = 0;
+        for i in (0..8).rev() {
+            let bit = byte & (1 << i) != 0;
+            match self.mode.phase {
+                Phase::CaptureOnFirstTransition => {
+                    self.set_mosi(bit);
+                    self.delay.delay_us(self.half_period_us);
+                    self.set_sck(true);
+                    received = (received << 1) | self.miso.is_high().unwrap_or(false) as u8;
+                    self.delay.delay_us(self.half_period_us);
+                    self.set_sck(false);
+                }
+                Phase::CaptureOnSecondTransition => {
+                    self.set_sck(true);
+                    self.set_mosi(bit);
+                    self.delay.delay_us(self.half_period_us);
+                    self.set_sck(false);
+                    received = (received << 1) | self.miso.is_high().unwrap_or(false) as u8;
+                    self.delay.delay_us(self.half_period_us);
+                }
+            }
+        }
+        received
+    }
+}
+
+impl<SCK, MISO, MOSI, DELAY> FullDuplex<u8> for SoftSpi<SCK, MISO, MOSI, DELAY>
+where
+    SCK: OutputPin<Error = Infallible>,
+    MISO: InputPin<Error = Infallible>,
+    MOSI: OutputPin<Error = Infallible>,
+    DELAY: DelayUs<u32>,
+{
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        self.received.take().ok_or(nb::Error::WouldBlock)
+    }
+
+    fn send(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        let received = self.transfer_byte(byte);
+        self.received = Some(received);
+        Ok(())
+    }
+}
+
+impl<SCK, MISO, MOSI, DELAY> hal::blocking::spi::transfer::Default<u8>
+    for SoftSpi<SCK, MISO, MOSI, DELAY>
+where
+    SCK: OutputPin<Error = Infallible>,
+    MISO: InputPin<Error = Infallible>,
+    MOSI: OutputPin<Error = Infallible>,
+    DELAY: DelayUs<u32>,
+{
+}
+
+impl<SCK, MISO, MOSI, DELAY> hal::blocking::spi::write::Default<u8>
+    for SoftSpi<SCK, MISO, MOSI, DELAY>
+where
+    SCK: OutputPin<Error = Infallible>,
+    MISO: InputPin<Error = Infallible>,
+    MOSI: OutputPin<Error = Infallible>,
+    DELAY: DelayUs<u32>,
+{
+}
@@ -14,6 +14,12 @@
 //!
 //! let result = crc.result();
 //! assert!(result == 0x78cb);
+//!
+//! // Or a preset, fed as the frame comes in
+//! let mut crc = dp.CRC.constrain(&mut rcc).crc16_modbus().freeze();
+//! crc.update(&[0x01, 0x03]);
+//! let result = crc.update(&[0x00, 0x00, 0x00, 0x01]);
+//! assert!(result == 0x0a84);
 //! ```
 
 #![deny(missing_docs)]
@@ -40,6 +46,7 @@ impl CrcExt for CRC {
             polynomial: Polynomial::L32(0x04c1_1db7),
             input_bit_reversal: None,
             output_bit_reversal: false,
+            output_xor: 0,
         }
     }
 }
@@ -72,6 +79,7 @@ pub struct Config {
     polynomial: Polynomial,
     input_bit_reversal: Option<BitReversal>,
     output_bit_reversal: bool,
+    output_xor: u32,
 }
 
 impl Config {
@@ -103,16 +111,51 @@ impl Config {
         self
     }
 
+    /// Sets the value XORed with the results, in software as the unit has no final XOR.
+    pub fn output_xor(mut self, xor: u32) -> Self {
+        self.output_xor = xor;
+
+        self
+    }
+
+    /// Configures the CRC-32 of Ethernet, zlib and PNG.
+    pub fn crc32(self) -> Self {
+        self.polynomial(Polynomial::L32(0x04c1_1db7))
+            .initial_value(0xffff_ffff)
+            .input_bit_reversal(Some(BitReversal::ByByte))
+            .output_bit_reversal(true)
+            .output_xor(0xffff_ffff)
+    }
+
+    /// Configures the CRC-16 of Modbus RTU, which is sent low byte first.
+    pub fn crc16_modbus(self) -> Self {
+        self.polynomial(Polynomial::L16(0x8005))
+            .initial_value(0xffff)
+            .input_bit_reversal(Some(BitReversal::ByByte))
+            .output_bit_reversal(true)
+            .output_xor(0)
+    }
+
+    /// Configures the CRC-16/CCITT-FALSE, as used by many radio frame formats.
+    pub fn crc16_ccitt_false(self) -> Self {
+        self.polynomial(Polynomial::L16(0x1021))
+            .initial_value(0xffff)
+            .input_bit_reversal(None)
+            .output_bit_reversal(false)
+            .output_xor(0)
+    }
+
     /// Freezes the peripheral, making the configuration take effect.
     pub fn freeze(self) -> Crc {
         let crc = unsafe { &(*CRC::ptr()) };
 
-        let (poly, poly_bits, init) = match self.polynomial {
-            Polynomial::L7(val) => ((val & 0x7f) as u32, 0b11, self.initial_value & 0x7f),
-            Polynomial::L8(val) => (val as u32, 0b10, self.initial_value & 0xff),
-            Polynomial::L16(val) => (val as u32, 0b01, self.initial_value & 0xffff),
-            Polynomial::L32(val) => (val, 0b00, self.initial_value),
+        let (poly, poly_bits, mask) = match self.polynomial {
+            Polynomial::L7(val) => ((val & 0x7f) as u32, 0b11, 0x7f),
+            Polynomial::L8(val) => (val as u32, 0b10, 0xff),
+            Polynomial::L16(val) => (val as u32, 0b01, 0xffff),
+            Polynomial::L32(val) => (val, 0b00, 0xffff_ffff),
         };
+        let init = self.initial_value & mask;
 
         let in_rev_bits = match self.input_bit_reversal {
             None => 0b00,
@@ -140,12 +183,19 @@ impl Config {
             }
         });
 
-        Crc {}
+        Crc {
+            mask,
+            output_xor: self.output_xor & mask,
+        }
     }
 }
 
 /// Constrained CRC peripheral.
-pub struct Crc {}
+pub struct Crc {
+    /// Result bits of the polynomial size
+    mask: u32,
+    output_xor: u32,
+}
 
 impl Crc {
     /// This will reset the CRC to its initial condition.
@@ -172,16 +222,23 @@ impl Crc {
     /// Feed the CRC with data
     #[inline]
     pub fn feed(&mut self, data: &[u8]) {
-        let crc = unsafe { &(*CRC::ptr()) };
         for byte in data {
             unsafe {
                 // Workaround with svd2rust, it does not generate the byte interface to the DR
                 // register
-                ptr::write_volatile(&crc.dr as *const _ as *mut u8, *byte);
+                ptr::write_volatile(ptr::addr_of!((*CRC::ptr()).dr) as *mut u8, *byte);
             }
         }
     }
 
+    /// Feeds the CRC with the next part of a message, and returns the CRC of the message so
+    /// far, without resetting the CRC.
+    #[inline]
+    pub fn update(&mut self, data: &[u8]) -> u32 {
+        self.feed(data);
+        self.peek_result()
+    }
+
     /// Get the result of the CRC, depending on the polynomial chosen only a certain amount of the
     /// bits are the result. This will reset the CRC peripheral after use.
     #[inline]
@@ -193,13 +250,13 @@ impl Crc {
         ret
     }
 
-    /// Get a peed at the result of the CRC, depending on the polynomial chosen only a certain
-    /// amount of the bits are the result.
+    /// Get a peek at the result of the CRC, the bits above the polynomial size are cleared and
+    /// the output XOR is applied.
     #[inline]
     pub fn peek_result(&self) -> u32 {
         let crc = unsafe { &(*CRC::ptr()) };
 
-        crc.dr.read().bits()
+        (crc.dr.read().bits() & self.mask) ^ self.output_xor
     }
 }
 
@@ -0,0 +1,82 @@
+//! # Register snapshots
+//!
+//! Drivers implementing [`Dump`] can copy their key registers into a plain struct, to log
+//! the peripheral state with `{:?}` without reaching for the PAC pointers:
+//!
+//! ```ignore
+//! let regs = debug::dump(&serial);
+//! writeln!(log, "{:?}", regs).ok();
+//! ```
+
+/// Driver able to take a snapshot of its registers
+pub trait Dump {
+    /// Register values
+    type Registers: core::fmt::Debug + Copy;
+
+    /// Reads the registers, without side effects on the peripheral state
+    fn dump(&self) -> Self::Registers;
+}
+
+/// Takes a snapshot of the registers of a driver
+pub fn dump<D: Dump>(driver: &D) -> D::Registers {
+    driver.dump()
+}
+
+/// USART/LPUART registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialRegisters {
+    pub cr1: u32,
+    pub cr2: u32,
+    pub cr3: u32,
+    pub brr: u32,
+    pub isr: u32,
+}
+
+/// SPI registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpiRegisters {
+    pub cr1: u32,
+    pub cr2: u32,
+    pub sr: u32,
+}
+
+/// I2C registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct I2cRegisters {
+    pub cr1: u32,
+    pub cr2: u32,
+    pub timingr: u32,
+    pub isr: u32,
+}
+
+/// Timer registers, common to all the timers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerRegisters {
+    pub cr1: u32,
+    pub dier: u32,
+    pub sr: u32,
+    pub psc: u32,
+    pub arr: u32,
+    pub cnt: u32,
+}
+
+/// ADC registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdcRegisters {
+    pub isr: u32,
+    pub ier: u32,
+    pub cr: u32,
+    pub cfgr1: u32,
+    pub cfgr2: u32,
+    pub smpr: u32,
+    pub chselr: u32,
+}
+
+/// DMA channel registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaChannelRegisters {
+    pub cr: u32,
+    pub ndtr: u32,
+    pub par: u32,
+    pub mar: u32,
+}
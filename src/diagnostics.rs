@@ -0,0 +1,139 @@
+//! # Supply diagnostics
+//!
+//! [`check_power`] cross-checks the supply monitors, as the IEC 60730 class B startup
+//! self-tests require: the factory calibration of VREFINT, VDDA measured with VREFINT, and
+//! on the G0x1 the PVD output and the brown-out reset thresholds of the option bytes,
+//! which have to agree with the measurement. A disagreement points at a drifting
+//! reference or at a faulty supervisor.
+//!
+//! VDD and VDDA share a pin on the G0. The thresholds are the typical values of the
+//! datasheet, the checks allow for a 100 mV tolerance so that a supply close to a
+//! threshold is not reported.
+use core::ops::RangeInclusive;
+
+use crate::analog::adc::{Adc, VRef};
+use crate::power::Power;
+use crate::stm32::FLASH;
+
+#[cfg(feature = "stm32g0x1")]
+use crate::flash::{BrownOut, FlashExt};
+#[cfg(feature = "stm32g0x1")]
+use crate::power::PvdLevel;
+
+/// VDDA operating range, in millivolts
+const VDDA_RANGE_MV: RangeInclusive<u32> = 1_700..=3_600;
+
+/// Tolerance of the VREFINT measurement and of the thresholds, in millivolts
+const MARGIN_MV: u32 = 100;
+
+/// VREFINT_CAL values for VREFINT between 1.182 V and 1.232 V at VDDA = 3.0 V, with
+/// some margin
+const VREFINT_CAL_RANGE: RangeInclusive<u16> = 1_580..=1_720;
+
+/// Fault found by [`check_power`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerFault {
+    /// VREFINT_CAL is out of the datasheet range, the factory data is corrupted
+    Calibration = 0,
+    /// VREFINT could not be converted
+    Conversion = 1,
+    /// VDDA is out of the 1.7 V to 3.6 V operating range
+    VddaOutOfRange = 2,
+    /// The PVD output does not match VDDA
+    #[cfg(feature = "stm32g0x1")]
+    PvdMismatch = 3,
+    /// VDDA is below the brown-out reset threshold, which should hold the device in reset
+    #[cfg(feature = "stm32g0x1")]
+    BrownOutMismatch = 4,
+}
+
+/// Supply health report, see [`check_power`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerReport {
+    /// VDDA measured with VREFINT, in millivolts
+    pub vdda_mv: Option<u32>,
+    /// Rising and falling thresholds of the PVD, `None` when it is disabled
+    #[cfg(feature = "stm32g0x1")]
+    pub pvd: Option<(PvdLevel, PvdLevel)>,
+    /// The PVD output flags VDD below its threshold
+    #[cfg(feature = "stm32g0x1")]
+    pub vdd_low: bool,
+    /// Brown-out reset thresholds, `None` when only the power-on/power-down reset is active
+    #[cfg(feature = "stm32g0x1")]
+    pub brown_out: Option<BrownOut>,
+    faults: u8,
+}
+
+impl PowerReport {
+    /// Returns true if `fault` has been found
+    pub fn contains(&self, fault: PowerFault) -> bool {
+        self.faults & 1 << fault as u8 != 0
+    }
+
+    /// Returns true if no fault has been found
+    pub fn is_healthy(&self) -> bool {
+        self.faults == 0
+    }
+
+    fn flag(&mut self, fault: PowerFault) {
+        self.faults |= 1 << fault as u8;
+    }
+}
+
+/// Measures VDDA and checks it against the calibration data and the supply monitors
+///
+/// Blocks for the VREFINT conversion. The PVD has to be enabled beforehand to be checked,
+/// the brown-out reset configuration is read from the option bytes. A disabled PVD or
+/// brown-out reset is not a fault, the application decides whether it needs them from the
+/// report.
+pub fn check_power(adc: &mut Adc, power: &Power, flash: &FLASH) -> PowerReport {
+    let vdda_mv = nb::block!(adc.read_vdda()).ok();
+    let mut report = PowerReport {
+        vdda_mv,
+        #[cfg(feature = "stm32g0x1")]
+        pvd: power.pvd_thresholds(),
+        #[cfg(feature = "stm32g0x1")]
+        vdd_low: power.is_vdd_low(),
+        #[cfg(feature = "stm32g0x1")]
+        brown_out: flash.option_bytes().brown_out,
+        faults: 0,
+    };
+    #[cfg(feature = "stm32g0x0")]
+    let _ = (power, flash);
+
+    if !VREFINT_CAL_RANGE.contains(&VRef::calibration()) {
+        report.flag(PowerFault::Calibration);
+    }
+    let vdda_mv = match vdda_mv {
+        Some(vdda_mv) => vdda_mv,
+        None => {
+            report.flag(PowerFault::Conversion);
+            return report;
+        }
+    };
+    let (min, max) = (*VDDA_RANGE_MV.start(), *VDDA_RANGE_MV.end());
+    if vdda_mv + MARGIN_MV < min || vdda_mv > max + MARGIN_MV {
+        report.flag(PowerFault::VddaOutOfRange);
+    }
+
+    #[cfg(feature = "stm32g0x1")]
+    {
+        if let Some((rising, falling)) = report.pvd {
+            // The output is set below the falling threshold and cleared above the rising one
+            let mismatch = if report.vdd_low {
+                vdda_mv > rising.rising_mv() + MARGIN_MV
+            } else {
+                vdda_mv + MARGIN_MV < falling.falling_mv()
+            };
+            if mismatch {
+                report.flag(PowerFault::PvdMismatch);
+            }
+        }
+        if let Some(bor) = report.brown_out {
+            if vdda_mv + MARGIN_MV < bor.falling.falling_mv() {
+                report.flag(PowerFault::BrownOutMismatch);
+            }
+        }
+    }
+    report
+}
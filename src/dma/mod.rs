@@ -1,10 +1,17 @@
 //! Direct Memory Access Engine
-use crate::dmamux::{self, DmaMuxIndex};
+use crate::debug::{DmaChannelRegisters, Dump};
+use crate::dmamux::{self, DmaMuxIndex, DmaMuxTriggerSync, SyncPolarity};
 use crate::rcc::{Enable, Rcc, Reset};
 use crate::stm32::{self, DMA, DMAMUX};
 
 use crate::dmamux::DmaMuxExt;
 
+mod pool;
+mod transfer;
+
+pub use pool::BufferPool;
+pub use transfer::*;
+
 /// Extension trait to split a DMA peripheral into independent channels
 pub trait DmaExt {
     /// The type to split the DMA into
@@ -102,6 +109,13 @@ pub trait Channel: private::Channel {
     /// Connects the DMAMUX channel to the peripheral corresponding to index
     fn select_peripheral(&mut self, index: DmaMuxIndex);
 
+    /// Releases `requests` peripheral requests on each edge of a synchronization input,
+    /// see [`DmaMuxChannel::enable_sync`](dmamux::DmaMuxChannel::enable_sync)
+    fn enable_sync(&mut self, input: DmaMuxTriggerSync, polarity: SyncPolarity, requests: u8);
+
+    /// Stops gating the peripheral requests
+    fn disable_sync(&mut self);
+
     /// Is the interrupt flag for the given event set?
     fn event_occurred(&self, event: Event) -> bool;
 
@@ -133,7 +147,7 @@ pub trait Channel: private::Channel {
     ///
     /// Panics if this channel is enabled.
     fn set_peripheral_address(&mut self, address: u32, inc: bool) {
-        assert!(!self.is_enabled());
+        hal_assert!(!self.is_enabled());
 
         self.ch().par.write(|w| unsafe { w.pa().bits(address) });
         self.ch().cr.modify(|_, w| w.pinc().bit(inc));
@@ -148,7 +162,7 @@ pub trait Channel: private::Channel {
     ///
     /// Panics if this channel is enabled.
     fn set_memory_address(&mut self, address: u32, inc: bool) {
-        assert!(!self.is_enabled());
+        hal_assert!(!self.is_enabled());
 
         self.ch().mar.write(|w| unsafe { w.ma().bits(address) });
         self.ch().cr.modify(|_, w| w.minc().bit(inc));
@@ -162,11 +176,16 @@ pub trait Channel: private::Channel {
     ///
     /// Panics if this channel is enabled.
     fn set_transfer_length(&mut self, len: u16) {
-        assert!(!self.is_enabled());
+        hal_assert!(!self.is_enabled());
 
         self.ch().ndtr.write(|w| unsafe { w.ndt().bits(len) });
     }
 
+    /// Get the number of words left to transfer.
+    fn get_remaining_transfers(&self) -> u16 {
+        self.ch().ndtr.read().ndt().bits()
+    }
+
     /// Set the word size.
     fn set_word_size(&mut self, wsize: WordSize) {
         self.ch().cr.modify(|_, w| unsafe {
@@ -239,6 +258,15 @@ pub trait Channel: private::Channel {
     }
 }
 
+fn dump_channel(ch: &stm32::dma::CH) -> DmaChannelRegisters {
+    DmaChannelRegisters {
+        cr: ch.cr.read().bits(),
+        ndtr: ch.ndtr.read().bits(),
+        par: ch.par.read().bits(),
+        mar: ch.mar.read().bits(),
+    }
+}
+
 macro_rules! dma {
     (
         channels: {
@@ -290,6 +318,19 @@ macro_rules! dma {
                     self.mux().select_peripheral(index);
                 }
 
+                fn enable_sync(
+                    &mut self,
+                    input: DmaMuxTriggerSync,
+                    polarity: SyncPolarity,
+                    requests: u8,
+                ) {
+                    self.mux().enable_sync(input, polarity, requests);
+                }
+
+                fn disable_sync(&mut self) {
+                    self.mux().disable_sync();
+                }
+
                 fn event_occurred(&self, event: Event) -> bool {
                     use Event::*;
 
@@ -318,7 +359,141 @@ macro_rules! dma {
                 }
 
             }
+
+            impl Dump for $Ci {
+                type Registers = DmaChannelRegisters;
+
+                fn dump(&self) -> DmaChannelRegisters {
+                    dump_channel(private::Channel::ch(self))
+                }
+            }
+
+            impl From<$Ci> for AnyChannel {
+                fn from(ch: $Ci) -> Self {
+                    AnyChannel::$Ci(ch)
+                }
+            }
         )+
+
+        /// Any of the DMA channels, as handed out by a [`DmaPool`]
+        pub enum AnyChannel {
+            $( $Ci($Ci), )+
+        }
+
+        impl AnyChannel {
+            pub fn mux(&mut self) -> &mut dyn dmamux::DmaMuxChannel {
+                match self {
+                    $( AnyChannel::$Ci(ch) => ch.mux(), )+
+                }
+            }
+        }
+
+        impl Dump for AnyChannel {
+            type Registers = DmaChannelRegisters;
+
+            fn dump(&self) -> DmaChannelRegisters {
+                dump_channel(private::Channel::ch(self))
+            }
+        }
+
+        impl private::Channel for AnyChannel {
+            fn ch(&self) -> &stm32::dma::CH {
+                match self {
+                    $( AnyChannel::$Ci(ch) => ch.ch(), )+
+                }
+            }
+        }
+
+        impl Channel for AnyChannel {
+            fn select_peripheral(&mut self, index: DmaMuxIndex) {
+                match self {
+                    $( AnyChannel::$Ci(ch) => ch.select_peripheral(index), )+
+                }
+            }
+
+            fn enable_sync(&mut self, input: DmaMuxTriggerSync, polarity: SyncPolarity, requests: u8) {
+                match self {
+                    $( AnyChannel::$Ci(ch) => ch.enable_sync(input, polarity, requests), )+
+                }
+            }
+
+            fn disable_sync(&mut self) {
+                match self {
+                    $( AnyChannel::$Ci(ch) => ch.disable_sync(), )+
+                }
+            }
+
+            fn event_occurred(&self, event: Event) -> bool {
+                match self {
+                    $( AnyChannel::$Ci(ch) => ch.event_occurred(event), )+
+                }
+            }
+
+            fn clear_event(&mut self, event: Event) {
+                match self {
+                    $( AnyChannel::$Ci(ch) => ch.clear_event(event), )+
+                }
+            }
+        }
+
+        /// Runtime allocator of DMA channels
+        ///
+        /// Drivers acquire a channel for a transfer and give it back once done, so more
+        /// DMA users than channels can share them, as long as they don't all transfer at
+        /// the same time.
+        pub struct DmaPool {
+            channels: [Option<AnyChannel>; dma!(@count $($Ci)+)],
+        }
+
+        impl Channels {
+            /// Turns the channels into a pool
+            pub fn into_pool(self) -> DmaPool {
+                DmaPool {
+                    channels: [$( Some(AnyChannel::$Ci(self.$chi)) ),+],
+                }
+            }
+        }
+    };
+    (@count) => { 0 };
+    (@count $head:ident $($tail:ident)*) => { 1 + dma!(@count $($tail)*) };
+}
+
+impl DmaPool {
+    /// Takes a free channel out of the pool
+    pub fn acquire(&mut self) -> Option<AnyChannel> {
+        self.channels.iter_mut().find_map(|ch| ch.take())
+    }
+
+    /// Number of free channels in the pool
+    pub fn available(&self) -> usize {
+        self.channels.iter().filter(|ch| ch.is_some()).count()
+    }
+
+    /// Stops the channel and returns it to the pool
+    pub fn release(&mut self, mut ch: AnyChannel) {
+        ch.disable();
+        ch.unlisten(Event::Any);
+        ch.clear_event(Event::Any);
+        ch.set_circular_mode(false);
+        if let Some(slot) = self.channels.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(ch);
+        }
+    }
+
+    /// Returns the channel to the pool if its transfer is over, gives it back otherwise
+    ///
+    /// A channel in circular mode never completes, it has to be [`release`]d explicitly.
+    ///
+    /// [`release`]: DmaPool::release
+    pub fn try_release(&mut self, ch: AnyChannel) -> Result<(), AnyChannel> {
+        if ch.is_enabled()
+            && !ch.event_occurred(Event::TransferComplete)
+            && !ch.event_occurred(Event::TransferError)
+        {
+            return Err(ch);
+        }
+        self.release(ch);
+        Ok(())
     }
 }
 
@@ -0,0 +1,81 @@
+//! Static buffer pool
+//!
+//! The DMA drivers take `&'static mut` buffers, which are usually obtained once at startup
+//! with `cortex_m::singleton!`. A [`BufferPool`] hands out such buffers from a `static`
+//! too, and takes them back once the driver has released them, so that a few buffers can
+//! be shared by transfers that do not run at the same time:
+//!
+//! ```ignore
+//! static POOL: BufferPool<u8, 4, 64> = BufferPool::new(0);
+//!
+//! let buf = POOL.take().unwrap();
+//! let transfer = Transfer::memory_to_peripheral(ch, buf, tx);
+//! let (ch, buf, tx) = transfer.wait().unwrap();
+//! POOL.free(buf).unwrap();
+//! ```
+//!
+//! A buffer that is never given back, e.g. because its transfer was dropped while running,
+//! is simply lost for the pool.
+use core::cell::{Cell, UnsafeCell};
+
+use cortex_m::interrupt;
+
+/// Pool of `N` buffers of `LEN` words, to be declared as a `static`
+pub struct BufferPool<W, const N: usize, const LEN: usize> {
+    buffers: UnsafeCell<[[W; LEN]; N]>,
+    /// One bit per buffer handed out
+    taken: Cell<u32>,
+}
+
+// NOTE(unsafe) each buffer is handed out to a single owner at a time, the bitmap is only
+// accessed in critical sections
+unsafe impl<W: Send, const N: usize, const LEN: usize> Sync for BufferPool<W, N, LEN> {}
+
+impl<W: Copy, const N: usize, const LEN: usize> BufferPool<W, N, LEN> {
+    /// Evaluated when the pool is created, fails the build if `N` is above 32
+    const CAPACITY: () = assert!(N <= 32, "a pool holds up to 32 buffers");
+
+    /// Creates a pool with all the words set to `init`
+    ///
+    /// Pools of more than 32 buffers do not build.
+    pub const fn new(init: W) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::CAPACITY;
+        BufferPool {
+            buffers: UnsafeCell::new([[init; LEN]; N]),
+            taken: Cell::new(0),
+        }
+    }
+
+    /// Takes a free buffer, holding the words left by its previous owner
+    #[allow(clippy::mut_from_ref)]
+    pub fn take(&'static self) -> Option<&'static mut [W; LEN]> {
+        interrupt::free(|_| {
+            let taken = self.taken.get();
+            let index = (0..N).find(|i| taken & 1 << i == 0)?;
+            self.taken.set(taken | 1 << index);
+            // NOTE(unsafe) the buffer was free, nobody else holds a reference to it
+            Some(unsafe { &mut (*self.buffers.get())[index] })
+        })
+    }
+
+    /// Gives a buffer back to the pool
+    ///
+    /// A buffer that was not taken from this pool is handed back as the error.
+    pub fn free(&'static self, buf: &'static mut [W; LEN]) -> Result<(), &'static mut [W; LEN]> {
+        let base = self.buffers.get() as usize;
+        let offset = (buf as *mut [W; LEN] as usize).wrapping_sub(base);
+        let size = core::mem::size_of::<[W; LEN]>().max(1);
+        if offset >= size * N || !offset.is_multiple_of(size) {
+            return Err(buf);
+        }
+        let index = offset / size;
+        interrupt::free(|_| self.taken.set(self.taken.get() & !(1 << index)));
+        Ok(())
+    }
+
+    /// Number of buffers that can be taken
+    pub fn available(&self) -> usize {
+        N - interrupt::free(|_| self.taken.get()).count_ones() as usize
+    }
+}
@@ -0,0 +1,207 @@
+//! Safe one-shot DMA transfers
+//!
+//! A [`Transfer`] owns the DMA channel, the peripheral and the buffers for the time of the
+//! transfer and gives them back once it is over, so the buffers can not be touched while
+//! the DMA accesses them. The buffers are described by the [`ReadBuffer`] and
+//! [`WriteBuffer`] traits of the `embedded-dma` crate, so drivers can take any kind of
+//! static buffer, of `u8`, `u16` or `u32` words.
+use core::sync::atomic::{compiler_fence, Ordering};
+
+pub use embedded_dma::{ReadBuffer, WriteBuffer};
+
+use super::{Channel, Direction, Event, Target, WordSize};
+
+/// Word transferred by the DMA
+pub trait Word: Copy + crate::Sealed {
+    /// Size of the word on the bus
+    const SIZE: WordSize;
+}
+
+impl crate::Sealed for u8 {}
+impl crate::Sealed for u16 {}
+impl crate::Sealed for u32 {}
+
+impl Word for u8 {
+    const SIZE: WordSize = WordSize::BITS8;
+}
+
+impl Word for u16 {
+    const SIZE: WordSize = WordSize::BITS16;
+}
+
+impl Word for u32 {
+    const SIZE: WordSize = WordSize::BITS32;
+}
+
+/// DMA target with a data register
+pub trait DataTarget: Target {
+    /// Word size of the data register
+    type Word: Word;
+
+    /// Address of the data register
+    fn data_register(&self) -> u32;
+}
+
+/// DMA transfer in progress
+///
+/// `SRC` and `DST` are the peripheral and the buffer, or both buffers of a memory to
+/// memory transfer. Dropping the transfer does not stop it, use [`Transfer::abort`].
+pub struct Transfer<CH, SRC, DST> {
+    ch: CH,
+    src: SRC,
+    dst: DST,
+}
+
+impl<CH, PERIPH, BUF> Transfer<CH, BUF, PERIPH>
+where
+    CH: Channel,
+    PERIPH: DataTarget,
+    BUF: ReadBuffer<Word = PERIPH::Word>,
+{
+    /// Sends `buf` to the peripheral
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffer is empty or longer than 65535 words.
+    pub fn memory_to_peripheral(mut ch: CH, buf: BUF, mut periph: PERIPH) -> Self {
+        let (ptr, len) = unsafe { buf.read_buffer() };
+        configure(&mut ch, Direction::FromMemory, BUF::Word::SIZE, len);
+        ch.set_peripheral_address(periph.data_register(), false);
+        ch.set_memory_address(ptr as u32, true);
+        ch.select_peripheral(periph.dmamux());
+        start(&mut ch);
+        periph.enable_dma();
+        Transfer {
+            ch,
+            src: buf,
+            dst: periph,
+        }
+    }
+}
+
+impl<CH, PERIPH, BUF> Transfer<CH, PERIPH, BUF>
+where
+    CH: Channel,
+    PERIPH: DataTarget,
+    BUF: WriteBuffer<Word = PERIPH::Word>,
+{
+    /// Fills `buf` with data received by the peripheral
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffer is empty or longer than 65535 words.
+    pub fn peripheral_to_memory(mut ch: CH, mut periph: PERIPH, mut buf: BUF) -> Self {
+        let (ptr, len) = unsafe { buf.write_buffer() };
+        configure(&mut ch, Direction::FromPeripheral, BUF::Word::SIZE, len);
+        ch.set_peripheral_address(periph.data_register(), false);
+        ch.set_memory_address(ptr as u32, true);
+        ch.select_peripheral(periph.dmamux());
+        start(&mut ch);
+        periph.enable_dma();
+        Transfer {
+            ch,
+            src: periph,
+            dst: buf,
+        }
+    }
+}
+
+impl<CH, SRC, DST> Transfer<CH, SRC, DST>
+where
+    CH: Channel,
+    SRC: ReadBuffer,
+    SRC::Word: Word,
+    DST: WriteBuffer<Word = SRC::Word>,
+{
+    /// Copies `src` to the start of `dst`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `src` is empty, longer than 65535 words or longer than `dst`.
+    pub fn memory_to_memory(mut ch: CH, src: SRC, mut dst: DST) -> Self {
+        let (src_ptr, len) = unsafe { src.read_buffer() };
+        let (dst_ptr, dst_len) = unsafe { dst.write_buffer() };
+        assert!(len <= dst_len, "destination buffer too short");
+        // The source goes in the peripheral address register, read with DIR = 0
+        configure(&mut ch, Direction::FromPeripheral, SRC::Word::SIZE, len);
+        ch.set_peripheral_address(src_ptr as u32, true);
+        ch.set_memory_address(dst_ptr as u32, true);
+        ch.ch().cr.modify(|_, w| w.mem2mem().set_bit());
+        start(&mut ch);
+        Transfer { ch, src, dst }
+    }
+}
+
+impl<CH: Channel, SRC, DST> Transfer<CH, SRC, DST> {
+    /// Returns `true` once the transfer completed or failed
+    pub fn is_done(&self) -> bool {
+        self.ch.event_occurred(Event::TransferComplete)
+            || self.ch.event_occurred(Event::TransferError)
+    }
+
+    /// Is the flag of the given event set?
+    pub fn event_occurred(&self, event: Event) -> bool {
+        self.ch.event_occurred(event)
+    }
+
+    /// Clears the flag of the given event
+    pub fn clear_event(&mut self, event: Event) {
+        self.ch.clear_event(event);
+    }
+
+    /// Enables the channel interrupt for the given event
+    pub fn listen(&mut self, event: Event) {
+        self.ch.listen(event);
+    }
+
+    /// Disables the channel interrupt for the given event
+    pub fn unlisten(&mut self, event: Event) {
+        self.ch.unlisten(event);
+    }
+
+    /// Number of words left to transfer
+    pub fn remaining(&self) -> u16 {
+        self.ch.get_remaining_transfers()
+    }
+
+    /// Waits for the end of the transfer and releases the resources
+    ///
+    /// The error is returned with the resources if the DMA hit a bus error.
+    #[allow(clippy::type_complexity)]
+    pub fn wait(self) -> Result<(CH, SRC, DST), (CH, SRC, DST)> {
+        while !self.is_done() {}
+        let failed = self.ch.event_occurred(Event::TransferError);
+        let parts = self.abort();
+        if failed {
+            Err(parts)
+        } else {
+            Ok(parts)
+        }
+    }
+
+    /// Stops the transfer and releases the resources
+    pub fn abort(mut self) -> (CH, SRC, DST) {
+        self.ch.disable();
+        self.ch.ch().cr.modify(|_, w| w.mem2mem().clear_bit());
+        // The buffer accesses of the DMA are done before the buffers are handed back
+        compiler_fence(Ordering::Acquire);
+        (self.ch, self.src, self.dst)
+    }
+}
+
+pub(crate) fn configure<CH: Channel>(ch: &mut CH, dir: Direction, size: WordSize, len: usize) {
+    hal_assert!(len > 0 && len <= u16::MAX as usize);
+    ch.disable();
+    ch.set_direction(dir);
+    ch.set_word_size(size);
+    ch.set_transfer_length(len as u16);
+    ch.set_circular_mode(false);
+    // The flags left by a previous transfer on the channel would end this one
+    ch.clear_event(Event::Any);
+}
+
+fn start<CH: Channel>(ch: &mut CH) {
+    // The buffer contents are written before the DMA starts reading them
+    compiler_fence(Ordering::Release);
+    ch.enable();
+}
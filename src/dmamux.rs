@@ -9,6 +9,7 @@ pub trait DmaMuxExt {
     fn split(self) -> Self::Channels;
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DmaMuxIndex {
     dmamux_req_gen0 = 0,
     dmamux_req_gen1 = 1,
@@ -145,8 +146,25 @@ impl DmaMuxTriggerSync {
     }
 }
 
+/// Edge of the synchronization input that releases the requests
+pub enum SyncPolarity {
+    Rising = 0b01,
+    Falling = 0b10,
+    Both = 0b11,
+}
+
 pub trait DmaMuxChannel {
     fn select_peripheral(&mut self, index: DmaMuxIndex);
+
+    /// Gates the requests of the peripheral: after each edge of `input`, `requests`
+    /// requests (1 to 32) are forwarded to the DMA channel, the next ones wait for the
+    /// following edge
+    ///
+    /// Call it after `select_peripheral`, which clears the synchronization.
+    fn enable_sync(&mut self, input: DmaMuxTriggerSync, polarity: SyncPolarity, requests: u8);
+
+    /// Forwards the requests of the peripheral as they come
+    fn disable_sync(&mut self);
 }
 
 macro_rules! dma_mux {
@@ -176,6 +194,31 @@ macro_rules! dma_mux {
                     });
 
                 }
+
+                fn enable_sync(
+                    &mut self,
+                    input: DmaMuxTriggerSync,
+                    polarity: SyncPolarity,
+                    requests: u8,
+                ) {
+                    hal_assert!(requests > 0 && requests <= 32);
+                    let reg = unsafe { &(*DMAMUX::ptr()).$cr };
+                    reg.modify(|_, w| unsafe {
+                        w.sync_id()
+                            .bits(input.val())
+                            .spol()
+                            .bits(polarity as u8)
+                            .nbreq()
+                            .bits(requests - 1)
+                            .se()
+                            .set_bit()
+                    });
+                }
+
+                fn disable_sync(&mut self) {
+                    let reg = unsafe { &(*DMAMUX::ptr()).$cr };
+                    reg.modify(|_, w| w.se().clear_bit());
+                }
             }
         )+
 
@@ -1,6 +1,17 @@
 //! External interrupt controller
 use crate::gpio::SignalEdge;
 use crate::stm32::EXTI;
+#[cfg(feature = "exti-dispatch")]
+use crate::stm32::{interrupt, Interrupt};
+use crate::time::{Hertz, MicroSecond};
+#[cfg(feature = "exti-dispatch")]
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "exti-dispatch")]
+use cortex_m::{
+    interrupt::{free, Mutex},
+    peripheral::NVIC,
+};
 
 /// EXTI trigger event
 #[derive(PartialEq, PartialOrd, Clone, Copy)]
@@ -61,8 +72,8 @@ impl Event {
             12 => Event::GPIO12,
             13 => Event::GPIO13,
             14 => Event::GPIO14,
-            15 => Event::GPIO15,
-            _ => unreachable!(),
+            // Pin numbers are below 16
+            _ => Event::GPIO15,
         }
     }
 }
@@ -74,77 +85,136 @@ const TRIGGER_MAX: u8 = 16;
 #[cfg(any(feature = "stm32g030", feature = "stm32g070"))]
 const TRIGGER_MAX: u8 = 15;
 
+/// EXTI line configuration
+///
+/// Each method only touches the bits of its line, in a critical section, so drivers of
+/// different lines can share the EXTI peripheral by reference.
 pub trait ExtiExt {
     fn wakeup(&self, ev: Event);
     fn listen(&self, ev: Event, edge: SignalEdge);
     fn unlisten(&self, ev: Event);
     fn is_pending(&self, ev: Event, edge: SignalEdge) -> bool;
     fn unpend(&self, ev: Event);
+
+    /// Generates a Cortex-M event instead of an interrupt on the given edge, to wake up
+    /// the core from `WFE` without running any handler
+    fn listen_event(&self, ev: Event, edge: SignalEdge);
+    /// Stops generating events for the line
+    fn unlisten_event(&self, ev: Event);
+}
+
+fn set_trigger_edge(exti: &EXTI, line: u8, edge: SignalEdge) {
+    hal_assert!(line <= TRIGGER_MAX);
+    let mask = 1 << line;
+    cortex_m::interrupt::free(|_| match edge {
+        SignalEdge::Rising => {
+            exti.rtsr1.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        }
+        SignalEdge::Falling => {
+            exti.ftsr1.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        }
+        SignalEdge::All => {
+            exti.rtsr1.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+            exti.ftsr1.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        }
+    });
 }
 
 impl ExtiExt for EXTI {
     fn listen(&self, ev: Event, edge: SignalEdge) {
+        set_trigger_edge(self, ev as u8, edge);
+        self.wakeup(ev);
+    }
+
+    fn listen_event(&self, ev: Event, edge: SignalEdge) {
         let line = ev as u8;
-        assert!(line <= TRIGGER_MAX);
-        let mask = 1 << line;
-        match edge {
-            SignalEdge::Rising => {
-                self.rtsr1.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
-            }
-            SignalEdge::Falling => {
-                self.ftsr1.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        if line <= TRIGGER_MAX {
+            set_trigger_edge(self, line, edge);
+        }
+
+        cortex_m::interrupt::free(|_| {
+            #[cfg(any(
+                feature = "stm32g030",
+                feature = "stm32g070",
+                feature = "stm32g031",
+                feature = "stm32g041"
+            ))]
+            self.emr1
+                .modify(|r, w| unsafe { w.bits(r.bits() | 1 << line) });
+
+            #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+            match line {
+                line if line < 32 => self
+                    .emr1
+                    .modify(|r, w| unsafe { w.bits(r.bits() | 1 << line) }),
+                line => self
+                    .emr2
+                    .modify(|r, w| unsafe { w.bits(r.bits() | 1 << (line - 32)) }),
             }
-            SignalEdge::All => {
-                self.rtsr1.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
-                self.ftsr1.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        });
+    }
+
+    fn unlisten_event(&self, ev: Event) {
+        let line = ev as u8;
+        self.unpend(ev);
+
+        cortex_m::interrupt::free(|_| {
+            #[cfg(any(
+                feature = "stm32g030",
+                feature = "stm32g070",
+                feature = "stm32g031",
+                feature = "stm32g041"
+            ))]
+            self.emr1
+                .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << line)) });
+
+            #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+            match line {
+                line if line < 32 => self
+                    .emr1
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << line)) }),
+                line => self
+                    .emr2
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << (line - 32))) }),
             }
-        }
-        self.wakeup(ev);
+        });
     }
 
     fn wakeup(&self, ev: Event) {
-        #[cfg(any(
-            feature = "stm32g030",
-            feature = "stm32g070",
-            feature = "stm32g031",
-            feature = "stm32g041"
-        ))]
-        self.imr1
-            .modify(|r, w| unsafe { w.bits(r.bits() | 1 << ev as u8) });
-
-        #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
-        match ev as u8 {
-            line if line < 32 => self
-                .imr1
-                .modify(|r, w| unsafe { w.bits(r.bits() | 1 << line) }),
-            line => self
-                .imr2
-                .modify(|r, w| unsafe { w.bits(r.bits() | 1 << (line - 32)) }),
-        }
+        cortex_m::interrupt::free(|_| {
+            #[cfg(any(
+                feature = "stm32g030",
+                feature = "stm32g070",
+                feature = "stm32g031",
+                feature = "stm32g041"
+            ))]
+            self.imr1
+                .modify(|r, w| unsafe { w.bits(r.bits() | 1 << ev as u8) });
+
+            #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+            match ev as u8 {
+                line if line < 32 => self
+                    .imr1
+                    .modify(|r, w| unsafe { w.bits(r.bits() | 1 << line) }),
+                line => self
+                    .imr2
+                    .modify(|r, w| unsafe { w.bits(r.bits() | 1 << (line - 32)) }),
+            }
+        });
     }
 
     fn unlisten(&self, ev: Event) {
         self.unpend(ev);
 
-        #[cfg(any(
-            feature = "stm32g030",
-            feature = "stm32g070",
-            feature = "stm32g031",
-            feature = "stm32g041"
-        ))]
-        {
-            let line = ev as u8;
-            let mask = !(1 << line);
-            self.imr1.modify(|r, w| unsafe { w.bits(r.bits() & mask) });
-            if line <= TRIGGER_MAX {
-                self.rtsr1.modify(|r, w| unsafe { w.bits(r.bits() & mask) });
-                self.ftsr1.modify(|r, w| unsafe { w.bits(r.bits() & mask) });
-            }
-        }
-
-        #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
-        match ev as u8 {
-            line if line < 32 => {
+        cortex_m::interrupt::free(|_| {
+            #[cfg(any(
+                feature = "stm32g030",
+                feature = "stm32g070",
+                feature = "stm32g031",
+                feature = "stm32g041"
+            ))]
+            {
+                let line = ev as u8;
                 let mask = !(1 << line);
                 self.imr1.modify(|r, w| unsafe { w.bits(r.bits() & mask) });
                 if line <= TRIGGER_MAX {
@@ -152,11 +222,23 @@ impl ExtiExt for EXTI {
                     self.ftsr1.modify(|r, w| unsafe { w.bits(r.bits() & mask) });
                 }
             }
-            line => {
-                let mask = !(1 << (line - 32));
-                self.imr2.modify(|r, w| unsafe { w.bits(r.bits() & mask) })
+
+            #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+            match ev as u8 {
+                line if line < 32 => {
+                    let mask = !(1 << line);
+                    self.imr1.modify(|r, w| unsafe { w.bits(r.bits() & mask) });
+                    if line <= TRIGGER_MAX {
+                        self.rtsr1.modify(|r, w| unsafe { w.bits(r.bits() & mask) });
+                        self.ftsr1.modify(|r, w| unsafe { w.bits(r.bits() & mask) });
+                    }
+                }
+                line => {
+                    let mask = !(1 << (line - 32));
+                    self.imr2.modify(|r, w| unsafe { w.bits(r.bits() & mask) })
+                }
             }
-        }
+        });
     }
 
     fn is_pending(&self, ev: Event, edge: SignalEdge) -> bool {
@@ -182,3 +264,155 @@ impl ExtiExt for EXTI {
         }
     }
 }
+
+/// Edge counter driven by the EXTI interrupt of a GPIO line
+///
+/// For flow meters, anemometers and other pulse outputs when no timer input is left:
+/// configure the pin with `listen`, then call [`count_pending`](PulseCounter::count_pending)
+/// (or [`increment`](PulseCounter::increment) from an `exti-dispatch` handler) in the
+/// interrupt handler. The counter can be a `static`, it is only written from the interrupt
+/// handler and in critical sections.
+pub struct PulseCounter {
+    count: AtomicU32,
+}
+
+impl PulseCounter {
+    pub const fn new() -> Self {
+        PulseCounter {
+            count: AtomicU32::new(0),
+        }
+    }
+
+    /// Counts one edge, to be called from the interrupt handler only
+    pub fn increment(&self) {
+        // thumbv6m has no atomic read-modify-write, `take` runs in a critical section
+        let count = self.count.load(Ordering::Relaxed);
+        self.count.store(count.wrapping_add(1), Ordering::Relaxed);
+    }
+
+    /// Counts and clears the pending edge of the line, if any, to be called from the
+    /// interrupt handler only
+    ///
+    /// Returns true if an edge was pending.
+    pub fn count_pending(&self, exti: &EXTI, ev: Event) -> bool {
+        let line = ev as u8;
+        if line > TRIGGER_MAX {
+            return false;
+        }
+        let mask = 1 << line;
+        let pending = (exti.rpr1.read().bits() | exti.fpr1.read().bits()) & mask != 0;
+        if pending {
+            exti.unpend(ev);
+            self.increment();
+        }
+        pending
+    }
+
+    /// Number of edges counted since the last [`take`](PulseCounter::take)
+    pub fn count(&self) -> u32 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of edges counted and resets the counter
+    pub fn take(&self) -> u32 {
+        cortex_m::interrupt::free(|_| {
+            let count = self.count.load(Ordering::Relaxed);
+            self.count.store(0, Ordering::Relaxed);
+            count
+        })
+    }
+
+    /// Returns the average edge rate over the window elapsed since the last call, and
+    /// resets the counter
+    pub fn take_rate(&self, window: MicroSecond) -> Hertz {
+        hal_assert!(window.ticks() > 0);
+        let count = self.take() as u64;
+        let window = window.ticks() as u64;
+        Hertz::from_raw(((count * 1_000_000 + window / 2) / window) as u32)
+    }
+}
+
+impl Default for PulseCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// GPIO line callback, called with the edge that triggered the interrupt
+#[cfg(feature = "exti-dispatch")]
+pub type Handler = fn(SignalEdge);
+
+#[cfg(feature = "exti-dispatch")]
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: Cell<Option<Handler>> = Cell::new(None);
+#[cfg(feature = "exti-dispatch")]
+static HANDLERS: Mutex<[Cell<Option<Handler>>; 16]> = Mutex::new([NO_HANDLER; 16]);
+
+/// Registers the callback called from the EXTI interrupt handler of a GPIO line
+///
+/// The line still has to be configured with `listen`, the matching NVIC interrupt is
+/// unmasked here. Registering a line again replaces its callback.
+#[cfg(feature = "exti-dispatch")]
+pub fn register_handler(ev: Event, handler: Handler) {
+    let line = ev as usize;
+    hal_assert!(line < 16);
+    free(|cs| HANDLERS.borrow(cs)[line].set(Some(handler)));
+
+    let irq = match line {
+        0..=1 => Interrupt::EXTI0_1,
+        2..=3 => Interrupt::EXTI2_3,
+        _ => Interrupt::EXTI4_15,
+    };
+    // NOTE(unsafe) the handler dispatching this interrupt is provided below
+    unsafe { NVIC::unmask(irq) };
+}
+
+/// Removes the callback of a GPIO line, its pending events are then only cleared
+#[cfg(feature = "exti-dispatch")]
+pub fn unregister_handler(ev: Event) {
+    let line = ev as usize;
+    hal_assert!(line < 16);
+    free(|cs| HANDLERS.borrow(cs)[line].set(None));
+}
+
+#[cfg(feature = "exti-dispatch")]
+fn dispatch(lines: core::ops::RangeInclusive<u8>) {
+    // NOTE(unsafe) only the pending registers are accessed, with write-1-to-clear semantics
+    let exti = unsafe { &*EXTI::ptr() };
+    let rising = exti.rpr1.read().bits();
+    let falling = exti.fpr1.read().bits();
+    for line in lines {
+        let mask = 1 << line;
+        let edge = if rising & mask != 0 {
+            SignalEdge::Rising
+        } else if falling & mask != 0 {
+            SignalEdge::Falling
+        } else {
+            continue;
+        };
+        exti.rpr1.write(|w| unsafe { w.bits(rising & mask) });
+        exti.fpr1.write(|w| unsafe { w.bits(falling & mask) });
+        let handler = free(|cs| HANDLERS.borrow(cs)[line as usize].get());
+        if let Some(handler) = handler {
+            handler(edge);
+        }
+    }
+}
+
+#[cfg(feature = "exti-dispatch")]
+#[interrupt]
+fn EXTI0_1() {
+    dispatch(0..=1);
+}
+
+#[cfg(feature = "exti-dispatch")]
+#[interrupt]
+fn EXTI2_3() {
+    dispatch(2..=3);
+}
+
+#[cfg(feature = "exti-dispatch")]
+#[interrupt]
+fn EXTI4_15() {
+    dispatch(4..=15);
+}
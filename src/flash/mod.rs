@@ -1,9 +1,20 @@
+//! # Flash memory programming
+//!
+//! [`FlashExt::unlock`] gives an [`UnlockedFlash`], which erases pages and programs double
+//! words. A [`FlashRegion`] reserves pages for application data, such as configuration
+//! parameters or a firmware image received over a serial port.
+//! [`FlashLog`](crate::flashlog::FlashLog) keeps a ring log of records in a region.
+mod option_bytes;
+mod region;
 mod traits;
 
 use crate::stm32::FLASH;
-use core::convert::TryInto;
 use core::mem;
 use cortex_m::interrupt;
+#[cfg(feature = "stm32g0x1")]
+pub use option_bytes::BrownOut;
+pub use option_bytes::{BootConfig, OptionBytes, ReadProtection, WriteProtection};
+pub use region::FlashRegion;
 pub use traits::{Error, FlashPage, Read, Result, WriteErase};
 
 /// The first address of flash memory
@@ -15,9 +26,57 @@ pub const PAGE_SIZE: u32 = 2048;
 /// How many Flash memory pages there are
 pub const NUM_PAGES: u32 = 64;
 
+/// Flash size data register, in Kbytes
+const FLASH_SIZE_REG: *const u16 = 0x1FFF_75E0 as *const u16;
+
 const FLASH_KEY1: u32 = 0x4567_0123;
 const FLASH_KEY2: u32 = 0xCDEF_89AB;
 
+/// Brown-out reset threshold (FLASH_OPTR BORR_LEV/BORF_LEV)
+///
+/// The rising thresholds are about 2.1 V, 2.3 V, 2.6 V and 2.9 V, see the datasheet for
+/// the exact values and the hysteresis.
+#[cfg(feature = "stm32g0x1")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub enum BrownOutLevel {
+    Level1 = 0b00,
+    Level2 = 0b01,
+    Level3 = 0b10,
+    Level4 = 0b11,
+}
+
+#[cfg(feature = "stm32g0x1")]
+impl BrownOutLevel {
+    /// Typical rising threshold, in millivolts
+    pub fn rising_mv(&self) -> u32 {
+        [2_100, 2_310, 2_620, 2_920][*self as usize]
+    }
+
+    /// Typical falling threshold, in millivolts
+    pub fn falling_mv(&self) -> u32 {
+        [2_000, 2_200, 2_500, 2_800][*self as usize]
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => BrownOutLevel::Level1,
+            0b01 => BrownOutLevel::Level2,
+            0b10 => BrownOutLevel::Level3,
+            _ => BrownOutLevel::Level4,
+        }
+    }
+}
+
+/// Size of the Flash memory of the device, in bytes
+///
+/// `NUM_PAGES` is the size of the largest devices, parts with 32 or 64 Kbytes only have
+/// the first pages.
+pub fn flash_size() -> usize {
+    // NOTE(unsafe) read-only factory data
+    let kbytes = unsafe { FLASH_SIZE_REG.read_volatile() } as usize;
+    (kbytes * 1024).min(NUM_PAGES as usize * PAGE_SIZE as usize)
+}
+
 impl FlashPage {
     /// This gives the starting address of a flash page in physical address
     pub const fn to_address(&self) -> usize {
@@ -28,10 +87,23 @@ impl FlashPage {
 pub trait FlashExt {
     /// Unlocks Flash memory for erasure and writing
     fn unlock(self) -> core::result::Result<UnlockedFlash, FLASH>;
+
+    /// Option bytes loaded at the last reset
+    fn option_bytes(&self) -> OptionBytes;
+
+    /// Brown-out reset threshold loaded from the option bytes, `None` when the BOR is
+    /// disabled and only the power-on/power-down reset is active
+    #[cfg(feature = "stm32g0x1")]
+    fn brown_out_level(&self) -> Option<BrownOutLevel>;
 }
 
 impl FlashExt for FLASH {
     fn unlock(self) -> core::result::Result<UnlockedFlash, FLASH> {
+        // Writing the keys again while the flash is unlocked locks it until the next reset
+        if self.cr.read().lock().bit_is_clear() {
+            return Ok(UnlockedFlash { f: self });
+        }
+
         // Wait, while the memory interface is busy.
         while self.sr.read().bsy().bit_is_set() {}
 
@@ -46,6 +118,20 @@ impl FlashExt for FLASH {
             Err(self)
         }
     }
+
+    fn option_bytes(&self) -> OptionBytes {
+        OptionBytes::read(self)
+    }
+
+    #[cfg(feature = "stm32g0x1")]
+    fn brown_out_level(&self) -> Option<BrownOutLevel> {
+        let optr = self.optr.read();
+        if optr.boren().bit_is_set() {
+            Some(BrownOutLevel::from_bits(optr.borr_lev().bits()))
+        } else {
+            None
+        }
+    }
 }
 
 /// Handle for an unlocked flash on which operations can be performed
@@ -86,19 +172,30 @@ impl WriteErase for UnlockedFlash {
     fn status(&self) -> Result {
         let sr = self.f.sr.read();
 
-        if sr.bsy().bit_is_set() {
+        if sr.bsy().bit_is_set() || sr.cfgbsy().bit_is_set() {
             return Err(Error::Busy);
         }
 
-        if sr.pgaerr().bit_is_set() || sr.progerr().bit_is_set() || sr.wrperr().bit_is_set() {
-            return Err(Error::Illegal);
+        if sr.wrperr().bit_is_set() {
+            Err(Error::WriteProtected)
+        } else if sr.pgaerr().bit_is_set() {
+            Err(Error::Alignment)
+        } else if sr.sizerr().bit_is_set() {
+            Err(Error::Size)
+        } else if sr.progerr().bit_is_set() {
+            Err(Error::NotErased)
+        } else if sr.pgserr().bit_is_set() || sr.miserr().bit_is_set() || sr.fasterr().bit_is_set()
+        {
+            Err(Error::Sequence)
+        } else if sr.rderr().bit_is_set() {
+            Err(Error::ReadProtected)
+        } else {
+            Ok(())
         }
-
-        Ok(())
     }
 
     fn erase_page(&mut self, page: FlashPage) -> Result {
-        if page.0 >= NUM_PAGES as usize {
+        if page.0 >= flash_size() / PAGE_SIZE as usize {
             return Err(Error::PageOutOfRange);
         }
 
@@ -124,6 +221,14 @@ impl WriteErase for UnlockedFlash {
     }
 
     fn write_native(&mut self, address: usize, array: &[Self::NativeType]) -> Result {
+        if !address.is_multiple_of(mem::size_of::<Self::NativeType>()) {
+            return Err(Error::Alignment);
+        }
+        let end = address.checked_add(mem::size_of_val(array));
+        if address < FLASH_START || end.is_none_or(|end| end > FLASH_START + flash_size()) {
+            return Err(Error::AddressOutOfRange);
+        }
+
         // Wait, while the memory interface is busy.
         while self.f.sr.read().bsy().bit_is_set() {}
 
@@ -133,6 +238,7 @@ impl WriteErase for UnlockedFlash {
 
         // It is only possible to program a double word (2 x 32-bit data).
         let mut address = address as *mut u32;
+        let mut result = Ok(());
 
         for &word in array {
             // We absoluty can't have any access to Flash while preparing the
@@ -149,7 +255,10 @@ impl WriteErase for UnlockedFlash {
                 }
             });
 
-            self.wait()?;
+            result = self.wait();
+            if result.is_err() {
+                break;
+            }
 
             if self.f.sr.read().eop().bit_is_set() {
                 self.f.sr.modify(|_, w| w.eop().clear_bit());
@@ -158,60 +267,61 @@ impl WriteErase for UnlockedFlash {
 
         self.f.cr.modify(|_, w| w.pg().clear_bit());
 
-        Ok(())
+        result
     }
 
-    fn write(&mut self, address: usize, data: &[u8]) -> Result {
-        let address_offset = address % mem::align_of::<Self::NativeType>();
-        let unaligned_size = (mem::size_of::<Self::NativeType>() - address_offset)
-            % mem::size_of::<Self::NativeType>();
+    fn write(&mut self, mut address: usize, mut data: &[u8]) -> Result {
+        const SIZE: usize = mem::size_of::<u64>();
 
-        if unaligned_size > 0 {
-            let unaligned_data = &data[..unaligned_size];
-            // Handle unaligned address data, make it into a native write
-            let mut data = 0xffff_ffff_ffff_ffffu64;
-            for b in unaligned_data {
-                data = (data >> 8) | ((*b as Self::NativeType) << 56);
-            }
+        while !data.is_empty() {
+            // The bytes of the double word that are not written are left erased
+            let offset = address % SIZE;
+            let len = (SIZE - offset).min(data.len());
+            let mut bytes = [0xff; SIZE];
+            bytes[offset..offset + len].copy_from_slice(&data[..len]);
 
-            let unaligned_address = address - address_offset;
-            let native = &[data];
-            self.write_native(unaligned_address, native)?;
+            self.write_native(address - offset, &[Self::NativeType::from_le_bytes(bytes)])?;
+            address += len;
+            data = &data[len..];
         }
 
-        // Handle aligned address data
-        let aligned_data = &data[unaligned_size..];
-        let mut aligned_address = if unaligned_size > 0 {
-            address - address_offset + mem::size_of::<Self::NativeType>()
-        } else {
-            address
-        };
-
-        let mut chunks = aligned_data.chunks_exact(mem::size_of::<Self::NativeType>());
-
-        for exact_chunk in &mut chunks {
-            // Write chunks
-            let native = &[Self::NativeType::from_ne_bytes(
-                exact_chunk.try_into().unwrap(),
-            )];
-            self.write_native(aligned_address, native)?;
-            aligned_address += mem::size_of::<Self::NativeType>();
-        }
+        Ok(())
+    }
+}
 
-        let rem = chunks.remainder();
+#[cfg(feature = "stm32g0x1")]
+impl UnlockedFlash {
+    /// Enables the brown-out reset at `level`, unless it is already enabled at this level
+    /// or a higher one
+    ///
+    /// Programming the option bytes reloads them, which resets the device: this function
+    /// only returns when nothing had to be changed, or on error.
+    pub fn raise_brown_out_level(&mut self, level: BrownOutLevel) -> Result {
+        if self
+            .f
+            .brown_out_level()
+            .is_some_and(|current| current >= level)
+        {
+            return Ok(());
+        }
 
-        if !rem.is_empty() {
-            let mut data = 0xffff_ffff_ffff_ffffu64;
-            // Write remainder
-            for b in rem.iter().rev() {
-                data = (data << 8) | *b as Self::NativeType;
-            }
+        while self.f.sr.read().bsy().bit_is_set() {}
+        self.clear_errors();
+        self.unlock_options()?;
 
-            let native = &[data];
-            self.write_native(aligned_address, native)?;
-        }
+        // The falling threshold can not be higher than the rising one
+        self.f.optr.modify(|_, w| unsafe {
+            w.boren()
+                .set_bit()
+                .borr_lev()
+                .bits(level as u8)
+                .borf_lev()
+                .bits(level as u8)
+        });
+        self.f.cr.modify(|_, w| w.optstrt().set_bit());
+        self.wait()?;
 
-        Ok(())
+        self.reload_option_bytes()
     }
 }
 
@@ -222,6 +332,10 @@ impl UnlockedFlash {
                 .set_bit()
                 .pgserr()
                 .set_bit()
+                .miserr()
+                .set_bit()
+                .fasterr()
+                .set_bit()
                 .rderr()
                 .set_bit()
                 .optverr()
@@ -236,7 +350,7 @@ impl UnlockedFlash {
     }
 
     fn wait(&self) -> Result {
-        while self.f.sr.read().bsy().bit_is_set() {}
+        while self.f.sr.read().bsy().bit_is_set() || self.f.sr.read().cfgbsy().bit_is_set() {}
         self.status()
     }
 }
@@ -0,0 +1,258 @@
+//! Option bytes
+//!
+//! The option bytes are loaded into FLASH_OPTR and the WRP registers at power-on, and on
+//! [`UnlockedFlash::launch`], which also resets the device. Programming them with
+//! [`UnlockedFlash::program_option_bytes`] only writes the flash, the device keeps running
+//! with the loaded values until the launch.
+use super::{flash_size, Error, Result, UnlockedFlash, PAGE_SIZE};
+use crate::rcc::ResetMode;
+use crate::stm32::FLASH;
+
+#[cfg(feature = "stm32g0x1")]
+use super::BrownOutLevel;
+
+const OPT_KEY1: u32 = 0x0819_2A3B;
+const OPT_KEY2: u32 = 0x4C5D_6E7F;
+
+/// WRP register value of a disabled area, the start offset is above the end offset
+const WRP_DISABLED: u32 = 0x0000_003F;
+
+/// Read protection level (FLASH_OPTR RDP)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadProtection {
+    /// No protection
+    Level0,
+    /// The flash can not be read by the debugger or when booting from SRAM or the system
+    /// memory, going back to level 0 erases the whole flash
+    Level1,
+    /// Level 1 with the debug port disabled for good, and the option bytes frozen
+    Level2,
+}
+
+impl ReadProtection {
+    fn from_bits(bits: u8) -> Self {
+        match bits {
+            0xAA => ReadProtection::Level0,
+            0xCC => ReadProtection::Level2,
+            _ => ReadProtection::Level1,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            ReadProtection::Level0 => 0xAA,
+            ReadProtection::Level1 => 0xBB,
+            ReadProtection::Level2 => 0xCC,
+        }
+    }
+}
+
+/// Brown-out reset thresholds, the reset is asserted below `falling` and released above
+/// `rising`
+#[cfg(feature = "stm32g0x1")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrownOut {
+    pub falling: BrownOutLevel,
+    pub rising: BrownOutLevel,
+}
+
+/// Write protected area, pages `first..=last` (FLASH_WRP1AR, FLASH_WRP1BR)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteProtection {
+    pub first: u8,
+    pub last: u8,
+}
+
+impl WriteProtection {
+    fn from_bits(bits: u32) -> Option<Self> {
+        let first = (bits & 0x3f) as u8;
+        let last = (bits >> 16 & 0x3f) as u8;
+        if first <= last {
+            Some(WriteProtection { first, last })
+        } else {
+            None
+        }
+    }
+}
+
+/// Boot configuration (FLASH_OPTR nBOOT_SEL, nBOOT1, nBOOT0)
+///
+/// The device boots from the main flash when BOOT0 is 0, otherwise from the system memory
+/// (the bootloader) when `n_boot1` is set, or from SRAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootConfig {
+    /// BOOT0 is the level of the PA14-BOOT0 pin at reset, instead of the inverse of
+    /// `n_boot0`
+    pub boot0_pin: bool,
+    pub n_boot0: bool,
+    pub n_boot1: bool,
+}
+
+/// Option bytes, as loaded at the last reset
+///
+/// The other option bits (watchdog and reset options, ...) are left as they are by
+/// [`UnlockedFlash::program_option_bytes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionBytes {
+    pub read_protection: ReadProtection,
+    /// Brown-out reset thresholds, `None` when only the power-on/power-down reset is active
+    #[cfg(feature = "stm32g0x1")]
+    pub brown_out: Option<BrownOut>,
+    /// Function of the NRST pin, which is shared with PF2
+    pub reset_mode: ResetMode,
+    pub boot: BootConfig,
+    /// SRAM parity check, a parity error raises an NMI (FLASH_OPTR RAM_PARITY_CHECK)
+    pub sram_parity: bool,
+    /// Write protected areas A and B
+    pub write_protection: [Option<WriteProtection>; 2],
+}
+
+impl OptionBytes {
+    pub(super) fn read(f: &FLASH) -> Self {
+        let optr = f.optr.read();
+        OptionBytes {
+            read_protection: ReadProtection::from_bits(optr.rdp().bits()),
+            #[cfg(feature = "stm32g0x1")]
+            brown_out: if optr.boren().bit_is_set() {
+                Some(BrownOut {
+                    falling: BrownOutLevel::from_bits(optr.borf_lev().bits()),
+                    rising: BrownOutLevel::from_bits(optr.borr_lev().bits()),
+                })
+            } else {
+                None
+            },
+            reset_mode: match optr.nrst_mode().bits() {
+                0b01 => ResetMode::ResetInput,
+                0b10 => ResetMode::GPIO,
+                _ => ResetMode::Bidirectional,
+            },
+            boot: BootConfig {
+                boot0_pin: optr.n_boot_sel().bit_is_clear(),
+                n_boot0: optr.n_boot0().bit_is_set(),
+                n_boot1: optr.n_boot1().bit_is_set(),
+            },
+            sram_parity: optr.ram_parity_check().bit_is_clear(),
+            write_protection: [
+                WriteProtection::from_bits(f.wrp1ar.read().bits()),
+                WriteProtection::from_bits(f.wrp1br.read().bits()),
+            ],
+        }
+    }
+}
+
+impl UnlockedFlash {
+    /// Option bytes loaded at the last reset
+    pub fn option_bytes(&self) -> OptionBytes {
+        OptionBytes::read(&self.f)
+    }
+
+    /// Programs the option bytes, which apply after [`launch`](Self::launch)
+    ///
+    /// Fails with `Error::Illegal` for the read protection level 2, which can only be set
+    /// with [`set_permanent_read_protection`](Self::set_permanent_read_protection), or for
+    /// a falling brown-out threshold above the rising one, and with
+    /// `Error::PageOutOfRange` for a write protected area beyond the flash. Going back from
+    /// read protection level 1 to level 0 erases the whole flash on the launch.
+    pub fn program_option_bytes(&mut self, ob: &OptionBytes) -> Result {
+        if ob.read_protection == ReadProtection::Level2 {
+            return Err(Error::Illegal);
+        }
+        #[cfg(feature = "stm32g0x1")]
+        if ob.brown_out.is_some_and(|bor| bor.falling > bor.rising) {
+            return Err(Error::Illegal);
+        }
+        let pages = flash_size() / PAGE_SIZE as usize;
+        let mut wrp = [WRP_DISABLED; 2];
+        for (bits, area) in wrp.iter_mut().zip(ob.write_protection.iter()) {
+            if let Some(area) = area {
+                if area.first > area.last || area.last as usize >= pages {
+                    return Err(Error::PageOutOfRange);
+                }
+                *bits = area.first as u32 | (area.last as u32) << 16;
+            }
+        }
+
+        while self.f.sr.read().bsy().bit_is_set() {}
+        self.clear_errors();
+        self.unlock_options()?;
+
+        self.f.optr.modify(|_, w| unsafe {
+            w.rdp()
+                .bits(ob.read_protection.bits())
+                .nrst_mode()
+                .bits(ob.reset_mode as u8)
+                .n_boot_sel()
+                .bit(!ob.boot.boot0_pin)
+                .n_boot0()
+                .bit(ob.boot.n_boot0)
+                .n_boot1()
+                .bit(ob.boot.n_boot1)
+                .ram_parity_check()
+                .bit(!ob.sram_parity);
+            #[cfg(feature = "stm32g0x1")]
+            match ob.brown_out {
+                Some(bor) => w
+                    .boren()
+                    .set_bit()
+                    .borf_lev()
+                    .bits(bor.falling as u8)
+                    .borr_lev()
+                    .bits(bor.rising as u8),
+                None => w.boren().clear_bit(),
+            };
+            w
+        });
+        // NOTE(unsafe) the WRP registers are writable while the options are unlocked, the
+        // PAC only declares them read-only
+        unsafe {
+            (&self.f.wrp1ar as *const _ as *mut u32).write_volatile(wrp[0]);
+            (&self.f.wrp1br as *const _ as *mut u32).write_volatile(wrp[1]);
+        }
+        self.f.cr.modify(|_, w| w.optstrt().set_bit());
+        self.wait()
+    }
+
+    /// Programs the read protection level 2, which applies after [`launch`](Self::launch)
+    ///
+    /// This is irreversible: the debug port is disabled and the option bytes can never be
+    /// changed again.
+    pub fn set_permanent_read_protection(&mut self) -> Result {
+        while self.f.sr.read().bsy().bit_is_set() {}
+        self.clear_errors();
+        self.unlock_options()?;
+        self.f
+            .optr
+            .modify(|_, w| unsafe { w.rdp().bits(ReadProtection::Level2.bits()) });
+        self.f.cr.modify(|_, w| w.optstrt().set_bit());
+        self.wait()
+    }
+
+    /// Loads the programmed option bytes, which resets the device
+    pub fn launch(mut self) -> ! {
+        self.reload_option_bytes()
+    }
+
+    pub(super) fn unlock_options(&mut self) -> Result {
+        if self.f.cr.read().optlock().bit_is_set() {
+            self.f
+                .optkeyr
+                .write(|w| unsafe { w.optkeyr().bits(OPT_KEY1) });
+            self.f
+                .optkeyr
+                .write(|w| unsafe { w.optkeyr().bits(OPT_KEY2) });
+        }
+        if self.f.cr.read().optlock().bit_is_set() {
+            return Err(Error::Illegal);
+        }
+        Ok(())
+    }
+
+    pub(super) fn reload_option_bytes(&mut self) -> ! {
+        // OBL_LAUNCH can only be set with the options unlocked
+        let _ = self.unlock_options();
+        self.f.cr.modify(|_, w| w.obl_launch().set_bit());
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use super::{flash_size, Error, FlashPage, Result, UnlockedFlash, WriteErase};
+use super::{FLASH_START, PAGE_SIZE};
+
+/// Pages of the Flash memory reserved for application data, e.g. configuration
+/// parameters or a firmware update image
+///
+/// The pages have to be kept out of the FLASH region of `memory.x`, so that the linker
+/// does not place code there. The region is memory mapped, reads need no unlocked flash.
+#[derive(Copy, Clone, Debug)]
+pub struct FlashRegion {
+    first: FlashPage,
+    pages: usize,
+}
+
+impl FlashRegion {
+    /// Region of `pages` pages starting at `first`
+    pub const fn new(first: FlashPage, pages: usize) -> Self {
+        FlashRegion { first, pages }
+    }
+
+    /// Address of the first byte of the region
+    pub const fn address(&self) -> usize {
+        self.first.to_address()
+    }
+
+    /// Size of the region, in bytes
+    pub const fn len(&self) -> usize {
+        self.pages * PAGE_SIZE as usize
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.pages == 0
+    }
+
+    /// Number of pages of the region
+    pub const fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Contents of the region
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region ends beyond the Flash memory of the device.
+    pub fn as_slice(&self) -> &[u8] {
+        assert!(
+            self.address() + self.len() <= FLASH_START + flash_size(),
+            "region beyond the Flash memory"
+        );
+        // NOTE(unsafe) the flash is always mapped, it is only written through `UnlockedFlash`
+        unsafe { core::slice::from_raw_parts(self.address() as *const u8, self.len()) }
+    }
+
+    /// Reads `buf.len()` bytes at `offset` from the start of the region
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result {
+        self.check(offset, buf.len())?;
+        buf.copy_from_slice(&self.as_slice()[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    /// Erases all the pages of the region
+    pub fn erase(&self, flash: &mut UnlockedFlash) -> Result {
+        for page in self.first.0..self.first.0 + self.pages {
+            flash.erase_page(FlashPage(page))?;
+        }
+        Ok(())
+    }
+
+    /// Erases the page `page` of the region, counted from its first page
+    pub fn erase_page(&self, flash: &mut UnlockedFlash, page: usize) -> Result {
+        if page >= self.pages {
+            return Err(Error::PageOutOfRange);
+        }
+        flash.erase_page(FlashPage(self.first.0 + page))
+    }
+
+    /// Writes `data` at `offset` from the start of the region
+    ///
+    /// The double words written have to be erased, the bytes of the first and last double
+    /// words outside of `data` are left erased.
+    pub fn write(&self, flash: &mut UnlockedFlash, offset: usize, data: &[u8]) -> Result {
+        self.check(offset, data.len())?;
+        flash.write(self.address() + offset, data)
+    }
+
+    fn check(&self, offset: usize, len: usize) -> Result {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len() => Ok(()),
+            _ => Err(Error::AddressOutOfRange),
+        }
+    }
+}
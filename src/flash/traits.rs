@@ -16,6 +16,20 @@ pub enum Error {
     PageOutOfRange,
     /// (Legal) command failed
     Failure,
+    /// The address is outside of the Flash memory, or of the region written to
+    AddressOutOfRange,
+    /// The page is write protected (FLASH_SR WRPERR)
+    WriteProtected,
+    /// The data is not aligned on a double word (FLASH_SR PGAERR)
+    Alignment,
+    /// The data is not written as a whole double word (FLASH_SR SIZERR)
+    Size,
+    /// The double word was not erased before programming (FLASH_SR PROGERR)
+    NotErased,
+    /// Programming or erase sequence not followed (FLASH_SR PGSERR, MISERR, FASTERR)
+    Sequence,
+    /// Read of a PCROP area (FLASH_SR RDERR)
+    ReadProtected,
 }
 
 /// A type alias for the result of a Flash operation.
@@ -0,0 +1,226 @@
+//! # Flash black-box log
+//!
+//! [`FlashLog`] appends records to the pages of a [`FlashRegion`] used as a ring, so that
+//! fault traces or encoded events (e.g. defmt frames) can be read back after a reset
+//! without external storage. When the last page is full the oldest page is erased, the log
+//! keeps the latest `pages - 1` pages of records at least.
+//!
+//! Each page starts with a double word holding a marker and the page sequence number, the
+//! log resumes at the page with the highest number. Each record is a double word header,
+//! holding the length and a CRC-32 of the data, followed by the data padded to double
+//! words. A record cut by a reset while it was written fails its CRC check and is
+//! skipped when reading, the next record is appended after it.
+//!
+//! The CRC is computed in software, so that the hardware CRC unit keeps the configuration
+//! of the application.
+use core::convert::TryInto;
+
+use crate::flash::{self, FlashRegion, UnlockedFlash, PAGE_SIZE};
+
+/// Marker of the first word of the pages in use
+const PAGE_MAGIC: u32 = 0x474f_4c42;
+const ERASED: u64 = u64::MAX;
+const HEADER_SIZE: usize = 8;
+
+/// Largest record, which fills a page
+pub const MAX_RECORD: usize = PAGE_SIZE as usize - 2 * HEADER_SIZE;
+
+/// Ring log in reserved flash pages, see the [module documentation](self)
+pub struct FlashLog {
+    region: FlashRegion,
+    /// Page being written, `None` until the first record
+    head: Option<usize>,
+    /// Sequence number of the head page
+    seq: u32,
+    /// Offset of the next record in the head page
+    offset: usize,
+}
+
+impl FlashLog {
+    /// Opens the log stored in `region`, which has been erased or written by the log only
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region has less than 2 pages.
+    pub fn open(region: FlashRegion) -> Self {
+        hal_assert!(region.pages() >= 2);
+        let mut log = FlashLog {
+            region,
+            head: None,
+            seq: 0,
+            offset: PAGE_SIZE as usize,
+        };
+        for page in 0..region.pages() {
+            if let Some(seq) = log.page_seq(page) {
+                if log.head.is_none() || seq > log.seq {
+                    log.head = Some(page);
+                    log.seq = seq;
+                }
+            }
+        }
+        if let Some(head) = log.head {
+            let mut records = Records::page(&log, head);
+            while records.next_header().is_some() {}
+            log.offset = records.offset;
+        }
+        log
+    }
+
+    /// Appends a record, erasing the oldest page when the head page is full
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` is longer than [`MAX_RECORD`].
+    pub fn append(&mut self, flash: &mut UnlockedFlash, data: &[u8]) -> flash::Result {
+        hal_assert!(data.len() <= MAX_RECORD);
+        let size = HEADER_SIZE + data.len().div_ceil(HEADER_SIZE) * HEADER_SIZE;
+        if self.head.is_none() || self.offset + size > PAGE_SIZE as usize {
+            self.next_page(flash)?;
+        }
+        let start = self.page_offset(self.head.unwrap_or(0)) + self.offset;
+        // The offset moves past the record even if it fails, the flash may be programmed
+        self.offset += size;
+        let header = data.len() as u32 | (!data.len() as u32) << 16;
+        let header = header as u64 | (crc32(data) as u64) << 32;
+        self.region.write(flash, start, &header.to_le_bytes())?;
+        self.region.write(flash, start + HEADER_SIZE, data)
+    }
+
+    /// Records from the oldest to the newest, the corrupted ones are skipped
+    pub fn records(&self) -> Records<'_> {
+        let pages = self.region.pages();
+        let first = self.head.map_or(0, |head| (head + 1) % pages);
+        let mut records = Records::page(self, first);
+        records.pages_left = pages - 1;
+        records
+    }
+
+    /// Erases the whole log
+    pub fn clear(&mut self, flash: &mut UnlockedFlash) -> flash::Result {
+        self.head = None;
+        self.seq = 0;
+        self.offset = PAGE_SIZE as usize;
+        self.region.erase(flash)
+    }
+
+    /// Releases the flash region
+    pub fn release(self) -> FlashRegion {
+        self.region
+    }
+
+    fn next_page(&mut self, flash: &mut UnlockedFlash) -> flash::Result {
+        let (page, seq) = match self.head {
+            Some(head) => ((head + 1) % self.region.pages(), self.seq.wrapping_add(1)),
+            None => (0, 0),
+        };
+        self.region.erase_page(flash, page)?;
+        self.head = Some(page);
+        self.seq = seq;
+        self.offset = PAGE_SIZE as usize;
+        let header = PAGE_MAGIC as u64 | (seq as u64) << 32;
+        self.region
+            .write(flash, self.page_offset(page), &header.to_le_bytes())?;
+        self.offset = HEADER_SIZE;
+        Ok(())
+    }
+
+    fn page_seq(&self, page: usize) -> Option<u32> {
+        let header = self.read(self.page_offset(page));
+        if header as u32 == PAGE_MAGIC {
+            Some((header >> 32) as u32)
+        } else {
+            None
+        }
+    }
+
+    fn page_offset(&self, page: usize) -> usize {
+        page * PAGE_SIZE as usize
+    }
+
+    fn read(&self, offset: usize) -> u64 {
+        let bytes = &self.region.as_slice()[offset..offset + HEADER_SIZE];
+        u64::from_le_bytes(bytes.try_into().unwrap_or([0xff; HEADER_SIZE]))
+    }
+}
+
+/// Iterator over the records of a [`FlashLog`], see [`FlashLog::records`]
+pub struct Records<'a> {
+    log: &'a FlashLog,
+    page: usize,
+    pages_left: usize,
+    offset: usize,
+}
+
+impl<'a> Records<'a> {
+    fn page(log: &'a FlashLog, page: usize) -> Self {
+        let offset = if log.page_seq(page).is_some() {
+            HEADER_SIZE
+        } else {
+            PAGE_SIZE as usize
+        };
+        Records {
+            log,
+            page,
+            pages_left: 0,
+            offset,
+        }
+    }
+
+    /// Returns the next record of the page, with its CRC, and moves past it
+    fn next_header(&mut self) -> Option<(&'a [u8], u32)> {
+        if self.offset + HEADER_SIZE > PAGE_SIZE as usize {
+            return None;
+        }
+        let start = self.log.page_offset(self.page) + self.offset;
+        let header = self.log.read(start);
+        let len = header as u16;
+        if header == ERASED || (header >> 16) as u16 != !len || len as usize > MAX_RECORD {
+            // End of the records, or a header cut by a reset: the rest of the page is unused
+            if header != ERASED {
+                self.offset = PAGE_SIZE as usize;
+            }
+            return None;
+        }
+        let len = len as usize;
+        self.offset += HEADER_SIZE + len.div_ceil(HEADER_SIZE) * HEADER_SIZE;
+        let data = &self.log.region.as_slice()[start + HEADER_SIZE..start + HEADER_SIZE + len];
+        Some((data, (header >> 32) as u32))
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_header() {
+                Some((data, crc)) if crc32(data) == crc => return Some(data),
+                Some(_) => continue,
+                None if self.pages_left > 0 => {
+                    let page = (self.page + 1) % self.log.region.pages();
+                    *self = Records {
+                        pages_left: self.pages_left - 1,
+                        ..Records::page(self.log, page)
+                    };
+                }
+                None => return None,
+            }
+        }
+    }
+}
+
+/// CRC-32 (ISO-HDLC), the CRC of zip and Ethernet
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
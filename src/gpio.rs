@@ -16,6 +16,19 @@ pub trait GpioExt {
     fn split(self, rcc: &mut Rcc) -> Self::Parts;
 }
 
+/// Output access to a whole port, implemented by the `Port` of each GPIO for the drivers of
+/// pin groups
+pub trait OutputPort {
+    /// Drives the pins of `high` high and the pins of `low` low, in the same bus cycle
+    fn write_pins(&mut self, high: u16, low: u16);
+}
+
+/// Input access to a whole port, implemented by the `Port` of each GPIO
+pub trait InputPort {
+    /// Input levels of all the pins
+    fn read_pins(&self) -> u16;
+}
+
 /// Input mode (type state)
 pub struct Input<MODE> {
     _mode: PhantomData<MODE>,
@@ -52,6 +65,30 @@ pub enum Speed {
     VeryHigh = 3,
 }
 
+/// Pin mode read back from MODER/AFRx, for diagnostics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinMode {
+    Input,
+    /// General purpose output, `true` when open drain
+    Output {
+        open_drain: bool,
+    },
+    /// Alternate function number, `true` when open drain
+    Alternate {
+        af: u8,
+        open_drain: bool,
+    },
+    Analog,
+}
+
+/// Pull resistor configuration (PUPDR)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pull {
+    None = 0b00,
+    Up = 0b01,
+    Down = 0b10,
+}
+
 /// Trigger edgw
 pub enum SignalEdge {
     Rising,
@@ -71,15 +108,95 @@ pub(crate) enum AltFunction {
     AF7 = 7,
 }
 
+fn decode_mode(moder: u32, otyper: u32, afr: u32, i: u8) -> PinMode {
+    let open_drain = otyper & (1 << i) != 0;
+    match (moder >> (2 * i)) & 0b11 {
+        0b00 => PinMode::Input,
+        0b01 => PinMode::Output { open_drain },
+        0b10 => {
+            let af = ((afr >> (4 * (i % 8))) & 0b1111) as u8;
+            PinMode::Alternate { af, open_drain }
+        }
+        _ => PinMode::Analog,
+    }
+}
+
+fn decode_pull(pupdr: u32, i: u8) -> Pull {
+    match (pupdr >> (2 * i)) & 0b11 {
+        0b01 => Pull::Up,
+        0b10 => Pull::Down,
+        _ => Pull::None,
+    }
+}
+
+/// embedded-hal 1.0 traits of a pin
+#[cfg(feature = "eh1")]
+macro_rules! eh1_pin {
+    ($PX:ident) => {
+        impl<MODE> hal1::digital::ErrorType for $PX<MODE> {
+            type Error = Infallible;
+        }
+
+        impl<MODE> hal1::digital::OutputPin for $PX<Output<MODE>> {
+            fn set_low(&mut self) -> Result<(), Infallible> {
+                self.internal_set_state(PinState::Low);
+                Ok(())
+            }
+
+            fn set_high(&mut self) -> Result<(), Infallible> {
+                self.internal_set_state(PinState::High);
+                Ok(())
+            }
+        }
+
+        impl<MODE> hal1::digital::StatefulOutputPin for $PX<Output<MODE>> {
+            fn is_set_high(&mut self) -> Result<bool, Infallible> {
+                Ok(!self.internal_is_set_low())
+            }
+
+            fn is_set_low(&mut self) -> Result<bool, Infallible> {
+                Ok(self.internal_is_set_low())
+            }
+
+            fn toggle(&mut self) -> Result<(), Infallible> {
+                self.internal_toggle();
+                Ok(())
+            }
+        }
+
+        impl<MODE> hal1::digital::InputPin for $PX<Output<MODE>> {
+            fn is_high(&mut self) -> Result<bool, Infallible> {
+                Ok(!self.internal_is_low())
+            }
+
+            fn is_low(&mut self) -> Result<bool, Infallible> {
+                Ok(self.internal_is_low())
+            }
+        }
+
+        impl<MODE> hal1::digital::InputPin for $PX<Input<MODE>> {
+            fn is_high(&mut self) -> Result<bool, Infallible> {
+                Ok(!self.internal_is_low())
+            }
+
+            fn is_low(&mut self) -> Result<bool, Infallible> {
+                Ok(self.internal_is_low())
+            }
+        }
+    };
+}
+
 macro_rules! gpio {
     ($GPIOX:ident, $gpiox:ident, $PXx:ident, $Pxn:expr, [
         $($PXi:ident: ($pxi:ident, $i:expr),)+
     ]) => {
         /// GPIO
         pub mod $gpiox {
+            #[cfg(any(feature = "legacy", feature = "eh1"))]
             use core::convert::Infallible;
             use core::marker::PhantomData;
-            use hal::digital::v2::{toggleable, InputPin, OutputPin, StatefulOutputPin};
+            #[cfg(feature = "legacy")]
+            use hal::digital::v2::{InputPin, OutputPin, StatefulOutputPin, ToggleableOutputPin};
             use crate::stm32::{EXTI, $GPIOX};
             use crate::exti::{ExtiExt, Event};
             use crate::rcc::{Enable, Rcc};
@@ -90,6 +207,91 @@ macro_rules! gpio {
                 $(
                     pub $pxi: $PXi<DefaultMode>,
                 )+
+                pub port: Port,
+            }
+
+            /// Whole port access, to update several output pins with a single store
+            ///
+            /// Only the pins configured as outputs are driven, the output data of the other
+            /// pins is updated but has no effect until they are switched to an output mode.
+            pub struct Port {
+                _private: (),
+            }
+
+            impl Port {
+                /// Drives the pins of `high` high and the pins of `low` low, in the same bus
+                /// cycle
+                ///
+                /// A pin in both masks is driven high.
+                #[inline(always)]
+                pub fn write_pins(&mut self, high: u16, low: u16) {
+                    // NOTE(unsafe) atomic write to a stateless register
+                    unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(high as u32 | (low as u32) << 16)) };
+                }
+
+                /// Drives the pins of `mask` low
+                #[inline(always)]
+                pub fn clear_pins(&mut self, mask: u16) {
+                    // NOTE(unsafe) atomic write to a stateless register
+                    unsafe { (*$GPIOX::ptr()).brr.write(|w| w.bits(mask as u32)) };
+                }
+
+                /// Inverts the pins of `mask`, the other pins are left untouched even if they
+                /// change in between the read and the write
+                #[inline(always)]
+                pub fn toggle_pins(&mut self, mask: u16) {
+                    let gpio = unsafe { &(*$GPIOX::ptr()) };
+                    let odr = gpio.odr.read().bits() & mask as u32;
+                    unsafe { gpio.bsrr.write(|w| w.bits(odr << 16 | (!odr & mask as u32))) };
+                }
+
+                /// Input levels of all the pins
+                #[inline(always)]
+                pub fn read_pins(&self) -> u16 {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$GPIOX::ptr()).idr.read().bits() as u16 }
+                }
+
+                /// Read-only access to the input levels, which can be used next to the port
+                pub fn input(&self) -> PortInput {
+                    PortInput { _private: () }
+                }
+            }
+
+            /// Input levels of the port, see [`Port::input`]
+            #[derive(Clone, Copy)]
+            pub struct PortInput {
+                _private: (),
+            }
+
+            impl PortInput {
+                /// Input levels of all the pins
+                #[inline(always)]
+                pub fn read_pins(&self) -> u16 {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$GPIOX::ptr()).idr.read().bits() as u16 }
+                }
+            }
+
+            impl OutputPort for Port {
+                #[inline(always)]
+                fn write_pins(&mut self, high: u16, low: u16) {
+                    Port::write_pins(self, high, low);
+                }
+            }
+
+            impl InputPort for Port {
+                #[inline(always)]
+                fn read_pins(&self) -> u16 {
+                    Port::read_pins(self)
+                }
+            }
+
+            impl InputPort for PortInput {
+                #[inline(always)]
+                fn read_pins(&self) -> u16 {
+                    PortInput::read_pins(self)
+                }
             }
 
             impl GpioExt for $GPIOX {
@@ -102,6 +304,7 @@ macro_rules! gpio {
                         $(
                             $pxi: $PXi { _mode: PhantomData },
                         )+
+                        port: Port { _private: () },
                     }
                 }
             }
@@ -112,73 +315,117 @@ macro_rules! gpio {
                 _mode: PhantomData<MODE>,
             }
 
+            /// Pin accesses shared by the embedded-hal 0.2 and 1.0 implementations
+            #[cfg(any(feature = "legacy", feature = "eh1"))]
+            impl<MODE> $PXx<MODE> {
+                fn internal_set_state(&mut self, state: PinState) {
+                    let bit = match state {
+                        PinState::High => self.i,
+                        PinState::Low => self.i + 16,
+                    };
+                    // NOTE(unsafe) atomic write to a stateless register
+                    unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(1 << bit)) };
+                }
+
+                fn internal_is_set_low(&self) -> bool {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$GPIOX::ptr()).odr.read().bits() & (1 << self.i) == 0 }
+                }
+
+                #[inline(always)]
+                fn internal_toggle(&mut self) {
+                    // A single store to BSRR, the other pins of the port are not rewritten
+                    let gpio = unsafe { &(*$GPIOX::ptr()) };
+                    let mask = 1 << self.i;
+                    let bits = if gpio.odr.read().bits() & mask != 0 { mask << 16 } else { mask };
+                    unsafe { gpio.bsrr.write(|w| w.bits(bits)) };
+                }
+
+                fn internal_is_low(&self) -> bool {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << self.i) == 0 }
+                }
+            }
+
+            #[cfg(feature = "legacy")]
             impl<MODE> OutputPin for $PXx<Output<MODE>> {
                 type Error = Infallible;
 
                 fn set_high(&mut self) -> Result<(), Self::Error> {
-                    // NOTE(unsafe) atomic write to a stateless register
-                    unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(1 << self.i)) };
+                    self.internal_set_state(PinState::High);
                     Ok(())
                 }
 
                 fn set_low(&mut self) -> Result<(), Self::Error> {
-                    // NOTE(unsafe) atomic write to a stateless register
-                    unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(1 << (self.i + 16))) };
+                    self.internal_set_state(PinState::Low);
+                    Ok(())
+                }
+
+                fn set_state(&mut self, state: PinState) -> Result<(), Self::Error> {
+                    self.internal_set_state(state);
                     Ok(())
                 }
             }
 
+            #[cfg(feature = "legacy")]
             impl<MODE> StatefulOutputPin for $PXx<Output<MODE>> {
                 fn is_set_high(&self) -> Result<bool, Self::Error> {
-                    let is_set_high = !self.is_set_low()?;
-                    Ok(is_set_high)
+                    Ok(!self.internal_is_set_low())
                 }
 
                 fn is_set_low(&self) -> Result<bool, Self::Error> {
-                    // NOTE(unsafe) atomic read with no side effects
-                    let is_set_low = unsafe { (*$GPIOX::ptr()).odr.read().bits() & (1 << self.i) == 0 };
-                    Ok(is_set_low)
+                    Ok(self.internal_is_set_low())
                 }
             }
 
-            impl<MODE> toggleable::Default for $PXx<Output<MODE>> {
+            #[cfg(feature = "legacy")]
+            impl<MODE> ToggleableOutputPin for $PXx<Output<MODE>> {
+                type Error = Infallible;
+
+                #[inline(always)]
+                fn toggle(&mut self) -> Result<(), Self::Error> {
+                    self.internal_toggle();
+                    Ok(())
+                }
             }
 
+            #[cfg(feature = "legacy")]
             impl<MODE> InputPin for $PXx<Output<MODE>> {
                 type Error = Infallible;
 
                 fn is_high(&self) -> Result<bool, Self::Error> {
-                    let is_high = !self.is_low()?;
-                    Ok(is_high)
+                    Ok(!self.internal_is_low())
                 }
 
                 fn is_low(&self) -> Result<bool, Self::Error>  {
-                    // NOTE(unsafe) atomic read with no side effects
-                    let is_low = unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << self.i) == 0 };
-                    Ok(is_low)
+                    Ok(self.internal_is_low())
                 }
             }
 
+            #[cfg(feature = "legacy")]
             impl<MODE> InputPin for $PXx<Input<MODE>> {
                 type Error = Infallible;
 
                 fn is_high(&self) -> Result<bool, Self::Error> {
-                    let is_high = !self.is_low()?;
-                    Ok(is_high)
+                    Ok(!self.internal_is_low())
                 }
 
                 fn is_low(&self) -> Result<bool, Self::Error> {
-                    // NOTE(unsafe) atomic read with no side effects
-                    let is_low = unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << self.i) == 0 };
-                    Ok(is_low)
+                    Ok(self.internal_is_low())
                 }
             }
 
+            #[cfg(feature = "eh1")]
+            eh1_pin!($PXx);
+
             $(
                 pub struct $PXi<MODE> {
                     _mode: PhantomData<MODE>,
                 }
 
+                #[cfg(feature = "eh1")]
+                eh1_pin!($PXi);
+
                 #[allow(clippy::from_over_into)]
                 impl Into<$PXi<Input<PullDown>>> for $PXi<DefaultMode> {
                     fn into(self) -> $PXi<Input<PullDown>> {
@@ -328,7 +575,7 @@ macro_rules! gpio {
                     }
 
                     /// Configures the pin as external trigger
-                    pub fn listen(self, edge: SignalEdge, exti: &mut EXTI) -> $PXi<Input<Floating>> {
+                    pub fn listen(self, edge: SignalEdge, exti: &EXTI) -> $PXi<Input<Floating>> {
                         let offset = 2 * $i;
                         unsafe {
                             let _ = &(*$GPIOX::ptr()).pupdr.modify(|r, w| {
@@ -341,25 +588,54 @@ macro_rules! gpio {
                         let offset = ($i % 4) * 8;
                         let mask = $Pxn << offset;
                         let reset = !(0xff << offset);
-                        match $i as u8 {
-                            0..=3   => exti.exticr1.modify(|r, w| unsafe {
-                                w.bits(r.bits() & reset | mask)
-                            }),
-                            4..=7  => exti.exticr2.modify(|r, w| unsafe {
-                                w.bits(r.bits() & reset | mask)
-                            }),
-                            8..=11 => exti.exticr3.modify(|r, w| unsafe {
-                                w.bits(r.bits() & reset | mask)
-                            }),
-                            12..=16 => exti.exticr4.modify(|r, w| unsafe {
-                                w.bits(r.bits() & reset | mask)
-                            }),
-                            _ => unreachable!(),
-                        }
+                        // EXTICR fields of the other lines may be set from interrupts
+                        cortex_m::interrupt::free(|_| {
+                            match $i as u8 {
+                                0..=3   => exti.exticr1.modify(|r, w| unsafe {
+                                    w.bits(r.bits() & reset | mask)
+                                }),
+                                4..=7  => exti.exticr2.modify(|r, w| unsafe {
+                                    w.bits(r.bits() & reset | mask)
+                                }),
+                                8..=11 => exti.exticr3.modify(|r, w| unsafe {
+                                    w.bits(r.bits() & reset | mask)
+                                }),
+                                12..=16 => exti.exticr4.modify(|r, w| unsafe {
+                                    w.bits(r.bits() & reset | mask)
+                                }),
+                                _ => unreachable!(),
+                            }
+                        });
                         exti.listen(Event::from_code($i), edge);
                         $PXi { _mode: PhantomData }
                     }
 
+                    /// Current mode of the pin, as configured in the registers
+                    pub fn get_mode(&self) -> PinMode {
+                        let gpio = unsafe { &*$GPIOX::ptr() };
+                        let afr = if $i < 8 {
+                            gpio.afrl.read().bits()
+                        } else {
+                            gpio.afrh.read().bits()
+                        };
+                        decode_mode(gpio.moder.read().bits(), gpio.otyper.read().bits(), afr, $i)
+                    }
+
+                    /// Current pull resistor configuration of the pin
+                    pub fn get_pull(&self) -> Pull {
+                        decode_pull(unsafe { (*$GPIOX::ptr()).pupdr.read().bits() }, $i)
+                    }
+
+                    /// Sets the pull resistor, keeping the mode of the pin
+                    ///
+                    /// The pull is kept when the pin is handed to a peripheral driver, which
+                    /// only switches it to its alternate function: e.g. call this on the
+                    /// open drain I2C pins to use the internal pull-ups.
+                    pub fn with_pull(self, pull: Pull) -> Self {
+                        self.set_pull(pull);
+                        self
+                    }
+
                     /// Set pin speed
                     pub fn set_speed(self, speed: Speed) -> Self {
                         let offset = 2 * $i;
@@ -394,6 +670,56 @@ macro_rules! gpio {
                         }
                     }
 
+                    pub(crate) fn set_pull(&self, pull: Pull) {
+                        let offset = 2 * $i;
+                        unsafe {
+                            (*$GPIOX::ptr()).pupdr.modify(|r, w| {
+                                w.bits((r.bits() & !(0b11 << offset)) | ((pull as u32) << offset))
+                            });
+                        }
+                    }
+
+                    /// Drives the pin low, then releases it as an open drain output and counts
+                    /// the input polls until it reads high, the pin configuration is restored
+                    /// afterwards
+                    ///
+                    /// Returns `None` if the pin is still low after `max_polls` polls.
+                    #[allow(dead_code)]
+                    pub(crate) fn time_rise(&self, max_polls: u32) -> Option<u32> {
+                        let offset = 2 * $i;
+                        let mask = 1 << $i;
+                        let gpio = unsafe { &(*$GPIOX::ptr()) };
+                        let odr = gpio.odr.read().bits();
+                        // MODER and OTYPER are shared with the other pins of the port, an
+                        // interrupt handler must not change them between the read and the restore
+                        let polls = cortex_m::interrupt::free(|_| unsafe {
+                            let moder = gpio.moder.read().bits();
+                            let otyper = gpio.otyper.read().bits();
+                            gpio.bsrr.write(|w| w.bits(mask << 16));
+                            gpio.otyper.write(|w| w.bits(otyper | mask));
+                            gpio.moder.write(|w| w.bits((moder & !(0b11 << offset)) | (0b01 << offset)));
+                            let mut polls = 0;
+                            while gpio.idr.read().bits() & mask != 0 && polls < max_polls {
+                                polls += 1;
+                            }
+                            gpio.bsrr.write(|w| w.bits(mask));
+                            let mut polls = 0;
+                            while gpio.idr.read().bits() & mask == 0 && polls < max_polls {
+                                polls += 1;
+                            }
+                            let restore = if odr & mask != 0 { mask } else { mask << 16 };
+                            gpio.bsrr.write(|w| w.bits(restore));
+                            gpio.otyper.write(|w| w.bits(otyper));
+                            gpio.moder.write(|w| w.bits(moder));
+                            polls
+                        });
+                        if polls < max_polls {
+                            Some(polls)
+                        } else {
+                            None
+                        }
+                    }
+
                     fn internal_set_state(&mut self, state: PinState) {
                         match state {
                             PinState::High => {
@@ -406,6 +732,29 @@ macro_rules! gpio {
                             }
                         }
                     }
+
+                    #[cfg(any(feature = "legacy", feature = "eh1"))]
+                    fn internal_is_set_low(&self) -> bool {
+                        // NOTE(unsafe) atomic read with no side effects
+                        unsafe { (*$GPIOX::ptr()).odr.read().bits() & (1 << $i) == 0 }
+                    }
+
+                    #[cfg(any(feature = "legacy", feature = "eh1"))]
+                    #[inline(always)]
+                    fn internal_toggle(&mut self) {
+                        // A single store to BSRR, the other pins of the port are not rewritten
+                        let gpio = unsafe { &(*$GPIOX::ptr()) };
+                        let mask = 1 << $i;
+                        let high = gpio.odr.read().bits() & mask != 0;
+                        let bits = if high { mask << 16 } else { mask };
+                        unsafe { gpio.bsrr.write(|w| w.bits(bits)) };
+                    }
+
+                    #[cfg(any(feature = "legacy", feature = "eh1"))]
+                    fn internal_is_low(&self) -> bool {
+                        // NOTE(unsafe) atomic read with no side effects
+                        unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << $i) == 0 }
+                    }
                 }
 
                 impl<MODE> $PXi<Output<MODE>> {
@@ -418,6 +767,7 @@ macro_rules! gpio {
                     }
                 }
 
+                #[cfg(feature = "legacy")]
                 impl<MODE> OutputPin for $PXi<Output<MODE>> {
                     type Error = Infallible;
 
@@ -430,36 +780,45 @@ macro_rules! gpio {
                         self.internal_set_state(PinState::Low);
                         Ok(())
                     }
+
+                    fn set_state(&mut self, state: PinState) -> Result<(), Self::Error> {
+                        self.internal_set_state(state);
+                        Ok(())
+                    }
                 }
 
+                #[cfg(feature = "legacy")]
                 impl<MODE> StatefulOutputPin for $PXi<Output<MODE>> {
                     fn is_set_high(&self) -> Result<bool, Self::Error> {
-                        let is_set_high = !self.is_set_low()?;
-                        Ok(is_set_high)
+                        Ok(!self.internal_is_set_low())
                     }
 
                     fn is_set_low(&self) -> Result<bool, Self::Error> {
-                        // NOTE(unsafe) atomic read with no side effects
-                        let is_set_low = unsafe { (*$GPIOX::ptr()).odr.read().bits() & (1 << $i) == 0 };
-                        Ok(is_set_low)
+                        Ok(self.internal_is_set_low())
                     }
                 }
 
-                impl<MODE> toggleable::Default for $PXi<Output<MODE>> {
+                #[cfg(feature = "legacy")]
+                impl<MODE> ToggleableOutputPin for $PXi<Output<MODE>> {
+                    type Error = Infallible;
+
+                    #[inline(always)]
+                    fn toggle(&mut self) -> Result<(), Self::Error> {
+                        self.internal_toggle();
+                        Ok(())
+                    }
                 }
 
+                #[cfg(feature = "legacy")]
                 impl<MODE> InputPin for $PXi<Output<MODE>> {
                     type Error = Infallible;
 
                     fn is_high(&self) -> Result<bool, Self::Error> {
-                        let is_high = !self.is_low()?;
-                        Ok(is_high)
+                        Ok(!self.internal_is_low())
                     }
 
                     fn is_low(&self) -> Result<bool, Self::Error>  {
-                        // NOTE(unsafe) atomic read with no side effects
-                        let is_low = unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << $i) == 0 };
-                        Ok(is_low)
+                        Ok(self.internal_is_low())
                     }
                 }
 
@@ -473,18 +832,16 @@ macro_rules! gpio {
                     }
                 }
 
+                #[cfg(feature = "legacy")]
                 impl<MODE> InputPin for $PXi<Input<MODE>> {
                     type Error = Infallible;
 
                     fn is_high(&self) -> Result<bool, Self::Error> {
-                        let is_high = !self.is_low()?;
-                        Ok(is_high)
+                        Ok(!self.internal_is_low())
                     }
 
                     fn is_low(&self) -> Result<bool, Self::Error> {
-                        // NOTE(unsafe) atomic read with no side effects
-                        let is_low = unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << $i) == 0 };
-                        Ok(is_low)
+                        Ok(self.internal_is_low())
                     }
                 }
             )+
@@ -493,6 +850,22 @@ macro_rules! gpio {
                 pub fn get_id (&self) -> u8 {
                     self.i
                 }
+
+                /// Current mode of the pin, as configured in the registers
+                pub fn get_mode(&self) -> PinMode {
+                    let gpio = unsafe { &*$GPIOX::ptr() };
+                    let afr = if self.i < 8 {
+                        gpio.afrl.read().bits()
+                    } else {
+                        gpio.afrh.read().bits()
+                    };
+                    decode_mode(gpio.moder.read().bits(), gpio.otyper.read().bits(), afr, self.i)
+                }
+
+                /// Current pull resistor configuration of the pin
+                pub fn get_pull(&self) -> Pull {
+                    decode_pull(unsafe { (*$GPIOX::ptr()).pupdr.read().bits() }, self.i)
+                }
             }
         }
     }
@@ -1,12 +1,22 @@
 //! I2C
+use crate::debug::{Dump, I2cRegisters};
 use crate::gpio::{gpioa::*, gpiob::*};
-use crate::gpio::{AltFunction, OpenDrain, Output};
-use crate::i2c::config::Config;
-use crate::i2c::{Error, I2c, I2cDirection, I2cExt, SCLPin, SDAPin};
+use crate::gpio::{AltFunction, OpenDrain, Output, Pull};
+use crate::i2c::config::{ClockSource, Config};
+use crate::i2c::{wait, wait_stop, AsOperation, Operation};
+use crate::i2c::{BusHealth, Error, I2c, I2cDirection, I2cExt, LineHealth, SCLPin, SDAPin};
+use crate::power::WaitIdle;
 use crate::rcc::*;
-use crate::stm32::{I2C1, I2C2};
+use crate::stm32::{i2c1, I2C1, I2C2};
+use crate::syscfg::{self, FastModePlus};
+use crate::time::NanoSecond;
+use core::ops::Deref;
+#[cfg(feature = "legacy")]
 use hal::blocking::i2c::{Read, Write, WriteRead};
 
+/// Approximate number of core clock cycles of a GPIO input poll, for `bus_health`
+const CYCLES_PER_POLL: u64 = 4;
+
 pub trait I2cSlave {
     /// Enable/ disable sbc. Default sbc is switched on.
     /// For master write/read the transaction should start with sbc disabled.
@@ -107,8 +117,151 @@ macro_rules! busy_wait {
     };
 }
 
+impl<I2C, SDA, SCL> I2c<I2C, SDA, SCL>
+where
+    I2C: Deref<Target = i2c1::RegisterBlock>,
+{
+    /// Writes `bytes` to the slave at `addr`
+    pub fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.transaction(addr, &mut [Operation::Write(bytes)])
+    }
+
+    /// Reads `buffer.len()` bytes from the slave at `addr`
+    pub fn read(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.transaction(addr, &mut [Operation::Read(buffer)])
+    }
+
+    /// Writes `bytes` then reads into `buffer` after a repeated START
+    pub fn write_read(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        self.transaction(
+            addr,
+            &mut [Operation::Write(bytes), Operation::Read(buffer)],
+        )
+    }
+
+    /// Runs a master transaction
+    ///
+    /// Adjacent operations in the same direction are merged into a single transfer,
+    /// continued with RELOAD every 255 bytes. A change of direction sends a repeated START
+    /// and the transaction ends with a STOP.
+    pub(crate) fn transaction<O: AsOperation>(
+        &mut self,
+        addr: u8,
+        operations: &mut [O],
+    ) -> Result<(), Error> {
+        // Wait for any previous address sequence to end automatically
+        while self.i2c.cr2.read().start().bit_is_set() {}
+
+        let mut first = 0;
+        while first < operations.len() {
+            let read = matches!(operations[first].as_operation(), Operation::Read(_));
+            let mut end = first + 1;
+            while end < operations.len()
+                && matches!(operations[end].as_operation(), Operation::Read(_)) == read
+            {
+                end += 1;
+            }
+            let last = end == operations.len();
+            self.transfer(addr, read, &mut operations[first..end], last)?;
+            first = end;
+        }
+        Ok(())
+    }
+
+    /// Transfers the bytes of operations in the same direction, after a (repeated) START
+    fn transfer<O: AsOperation>(
+        &mut self,
+        addr: u8,
+        read: bool,
+        operations: &mut [O],
+        last: bool,
+    ) -> Result<(), Error> {
+        let mut remaining: usize = operations
+            .iter_mut()
+            .map(|op| match op.as_operation() {
+                Operation::Read(buffer) => buffer.len(),
+                Operation::Write(bytes) => bytes.len(),
+            })
+            .sum();
+        let mut nbytes = remaining.min(255);
+        remaining -= nbytes;
+
+        if read {
+            let _ = self.i2c.rxdr.read().rxdata().bits();
+        } else {
+            self.i2c.isr.write(|w| w.txe().set_bit());
+        }
+        self.i2c.cr2.write(|w| unsafe {
+            w.nbytes()
+                .bits(nbytes as u8)
+                .sadd()
+                .bits((addr << 1) as u16)
+                .add10()
+                .clear_bit()
+                .rd_wrn()
+                .bit(read)
+                .reload()
+                .bit(remaining > 0)
+                .autoend()
+                .bit(last && remaining == 0)
+                .start()
+                .set_bit()
+        });
+
+        for op in operations.iter_mut() {
+            match op.as_operation() {
+                Operation::Write(bytes) => {
+                    for byte in bytes.iter() {
+                        self.next_byte(&mut nbytes, &mut remaining, last)?;
+                        wait(&self.i2c, |isr| isr.txis().bit_is_set())?;
+                        self.i2c.txdr.write(|w| unsafe { w.txdata().bits(*byte) });
+                    }
+                }
+                Operation::Read(buffer) => {
+                    for byte in buffer.iter_mut() {
+                        self.next_byte(&mut nbytes, &mut remaining, last)?;
+                        wait(&self.i2c, |isr| isr.rxne().bit_is_set())?;
+                        *byte = self.i2c.rxdr.read().rxdata().bits();
+                    }
+                }
+            }
+        }
+
+        if last {
+            wait_stop(&self.i2c)
+        } else {
+            // Software end mode, the next transfer starts with a repeated START
+            wait(&self.i2c, |isr| isr.tc().bit_is_set())
+        }
+    }
+
+    /// Counts a byte of the transfer, setting up the next chunk once NBYTES are done
+    fn next_byte(
+        &mut self,
+        nbytes: &mut usize,
+        remaining: &mut usize,
+        last: bool,
+    ) -> Result<(), Error> {
+        if *nbytes == 0 {
+            wait(&self.i2c, |isr| isr.tcr().bit_is_set())?;
+            *nbytes = (*remaining).min(255);
+            *remaining -= *nbytes;
+            self.i2c.cr2.modify(|_, w| unsafe {
+                w.nbytes()
+                    .bits(*nbytes as u8)
+                    .reload()
+                    .bit(*remaining > 0)
+                    .autoend()
+                    .bit(last && *remaining == 0)
+            });
+        }
+        *nbytes -= 1;
+        Ok(())
+    }
+}
+
 macro_rules! i2c {
-    ($I2CX:ident, $i2cx:ident,
+    ($I2CX:ident, $i2cx:ident $(: $i2cxsel:ident)?, $fmp:ident,
         sda: [ $($PSDA:ty,)+ ],
         scl: [ $($PSCL:ty,)+ ],
     ) => {
@@ -121,6 +274,14 @@ macro_rules! i2c {
                 fn release(self) -> Self {
                     self.into_open_drain_output()
                 }
+
+                fn set_pull_up(&self, enable: bool) {
+                    self.set_pull(if enable { Pull::Up } else { Pull::None });
+                }
+
+                fn time_rise(&self, max_polls: u32) -> Option<u32> {
+                    self.time_rise(max_polls)
+                }
             }
         )+
 
@@ -133,6 +294,14 @@ macro_rules! i2c {
                 fn release(self) -> Self {
                     self.into_open_drain_output()
                 }
+
+                fn set_pull_up(&self, enable: bool) {
+                    self.set_pull(if enable { Pull::Up } else { Pull::None });
+                }
+
+                fn time_rise(&self, max_polls: u32) -> Option<u32> {
+                    self.time_rise(max_polls)
+                }
             }
         )+
 
@@ -168,9 +337,25 @@ macro_rules! i2c {
                 // Make sure the I2C unit is disabled so we can configure it
                 i2c.cr1.modify(|_, w| w.pe().clear_bit());
 
+                // Select the kernel clock, only the instances with an I2CxSEL mux can
+                // use something else than PCLK
+                let _i2c_clk = rcc.clocks.apb_clk;
+                $(
+                    if config.clock_source == ClockSource::HSI16 {
+                        rcc.enable_hsi();
+                    }
+                    rcc.ccipr.modify(|_, w| unsafe {
+                        w.$i2cxsel().bits(config.clock_source as u8)
+                    });
+                    let _i2c_clk = config.clock_source.freq(&rcc.clocks);
+                )?
+
                 // Setup protocol timings
-                let timing_bits = config.timing_bits(rcc.clocks.apb_clk);
+                let timing = config.timing_bits(_i2c_clk);
+                hal_assert!(timing.is_ok(), "I2C timing out of the bus specification");
+                let timing_bits = timing.unwrap_or_else(|err| err.closest);
                 i2c.timingr.write(|w| unsafe { w.bits(timing_bits) });
+                syscfg::set_fast_mode_plus(FastModePlus::$fmp, config.is_fast_mode_plus());
 
                 // Enable the I2C processing
                 i2c.cr1.modify(|_, w| unsafe {
@@ -180,6 +365,8 @@ macro_rules! i2c {
                         .bits(config.digital_filter)
                         .anfoff()
                         .bit(!config.analog_filter)
+                        .gcen()
+                        .bit(config.general_call)
                 });
 
                 if config.slave_address_1 > 0 {
@@ -203,6 +390,8 @@ macro_rules! i2c {
                 }
 
                 // Enable pins
+                sda.set_pull_up(config.internal_pull_up);
+                scl.set_pull_up(config.internal_pull_up);
                 sda.setup();
                 scl.setup();
 
@@ -212,8 +401,78 @@ macro_rules! i2c {
             pub fn release(self) -> ($I2CX, SDA, SCL) {
                 (self.i2c, self.sda.release(), self.scl.release())
             }
+
+            /// Checks the pull-ups of the bus by timing the rise of both lines
+            ///
+            /// Each line is driven low as a GPIO and released, the time it takes to read
+            /// high again is estimated from the number of input polls, so the result is
+            /// only accurate to a few core clock cycles. The bus has to be idle, the
+            /// peripheral is disabled during the test.
+            pub fn bus_health(&mut self, max_rise: NanoSecond, rcc: &Rcc) -> BusHealth {
+                let enabled = self.i2c.cr1.read().pe().bit_is_set();
+                self.i2c.cr1.modify(|_, w| w.pe().clear_bit());
+
+                let clk = rcc.clocks.core_clk.raw() as u64;
+                let to_ns = |polls: u32| {
+                    (polls as u64 * CYCLES_PER_POLL * 1_000_000_000 / clk) as u32
+                };
+                // Wait ten times the expected rise time before declaring the line stuck
+                let max_polls = (10 * max_rise.ticks() as u64 * clk
+                    / (CYCLES_PER_POLL * 1_000_000_000))
+                    .clamp(16, u32::MAX as u64) as u32;
+                let health = |polls: Option<u32>| match polls {
+                    Some(polls) if to_ns(polls) <= max_rise.ticks() => LineHealth::Ok(to_ns(polls)),
+                    Some(polls) => LineHealth::SlowRise(to_ns(polls)),
+                    None => LineHealth::StuckLow,
+                };
+                let result = BusHealth {
+                    scl: health(self.scl.time_rise(max_polls)),
+                    sda: health(self.sda.time_rise(max_polls)),
+                };
+
+                self.i2c.cr1.modify(|_, w| w.pe().bit(enabled));
+                result
+            }
+
+            /// Gives scoped access to the peripheral registers
+            ///
+            /// # Safety
+            ///
+            /// The driver keeps relying on its own setup, `f` must leave the peripheral
+            /// clock, the I2C enable and the configuration registers as it found them.
+            pub unsafe fn with_registers<F, R>(&mut self, f: F) -> R
+            where
+                F: FnOnce(&$I2CX) -> R,
+            {
+                f(&self.i2c)
+            }
+        }
+
+        impl<SDA, SCL> WaitIdle for I2c<$I2CX, SDA, SCL>
+        where
+            SDA: SDAPin<$I2CX>,
+            SCL: SCLPin<$I2CX>,
+        {
+            /// Waits until the bus is released by the current transfer (BUSY)
+            fn wait_idle(&mut self) {
+                while self.i2c.isr.read().busy().bit_is_set() {}
+            }
+        }
+
+        impl<SDA, SCL> Dump for I2c<$I2CX, SDA, SCL> {
+            type Registers = I2cRegisters;
+
+            fn dump(&self) -> I2cRegisters {
+                I2cRegisters {
+                    cr1: self.i2c.cr1.read().bits(),
+                    cr2: self.i2c.cr2.read().bits(),
+                    timingr: self.i2c.timingr.read().bits(),
+                    isr: self.i2c.isr.read().bits(),
+                }
+            }
         }
 
+        #[cfg(feature = "legacy")]
         impl<SDA, SCL> WriteRead for I2c<$I2CX, SDA, SCL> {
             type Error = Error;
 
@@ -223,162 +482,25 @@ macro_rules! i2c {
                 snd_buffer: &[u8],
                 rcv_buffer: &mut [u8],
             ) -> Result<(), Self::Error> {
-                // TODO support transfers of more than 255 bytes
-                let sndlen = snd_buffer.len();
-                let rcvlen = rcv_buffer.len();
-                assert!(sndlen < 256 && sndlen > 0);
-                assert!(rcvlen < 256 && rcvlen > 0);
-
-                // Wait for any previous address sequence to end automatically.
-                // This could be up to 50% of a bus cycle (ie. up to 0.5/freq)
-                while self.i2c.cr2.read().start().bit_is_set() { () };
-
-                // flush i2c tx register
-                self.i2c.isr.write(|w| w.txe().set_bit());
-
-                // Set START and prepare to send `bytes`.
-                // The START bit can be set even if the bus is BUSY or
-                // I2C is in slave mode.
-                self.i2c.cr2.write(|w| unsafe {
-                    w
-                        // Set number of bytes to transfer
-                        .nbytes().bits(sndlen as u8)
-                        // Set address to transfer to/from
-                        .sadd().bits((addr << 1) as u16)
-                        // 7-bit addressing mode
-                        .add10().clear_bit()
-                        // Set transfer direction to write
-                        .rd_wrn().clear_bit()
-                        // Software end mode
-                        .autoend().clear_bit()
-                        .reload().clear_bit()
-                        // Start transfer
-                        .start().set_bit()
-                });
-                let mut idx = 0;
-                // Wait until we are allowed to send data
-                // (START has been ACKed or last byte went through)
-                // macro will return false when the tc bit is set
-                for byte in snd_buffer {
-                    busy_wait!(self.i2c, txis, bit_is_set, idx, sndlen);
-                    // Put byte on the wire
-                    self.i2c.txdr.write(|w| unsafe { w.txdata().bits(*byte) });
-                    idx += 1;
-                }
-                // Wait until the write finishes before beginning to read.
-                let dummy  = 0xFE;
-                busy_wait!(self.i2c, tc, bit_is_set, idx, dummy );
-
-                // reSTART and prepare to receive bytes into `rcv_buffer`
-                self.i2c.cr2.write(|w| unsafe {
-                    w
-                        // Set number of bytes to transfer
-                        .nbytes().bits(rcvlen as u8)
-                        // Set address to transfer to/from
-                        .sadd().bits((addr << 1) as u16)
-                        // 7-bit addressing mode
-                        .add10().clear_bit()
-                        // Set transfer direction to read
-                        .rd_wrn().set_bit()
-                        // Automatic end mode
-                        .autoend().set_bit()
-                        .reload().clear_bit()
-                        // Start transfer
-                        .start().set_bit()
-                });
-
-                idx = 0;
-                loop {
-                    // Wait until we have received something. Handle all state in busy_wait macro
-                    busy_wait!(self.i2c, rxne, bit_is_set, idx, rcvlen);
-                    if idx < rcvlen {
-                        rcv_buffer[idx] = self.i2c.rxdr.read().rxdata().bits();
-                        idx +=1;
-                    }
-                }
+                I2c::write_read(self, addr, snd_buffer, rcv_buffer)
             }
         }
 
+        #[cfg(feature = "legacy")]
         impl<SDA, SCL> Write for I2c<$I2CX, SDA, SCL> {
             type Error = Error;
 
             fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Self::Error> {
-                let buflen = bytes.len();
-                assert!(buflen < 256 && buflen > 0);
-
-                // Wait for any previous address sequence to end automatically.
-                // This could be up to 50% of a bus cycle (ie. up to 0.5/freq)
-                while self.i2c.cr2.read().start().bit_is_set() { () };
-
-                self.i2c.cr2.modify(|_, w| unsafe {
-                    w
-                        // Start transfer
-                        .start().set_bit()
-                        // Set number of bytes to transfer
-                        .nbytes().bits(buflen as u8)
-                        // Set address to transfer to/from
-                        .sadd().bits((addr << 1) as u16)
-                        // Set transfer direction to write
-                        .rd_wrn().clear_bit()
-                        // Automatic end mode
-                        .autoend().set_bit()
-                        .reload().clear_bit()
-                });
-
-                let mut idx = 0;
-                loop {
-                    // Wait until we are allowed to send data, handle all state in busy_wait macro
-                    busy_wait!(self.i2c, txis, bit_is_set, idx, buflen);
-
-                    // Put byte on the wire
-                    if idx < buflen {
-                        self.i2c.txdr.write(|w| unsafe { w.txdata().bits(bytes[idx]) });
-                        idx += 1;
-                    }
-                }
+                I2c::write(self, addr, bytes)
             }
         }
 
+        #[cfg(feature = "legacy")]
         impl<SDA, SCL> Read for I2c<$I2CX, SDA, SCL> {
             type Error = Error;
 
             fn read(&mut self, addr: u8, bytes: &mut [u8]) -> Result<(), Self::Error> {
-                let buflen = bytes.len();
-                // TODO support transfers of more than 255 bytes
-                assert!(buflen < 256 && buflen > 0);
-
-                // Wait for any previous address sequence to end automatically.
-                // This could be up to 50% of a bus cycle (ie. up to 0.5/freq)
-                while self.i2c.cr2.read().start().bit_is_set() { () };
-                // Flush rxdr register
-                let _ = self.i2c.rxdr.read().rxdata().bits();
-
-                // Set START and prepare to receive bytes into `buffer`.
-                // The START bit can be set even if the bus
-                // is BUSY or I2C is in slave mode.
-                self.i2c.cr2.modify(|_, w| unsafe {
-                    w
-                        // Start transfer
-                        .start().set_bit()
-                        // Set number of bytes to transfer
-                        .nbytes().bits(buflen as u8)
-                        // Set address to transfer to/from
-                        .sadd().bits((addr << 1) as u16)
-                        // Set transfer direction to read
-                        .rd_wrn().set_bit()
-                        // automatic end mode
-                        .autoend().set_bit()
-                        .reload().clear_bit()
-                    });
-                let mut idx = 0;
-                loop {
-                    // Wait until we have received something
-                    busy_wait!(self.i2c, rxne, bit_is_set, idx, buflen);
-                    if idx < buflen {
-                        bytes[idx] = self.i2c.rxdr.read().rxdata().bits();
-                        idx +=1;
-                    }
-                }
+                I2c::read(self, addr, bytes)
             }
         }
 
@@ -414,7 +536,9 @@ macro_rules! i2c {
             fn slave_write(&mut self, bytes: &[u8]) -> Result<(), Error> {
                 let buflen = bytes.len();
                 // TODO support transfers of more than 255 bytes
-                assert!(buflen < 256 && buflen > 0);
+                if !(1..256).contains(&buflen) {
+                    return Err(Error::IncorrectFrameSize(buflen));
+                }
 
                 // Set the nbytes and prepare to send bytes into `buffer`.
                 self.i2c.cr2.modify(|_, w| unsafe {
@@ -447,7 +571,9 @@ macro_rules! i2c {
             fn slave_read(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
                 let buflen = bytes.len();
                 // TODO support transfers of more than 255 bytes
-                assert!(buflen < 256 && buflen > 0);
+                if !(1..256).contains(&buflen) {
+                    return Err(Error::IncorrectFrameSize(buflen));
+                }
 
                 // Set the nbytes START and prepare to receive bytes into `buffer`.
                 self.i2c.cr2.modify(|_, w| unsafe {
@@ -481,7 +607,8 @@ macro_rules! i2c {
 
 i2c!(
     I2C1,
-    i2c1,
+    i2c1: i2c1sel,
+    I2c1,
     sda: [
         PA10<Output<OpenDrain>>,
         PB7<Output<OpenDrain>>,
@@ -497,6 +624,7 @@ i2c!(
 i2c!(
     I2C2,
     i2c2,
+    I2c2,
     sda: [
         PA12<Output<OpenDrain>>,
         PB11<Output<OpenDrain>>,
@@ -1,16 +1,84 @@
 use crate::i2c::SlaveAddressMask;
+use crate::rcc::{Clocks, HSI_FREQ};
 use crate::time::Hertz;
 use core::cmp;
+use fugit::RateExtU32;
+
+/// I2C kernel clock source (RCC_CCIPR I2CxSEL)
+///
+/// Only I2C1 has a kernel clock mux, I2C2 is always clocked from PCLK.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSource {
+    Pclk = 0b00,
+    SysClk = 0b01,
+    HSI16 = 0b10,
+}
+
+impl ClockSource {
+    /// Kernel clock frequency for the given clock configuration
+    pub fn freq(&self, clocks: &Clocks) -> Hertz {
+        match self {
+            ClockSource::Pclk => clocks.apb_clk,
+            ClockSource::SysClk => clocks.sys_clk,
+            ClockSource::HSI16 => HSI_FREQ.Hz(),
+        }
+    }
+}
+
+/// Bus characteristics from the I2C-bus specification (UM10204, table 10), in nanoseconds
+struct BusSpec {
+    low_min: u32,
+    high_min: u32,
+    su_dat_min: u32,
+    hd_dat_max: u32,
+}
+
+const STANDARD_MODE: BusSpec = BusSpec {
+    low_min: 4_700,
+    high_min: 4_000,
+    su_dat_min: 250,
+    hd_dat_max: 3_450,
+};
+
+const FAST_MODE: BusSpec = BusSpec {
+    low_min: 1_300,
+    high_min: 600,
+    su_dat_min: 100,
+    hd_dat_max: 900,
+};
+
+const FAST_MODE_PLUS: BusSpec = BusSpec {
+    low_min: 500,
+    high_min: 260,
+    su_dat_min: 50,
+    hd_dat_max: 450,
+};
+
+/// Analog filter delay range (DS12766 table "I2C analog filter characteristics")
+const ANALOG_FILTER_MIN_NS: u32 = 50;
+const ANALOG_FILTER_MAX_NS: u32 = 260;
+
+/// The I2C timing can not meet the bus specification with the given kernel clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTiming {
+    /// TIMINGR value closest to the specification
+    pub closest: u32,
+}
 
 pub struct Config {
     pub speed: Option<Hertz>,
     pub timing: Option<u32>,
+    pub clock_source: ClockSource,
+    pub rise_time_ns: u32,
+    pub fall_time_ns: u32,
     pub analog_filter: bool,
     pub digital_filter: u8,
     pub slave_address_1: u16,
     pub address_11bits: bool,
     pub slave_address_2: u8,
     pub slave_address_mask: SlaveAddressMask,
+    pub general_call: bool,
+    pub internal_pull_up: bool,
 }
 
 impl Config {
@@ -18,12 +86,17 @@ impl Config {
         Config {
             speed: Some(speed),
             timing: None,
+            clock_source: ClockSource::Pclk,
+            rise_time_ns: 100,
+            fall_time_ns: 10,
             analog_filter: true,
             digital_filter: 0,
             slave_address_1: 0,
             address_11bits: false,
             slave_address_2: 0,
             slave_address_mask: SlaveAddressMask::MaskNone,
+            general_call: false,
+            internal_pull_up: false,
         }
     }
 
@@ -31,48 +104,175 @@ impl Config {
         Config {
             timing: Some(timing),
             speed: None,
+            clock_source: ClockSource::Pclk,
+            rise_time_ns: 100,
+            fall_time_ns: 10,
             analog_filter: true,
             digital_filter: 0,
             slave_address_1: 0,
             address_11bits: false,
             slave_address_2: 0,
             slave_address_mask: SlaveAddressMask::MaskNone,
+            general_call: false,
+            internal_pull_up: false,
         }
     }
 
+    /// Standard-mode preset (100 kHz)
+    pub fn standard() -> Self {
+        Config::new(100.kHz())
+    }
+
+    /// Fast-mode preset (400 kHz)
+    pub fn fast() -> Self {
+        Config::new(400.kHz())
+    }
+
+    /// Fast-mode Plus preset (1 MHz)
+    ///
+    /// The I2C driver enables the SYSCFG Fast-mode Plus drive of the instance for speeds
+    /// above 400 kHz.
+    pub fn fast_plus() -> Self {
+        Config::new(1.MHz())
+    }
+
+    /// Select the kernel clock used for timing computation (I2C1 only)
+    pub fn clock_source(mut self, clock_source: ClockSource) -> Self {
+        self.clock_source = clock_source;
+        self
+    }
+
+    /// SCL/SDA rise time of the bus in nanoseconds, depends on the pull-ups and bus capacitance
+    pub fn rise_time(mut self, ns: u32) -> Self {
+        self.rise_time_ns = ns;
+        self
+    }
+
+    /// SCL/SDA fall time of the bus in nanoseconds
+    pub fn fall_time(mut self, ns: u32) -> Self {
+        self.fall_time_ns = ns;
+        self
+    }
+
     pub fn disable_analog_filter(mut self) -> Self {
         self.analog_filter = false;
         self
     }
 
+    /// Enables the internal pull-ups of the SDA and SCL pins
+    ///
+    /// The internal pull-ups are around 40 kOhm, far weaker than the usual 2.2 to 10 kOhm
+    /// bus pull-ups: they are only enough for a short bus at a low speed. The rise time
+    /// should be set accordingly, and can be checked with `I2c::bus_health`.
+    pub fn enable_internal_pull_up(mut self) -> Self {
+        self.internal_pull_up = true;
+        self
+    }
+
+    /// Acknowledges the general call address (0x00) in slave mode
+    pub fn enable_general_call(mut self) -> Self {
+        self.general_call = true;
+        self
+    }
+
     pub fn enable_digital_filter(mut self, cycles: u8) -> Self {
-        assert!(cycles <= 16);
+        hal_assert!(cycles <= 16);
         self.digital_filter = cycles;
         self
     }
 
-    pub fn timing_bits(&self, i2c_clk: Hertz) -> u32 {
+    /// Returns `true` if the bus runs above 400 kHz and needs the Fast-mode Plus drive
+    pub(crate) fn is_fast_mode_plus(&self) -> bool {
+        matches!(self.speed, Some(speed) if speed.raw() > 400_000)
+    }
+
+    /// Computes the TIMINGR value for the given kernel clock
+    ///
+    /// The SCL low/high periods are split according to the bus specification of the selected
+    /// speed, after subtracting the rise/fall times and the filter delays from the period.
+    /// The smallest prescaler that fits all the fields is used, to get the best resolution.
+    ///
+    /// Fails if the kernel clock is too slow for the SCL low and high times or the data hold
+    /// time of the specification, or too fast for the fields of TIMINGR.
+    pub fn timing_bits(&self, i2c_clk: Hertz) -> Result<u32, InvalidTiming> {
         if let Some(bits) = self.timing {
-            return bits;
+            return Ok(bits);
         }
-        let speed = self.speed.unwrap();
-        let (psc, scll, sclh, sdadel, scldel) = if speed.raw() <= 100_000 {
-            let psc = 3;
-            let scll = cmp::min((((i2c_clk.raw() >> 1) / (psc + 1)) / speed.raw()) - 1, 255);
-            let sclh = scll - 4;
-            let sdadel = 2;
-            let scldel = 4;
-            (psc, scll, sclh, sdadel, scldel)
+        let speed = self.speed.map_or(100_000, |speed| speed.raw());
+        let clk = i2c_clk.raw();
+        let spec = if speed <= 100_000 {
+            &STANDARD_MODE
+        } else if speed <= 400_000 {
+            &FAST_MODE
+        } else {
+            &FAST_MODE_PLUS
+        };
+
+        let (af_min, af_max) = if self.analog_filter {
+            (ANALOG_FILTER_MIN_NS, ANALOG_FILTER_MAX_NS)
         } else {
-            let psc = 1;
-            let scll = cmp::min((((i2c_clk.raw() >> 1) / (psc + 1)) / speed.raw()) - 1, 255);
-            let sclh = scll - 6;
-            let sdadel = 1;
-            let scldel = 3;
-            (psc, scll, sclh, sdadel, scldel)
+            (0, 0)
         };
-        psc << 28 | scldel << 20 | sdadel << 16 | sclh << 8 | scll
+        let dnf = self.digital_filter as u32;
+
+        // t_SCL = t_SYNC1 + t_SYNC2 + t_SCLL + t_SCLH, where the synchronization
+        // delays include the bus edges and the filter delays.
+        let period = clk.div_ceil(speed);
+        let sync =
+            ns_to_cycles(self.rise_time_ns + self.fall_time_ns + 2 * af_min, clk) + 2 * (dnf + 2);
+        let scl = cmp::max(period.saturating_sub(sync), 2);
+        // The bus sees t_SYNC1 >= t_f + t_AF(min) + (DNF + 2) * t_I2CCLK on top of t_SCLL,
+        // and t_SYNC2 >= t_AF(min) + (DNF + 2) * t_I2CCLK on top of t_SCLH
+        let low_min = ns_to_cycles(spec.low_min.saturating_sub(self.fall_time_ns + af_min), clk)
+            .saturating_sub(dnf + 2);
+        let high_min =
+            ns_to_cycles(spec.high_min.saturating_sub(af_min), clk).saturating_sub(dnf + 2);
+        let low = cmp::max(scl * spec.low_min / (spec.low_min + spec.high_min), low_min);
+        let high = cmp::max(scl.saturating_sub(low), 1);
+
+        // t_SDADEL >= t_f + t_HD;DAT(min) - t_AF(min) - (DNF + 3) * t_I2CCLK
+        let sdadel_min =
+            ns_to_cycles(self.fall_time_ns.saturating_sub(af_min), clk).saturating_sub(dnf + 3);
+        // t_SDADEL <= t_HD;DAT(max) - t_r - t_AF(max) - (DNF + 4) * t_I2CCLK
+        let sdadel_max = ns_to_cycles(
+            spec.hd_dat_max
+                .saturating_sub(self.rise_time_ns)
+                .saturating_sub(af_max),
+            clk,
+        )
+        .saturating_sub(dnf + 4);
+        // t_SCLDEL >= t_r + t_SU;DAT(min)
+        let scldel_min = ns_to_cycles(self.rise_time_ns + spec.su_dat_min, clk);
+
+        let mut psc = 0;
+        while psc < 15 {
+            let div = psc + 1;
+            if low.div_ceil(div) <= 256
+                && high.div_ceil(div) <= 256
+                && scldel_min.div_ceil(div) <= 16
+                && sdadel_min.div_ceil(div) <= 15
+            {
+                break;
+            }
+            psc += 1;
+        }
+        let div = psc + 1;
+
+        let scll = cmp::min(low.div_ceil(div), 256).saturating_sub(1);
+        let sclh = cmp::min(high.div_ceil(div), 256).saturating_sub(1);
+        let sdadel = cmp::min(cmp::min(sdadel_min.div_ceil(div), sdadel_max / div), 15);
+        let scldel = cmp::min(scldel_min.div_ceil(div), 16).saturating_sub(1);
+        let bits = psc << 28 | scldel << 20 | sdadel << 16 | sclh << 8 | scll;
+
+        let fits = (scll + 1) * div >= low && (sclh + 1) * div >= high;
+        let in_spec = high >= high_min && sdadel * div >= sdadel_min && sdadel_min <= sdadel_max;
+        if fits && in_spec && (scldel + 1) * div >= scldel_min {
+            Ok(bits)
+        } else {
+            Err(InvalidTiming { closest: bits })
+        }
     }
+
     /// Slave address 1 as 7 bit address, in range 0 .. 127
     pub fn slave_address(&mut self, own_address: u8) {
         //assert!(own_address < (2 ^ 7));
@@ -99,3 +299,86 @@ impl From<Hertz> for Config {
         Config::new(speed)
     }
 }
+
+fn ns_to_cycles(ns: u32, clk: u32) -> u32 {
+    (ns as u64 * clk as u64).div_ceil(1_000_000_000) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SCL low and high times seen on the bus, SDADEL and SCLDEL in nanoseconds, decoded
+    /// from a TIMINGR value with the minimum synchronization delays of RM0444 "I2C timings"
+    fn decode(bits: u32, clk: u32, config: &Config) -> (u32, u32, u32, u32) {
+        let ns = |cycles: u32| (cycles as u64 * 1_000_000_000 / clk as u64) as u32;
+        let div = (bits >> 28) + 1;
+        let sync = 2 + config.digital_filter as u32;
+        let af = if config.analog_filter {
+            ANALOG_FILTER_MIN_NS
+        } else {
+            0
+        };
+        let low = ns(((bits & 0xff) + 1) * div + sync) + config.fall_time_ns + af;
+        let high = ns(((bits >> 8 & 0xff) + 1) * div + sync) + af;
+        let sdadel = ns((bits >> 16 & 0xf) * div);
+        let scldel = ns(((bits >> 20 & 0xf) + 1) * div);
+        (low, high, sdadel, scldel)
+    }
+
+    #[test]
+    fn timing_16mhz() {
+        // RM0444 gives 0x3042_0f13, 0x1032_0309 and 0x0020_0204 for a 16 MHz kernel clock
+        assert_eq!(Config::standard().timing_bits(16.MHz()), Ok(0x0050_4551));
+        assert_eq!(Config::fast().timing_bits(16.MHz()), Ok(0x0030_0a14));
+        assert_eq!(Config::fast_plus().timing_bits(16.MHz()), Ok(0x0020_0105));
+    }
+
+    #[test]
+    fn timing_meets_spec() {
+        let modes = [
+            (100_000, &STANDARD_MODE),
+            (400_000, &FAST_MODE),
+            (1_000_000, &FAST_MODE_PLUS),
+        ];
+        for clk in [8_000_000, 16_000_000, 24_000_000, 48_000_000, 64_000_000] {
+            for (speed, spec) in modes {
+                let config = Config::new(speed.Hz());
+                let bits = config.timing_bits(clk.Hz()).unwrap();
+                let (low, high, sdadel, scldel) = decode(bits, clk, &config);
+                assert!(low >= spec.low_min, "{} {} {:#x}", clk, speed, bits);
+                assert!(high >= spec.high_min, "{} {} {:#x}", clk, speed, bits);
+                assert!(
+                    sdadel + ANALOG_FILTER_MAX_NS + config.rise_time_ns <= spec.hd_dat_max,
+                    "{} {} {:#x}",
+                    clk,
+                    speed,
+                    bits
+                );
+                assert!(
+                    scldel >= config.rise_time_ns + spec.su_dat_min,
+                    "{} {} {:#x}",
+                    clk,
+                    speed,
+                    bits
+                );
+                // Never faster than requested
+                let period = ns_to_cycles(low + high + config.rise_time_ns, clk);
+                assert!(clk / period <= speed, "{} {} {:#x}", clk, speed, bits);
+            }
+        }
+    }
+
+    #[test]
+    fn timing_out_of_spec() {
+        // A slow rising edge eats into the SCL high time
+        let config = Config::fast_plus().rise_time(400);
+        assert!(config.timing_bits(64.MHz()).is_err());
+        // The falling edge is longer than the maximum data hold time
+        let config = Config::fast().fall_time(1000);
+        assert!(config.timing_bits(16.MHz()).is_err());
+        // Fixed timings are used as they are
+        let config = Config::with_timing(0x1042_0f13);
+        assert_eq!(config.timing_bits(8.MHz()), Ok(0x1042_0f13));
+    }
+}
@@ -1,8 +1,12 @@
 pub mod blocking;
 pub mod config;
+pub mod slave;
+pub mod smbus;
 
 use crate::rcc::*;
-pub use config::Config;
+use crate::stm32::i2c1;
+pub use config::{ClockSource, Config, InvalidTiming};
+pub use slave::{RegisterAccess, RegisterMap, SlaveEvent};
 
 #[derive(Debug, Clone, Copy)]
 pub enum SlaveAddressMask {
@@ -16,7 +20,7 @@ pub enum SlaveAddressMask {
     MaskAllBits,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum I2cDirection {
     MasterReadSlaveWrite = 0,
     MasterWriteSlaveRead = 1,
@@ -31,18 +35,68 @@ pub enum Error {
     BusError,
     ArbitrationLost,
     IncorrectFrameSize(usize),
+    /// SMBus clock low timeout, see [`smbus::I2cSmbusExt`]
+    Timeout,
+}
+
+#[cfg(feature = "eh1")]
+impl hal1::i2c::Error for Error {
+    fn kind(&self) -> hal1::i2c::ErrorKind {
+        use hal1::i2c::{ErrorKind, NoAcknowledgeSource};
+        match self {
+            Error::Overrun => ErrorKind::Overrun,
+            Error::Nack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            Error::BusError => ErrorKind::Bus,
+            Error::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            Error::PECError | Error::IncorrectFrameSize(_) | Error::Timeout => ErrorKind::Other,
+        }
+    }
+}
+
+/// State of a bus line, see [`I2c::bus_health`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineHealth {
+    /// The line rises within the expected time, the estimated rise time is given in ns
+    Ok(u32),
+    /// The line rises slower than expected, the pull-up is too weak or the bus capacitance
+    /// too large. The estimated rise time is given in ns
+    SlowRise(u32),
+    /// The line does not rise, the pull-up is missing or a device holds the line low
+    StuckLow,
+}
+
+/// Result of [`I2c::bus_health`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusHealth {
+    pub sda: LineHealth,
+    pub scl: LineHealth,
+}
+
+impl BusHealth {
+    /// Returns true when both lines rise in time
+    pub fn is_ok(&self) -> bool {
+        matches!((self.sda, self.scl), (LineHealth::Ok(_), LineHealth::Ok(_)))
+    }
 }
 
 /// I2C SDA pin
 pub trait SDAPin<I2C> {
     fn setup(&self);
     fn release(self) -> Self;
+    /// Enables or disables the internal pull-up
+    fn set_pull_up(&self, enable: bool);
+    /// Releases the line from low and counts the polls until it reads high
+    fn time_rise(&self, max_polls: u32) -> Option<u32>;
 }
 
 /// I2C SCL pin
 pub trait SCLPin<I2C> {
     fn setup(&self);
     fn release(self) -> Self;
+    /// Enables or disables the internal pull-up
+    fn set_pull_up(&self, enable: bool);
+    /// Releases the line from low and counts the polls until it reads high
+    fn time_rise(&self, max_polls: u32) -> Option<u32>;
 }
 
 pub trait I2cExt<I2C> {
@@ -64,3 +118,89 @@ pub struct I2c<I2C, SDA, SCL> {
     sda: SDA,
     scl: SCL,
 }
+
+/// Operation of a master transaction, see [`I2c::transaction`]
+pub(crate) enum Operation<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+/// Operations a master transaction can be made of
+pub(crate) trait AsOperation {
+    fn as_operation(&mut self) -> Operation<'_>;
+}
+
+impl AsOperation for Operation<'_> {
+    fn as_operation(&mut self) -> Operation<'_> {
+        match self {
+            Operation::Read(buffer) => Operation::Read(buffer),
+            Operation::Write(bytes) => Operation::Write(bytes),
+        }
+    }
+}
+
+#[cfg(feature = "eh1")]
+impl AsOperation for hal1::i2c::Operation<'_> {
+    fn as_operation(&mut self) -> Operation<'_> {
+        match self {
+            hal1::i2c::Operation::Read(buffer) => Operation::Read(buffer),
+            hal1::i2c::Operation::Write(bytes) => Operation::Write(bytes),
+        }
+    }
+}
+
+#[cfg(feature = "eh1")]
+impl<I2C, SDA, SCL> hal1::i2c::ErrorType for I2c<I2C, SDA, SCL> {
+    type Error = Error;
+}
+
+/// embedded-hal 1.0 bus, on the same transactions as the 0.2 blocking traits
+#[cfg(feature = "eh1")]
+impl<I2C, SDA, SCL> hal1::i2c::I2c for I2c<I2C, SDA, SCL>
+where
+    I2C: core::ops::Deref<Target = i2c1::RegisterBlock>,
+{
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [hal1::i2c::Operation<'_>],
+    ) -> Result<(), Error> {
+        I2c::transaction(self, address, operations)
+    }
+}
+
+fn wait_stop(i2c: &i2c1::RegisterBlock) -> Result<(), Error> {
+    wait(i2c, |isr| isr.stopf().bit_is_set())?;
+    i2c.icr.write(|w| w.stopcf().set_bit());
+    Ok(())
+}
+
+/// Waits for `ready`, returning the bus errors, the timeouts and the PEC errors
+fn wait(i2c: &i2c1::RegisterBlock, ready: impl Fn(&i2c1::isr::R) -> bool) -> Result<(), Error> {
+    loop {
+        let isr = i2c.isr.read();
+        if isr.berr().bit_is_set() {
+            i2c.icr.write(|w| w.berrcf().set_bit());
+            return Err(Error::BusError);
+        } else if isr.arlo().bit_is_set() {
+            i2c.icr.write(|w| w.arlocf().set_bit());
+            return Err(Error::ArbitrationLost);
+        } else if isr.timeout().bit_is_set() {
+            i2c.icr.write(|w| w.timoutcf().set_bit());
+            return Err(Error::Timeout);
+        } else if isr.pecerr().bit_is_set() {
+            i2c.icr.write(|w| w.peccf().set_bit());
+            return Err(Error::PECError);
+        } else if isr.nackf().bit_is_set() {
+            // The STOP is generated automatically after a NACK
+            while i2c.isr.read().stopf().bit_is_clear() {}
+            i2c.icr.write(|w| w.nackcf().set_bit().stopcf().set_bit());
+            return Err(Error::Nack);
+        } else if ready(&isr) {
+            return Ok(());
+        } else if isr.stopf().bit_is_set() {
+            i2c.icr.write(|w| w.stopcf().set_bit());
+            return Err(Error::Nack);
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Event driven I2C slave
+//!
+//! Each byte is acknowledged by the hardware and the clock is stretched until the
+//! application has handled the event, so [`I2c::slave_event`] can be polled or called
+//! from the I2C interrupt without losing data, whatever the latency.
+//!
+//! [`RegisterMap`] builds on the events to answer like an EEPROM or a sensor: the first
+//! byte written by the master sets the register pointer, the next ones are written to the
+//! registers from the pointer, and reads, usually after a repeated start, return the
+//! registers from the pointer. The pointer moves to the next register after each byte and
+//! wraps around at the end of the table.
+use crate::i2c::config::Config;
+use crate::i2c::{Error, I2c, I2cDirection, SCLPin, SDAPin};
+use crate::rcc::Rcc;
+use crate::stm32::{I2C1, I2C2};
+
+/// Slave bus event, see [`I2c::slave_event`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlaveEvent {
+    /// A master addressed one of the own addresses, at the start of a transfer or on a
+    /// repeated start
+    ///
+    /// `address` is the 7 bit address sent by the master, 0 for a general call.
+    AddressMatch {
+        address: u8,
+        direction: I2cDirection,
+    },
+    /// The master wrote a byte
+    Received(u8),
+    /// The master reads a byte, which has to be provided with [`I2c::slave_transmit`]
+    TransmitRequest,
+    /// The master ended the transfer
+    Stop,
+}
+
+/// Transfer completed by a [`RegisterMap`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterAccess {
+    /// The master wrote `len` registers from `address`
+    Write { address: u8, len: usize },
+    /// The master read `len` registers from `address`
+    Read { address: u8, len: usize },
+}
+
+#[derive(Clone, Copy)]
+enum Segment {
+    Idle,
+    Pointer,
+    Write { address: u8, len: usize },
+    Read { address: u8, sent: usize },
+}
+
+/// I2C slave exposing a register table, see the [module documentation](self)
+pub struct RegisterMap<I2C, SDA, SCL> {
+    i2c: I2c<I2C, SDA, SCL>,
+    table: &'static mut [u8],
+    pointer: u8,
+    segment: Segment,
+}
+
+impl<I2C, SDA, SCL> RegisterMap<I2C, SDA, SCL> {
+    /// Registers, as last written by the master
+    pub fn registers(&self) -> &[u8] {
+        self.table
+    }
+
+    /// Registers to update between transfers
+    pub fn registers_mut(&mut self) -> &mut [u8] {
+        self.table
+    }
+
+    /// Register read or written by the next byte
+    pub fn pointer(&self) -> u8 {
+        self.pointer
+    }
+
+    /// Releases the slave and the table
+    pub fn release(self) -> (I2c<I2C, SDA, SCL>, &'static mut [u8]) {
+        (self.i2c, self.table)
+    }
+
+    fn advance(&mut self) {
+        self.pointer = ((self.pointer as usize + 1) % self.table.len()) as u8;
+    }
+
+    /// Reports the write or read ended by a stop or a repeated start
+    fn end_segment(&mut self) -> nb::Result<RegisterAccess, Error> {
+        match core::mem::replace(&mut self.segment, Segment::Idle) {
+            Segment::Write { address, len } if len > 0 => {
+                Ok(RegisterAccess::Write { address, len })
+            }
+            // The byte loaded after the last one acknowledged by the master was not sent
+            Segment::Read { address, sent } if sent > 1 => {
+                let len = sent - 1;
+                self.pointer = ((address as usize + len) % self.table.len()) as u8;
+                Ok(RegisterAccess::Read { address, len })
+            }
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+macro_rules! i2c_slave {
+    ($($I2CX:ident: $i2cx:ident,)+) => {
+        $(
+            impl<SDA, SCL> I2c<$I2CX, SDA, SCL>
+            where
+                SDA: SDAPin<$I2CX>,
+                SCL: SCLPin<$I2CX>,
+            {
+                /// Configures the peripheral as a slave answering the 7 bit `address`
+                ///
+                /// The second address, its mask and the general call are taken from
+                /// `config`, as well as the timings, which define the data setup and hold
+                /// times in slave mode too.
+                ///
+                /// # Panics
+                ///
+                /// Panics if `address` is 0 or does not fit in 7 bits.
+                pub fn new_slave(
+                    i2c: $I2CX,
+                    sda: SDA,
+                    scl: SCL,
+                    address: u8,
+                    config: impl Into<Config>,
+                    rcc: &mut Rcc,
+                ) -> Self {
+                    hal_assert!(address > 0 && address < 0x80);
+                    let mut config = config.into();
+                    config.slave_address(address);
+                    let i2c = Self::$i2cx(i2c, sda, scl, config, rcc);
+                    // Every byte is acknowledged, without the NBYTES reload
+                    i2c.i2c.cr1.modify(|_, w| w.sbc().clear_bit());
+                    i2c
+                }
+
+                /// Returns the next slave event
+                ///
+                /// The clock is stretched until the event is handled: the address match is
+                /// released by this call, a received byte is released once read, and a
+                /// transmit request once the byte is written with
+                /// [`Self::slave_transmit`]. A NACK of the master at the end of a read
+                /// is not an error and is followed by [`SlaveEvent::Stop`].
+                pub fn slave_event(&mut self) -> nb::Result<SlaveEvent, Error> {
+                    let isr = self.i2c.isr.read();
+                    if isr.berr().bit_is_set() {
+                        self.i2c.icr.write(|w| w.berrcf().set_bit());
+                        return Err(nb::Error::Other(Error::BusError));
+                    }
+                    if isr.ovr().bit_is_set() {
+                        self.i2c.icr.write(|w| w.ovrcf().set_bit());
+                        return Err(nb::Error::Other(Error::Overrun));
+                    }
+                    if isr.nackf().bit_is_set() {
+                        self.i2c.icr.write(|w| w.nackcf().set_bit());
+                    }
+                    if isr.rxne().bit_is_set() {
+                        return Ok(SlaveEvent::Received(self.i2c.rxdr.read().rxdata().bits()));
+                    }
+                    if isr.addr().bit_is_set() {
+                        let direction = if isr.dir().bit_is_set() {
+                            // Drop the byte left over from a previous read
+                            self.i2c.isr.write(|w| w.txe().set_bit());
+                            I2cDirection::MasterReadSlaveWrite
+                        } else {
+                            I2cDirection::MasterWriteSlaveRead
+                        };
+                        let address = isr.addcode().bits();
+                        self.i2c.icr.write(|w| w.addrcf().set_bit());
+                        return Ok(SlaveEvent::AddressMatch { address, direction });
+                    }
+                    if isr.txis().bit_is_set() {
+                        return Ok(SlaveEvent::TransmitRequest);
+                    }
+                    if isr.stopf().bit_is_set() {
+                        self.i2c.icr.write(|w| w.stopcf().set_bit());
+                        self.i2c.isr.write(|w| w.txe().set_bit());
+                        return Ok(SlaveEvent::Stop);
+                    }
+                    Err(nb::Error::WouldBlock)
+                }
+
+                /// Sends `byte` in answer to a [`SlaveEvent::TransmitRequest`]
+                pub fn slave_transmit(&mut self, byte: u8) {
+                    self.i2c.txdr.write(|w| unsafe { w.txdata().bits(byte) });
+                }
+
+                /// Enables raising the I2C interrupt on each slave event and on errors
+                pub fn listen_slave(&mut self) {
+                    self.i2c.cr1.modify(|_, w| {
+                        w.addrie()
+                            .set_bit()
+                            .rxie()
+                            .set_bit()
+                            .txie()
+                            .set_bit()
+                            .stopie()
+                            .set_bit()
+                            .nackie()
+                            .set_bit()
+                            .errie()
+                            .set_bit()
+                    });
+                }
+
+                /// Answers the master with the registers of `table`, see [`RegisterMap`]
+                ///
+                /// The peripheral has to be set up with [`Self::new_slave`].
+                ///
+                /// # Panics
+                ///
+                /// Panics if `table` is empty or has more than 256 registers.
+                pub fn register_map(self, table: &'static mut [u8]) -> RegisterMap<$I2CX, SDA, SCL> {
+                    hal_assert!(!table.is_empty() && table.len() <= 256);
+                    RegisterMap {
+                        i2c: self,
+                        table,
+                        pointer: 0,
+                        segment: Segment::Idle,
+                    }
+                }
+
+                /// Disables the slave interrupts
+                pub fn unlisten_slave(&mut self) {
+                    self.i2c.cr1.modify(|_, w| {
+                        w.addrie()
+                            .clear_bit()
+                            .rxie()
+                            .clear_bit()
+                            .txie()
+                            .clear_bit()
+                            .stopie()
+                            .clear_bit()
+                            .nackie()
+                            .clear_bit()
+                            .errie()
+                            .clear_bit()
+                    });
+                }
+            }
+
+            impl<SDA, SCL> RegisterMap<$I2CX, SDA, SCL>
+            where
+                SDA: SDAPin<$I2CX>,
+                SCL: SCLPin<$I2CX>,
+            {
+                /// Handles the next slave event, returns the access once the master has
+                /// ended a write or a read of the registers
+                ///
+                /// Meant to be called from the I2C interrupt handler, see
+                /// [`I2c::listen_slave`], or polled. Each call handles one event, the others
+                /// give `WouldBlock`. Writes that only set the pointer are not reported.
+                pub fn poll(&mut self) -> nb::Result<RegisterAccess, Error> {
+                    match self.i2c.slave_event()? {
+                        SlaveEvent::AddressMatch { direction, .. } => {
+                            let access = self.end_segment();
+                            self.segment = match direction {
+                                I2cDirection::MasterWriteSlaveRead => Segment::Pointer,
+                                I2cDirection::MasterReadSlaveWrite => Segment::Read {
+                                    address: self.pointer,
+                                    sent: 0,
+                                },
+                            };
+                            access
+                        }
+                        SlaveEvent::Received(byte) => {
+                            match self.segment {
+                                Segment::Pointer => {
+                                    self.pointer = (byte as usize % self.table.len()) as u8;
+                                    self.segment = Segment::Write {
+                                        address: self.pointer,
+                                        len: 0,
+                                    };
+                                }
+                                Segment::Write { address, len } => {
+                                    self.table[self.pointer as usize] = byte;
+                                    self.advance();
+                                    self.segment = Segment::Write { address, len: len + 1 };
+                                }
+                                _ => {}
+                            }
+                            Err(nb::Error::WouldBlock)
+                        }
+                        SlaveEvent::TransmitRequest => {
+                            self.i2c.slave_transmit(self.table[self.pointer as usize]);
+                            self.advance();
+                            if let Segment::Read { address, sent } = self.segment {
+                                self.segment = Segment::Read { address, sent: sent + 1 };
+                            }
+                            Err(nb::Error::WouldBlock)
+                        }
+                        SlaveEvent::Stop => self.end_segment(),
+                    }
+                }
+            }
+        )+
+    };
+}
+
+i2c_slave! {
+    I2C1: i2c1,
+    I2C2: i2c2,
+}
@@ -0,0 +1,251 @@
+//! SMBus extensions of I2C1
+//!
+//! Packet error checking (PEC) is computed and checked by the hardware, the clock low
+//! timeouts of the SMBus specification are detected by TIMEOUTR, and the SMBALERT# line
+//! is handled in both the host and the device roles. I2C2 does not implement SMBus.
+use crate::gpio::gpioa::PA1;
+use crate::gpio::gpiob::PB5;
+use crate::gpio::{AltFunction, OpenDrain, Output};
+use crate::i2c::{wait, wait_stop, Error, I2c};
+use crate::stm32::{i2c1, I2C1};
+use crate::time::{Hertz, MicroSecond};
+
+/// Largest TIMEOUTA / TIMEOUTB value
+const TIMEOUT_MAX: u32 = 0xfff;
+
+/// SMBALERT# pin
+pub trait SMBAPin<I2C> {
+    fn setup(&self);
+    fn release(self) -> Self;
+}
+
+macro_rules! smba_pins {
+    ($($PIN:ty,)+) => {
+        $(
+            impl SMBAPin<I2C1> for $PIN {
+                fn setup(&self) {
+                    self.set_alt_mode(AltFunction::AF6)
+                }
+
+                fn release(self) -> Self {
+                    self.into_open_drain_output()
+                }
+            }
+        )+
+    };
+}
+
+smba_pins! {
+    PA1<Output<OpenDrain>>,
+    PB5<Output<OpenDrain>>,
+}
+
+pub trait I2cSmbusExt {
+    /// Enables the hardware packet error checking, needed by the `*_pec` transfers
+    fn enable_pec(&mut self, enable: bool);
+
+    /// Writes `bytes` followed by their PEC
+    fn write_pec(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error>;
+
+    /// Reads `buffer.len()` bytes and checks the PEC that follows
+    fn read_pec(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes `bytes` then, after a repeated start, reads `buffer.len()` bytes and checks
+    /// the PEC of the whole transaction (e.g. the SMBus Read Word command)
+    fn write_read_pec(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Flags [`Error::Timeout`] when SCL is held low for longer than `timeout`
+    /// (tTIMEOUT, 25 ms to 35 ms for SMBus)
+    ///
+    /// `kernel_clk` is the I2C kernel clock. The timeout is rounded down to a multiple of
+    /// 2048 kernel clock cycles, and clamped to 4096 times that.
+    fn set_clock_low_timeout(&mut self, timeout: MicroSecond, kernel_clk: Hertz);
+
+    /// Flags [`Error::Timeout`] when the cumulative clock low extension of a transfer reaches
+    /// `timeout` (tLOW:SEXT for a device, tLOW:MEXT for a host)
+    fn set_cumulative_timeout(&mut self, timeout: MicroSecond, kernel_clk: Hertz);
+
+    /// Disables both timeout detections
+    fn disable_timeouts(&mut self);
+
+    /// Host role: detects the alerts signalled by the devices on the SMBALERT# pin
+    ///
+    /// The SMBus host address (0b0001000) is acknowledged as well, so that the devices
+    /// using Host Notify can reach the host.
+    fn enable_host_alert<PIN: SMBAPin<I2C1>>(&mut self, pin: &PIN);
+
+    /// Returns `true` when an alert has been detected since the last [`Self::clear_alert`]
+    fn is_alert(&self) -> bool;
+
+    fn clear_alert(&mut self);
+
+    /// Device role: pulls the SMBALERT# pin low while `assert` is `true`
+    ///
+    /// The SMBus device default address (0b1100001) is acknowledged while the
+    /// alert is asserted, for the Alert Response Address protocol.
+    fn set_device_alert<PIN: SMBAPin<I2C1>>(&mut self, pin: &PIN, assert: bool);
+}
+
+impl<SDA, SCL> I2cSmbusExt for I2c<I2C1, SDA, SCL> {
+    fn enable_pec(&mut self, enable: bool) {
+        with_disabled(&self.i2c, |i2c| {
+            i2c.cr1.modify(|_, w| w.pecen().bit(enable))
+        });
+    }
+
+    fn write_pec(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+        let len = bytes.len();
+        if !(1..255).contains(&len) {
+            return Err(Error::IncorrectFrameSize(len));
+        }
+        while self.i2c.cr2.read().start().bit_is_set() {}
+        self.i2c.isr.write(|w| w.txe().set_bit());
+        start(&self.i2c, addr, len + 1, false, true);
+        for byte in bytes {
+            wait(&self.i2c, |isr| isr.txis().bit_is_set())?;
+            self.i2c.txdr.write(|w| unsafe { w.txdata().bits(*byte) });
+        }
+        // The PEC byte is sent by the hardware
+        wait_stop(&self.i2c)
+    }
+
+    fn read_pec(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        let len = buffer.len();
+        if !(1..255).contains(&len) {
+            return Err(Error::IncorrectFrameSize(len));
+        }
+        while self.i2c.cr2.read().start().bit_is_set() {}
+        let _ = self.i2c.rxdr.read().rxdata().bits();
+        start(&self.i2c, addr, len + 1, true, true);
+        receive_pec(&self.i2c, buffer)
+    }
+
+    fn write_read_pec(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        let (sndlen, rcvlen) = (bytes.len(), buffer.len());
+        if !(1..256).contains(&sndlen) {
+            return Err(Error::IncorrectFrameSize(sndlen));
+        }
+        if !(1..255).contains(&rcvlen) {
+            return Err(Error::IncorrectFrameSize(rcvlen));
+        }
+        while self.i2c.cr2.read().start().bit_is_set() {}
+        self.i2c.isr.write(|w| w.txe().set_bit());
+        start(&self.i2c, addr, sndlen, false, false);
+        for byte in bytes {
+            wait(&self.i2c, |isr| isr.txis().bit_is_set())?;
+            self.i2c.txdr.write(|w| unsafe { w.txdata().bits(*byte) });
+        }
+        wait(&self.i2c, |isr| isr.tc().bit_is_set())?;
+        // The PEC covers both parts of the transaction
+        start(&self.i2c, addr, rcvlen + 1, true, true);
+        receive_pec(&self.i2c, buffer)
+    }
+
+    fn set_clock_low_timeout(&mut self, timeout: MicroSecond, kernel_clk: Hertz) {
+        let bits = timeout_bits(timeout, kernel_clk);
+        self.i2c.timeoutr.modify(|_, w| w.timouten().clear_bit());
+        self.i2c.timeoutr.modify(|_, w| unsafe {
+            w.timeouta()
+                .bits(bits)
+                .tidle()
+                .clear_bit()
+                .timouten()
+                .set_bit()
+        });
+    }
+
+    fn set_cumulative_timeout(&mut self, timeout: MicroSecond, kernel_clk: Hertz) {
+        let bits = timeout_bits(timeout, kernel_clk);
+        self.i2c.timeoutr.modify(|_, w| w.texten().clear_bit());
+        self.i2c
+            .timeoutr
+            .modify(|_, w| unsafe { w.timeoutb().bits(bits).texten().set_bit() });
+    }
+
+    fn disable_timeouts(&mut self) {
+        self.i2c
+            .timeoutr
+            .modify(|_, w| w.timouten().clear_bit().texten().clear_bit());
+    }
+
+    fn enable_host_alert<PIN: SMBAPin<I2C1>>(&mut self, pin: &PIN) {
+        pin.setup();
+        with_disabled(&self.i2c, |i2c| {
+            i2c.cr1.modify(|_, w| {
+                w.smbden()
+                    .clear_bit()
+                    .smbhen()
+                    .set_bit()
+                    .alerten()
+                    .set_bit()
+            })
+        });
+        self.i2c.icr.write(|w| w.alertcf().set_bit());
+    }
+
+    fn is_alert(&self) -> bool {
+        self.i2c.isr.read().alert().bit_is_set()
+    }
+
+    fn clear_alert(&mut self) {
+        self.i2c.icr.write(|w| w.alertcf().set_bit());
+    }
+
+    fn set_device_alert<PIN: SMBAPin<I2C1>>(&mut self, pin: &PIN, assert: bool) {
+        pin.setup();
+        self.i2c.cr1.modify(|_, w| {
+            w.smbhen()
+                .clear_bit()
+                .smbden()
+                .bit(assert)
+                .alerten()
+                .bit(assert)
+        });
+    }
+}
+
+/// Runs `f` with the peripheral disabled, for the CR1 fields that can only change with PE = 0
+fn with_disabled(i2c: &i2c1::RegisterBlock, f: impl FnOnce(&i2c1::RegisterBlock)) {
+    let enabled = i2c.cr1.read().pe().bit_is_set();
+    i2c.cr1.modify(|_, w| w.pe().clear_bit());
+    f(i2c);
+    i2c.cr1.modify(|_, w| w.pe().bit(enabled));
+}
+
+fn timeout_bits(timeout: MicroSecond, kernel_clk: Hertz) -> u16 {
+    // t = (TIMEOUTx + 1) * 2048 * t_I2CCLK
+    let cycles = timeout.ticks() as u64 * kernel_clk.raw() as u64 / 1_000_000;
+    (cycles / 2048).clamp(1, TIMEOUT_MAX as u64 + 1) as u16 - 1
+}
+
+fn start(i2c: &i2c1::RegisterBlock, addr: u8, nbytes: usize, read: bool, autoend: bool) {
+    i2c.cr2.write(|w| unsafe {
+        w.nbytes()
+            .bits(nbytes as u8)
+            .sadd()
+            .bits((addr << 1) as u16)
+            .add10()
+            .clear_bit()
+            .rd_wrn()
+            .bit(read)
+            .pecbyte()
+            .bit(autoend)
+            .autoend()
+            .bit(autoend)
+            .reload()
+            .clear_bit()
+            .start()
+            .set_bit()
+    });
+}
+
+fn receive_pec(i2c: &i2c1::RegisterBlock, buffer: &mut [u8]) -> Result<(), Error> {
+    for byte in buffer.iter_mut() {
+        wait(i2c, |isr| isr.rxne().bit_is_set())?;
+        *byte = i2c.rxdr.read().rxdata().bits();
+    }
+    // The PEC byte is compared by the hardware, a mismatch is flagged before the STOP
+    wait(i2c, |isr| isr.rxne().bit_is_set())?;
+    let _ = i2c.rxdr.read().rxdata().bits();
+    wait_stop(i2c)
+}
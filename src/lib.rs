@@ -10,12 +10,28 @@ extern crate bare_metal;
 extern crate void;
 
 pub extern crate cortex_m;
+#[cfg(feature = "eh1")]
+pub extern crate eh1 as hal1;
 pub extern crate embedded_hal as hal;
 pub extern crate nb;
 pub extern crate stm32g0;
 
 pub use nb::block;
 
+/// Checks a precondition of the caller, only in debug builds with the `panic-never` feature
+///
+/// Only for the preconditions that memory safety does not rely on, a failed check then
+/// gives a wrong configuration at worst. The buffer lengths given to the DMA are checked
+/// with `assert!` instead, which the compiler removes for buffers of a known size.
+macro_rules! hal_assert {
+    ($($arg:tt)*) => {
+        #[cfg(not(feature = "panic-never"))]
+        assert!($($arg)*);
+        #[cfg(feature = "panic-never")]
+        debug_assert!($($arg)*);
+    };
+}
+
 #[cfg(feature = "device-selected")]
 pub use stm32 as pac;
 
@@ -40,24 +56,37 @@ pub use stm32g0::stm32g070 as stm32;
 #[cfg(feature = "rt")]
 pub use crate::stm32::interrupt;
 
+#[cfg(any(feature = "stm32g041", feature = "stm32g081"))]
+pub mod aes;
 pub mod analog;
+pub mod bitbang;
 pub mod crc;
+pub mod debug;
+pub mod diagnostics;
 pub mod dma;
 pub mod dmamux;
 pub mod exti;
 pub mod flash;
+pub mod flashlog;
 pub mod gpio;
 pub mod i2c;
+pub mod nvcounter;
 pub mod power;
 pub mod prelude;
 pub mod rcc;
 #[cfg(any(feature = "stm32g041", feature = "stm32g081"))]
 pub mod rng;
 pub mod rtc;
+pub mod safety;
 pub mod serial;
 pub mod spi;
+pub mod syscfg;
+pub mod tamp;
+pub mod tickless;
 pub mod time;
 pub mod timer;
+#[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+pub mod ucpd;
 pub mod watchdog;
 
 #[cfg(feature = "device-selected")]
@@ -0,0 +1,251 @@
+//! # Non-volatile counters
+//!
+//! Monotonic counters (boot count, error count, ...) kept in the TAMP backup registers,
+//! which survive resets and Standby for as long as VDD or VBAT is present. Incrementing a
+//! counter is a single register write, the flash memory is only used as a spillover log:
+//!
+//! - when the lower 32 bits of a counter wrap, the upper bits only live in RAM until
+//!   [`sync`](NvCounters::sync) records them in flash, [`needs_sync`](NvCounters::needs_sync)
+//!   tells when that is due
+//! - `sync` may also be called at controlled points (e.g. before entering Shutdown) to
+//!   checkpoint the full values, so that they survive the loss of the backup domain
+//!
+//! Each `sync` appends one double word per changed counter to one of two dedicated flash
+//! pages. When it is full, the other page is erased and the current values are written to
+//! it, the full page is only erased at the next compaction, so a reset at any point keeps
+//! the recorded values. When the backup domain is lost the counters restart from their
+//! last recorded values, they never go below them.
+//!
+//! The two consecutive pages have to be reserved for the counters, e.g. by shortening the
+//! FLASH region in `memory.x`. The counters use all the backup registers, they can not be combined with
+//! [`Tamper`](crate::tamp::Tamper).
+use core::ptr;
+
+use crate::flash::{self, FlashPage, UnlockedFlash, WriteErase, NUM_PAGES, PAGE_SIZE};
+use crate::rcc::Rcc;
+use crate::stm32::TAMP;
+
+/// Marker kept in the last backup register while the backup domain holds the counters
+const BACKUP_VALID: u32 = 0x4e56_4354;
+/// Flash records store the counter index in the top byte and the value below
+const VALUE_BITS: u32 = 56;
+const VALUE_MAX: u64 = (1 << VALUE_BITS) - 1;
+const ERASED: u64 = u64::MAX;
+const SLOTS: usize = PAGE_SIZE as usize / 8;
+
+/// Number of counters, one per backup register except the last one
+pub const COUNTERS: usize = 4;
+
+/// Counter selection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    C0 = 0,
+    C1 = 1,
+    C2 = 2,
+    C3 = 3,
+}
+
+/// Non-volatile counters stored in the TAMP backup registers
+pub struct NvCounters {
+    tamp: TAMP,
+    /// First of the two flash pages
+    page: FlashPage,
+    /// Page the records are appended to, 0 or 1
+    active: usize,
+    /// Upper 32 bits of the counters
+    high: [u32; COUNTERS],
+    /// Last values recorded in the active page
+    stored: [u64; COUNTERS],
+    /// First erased slot of the active page
+    next: usize,
+}
+
+impl NvCounters {
+    /// Loads the counters, restoring them from the flash pages `page` and `page + 1` if
+    /// the backup domain was reset
+    pub fn new(tamp: TAMP, page: FlashPage, rcc: &mut Rcc) -> Self {
+        hal_assert!(page.0 + 1 < NUM_PAGES as usize);
+        rcc.apbenr1.modify(|_, w| w.rtcapben().set_bit());
+        rcc.unlock_rtc();
+
+        let mut counters = NvCounters {
+            tamp,
+            page,
+            active: 0,
+            high: [0; COUNTERS],
+            stored: [0; COUNTERS],
+            next: 0,
+        };
+        let recorded = counters.scan();
+
+        let valid = counters.tamp.bkp4r.read().bits() == BACKUP_VALID;
+        for (i, &stored) in recorded.iter().enumerate() {
+            if valid {
+                let mut value = (stored & !0xffff_ffff) | counters.backup(i) as u64;
+                if value < stored {
+                    // The lower word wrapped after the last record
+                    value += 1 << 32;
+                }
+                counters.high[i] = (value >> 32) as u32;
+            } else {
+                counters.high[i] = (stored >> 32) as u32;
+                counters.set_backup(i, stored as u32);
+            }
+        }
+        counters
+            .tamp
+            .bkp4r
+            .write(|w| unsafe { w.bits(BACKUP_VALID) });
+        counters
+    }
+
+    /// Returns the value of a counter
+    pub fn read(&self, counter: Counter) -> u64 {
+        self.value(counter as usize)
+    }
+
+    /// Increments a counter and returns its new value, counters saturate at 2^56 - 1
+    pub fn increment(&mut self, counter: Counter) -> u64 {
+        let i = counter as usize;
+        let value = self.read(counter);
+        if value == VALUE_MAX {
+            return value;
+        }
+        let value = value + 1;
+        self.high[i] = (value >> 32) as u32;
+        self.set_backup(i, value as u32);
+        value
+    }
+
+    /// Returns `true` when the upper bits of a counter are ahead of the flash records,
+    /// they would be lost with the backup domain or on a reset after another wrap
+    pub fn needs_sync(&self) -> bool {
+        (0..COUNTERS).any(|i| self.high[i] != (self.stored[i] >> 32) as u32)
+    }
+
+    /// Records the counters that changed since the last record in the flash pages
+    pub fn sync(&mut self, flash: &mut UnlockedFlash) -> flash::Result {
+        for i in 0..COUNTERS {
+            let value = self.value(i);
+            if value == self.stored[i] {
+                continue;
+            }
+            if self.next == SLOTS {
+                // Records all the counters, including this one
+                self.compact(flash)?;
+            } else {
+                self.append(flash, i, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases the TAMP peripheral, the backup registers keep their values
+    pub fn release(self) -> TAMP {
+        self.tamp
+    }
+
+    /// Finds the active page and returns the last recorded value of every counter
+    ///
+    /// The values missing from the active page, after a reset during a compaction, are
+    /// left out of `stored` so that the next `sync` records them again.
+    fn scan(&mut self) -> [u64; COUNTERS] {
+        let mut used = [SLOTS; 2];
+        let mut values = [[0; COUNTERS]; 2];
+        for (page, used) in used.iter_mut().enumerate() {
+            for slot in 0..SLOTS {
+                let record = self.record(page, slot);
+                if record == ERASED {
+                    *used = slot;
+                    break;
+                }
+                let i = (record >> VALUE_BITS) as usize;
+                if i < COUNTERS {
+                    values[page][i] = record & VALUE_MAX;
+                }
+            }
+        }
+
+        // The page written last is never full, it holds the values of the full one
+        self.active = match used {
+            [SLOTS, _] => 1,
+            [_, SLOTS] => 0,
+            [first, second] => (second != 0 && (first == 0 || second < first)) as usize,
+        };
+        self.next = used[self.active];
+        self.stored = values[self.active];
+
+        let mut recorded = [0; COUNTERS];
+        for (i, recorded) in recorded.iter_mut().enumerate() {
+            *recorded = values[0][i].max(values[1][i]);
+        }
+        recorded
+    }
+
+    /// Erases the other page and writes the current value of every counter to it
+    ///
+    /// The full page keeps the previous records until the next compaction.
+    fn compact(&mut self, flash: &mut UnlockedFlash) -> flash::Result {
+        let other = 1 - self.active;
+        flash.erase_page(FlashPage(self.page.0 + other))?;
+        self.active = other;
+        self.next = 0;
+        self.stored = [0; COUNTERS];
+        for i in 0..COUNTERS {
+            let value = self.value(i);
+            if value != 0 {
+                self.append(flash, i, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, flash: &mut UnlockedFlash, i: usize, value: u64) -> flash::Result {
+        let record = (i as u64) << VALUE_BITS | value;
+        flash.write_native(self.slot_address(self.active, self.next), &[record])?;
+        self.next += 1;
+        self.stored[i] = value;
+        Ok(())
+    }
+
+    fn value(&self, i: usize) -> u64 {
+        (self.high[i] as u64) << 32 | self.backup(i) as u64
+    }
+
+    fn slot_address(&self, page: usize, slot: usize) -> usize {
+        FlashPage(self.page.0 + page).to_address() + slot * 8
+    }
+
+    fn record(&self, page: usize, slot: usize) -> u64 {
+        unsafe { ptr::read_volatile(self.slot_address(page, slot) as *const u64) }
+    }
+
+    fn backup(&self, i: usize) -> u32 {
+        match i {
+            0 => self.tamp.bkp0r.read().bits(),
+            1 => self.tamp.bkp1r.read().bits(),
+            2 => self.tamp.bkp2r.read().bits(),
+            _ => self.tamp.bkp3r.read().bits(),
+        }
+    }
+
+    fn set_backup(&mut self, i: usize, value: u32) {
+        match i {
+            0 => self.tamp.bkp0r.write(|w| unsafe { w.bits(value) }),
+            1 => self.tamp.bkp1r.write(|w| unsafe { w.bits(value) }),
+            2 => self.tamp.bkp2r.write(|w| unsafe { w.bits(value) }),
+            _ => self.tamp.bkp3r.write(|w| unsafe { w.bits(value) }),
+        }
+    }
+}
+
+/// Extension trait that sets up the non-volatile counters
+pub trait NvCountersExt {
+    fn nv_counters(self, page: FlashPage, rcc: &mut Rcc) -> NvCounters;
+}
+
+impl NvCountersExt for TAMP {
+    fn nv_counters(self, page: FlashPage, rcc: &mut Rcc) -> NvCounters {
+        NvCounters::new(self, page, rcc)
+    }
+}
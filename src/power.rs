@@ -1,23 +1,354 @@
 //! Power control
 
 use crate::{
-    rcc::{Enable, Rcc},
-    stm32::PWR,
+    exti::Event,
+    rcc::{Enable, InvalidClockConfig, Rcc},
+    stm32::{EXTI, FLASH, PWR, RTC, TAMP},
+    time::Hertz,
 };
+use cortex_m::peripheral::SCB;
+use fugit::RateExtU32;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LowPowerMode {
+    /// Stop 0 of the reference manual, main regulator on
     StopMode1 = 0b000,
+    /// Stop 1 of the reference manual, low-power regulator
     StopMode2 = 0b001,
     Standby = 0b011,
     Shutdown = 0b111,
 }
 
+/// Wakeup pin of the Standby and Shutdown modes, the RTC and TAMP events are enabled by
+/// their drivers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeupPin {
+    /// WKUP1 on PA0
+    Pin1 = 0,
+    /// WKUP2 on PC13
+    Pin2 = 1,
+    /// WKUP4 on PA2
+    Pin4 = 3,
+    /// WKUP5 on PC5
+    Pin5 = 4,
+    /// WKUP6 on PB5
+    Pin6 = 5,
+}
+
+/// Active edge of a wakeup pin (PWR_CR4 WPx)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeupEdge {
+    Rising,
+    Falling,
+}
+
+/// GPIO port whose pins are pulled in Standby and Shutdown modes (PWR_PUCRx/PDCRx)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StandbyPort {
+    A,
+    B,
+    C,
+    D,
+    F,
+}
+
+/// Low-power mode to enter, with its wakeup sources
+///
+/// The GPIOs lose their configuration in Standby and Shutdown modes, the pins that have
+/// to keep a level are pulled up or down by the PWR instead. Leaving these modes resets the
+/// device, [`Power::wakeup_cause`] then tells why it woke up.
+#[derive(Debug, Clone, Copy)]
+pub struct PowerConfiguration {
+    mode: LowPowerMode,
+    wakeup_pins: u8,
+    falling_edges: u8,
+    pull_ups: [u16; 5],
+    pull_downs: [u16; 5],
+    retain_sram: bool,
+}
+
+impl PowerConfiguration {
+    fn new(mode: LowPowerMode) -> Self {
+        PowerConfiguration {
+            mode,
+            wakeup_pins: 0,
+            falling_edges: 0,
+            pull_ups: [0; 5],
+            pull_downs: [0; 5],
+            retain_sram: false,
+        }
+    }
+
+    /// Stop 0: all clocks stopped, the fastest wakeup
+    pub fn stop0() -> Self {
+        Self::new(LowPowerMode::StopMode1)
+    }
+
+    /// Stop 1: Stop 0 with the low-power regulator, wakes up a few microseconds later
+    pub fn stop1() -> Self {
+        Self::new(LowPowerMode::StopMode2)
+    }
+
+    /// Standby: the core domain is powered off, the RTC and the backup registers stay on
+    pub fn standby() -> Self {
+        Self::new(LowPowerMode::Standby)
+    }
+
+    /// Shutdown: Standby without the brown-out reset, the lowest consumption
+    pub fn shutdown() -> Self {
+        Self::new(LowPowerMode::Shutdown)
+    }
+
+    /// Wakes the device up from Standby or Shutdown on the given edge of `pin`
+    pub fn wakeup_pin(mut self, pin: WakeupPin, edge: WakeupEdge) -> Self {
+        let mask = 1 << pin as u8;
+        self.wakeup_pins |= mask;
+        match edge {
+            WakeupEdge::Rising => self.falling_edges &= !mask,
+            WakeupEdge::Falling => self.falling_edges |= mask,
+        }
+        self
+    }
+
+    /// Pulls up the pins of `port` in `mask` during Standby and Shutdown
+    pub fn pull_up(mut self, port: StandbyPort, mask: u16) -> Self {
+        self.pull_ups[port as usize] |= mask;
+        self.pull_downs[port as usize] &= !mask;
+        self
+    }
+
+    /// Pulls down the pins of `port` in `mask` during Standby and Shutdown
+    pub fn pull_down(mut self, port: StandbyPort, mask: u16) -> Self {
+        self.pull_downs[port as usize] |= mask;
+        self.pull_ups[port as usize] &= !mask;
+        self
+    }
+
+    /// Keeps the SRAM content in Standby mode (PWR_CR3 RRS)
+    pub fn retain_sram(mut self, retain: bool) -> Self {
+        self.retain_sram = retain;
+        self
+    }
+
+    /// Enters the low-power mode
+    ///
+    /// Returns after the wakeup from a Stop mode, with the system clock back on HSI16:
+    /// the RCC has to be set up again if it was running from another clock. Standby and
+    /// Shutdown only end with a reset. The wakeup pin and Standby flags are cleared
+    /// before entering the mode, otherwise a pending flag prevents the entry.
+    pub fn enter(&self, power: &mut Power, scb: &mut SCB) {
+        let rb = &power.rb;
+        let pulls = self
+            .pull_ups
+            .iter()
+            .chain(self.pull_downs.iter())
+            .any(|m| *m != 0);
+        rb.pucra
+            .write(|w| unsafe { w.bits(self.pull_ups[0] as u32) });
+        rb.pdcra
+            .write(|w| unsafe { w.bits(self.pull_downs[0] as u32) });
+        rb.pucrb
+            .write(|w| unsafe { w.bits(self.pull_ups[1] as u32) });
+        rb.pdcrb
+            .write(|w| unsafe { w.bits(self.pull_downs[1] as u32) });
+        rb.pucrc
+            .write(|w| unsafe { w.bits(self.pull_ups[2] as u32) });
+        rb.pdcrc
+            .write(|w| unsafe { w.bits(self.pull_downs[2] as u32) });
+        rb.pucrd
+            .write(|w| unsafe { w.bits(self.pull_ups[3] as u32) });
+        rb.pdcrd
+            .write(|w| unsafe { w.bits(self.pull_downs[3] as u32) });
+        rb.pucrf
+            .write(|w| unsafe { w.bits(self.pull_ups[4] as u32) });
+        rb.pdcrf
+            .write(|w| unsafe { w.bits(self.pull_downs[4] as u32) });
+
+        rb.cr4
+            .modify(|r, w| unsafe { w.bits(r.bits() & !0x3f | self.falling_edges as u32) });
+        rb.cr3
+            .modify(|r, w| unsafe { w.bits(r.bits() & !0x3f | self.wakeup_pins as u32) });
+        rb.cr3
+            .modify(|_, w| w.apc().bit(pulls).rrs().bit(self.retain_sram));
+        power.clear_wakeup_flags();
+
+        let rb = &power.rb;
+        if let LowPowerMode::StopMode1 = self.mode {
+            // Stop 0 is entered as Stop 1 from the low-power run mode
+            rb.cr1.modify(|_, w| w.lpr().clear_bit());
+            while rb.sr2.read().reglpf().bit_is_set() {}
+        }
+        rb.cr1
+            .modify(|_, w| unsafe { w.lpms().bits(self.mode as u8) });
+
+        scb.set_sleepdeep();
+        cortex_m::asm::dsb();
+        cortex_m::asm::wfi();
+        scb.clear_sleepdeep();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PowerMode {
     Run,
     LowPower(LowPowerMode),
     UltraLowPower(LowPowerMode),
 }
 
+/// Highest system clock frequency in Low-power run mode
+pub const LP_RUN_MAX_SYS_CLK: u32 = 2_000_000;
+
+/// Voltage range of the main regulator (PWR_CR1 VOS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoltageScale {
+    /// High performance range, up to 64 MHz
+    Range1 = 0b01,
+    /// Low power range, up to 16 MHz
+    Range2 = 0b10,
+}
+
+impl VoltageScale {
+    /// Highest system clock frequency of the range
+    pub fn max_sys_clk(&self) -> Hertz {
+        match self {
+            VoltageScale::Range1 => 64.MHz(),
+            VoltageScale::Range2 => 16.MHz(),
+        }
+    }
+
+    /// Flash wait states needed at `sys_clk` in this range
+    pub(crate) fn flash_latency(&self, sys_clk: Hertz) -> u8 {
+        let freq = sys_clk.raw();
+        match self {
+            VoltageScale::Range1 if freq <= 24_000_000 => 0,
+            VoltageScale::Range1 if freq <= 48_000_000 => 1,
+            VoltageScale::Range1 => 2,
+            VoltageScale::Range2 if freq <= 8_000_000 => 0,
+            VoltageScale::Range2 => 1,
+        }
+    }
+
+    /// Range currently selected, read from PWR_CR1
+    pub(crate) fn current() -> Self {
+        let pwr = unsafe { &(*PWR::ptr()) };
+        if pwr.cr1.read().vos().bits() == VoltageScale::Range2 as u8 {
+            VoltageScale::Range2
+        } else {
+            VoltageScale::Range1
+        }
+    }
+}
+
+/// Power voltage detector threshold (PWR_CR2 PVDRT/PVDFT)
+///
+/// The rising thresholds go from about 2.1 V for level 0 to 2.9 V for level 6, see the
+/// datasheet for the exact values.
+#[cfg(feature = "stm32g0x1")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub enum PvdLevel {
+    Level0 = 0,
+    Level1 = 1,
+    Level2 = 2,
+    Level3 = 3,
+    Level4 = 4,
+    Level5 = 5,
+    Level6 = 6,
+}
+
+#[cfg(feature = "stm32g0x1")]
+impl PvdLevel {
+    fn from_bits(bits: u8) -> Self {
+        match bits {
+            0 => PvdLevel::Level0,
+            1 => PvdLevel::Level1,
+            2 => PvdLevel::Level2,
+            3 => PvdLevel::Level3,
+            4 => PvdLevel::Level4,
+            5 => PvdLevel::Level5,
+            _ => PvdLevel::Level6,
+        }
+    }
+
+    /// Typical rising threshold, in millivolts
+    pub fn rising_mv(&self) -> u32 {
+        [2_100, 2_250, 2_400, 2_550, 2_700, 2_850, 2_950][*self as usize]
+    }
+
+    /// Typical falling threshold, in millivolts
+    pub fn falling_mv(&self) -> u32 {
+        self.rising_mv() - 100
+    }
+}
+
+/// Event that may have woken the device up, see [`Power::wakeup_cause`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeupSource {
+    /// The device was in Standby mode (PWR_SR1 SBF)
+    Standby = 0,
+    /// WKUP1 pin (PWR_SR1 WUF1), the WKUPx flags are also set after a Shutdown exit
+    Pin1 = 1,
+    Pin2 = 2,
+    Pin4 = 4,
+    Pin5 = 5,
+    Pin6 = 6,
+    /// Internal wakeup line (PWR_SR1 WUFI): RTC, TAMP or LSE CSS event
+    Internal = 7,
+    /// RTC alarm A
+    RtcAlarmA = 8,
+    /// RTC alarm B
+    RtcAlarmB = 9,
+    /// RTC periodic wakeup timer
+    RtcWakeupTimer = 10,
+    /// RTC timestamp
+    RtcTimestamp = 11,
+    /// TAMP1 or TAMP2 input
+    Tamper = 12,
+}
+
+/// Set of the wakeup sources flagged after a low-power mode exit
+///
+/// Nothing is flagged after a cold boot, or after waking up from Stop mode on an event
+/// without a status flag, like the serial or I2C wake up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WakeupCause {
+    sources: u16,
+    exti: u32,
+}
+
+impl WakeupCause {
+    /// Returns true if `source` has been flagged
+    pub fn contains(&self, source: WakeupSource) -> bool {
+        self.sources & 1 << source as u8 != 0
+    }
+
+    /// Returns true if the configurable EXTI line of `event` is pending, on either edge:
+    /// GPIO lines, PVD and comparators
+    pub fn exti(&self, event: Event) -> bool {
+        let line = event as u8;
+        line < 32 && self.exti & 1 << line != 0
+    }
+
+    /// Returns true if no source has been flagged
+    pub fn is_empty(&self) -> bool {
+        self.sources == 0 && self.exti == 0
+    }
+
+    /// Returns true if the device came out of Standby or Shutdown mode, rather than a
+    /// reset or a Stop mode
+    pub fn is_from_standby_or_shutdown(&self) -> bool {
+        self.sources & 0x7f != 0
+    }
+}
+
+/// Driver able to wait for the end of its ongoing transfers
+///
+/// Entering Stop mode right after a write otherwise truncates the last frame, as the
+/// peripheral clock is gated while the data is still being shifted out.
+pub trait WaitIdle {
+    /// Blocks until the peripheral has nothing left to transmit and is not busy
+    fn wait_idle(&mut self);
+}
+
 pub struct Power {
     rb: PWR,
 }
@@ -56,6 +387,201 @@ impl Power {
     }
 }
 
+impl Power {
+    /// Voltage range of the main regulator
+    pub fn voltage_scale(&self) -> VoltageScale {
+        VoltageScale::current()
+    }
+
+    /// Switches the main regulator to `range`
+    ///
+    /// Range 2 lowers the consumption with the system clock at up to 16 MHz. The flash
+    /// wait states are adjusted to the range, and [`Rcc::freeze`] checks the clocks
+    /// against the range selected here. Fails in Low-power run mode, or if the system
+    /// clock or the PLL R output is too fast for the range. The other PLL outputs are
+    /// not checked.
+    pub fn set_voltage_scale(
+        &mut self,
+        range: VoltageScale,
+        rcc: &Rcc,
+    ) -> Result<(), InvalidClockConfig> {
+        let sys_clk = rcc.clocks.sys_clk;
+        let pll_on = rcc.cr.read().pllon().bit_is_set();
+        if self.is_low_power_run()
+            || sys_clk > range.max_sys_clk()
+            || (pll_on && rcc.clocks.pll_clk.r > range.max_sys_clk())
+        {
+            return Err(InvalidClockConfig);
+        }
+        let flash = unsafe { &(*FLASH::ptr()) };
+        let latency = range.flash_latency(sys_clk);
+        // More wait states are needed before the slower range is selected
+        if latency > flash.acr.read().latency().bits() {
+            flash
+                .acr
+                .modify(|_, w| unsafe { w.latency().bits(latency) });
+        }
+        self.rb
+            .cr1
+            .modify(|_, w| unsafe { w.vos().bits(range as u8) });
+        while self.rb.sr2.read().vosf().bit_is_set() {}
+        flash
+            .acr
+            .modify(|_, w| unsafe { w.latency().bits(latency) });
+        Ok(())
+    }
+
+    /// Enters Low-power run mode, the regulator in low-power mode then supplies the core
+    ///
+    /// Fails if the system clock is above [`LP_RUN_MAX_SYS_CLK`], lower it first, e.g. to
+    /// the HSI16 divided by 8. [`Rcc::freeze`] panics on faster clocks until
+    /// [`exit_low_power_run`](Power::exit_low_power_run) is called.
+    pub fn enter_low_power_run(&mut self, rcc: &Rcc) -> Result<(), InvalidClockConfig> {
+        if rcc.clocks.sys_clk.raw() > LP_RUN_MAX_SYS_CLK {
+            return Err(InvalidClockConfig);
+        }
+        self.rb.cr1.modify(|_, w| w.lpr().set_bit());
+        while self.rb.sr2.read().reglpf().bit_is_clear() {}
+        Ok(())
+    }
+
+    /// Goes back to Run mode with the main regulator
+    pub fn exit_low_power_run(&mut self) {
+        self.rb.cr1.modify(|_, w| w.lpr().clear_bit());
+        while self.rb.sr2.read().reglpf().bit_is_set() {}
+    }
+
+    /// Returns true in Low-power run mode (PWR_SR2 REGLPF)
+    pub fn is_low_power_run(&self) -> bool {
+        self.rb.sr2.read().reglpf().bit_is_set()
+    }
+}
+
+#[cfg(feature = "stm32g0x1")]
+impl Power {
+    /// Enables the power voltage detector, the falling threshold is one level below the
+    /// rising one to get some hysteresis
+    pub fn enable_pvd(&mut self, level: PvdLevel) {
+        let rising = level as u8;
+        let falling = rising.saturating_sub(1);
+        self.rb.cr2.modify(|_, w| unsafe {
+            w.pvdrt()
+                .bits(rising)
+                .pvdft()
+                .bits(falling)
+                .pvde()
+                .set_bit()
+        });
+    }
+
+    pub fn disable_pvd(&mut self) {
+        self.rb.cr2.modify(|_, w| w.pvde().clear_bit());
+    }
+
+    /// Rising and falling thresholds of the PVD, `None` when it is disabled
+    pub fn pvd_thresholds(&self) -> Option<(PvdLevel, PvdLevel)> {
+        let cr2 = self.rb.cr2.read();
+        if cr2.pvde().bit_is_set() {
+            Some((
+                PvdLevel::from_bits(cr2.pvdrt().bits()),
+                PvdLevel::from_bits(cr2.pvdft().bits()),
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Returns true while VDD is below the PVD threshold
+    pub fn is_vdd_low(&self) -> bool {
+        self.rb.sr2.read().pvdo().bit_is_set()
+    }
+
+    /// Waits until VDD has risen above the PVD threshold
+    ///
+    /// Meant to be called at boot before writing to flash or starting power hungry
+    /// peripherals, when the supply ramps up slowly. The PVD is left enabled, so
+    /// [`is_vdd_low`](Power::is_vdd_low) or the PVD EXTI line can be used afterwards.
+    pub fn wait_for_vdd(&mut self, level: PvdLevel) {
+        self.enable_pvd(level);
+        while self.is_vdd_low() {}
+    }
+}
+
+#[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+impl Power {
+    /// Disables the USB Type-C dead-battery pull-downs of both UCPD peripherals
+    /// (PWR_CR3 UCPD_DBDIS)
+    ///
+    /// Boards not using the UCPD should call it at boot, so that the CC pins can be used
+    /// as GPIOs. With the UCPD, configure its terminations first, see
+    /// [`Ucpd::take_cc_control`](crate::ucpd::Ucpd::take_cc_control).
+    pub fn disable_ucpd_dead_battery(&mut self) {
+        self.rb
+            .cr3
+            .modify(|r, w| unsafe { w.bits(r.bits() | 1 << 14) });
+    }
+
+    /// Keeps the UCPD terminations in Standby mode (PWR_CR3 UCPD_STDBY)
+    pub fn set_ucpd_standby(&mut self, retain: bool) {
+        self.rb
+            .cr3
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 13) | (retain as u32) << 13) });
+    }
+}
+
+impl Power {
+    /// Collects the wakeup flags of the PWR, RTC, TAMP and EXTI
+    ///
+    /// Meant to be called right after waking up, before the interrupt handlers clear the
+    /// flags. The flags are left untouched, the PWR ones are cleared with
+    /// [`clear_wakeup_flags`](Power::clear_wakeup_flags) and the others by their drivers.
+    /// The RTC flags read as 0 when the RTC APB clock is disabled.
+    pub fn wakeup_cause(&self) -> WakeupCause {
+        let sr1 = self.rb.sr1.read();
+        let rtc = unsafe { (*RTC::ptr()).sr.read() };
+        let tamp = unsafe { (*TAMP::ptr()).sr.read() };
+        let exti = unsafe { &(*EXTI::ptr()) };
+        let flags = [
+            (WakeupSource::Standby, sr1.sbf().bit_is_set()),
+            (WakeupSource::Pin1, sr1.wuf1().bit_is_set()),
+            (WakeupSource::Pin2, sr1.wuf2().bit_is_set()),
+            (WakeupSource::Pin4, sr1.wuf4().bit_is_set()),
+            (WakeupSource::Pin5, sr1.wuf5().bit_is_set()),
+            (WakeupSource::Pin6, sr1.wuf6().bit_is_set()),
+            (WakeupSource::Internal, sr1.wufi().bit_is_set()),
+            (WakeupSource::RtcAlarmA, rtc.alraf().bit_is_set()),
+            (WakeupSource::RtcAlarmB, rtc.alrbf().bit_is_set()),
+            (WakeupSource::RtcWakeupTimer, rtc.wutf().bit_is_set()),
+            (WakeupSource::RtcTimestamp, rtc.tsf().bit_is_set()),
+            (
+                WakeupSource::Tamper,
+                tamp.tamp1f().bit_is_set() || tamp.tamp2f().bit_is_set(),
+            ),
+        ];
+        let sources = flags
+            .iter()
+            .filter(|(_, set)| *set)
+            .fold(0, |acc, (source, _)| acc | 1 << *source as u8);
+        WakeupCause {
+            sources,
+            exti: exti.rpr1.read().bits() | exti.fpr1.read().bits(),
+        }
+    }
+
+    /// Clears the Standby and wakeup pin flags (PWR_SCR CSBF, CWUFx)
+    pub fn clear_wakeup_flags(&mut self) {
+        self.rb.scr.write(|w| unsafe { w.bits(0x13f) });
+    }
+
+    /// Waits for the given drivers to finish their transfers, then sets the mode
+    pub fn set_mode_when_idle(&mut self, mode: PowerMode, drivers: &mut [&mut dyn WaitIdle]) {
+        for driver in drivers.iter_mut() {
+            driver.wait_idle();
+        }
+        self.set_mode(mode);
+    }
+}
+
 pub trait PowerExt {
     fn constrain(self, rcc: &mut Rcc) -> Power;
 }
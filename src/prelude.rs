@@ -4,7 +4,11 @@ pub use hal::prelude::*;
 pub use hal::watchdog::Watchdog as _;
 pub use hal::watchdog::WatchdogEnable as _;
 
+#[cfg(any(feature = "stm32g041", feature = "stm32g081"))]
+pub use crate::aes::AesExt as _;
 pub use crate::analog::adc::AdcExt as _;
+pub use crate::analog::adc::DmaMode as _;
+pub use crate::analog::adc::InjectMode as _;
 #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
 pub use crate::analog::comparator::ComparatorExt as _;
 #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
@@ -15,17 +19,30 @@ pub use crate::analog::comparator::WindowComparatorExt as _;
 pub use crate::analog::dac::DacExt as _;
 #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
 pub use crate::analog::dac::DacOut as _;
+pub use crate::analog::ComparatorOutput as _;
 pub use crate::crc::CrcExt as _;
+pub use crate::timer::alarm::AlarmExt as _;
+pub use crate::timer::capture::PwmInputExt as _;
 pub use crate::timer::delay::DelayExt as _;
 // pub use crate::dma::CopyDma as _;
+pub use crate::dma::Channel as _;
 pub use crate::dma::DmaExt as _;
+pub use crate::dma::Target as _;
 // pub use crate::dma::ReadDma as _;
 // pub use crate::dma::WriteDma as _;
+pub use crate::dmamux::DmaMuxChannel as _;
+pub use crate::dmamux::DmaMuxExt as _;
 pub use crate::exti::ExtiExt as _;
 pub use crate::flash::FlashExt as _;
+pub use crate::flash::Read as _;
+pub use crate::flash::WriteErase as _;
 pub use crate::gpio::GpioExt as _;
+pub use crate::i2c::blocking::I2cSlave as _;
+pub use crate::i2c::smbus::I2cSmbusExt as _;
 pub use crate::i2c::I2cExt as _;
+pub use crate::nvcounter::NvCountersExt as _;
 pub use crate::power::PowerExt as _;
+pub use crate::power::WaitIdle as _;
 pub use crate::rcc::LSCOExt as _;
 pub use crate::rcc::MCOExt as _;
 pub use crate::rcc::RccExt as _;
@@ -36,12 +53,21 @@ pub use crate::rng::RngExt as _;
 pub use crate::rtc::RtcExt as _;
 pub use crate::serial::SerialExt as _;
 pub use crate::spi::SpiExt as _;
+pub use crate::tamp::TamperExt as _;
 pub use crate::time::U32Ext as _;
+#[cfg(feature = "stm32g0x1")]
+pub use crate::timer::lptim::LowPowerTimerExt as _;
+pub use crate::timer::opm::OneShotPulse as _;
 pub use crate::timer::opm::OpmExt as _;
+pub use crate::timer::opm::TransferStrobe as _;
 pub use crate::timer::pwm::PwmExt as _;
 pub use crate::timer::qei::QeiExt as _;
 pub use crate::timer::stopwatch::StopwatchExt as _;
+#[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+pub use crate::timer::zerocross::ZeroCrossExt as _;
 pub use crate::timer::TimerExt as _;
+#[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+pub use crate::ucpd::UcpdExt as _;
 pub use crate::watchdog::IWDGExt as _;
 pub use crate::watchdog::WWDGExt as _;
 pub use fugit::{ExtU32 as _, RateExtU32 as _};
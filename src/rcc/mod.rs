@@ -1,3 +1,4 @@
+use crate::power::{VoltageScale, LP_RUN_MAX_SYS_CLK};
 use crate::stm32::{rcc, FLASH, PWR, RCC};
 use crate::time::Hertz;
 use fugit::RateExtU32;
@@ -5,13 +6,21 @@ use fugit::RateExtU32;
 mod clockout;
 mod config;
 mod enable;
+mod trim;
 
 pub use clockout::*;
 pub use config::*;
+pub use trim::*;
 
 /// HSI speed
 pub const HSI_FREQ: u32 = 16_000_000;
 
+/// LSI typical speed
+pub const LSI_FREQ: u32 = 32_000;
+
+/// LSE crystal speed
+pub const LSE_FREQ: u32 = 32_768;
+
 /// Clock frequencies
 #[derive(Clone, Copy)]
 pub struct Clocks {
@@ -58,6 +67,7 @@ impl Default for Clocks {
 }
 
 /// Reset pin mode and remap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResetMode {
     /// Reset Input only: a low level on the NRST pin generates system reset, internal RESET not propagated to the NSRT pin
     ResetInput = 0b01,
@@ -67,6 +77,47 @@ pub enum ResetMode {
     Bidirectional = 0b11,
 }
 
+/// Snapshot of the peripheral clock enable registers
+///
+/// Each field holds the raw bits of the enable register of a bus, see the reference
+/// manual for the bit of each peripheral.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeripheralClocks {
+    /// AHBENR
+    pub ahb: u32,
+    /// APBENR1
+    pub apb1: u32,
+    /// APBENR2
+    pub apb2: u32,
+    /// IOPENR
+    pub iop: u32,
+}
+
+impl PeripheralClocks {
+    /// Clocks enabled in `self` but not in `other`
+    pub fn difference(&self, other: &PeripheralClocks) -> PeripheralClocks {
+        PeripheralClocks {
+            ahb: self.ahb & !other.ahb,
+            apb1: self.apb1 & !other.apb1,
+            apb2: self.apb2 & !other.apb2,
+            iop: self.iop & !other.iop,
+        }
+    }
+
+    /// Returns true if no clock is enabled
+    pub fn is_empty(&self) -> bool {
+        self.ahb == 0 && self.apb1 == 0 && self.apb2 == 0 && self.iop == 0
+    }
+
+    /// Number of clocks enabled
+    pub fn count(&self) -> u32 {
+        self.ahb.count_ones()
+            + self.apb1.count_ones()
+            + self.apb2.count_ones()
+            + self.iop.count_ones()
+    }
+}
+
 /// Constrained RCC peripheral
 pub struct Rcc {
     /// Clock configuration
@@ -83,7 +134,74 @@ impl core::ops::Deref for Rcc {
     }
 }
 
+impl Config {
+    /// System clock this configuration results in
+    fn sys_clk(&self) -> Hertz {
+        match self.sys_mux {
+            SysClockSrc::HSE(freq) | SysClockSrc::HSE_BYPASS(freq) => freq,
+            SysClockSrc::LSE(freq) | SysClockSrc::LSE_BYPASS(freq) => freq,
+            SysClockSrc::LSI => 32_768.Hz(),
+            SysClockSrc::HSI(prs) => hsi_div(prs).0.Hz(),
+            SysClockSrc::PLL => {
+                let freq = match self.pll_cfg.mux {
+                    PLLSrc::HSI => HSI_FREQ,
+                    PLLSrc::HSE(freq) | PLLSrc::HSE_BYPASS(freq) => freq.raw(),
+                };
+                let (m, r) = self.pll_cfg.dividers();
+                (freq / m as u32 * self.pll_cfg.n as u32 / r as u32).Hz()
+            }
+        }
+    }
+}
+
+impl PllConfig {
+    /// M and R dividers, clamped to what the PLLSYSCFGR fields can hold
+    fn dividers(&self) -> (PLLDiv, PLLDiv) {
+        (self.m.clamp(1, 8), self.r.clamp(2, 8))
+    }
+}
+
+/// HSI16 frequency and HSIDIV bits for a system clock prescaler
+fn hsi_div(prs: Prescaler) -> (u32, u8) {
+    match prs {
+        Prescaler::Div2 => (HSI_FREQ / 2, 0b001),
+        Prescaler::Div4 => (HSI_FREQ / 4, 0b010),
+        Prescaler::Div8 => (HSI_FREQ / 8, 0b011),
+        Prescaler::Div16 => (HSI_FREQ / 16, 0b100),
+        Prescaler::Div32 => (HSI_FREQ / 32, 0b101),
+        Prescaler::Div64 => (HSI_FREQ / 64, 0b110),
+        Prescaler::Div128 => (HSI_FREQ / 128, 0b111),
+        _ => (HSI_FREQ, 0b000),
+    }
+}
+
+fn low_power_run() -> bool {
+    unsafe { (*PWR::ptr()).cr1.read().lpr().bit_is_set() }
+}
+
+/// The clock configuration is out of the hardware limits
+#[derive(Debug)]
+pub struct InvalidClockConfig;
+
 impl Rcc {
+    /// Apply clock configuration, failing if it is out of the hardware limits
+    ///
+    /// Besides the PLL dividers, the resulting system clock is checked against the
+    /// current voltage range and, in Low-power run mode, against its 2 MHz limit.
+    pub fn try_freeze(self, rcc_cfg: Config) -> Result<Self, InvalidClockConfig> {
+        let pll = &rcc_cfg.pll_cfg;
+        if !(1..=8).contains(&pll.m) || !(2..=8).contains(&pll.r) {
+            return Err(InvalidClockConfig);
+        }
+        let sys_clk = rcc_cfg.sys_clk();
+        if sys_clk > VoltageScale::current().max_sys_clk()
+            || (sys_clk.raw() > LP_RUN_MAX_SYS_CLK && low_power_run())
+        {
+            return Err(InvalidClockConfig);
+        }
+        Ok(self.freeze(rcc_cfg))
+    }
+
     /// Apply clock configuration
     pub fn freeze(self, rcc_cfg: Config) -> Self {
         let pll_clk = self.config_pll(rcc_cfg.pll_cfg);
@@ -112,16 +230,7 @@ impl Rcc {
             }
             SysClockSrc::HSI(prs) => {
                 self.enable_hsi();
-                let (freq, div_bits) = match prs {
-                    Prescaler::Div2 => (HSI_FREQ / 2, 0b001),
-                    Prescaler::Div4 => (HSI_FREQ / 4, 0b010),
-                    Prescaler::Div8 => (HSI_FREQ / 8, 0b011),
-                    Prescaler::Div16 => (HSI_FREQ / 16, 0b100),
-                    Prescaler::Div32 => (HSI_FREQ / 32, 0b101),
-                    Prescaler::Div64 => (HSI_FREQ / 64, 0b110),
-                    Prescaler::Div128 => (HSI_FREQ / 128, 0b111),
-                    _ => (HSI_FREQ, 0b000),
-                };
+                let (freq, div_bits) = hsi_div(prs);
                 self.cr.write(|w| unsafe { w.hsidiv().bits(div_bits) });
                 (freq.Hz(), 0b000)
             }
@@ -147,18 +256,22 @@ impl Rcc {
             _ => (sys_clk.raw(), sys_clk.raw(), 0b000),
         };
 
+        let range = VoltageScale::current();
+        hal_assert!(
+            sys_clk <= range.max_sys_clk(),
+            "system clock too fast for the voltage range"
+        );
+        hal_assert!(
+            sys_clk.raw() <= LP_RUN_MAX_SYS_CLK || !low_power_run(),
+            "system clock too fast for Low-power run mode"
+        );
+
         unsafe {
             // Adjust flash wait states
             let flash = &(*FLASH::ptr());
-            flash.acr.modify(|_, w| {
-                w.latency().bits(if sys_clk.raw() <= 24_000_000 {
-                    0b000
-                } else if sys_clk.raw() <= 48_000_000 {
-                    0b001
-                } else {
-                    0b010
-                })
-            })
+            flash
+                .acr
+                .modify(|_, w| w.latency().bits(range.flash_latency(sys_clk)))
         }
 
         self.cfgr.modify(|_, w| unsafe {
@@ -185,6 +298,31 @@ impl Rcc {
         }
     }
 
+    /// Peripheral clocks currently enabled
+    ///
+    /// Meant to check that no unexpected clock is left running before entering a low
+    /// power mode, e.g. by comparing with a snapshot taken at startup:
+    /// `rcc.enabled_peripherals().difference(&baseline).is_empty()`.
+    pub fn enabled_peripherals(&self) -> PeripheralClocks {
+        PeripheralClocks {
+            ahb: self.rb.ahbenr.read().bits(),
+            apb1: self.rb.apbenr1.read().bits(),
+            apb2: self.rb.apbenr2.read().bits(),
+            iop: self.rb.iopenr.read().bits(),
+        }
+    }
+
+    /// Peripheral clocks enabled in Sleep mode
+    pub fn sleep_enabled_peripherals(&self) -> PeripheralClocks {
+        PeripheralClocks {
+            ahb: self.rb.ahbsmenr.read().bits(),
+            apb1: self.rb.apbsmenr1.read().bits(),
+            apb2: self.rb.apbsmenr2.read().bits(),
+            iop: self.rb.iopsmenr.read().bits(),
+        }
+    }
+
+    #[deprecated(note = "use `UnlockedFlash::program_option_bytes`, then `launch` them")]
     pub fn set_reset_mode(&mut self, mode: ResetMode) {
         unsafe {
             let flash = &(*FLASH::ptr());
@@ -204,8 +342,8 @@ impl Rcc {
     }
 
     fn config_pll(&self, pll_cfg: PllConfig) -> PLLClocks {
-        assert!(pll_cfg.m > 0 && pll_cfg.m <= 8);
-        assert!(pll_cfg.r > 1 && pll_cfg.r <= 8);
+        hal_assert!(pll_cfg.m > 0 && pll_cfg.m <= 8);
+        hal_assert!(pll_cfg.r > 1 && pll_cfg.r <= 8);
 
         // Disable PLL
         self.cr.modify(|_, w| w.pllon().clear_bit());
@@ -226,8 +364,9 @@ impl Rcc {
             }
         };
 
-        let pll_freq = freq / (pll_cfg.m as u32) * (pll_cfg.n as u32);
-        let r = (pll_freq / (pll_cfg.r as u32)).Hz();
+        let (m, r_div) = pll_cfg.dividers();
+        let pll_freq = freq / m as u32 * pll_cfg.n as u32;
+        let r = (pll_freq / r_div as u32).Hz();
         let q = match pll_cfg.q {
             Some(div) if div > 1 && div <= 8 => {
                 self.pllsyscfgr
@@ -252,11 +391,11 @@ impl Rcc {
             w.pllsrc()
                 .bits(pll_sw_bits)
                 .pllm()
-                .bits(pll_cfg.m - 1)
+                .bits(m - 1)
                 .plln()
                 .bits(pll_cfg.n)
                 .pllr()
-                .bits(pll_cfg.r - 1)
+                .bits(r_div - 1)
                 .pllren()
                 .set_bit()
         });
@@ -297,25 +436,30 @@ impl Rcc {
         while pwr.cr1.read().dbp().bit_is_clear() {}
     }
 
-    pub(crate) fn enable_rtc(&self, src: RTCSrc) {
+    /// Clocks the RTC from `src`, returns `true` if the backup domain had to be reset
+    ///
+    /// The backup domain is only reset to change the clock source, so that the calendar
+    /// keeps running across resets and Standby.
+    pub(crate) fn enable_rtc(&self, src: RTCSrc) -> bool {
+        self.apbenr1
+            .modify(|_, w| w.rtcapben().set_bit().pwren().set_bit());
+        self.apbsmenr1.modify(|_, w| w.rtcapbsmen().set_bit());
+        self.unlock_rtc();
+        let bdcr = self.bdcr.read();
+        let running = bdcr.rtcen().bit_is_set() && bdcr.rtcsel().bits() == src as u8;
+        if !running {
+            // The reset also stops the LSE, it is started afterwards
+            self.bdcr.modify(|_, w| w.bdrst().set_bit());
+            self.bdcr.modify(|_, w| w.bdrst().clear_bit());
+        }
         match src {
             RTCSrc::LSI => self.enable_lsi(),
             RTCSrc::HSE => self.enable_hse(false),
             RTCSrc::LSE => self.enable_lse(false),
         }
-        self.apbenr1
-            .modify(|_, w| w.rtcapben().set_bit().pwren().set_bit());
-        self.apbsmenr1.modify(|_, w| w.rtcapbsmen().set_bit());
-        self.unlock_rtc();
-        self.bdcr.modify(|_, w| w.bdrst().set_bit());
-        self.bdcr.modify(|_, w| unsafe {
-            w.rtcsel()
-                .bits(src as u8)
-                .rtcen()
-                .set_bit()
-                .bdrst()
-                .clear_bit()
-        });
+        self.bdcr
+            .modify(|_, w| unsafe { w.rtcsel().bits(src as u8).rtcen().set_bit() });
+        !running
     }
 }
 
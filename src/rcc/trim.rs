@@ -0,0 +1,165 @@
+use crate::rcc::*;
+use crate::stm32::TIM16;
+
+/// Reference periods between two captures (TIM16_CCMR1 IC1PSC = 11)
+pub(crate) const CAPTURE_PERIODS: u32 = 8;
+/// Captures accumulated by a measurement, 256 LSE periods or about 7.8 ms
+const CAPTURES: u32 = 32;
+const HSITRIM_MAX: u8 = 127;
+
+/// Low-speed clock captured by TIM16 channel 1 (TIM16_TISEL TI1SEL)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CaptureClock {
+    Lsi = 0b0001,
+    Lse = 0b0010,
+}
+
+impl CaptureClock {
+    pub(crate) fn freq(self) -> u32 {
+        match self {
+            CaptureClock::Lsi => LSI_FREQ,
+            CaptureClock::Lse => LSE_FREQ,
+        }
+    }
+}
+
+/// Outcome of the HSI16 calibration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HsiTrim {
+    /// HSITRIM value left in ICSCR
+    pub trim: u8,
+    /// Remaining frequency error of the HSI16, in parts per million
+    pub error_ppm: i32,
+}
+
+/// HSI16 calibration error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrimError {
+    /// The timer clock is not derived from the HSI16
+    NotHsi,
+    /// The LSE is not running
+    NoLse,
+}
+
+impl Rcc {
+    /// Tunes HSITRIM so that the HSI16 matches the LSE crystal
+    ///
+    /// The LSE is routed to the TIM16 capture input and the HSI16 derived timer clock is
+    /// counted over 256 LSE periods, HSITRIM is then stepped towards the nominal frequency
+    /// until the error stops decreasing. The LSE has to be running (e.g. enabled by the RTC
+    /// or the LSCO) and TIM16 must not be in use, it is reset and turned off afterwards.
+    ///
+    /// Calling it again from time to time compensates the temperature drift of the HSI16.
+    pub fn trim_hsi_against_lse(&mut self, tim: &mut TIM16) -> Result<HsiTrim, TrimError> {
+        let sws = self.cfgr.read().sws().bits();
+        let from_hsi =
+            sws == 0b000 || (sws == 0b010 && self.pllsyscfgr.read().pllsrc().bits() == 0b10);
+        if !from_hsi {
+            return Err(TrimError::NotHsi);
+        }
+        if self.bdcr.read().lserdy().bit_is_clear() {
+            return Err(TrimError::NoLse);
+        }
+
+        self.start_capture(tim, CaptureClock::Lse);
+        let expected = self.clocks.apb_tim_clk.raw() / (LSE_FREQ / (CAPTURE_PERIODS * CAPTURES));
+        let result = self.trim_loop(tim, expected);
+        self.stop_capture();
+        result
+    }
+
+    fn trim_loop(&mut self, tim: &TIM16, expected: u32) -> Result<HsiTrim, TrimError> {
+        let mut trim = self.icscr.read().hsitrim().bits();
+        let mut error = error_ppm(measure(tim)?, expected);
+        let step: i8 = if error > 0 { -1 } else { 1 };
+        loop {
+            let next = trim as i8 + step;
+            if next < 0 || next > HSITRIM_MAX as i8 {
+                break;
+            }
+            self.set_hsi_trim(next as u8);
+            let next_error = error_ppm(measure(tim)?, expected);
+            if next_error.abs() >= error.abs() {
+                self.set_hsi_trim(trim);
+                break;
+            }
+            trim = next as u8;
+            error = next_error;
+        }
+        Ok(HsiTrim {
+            trim,
+            error_ppm: error,
+        })
+    }
+
+    fn set_hsi_trim(&mut self, trim: u8) {
+        self.icscr.modify(|_, w| unsafe { w.hsitrim().bits(trim) });
+    }
+
+    /// Starts counting the timer clock cycles with TIM16, captured every 8 periods of
+    /// `clock`, which has to be running
+    pub(crate) fn start_capture(&mut self, tim: &TIM16, clock: CaptureClock) {
+        TIM16::enable(self);
+        TIM16::reset(self);
+        tim.psc.write(|w| unsafe { w.psc().bits(0) });
+        tim.arr.write(|w| unsafe { w.bits(0xffff) });
+        tim.tisel.write(|w| unsafe { w.ti1sel().bits(clock as u8) });
+        // IC1 mapped on TI1 (CC1S = 01), one capture every 8 edges (IC1PSC = 11)
+        tim.ccmr1_input()
+            .write(|w| unsafe { w.cc1s().bits(0b01).ic1psc().bits(0b11) });
+        tim.ccer.write(|w| w.cc1e().set_bit());
+        tim.cr1.write(|w| w.cen().set_bit());
+    }
+
+    /// Stops the captures, TIM16 is reset and turned off
+    pub(crate) fn stop_capture(&mut self) {
+        TIM16::reset(self);
+        TIM16::disable(self);
+    }
+}
+
+/// Waits for the next capture started by [`Rcc::start_capture`]
+///
+/// 8 periods of the low-speed clocks are below 65536 cycles of a 64 MHz timer clock, a
+/// second overflow without capture means that the clock stopped, `None` is returned.
+pub(crate) fn next_capture(tim: &TIM16) -> Option<u16> {
+    let mut overflows = 0;
+    loop {
+        let sr = tim.sr.read();
+        if sr.cc1if().bit_is_set() {
+            // Reading CCR1 clears CC1IF
+            return Some(tim.ccr1.read().bits() as u16);
+        }
+        if sr.uif().bit_is_set() {
+            tim.sr.write(|w| unsafe { w.bits(!1) });
+            overflows += 1;
+            if overflows > 2 {
+                return None;
+            }
+        }
+    }
+}
+
+/// Timer clock cycles over `CAPTURES` capture periods
+fn measure(tim: &TIM16) -> Result<u32, TrimError> {
+    // Start from a fresh capture, the first one only gives the starting point and lets
+    // a new trim settle
+    tim.sr
+        .modify(|_, w| w.cc1if().clear_bit().cc1of().clear_bit().uif().clear_bit());
+    let mut last = capture(tim)?;
+    let mut total = 0;
+    for _ in 0..CAPTURES {
+        let now = capture(tim)?;
+        total += now.wrapping_sub(last) as u32;
+        last = now;
+    }
+    Ok(total)
+}
+
+fn capture(tim: &TIM16) -> Result<u16, TrimError> {
+    next_capture(tim).ok_or(TrimError::NoLse)
+}
+
+fn error_ppm(measured: u32, expected: u32) -> i32 {
+    ((measured as i64 - expected as i64) * 1_000_000 / expected as i64) as i32
+}
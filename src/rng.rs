@@ -1,4 +1,12 @@
-use core::cmp;
+//! # True random number generator
+//!
+//! The RNG is clocked by the HSI16, SYSCLK or PLLQ, see [`RngClkSource`]. Its clock has to
+//! be above the AHB clock divided by 32, otherwise the clock error check stops the
+//! generation. A seed error, from a misbehaving noise source, is recovered by restarting
+//! the generation, the words generated since the error are dropped.
+//!
+//! With the `rand_core` feature, [`Rng`] implements the `rand_core` `RngCore` and
+//! `CryptoRng` traits.
 use core::mem;
 
 use crate::hal::blocking::rng;
@@ -52,10 +60,15 @@ impl Default for Config {
 
 #[derive(Debug)]
 pub enum ErrorKind {
+    /// The RNG clock is too slow (RNG_SR CECS)
     ClockError,
+    /// The noise source kept failing after the recoveries (RNG_SR SECS)
     SeedError,
 }
 
+/// Seed error recoveries of [`Rng::gen`] before giving up
+const SEED_RECOVERIES: u8 = 3;
+
 pub trait RngExt {
     fn constrain(self, cfg: Config, rcc: &mut Rcc) -> Rng;
 }
@@ -84,21 +97,40 @@ pub struct Rng {
 }
 
 impl Rng {
+    /// Returns a random word, recovering from the seed errors
+    ///
+    /// A clock error flags the data as unreliable until the RNG clock is fast enough
+    /// again, the generation then resumes by itself.
     pub fn gen(&mut self) -> Result<u32, ErrorKind> {
+        let mut recoveries = 0;
         loop {
             let status = self.rb.sr.read();
-            if status.drdy().bit() {
-                return Ok(self.rb.dr.read().rndata().bits());
+            if status.secs().bit() || status.seis().bit() {
+                if recoveries == SEED_RECOVERIES {
+                    return Err(ErrorKind::SeedError);
+                }
+                recoveries += 1;
+                self.recover();
+                continue;
             }
-            if status.cecs().bit() {
+            if status.cecs().bit() || status.ceis().bit() {
+                self.rb.sr.modify(|_, w| w.ceis().clear_bit());
                 return Err(ErrorKind::ClockError);
             }
-            if status.secs().bit() {
-                return Err(ErrorKind::SeedError);
+            if status.drdy().bit() {
+                return Ok(self.rb.dr.read().rndata().bits());
             }
         }
     }
 
+    /// Restarts the generation after a seed error (RM0444 RNG error management), the
+    /// pipeline holding the words generated since the error is flushed
+    fn recover(&mut self) {
+        self.rb.sr.modify(|_, w| w.seis().clear_bit());
+        self.rb.cr.modify(|_, w| w.rngen().clear_bit());
+        self.rb.cr.modify(|_, w| w.rngen().set_bit());
+    }
+
     pub fn release(self) -> RNG {
         self.rb
     }
@@ -109,7 +141,7 @@ impl Rng {
     }
 
     pub fn gen_ratio(&mut self, numerator: u32, denominator: u32) -> Result<bool, ErrorKind> {
-        assert!(denominator > 0 || denominator > numerator);
+        hal_assert!(denominator > 0 || denominator > numerator);
         let val = self.gen_range(0, denominator)?;
         Ok(numerator > val)
     }
@@ -159,21 +191,28 @@ macro_rules! rng_core {
 
                 // TODO: fix modulo bias
                 fn gen_range(&mut self, low: $type, high: $type) -> Result<$type, ErrorKind> {
-                    assert!(high > low);
+                    hal_assert!(high > low);
                     let range = high - low;
                     let val: $type = self.gen()? as $type;
                     Ok(low + val % range)
                 }
 
                 fn fill(&mut self, buffer: &mut [$type]) -> Result<(), ErrorKind> {
-                    const BATCH_SIZE: usize = 4 / mem::size_of::<$type>();
-                    let mut i = 0_usize;
-                    while i < buffer.len() {
-                        let random_word = self.gen()?;
-                        let bytes: [$type; BATCH_SIZE] = unsafe { mem::transmute(random_word) };
-                        let n = cmp::min(BATCH_SIZE, buffer.len() - i);
-                        buffer[i..i + n].copy_from_slice(&bytes[..n]);
-                        i += n;
+                    // Values are made of the bytes of the random words in order, a word is
+                    // split between several small values or spread over a large one
+                    let mut word = [0; 4];
+                    let mut used = word.len();
+                    for val in buffer.iter_mut() {
+                        let mut bytes = [0; mem::size_of::<$type>()];
+                        for byte in bytes.iter_mut() {
+                            if used == word.len() {
+                                word = self.gen()?.to_ne_bytes();
+                                used = 0;
+                            }
+                            *byte = word[used];
+                            used += 1;
+                        }
+                        *val = <$type>::from_ne_bytes(bytes);
                     }
                     Ok(())
                 }
@@ -183,3 +222,39 @@ macro_rules! rng_core {
 }
 
 rng_core!(usize, u32, u16, u8);
+
+#[cfg(feature = "rand_core")]
+impl From<ErrorKind> for rand_core::Error {
+    fn from(err: ErrorKind) -> Self {
+        let code = rand_core::Error::CUSTOM_START + err as u32;
+        // NOTE(unsafe) CUSTOM_START is not zero
+        rand_core::Error::from(unsafe { core::num::NonZeroU32::new_unchecked(code) })
+    }
+}
+
+/// The infallible methods retry until the RNG works again, randomness can not be made up
+#[cfg(feature = "rand_core")]
+impl rand_core::RngCore for Rng {
+    fn next_u32(&mut self) -> u32 {
+        loop {
+            if let Ok(val) = self.gen() {
+                return val;
+            }
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        while self.try_fill_bytes(dest).is_err() {}
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill(dest).map_err(rand_core::Error::from)
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl rand_core::CryptoRng for Rng {}
@@ -1,22 +1,140 @@
 //! Real Time Clock
+//!
+//! The calendar, the two alarms and the periodic wakeup timer run in the backup domain,
+//! in Stop, Standby and Shutdown modes. Their interrupts are routed to the EXTI line 19,
+//! which has to be enabled with `exti.wakeup(Event::RTC)` to wake the device up from
+//! Stop mode. In Standby and Shutdown modes the enabled RTC interrupts wake the device
+//! up directly, see [`Power::wakeup_cause`](crate::power::Power::wakeup_cause).
 use crate::rcc::{RTCSrc, Rcc};
 use crate::stm32::RTC;
 use crate::time::*;
 
+/// RTC alarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alarm {
+    A,
+    B,
+}
+
+/// Calendar fields compared by an alarm, the fields left to `None` match any value
+///
+/// The default value fires every second.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AlarmMatch {
+    /// Day of the month, 1 to 31
+    pub day: Option<u32>,
+    pub hours: Option<u32>,
+    pub minutes: Option<u32>,
+    pub seconds: Option<u32>,
+}
+
+impl AlarmMatch {
+    /// Matches every day at `time`
+    pub fn daily(time: &Time) -> Self {
+        AlarmMatch {
+            day: None,
+            hours: Some(time.hours),
+            minutes: Some(time.minutes),
+            seconds: Some(time.seconds),
+        }
+    }
+
+    /// ALRMxR value
+    fn bits(&self) -> u32 {
+        let field = |value: Option<u32>, shift: u32, mask: u32| match value {
+            Some(value) => bcd(value) << shift,
+            None => 1 << mask,
+        };
+        field(self.day, 24, 31)
+            | field(self.hours, 16, 23)
+            | field(self.minutes, 8, 15)
+            | field(self.seconds, 0, 7)
+    }
+}
+
+/// RTC interrupt event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    AlarmA,
+    AlarmB,
+    WakeupTimer,
+}
+
 pub struct Rtc {
     rb: RTC,
+    clock: Hertz,
 }
 
 impl Rtc {
+    /// Clocks the RTC from `src`
+    ///
+    /// The calendar keeps running if the RTC was already clocked from `src`, after a
+    /// reset or a wakeup from Standby. Otherwise the backup domain is reset and the
+    /// prescalers are set for the 32.768 kHz LSE or the 32 kHz LSI, with the HSE divided
+    /// by 32 [`set_clock_frequency`](Rtc::set_clock_frequency) has to be called.
     pub fn new(rtc: RTC, src: RTCSrc, rcc: &mut Rcc) -> Self {
-        let mut rtc = Rtc { rb: rtc };
-        rcc.enable_rtc(src);
-        rtc.modify(|rb| {
-            rb.cr.modify(|_, w| w.fmt().clear_bit());
-        });
+        let clock = match src {
+            RTCSrc::LSI => 32_000.Hz(),
+            _ => 32_768.Hz(),
+        };
+        let mut rtc = Rtc { rb: rtc, clock };
+        if rcc.enable_rtc(src) {
+            rtc.set_clock_frequency(clock);
+            rtc.modify(|rb| {
+                rb.cr.modify(|_, w| w.fmt().clear_bit());
+            });
+        } else {
+            let prer = rtc.rb.prer.read();
+            let divider = (prer.prediv_a().bits() as u32 + 1) * (prer.prediv_s().bits() as u32 + 1);
+            rtc.clock = divider.Hz();
+        }
         rtc
     }
 
+    /// Sets the prescalers for an RTC clock of `freq`, to count the calendar seconds
+    ///
+    /// # Panics
+    ///
+    /// Panics if `freq` can not be divided down to exactly 1 Hz, or is above 4.194 MHz.
+    pub fn set_clock_frequency(&mut self, freq: Hertz) {
+        let freq = freq.raw();
+        // The largest asynchronous prescaler gives the lowest consumption
+        let prescalers = (1..=128u32)
+            .rev()
+            .find(|a| freq.is_multiple_of(*a) && freq / a >= 1 && freq / a <= 0x8000);
+        hal_assert!(
+            prescalers.is_some(),
+            "the RTC clock can not be divided down to 1 Hz"
+        );
+        let a = prescalers.unwrap_or(128);
+        let s = (freq / a).clamp(1, 0x8000);
+        self.modify(|rb| {
+            rb.prer
+                .write(|w| unsafe { w.prediv_a().bits(a as u8 - 1).prediv_s().bits(s as u16 - 1) });
+        });
+        self.clock = freq.Hz();
+    }
+
+    /// Returns true once the calendar year field is not 0, i.e. a date in a year other
+    /// than 2000 has been set (RTC_ICSR INITS)
+    ///
+    /// The calendar keeps running through a reset or a wakeup from Standby, so this tells
+    /// whether it still has to be initialized.
+    pub fn is_calendar_set(&self) -> bool {
+        self.rb.icsr.read().inits().bit_is_set()
+    }
+
+    /// Disables the write protection of the RTC registers while running `closure`
+    fn unprotect<F>(&mut self, closure: F)
+    where
+        F: FnOnce(&mut RTC),
+    {
+        self.rb.wpr.write(|w| unsafe { w.bits(0xCA) });
+        self.rb.wpr.write(|w| unsafe { w.bits(0x53) });
+        closure(&mut self.rb);
+        self.rb.wpr.write(|w| unsafe { w.bits(0xFF) });
+    }
+
     fn modify<F>(&mut self, mut closure: F)
     where
         F: FnMut(&mut RTC),
@@ -59,7 +177,7 @@ impl Rtc {
                     .yu()
                     .bits(yu)
                     .wdu()
-                    .bits(date.day as u8)
+                    .bits(week_day(date))
             });
         });
     }
@@ -108,9 +226,142 @@ impl Rtc {
         )
     }
 
+    /// Day of the week, 1 for Monday to 7 for Sunday
     pub fn get_week_day(&self) -> u8 {
         self.rb.dr.read().wdu().bits()
     }
+
+    /// Sets and enables `alarm`, replacing its previous setting
+    ///
+    /// The alarm flag is raised on each match, [`listen`](Rtc::listen) enables its
+    /// interrupt, which also wakes the device up from Standby.
+    pub fn set_alarm(&mut self, alarm: Alarm, time: AlarmMatch) {
+        let bits = time.bits();
+        self.unprotect(|rb| match alarm {
+            Alarm::A => {
+                rb.cr.modify(|_, w| w.alrae().clear_bit());
+                while rb.icsr.read().alrawf().bit_is_clear() {}
+                rb.alrmar.write(|w| unsafe { w.bits(bits) });
+                // The sub-seconds are not compared
+                rb.alrmassr.write(|w| unsafe { w.bits(0) });
+                rb.scr.write(|w| w.calraf().set_bit());
+                rb.cr.modify(|_, w| w.alrae().set_bit());
+            }
+            Alarm::B => {
+                rb.cr.modify(|_, w| w.alrbe().clear_bit());
+                while rb.icsr.read().alrbwf().bit_is_clear() {}
+                rb.alrmbr.write(|w| unsafe { w.bits(bits) });
+                rb.alrmbssr.write(|w| unsafe { w.bits(0) });
+                rb.scr.write(|w| w.calrbf().set_bit());
+                rb.cr.modify(|_, w| w.alrbe().set_bit());
+            }
+        });
+    }
+
+    /// Disables `alarm`
+    pub fn disable_alarm(&mut self, alarm: Alarm) {
+        self.unprotect(|rb| match alarm {
+            Alarm::A => rb.cr.modify(|_, w| w.alrae().clear_bit()),
+            Alarm::B => rb.cr.modify(|_, w| w.alrbe().clear_bit()),
+        });
+    }
+
+    /// Starts the periodic wakeup timer, replacing its previous period
+    ///
+    /// Periods of whole seconds, up to 36 hours, are counted on the 1 Hz calendar clock,
+    /// the others on the RTC clock divided by 16, up to 32 s with the LSE.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is 0 or too long.
+    pub fn enable_wakeup_timer(&mut self, period: MilliSecond) {
+        let ms = period.ticks();
+        let (wucksel, ticks) = if ms.is_multiple_of(1000) && ms / 1000 <= 0x1_0000 {
+            (0b100, ms / 1000)
+        } else if ms.is_multiple_of(1000) {
+            // The counter value is extended by 2^16
+            (0b110, ms / 1000 - 0x1_0000)
+        } else {
+            (0b000, (ms as u64 * self.clock.raw() as u64 / 16_000) as u32)
+        };
+        hal_assert!(ticks > 0 && ticks <= 0x1_0000);
+        self.unprotect(|rb| {
+            rb.cr.modify(|_, w| w.wute().clear_bit());
+            while rb.icsr.read().wutwf().bit_is_clear() {}
+            rb.wutr
+                .write(|w| unsafe { w.wut().bits((ticks - 1) as u16) });
+            rb.cr.modify(|_, w| unsafe { w.wucksel().bits(wucksel) });
+            rb.scr.write(|w| w.cwutf().set_bit());
+            rb.cr.modify(|_, w| w.wute().set_bit());
+        });
+    }
+
+    /// Stops the wakeup timer
+    pub fn disable_wakeup_timer(&mut self) {
+        self.unprotect(|rb| rb.cr.modify(|_, w| w.wute().clear_bit()));
+    }
+
+    /// Corrects the frequency of the RTC clock by `ppb` parts per billion
+    ///
+    /// A positive correction speeds the calendar up, e.g. the opposite of the deviation of
+    /// the LSE measured against a reference. The correction goes from -487100 ppb to
+    /// +488500 ppb, by steps of 954 ppb (smooth calibration over 32 s).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ppb` is out of range.
+    pub fn set_calibration(&mut self, ppb: i32) {
+        // Pulses added or masked every 2^20 RTC clock cycles
+        let pulses = (ppb as i64 * (1 << 20)).div_euclid(1_000_000_000) as i32;
+        hal_assert!((-511..=512).contains(&pulses));
+        let (calp, calm) = if pulses > 0 {
+            (true, (512 - pulses) as u16)
+        } else {
+            (false, (-pulses) as u16)
+        };
+        self.unprotect(|rb| {
+            while rb.icsr.read().recalpf().bit_is_set() {}
+            rb.calr
+                .write(|w| unsafe { w.calp().bit(calp).calm().bits(calm) });
+        });
+    }
+
+    /// Enables raising the RTC interrupt on `event`
+    pub fn listen(&mut self, event: Event) {
+        self.unprotect(|rb| match event {
+            Event::AlarmA => rb.cr.modify(|_, w| w.alraie().set_bit()),
+            Event::AlarmB => rb.cr.modify(|_, w| w.alrbie().set_bit()),
+            Event::WakeupTimer => rb.cr.modify(|_, w| w.wutie().set_bit()),
+        });
+    }
+
+    /// Disables the interrupt on `event`
+    pub fn unlisten(&mut self, event: Event) {
+        self.unprotect(|rb| match event {
+            Event::AlarmA => rb.cr.modify(|_, w| w.alraie().clear_bit()),
+            Event::AlarmB => rb.cr.modify(|_, w| w.alrbie().clear_bit()),
+            Event::WakeupTimer => rb.cr.modify(|_, w| w.wutie().clear_bit()),
+        });
+    }
+
+    /// Returns true if `event` occurred
+    pub fn is_pending(&self, event: Event) -> bool {
+        let sr = self.rb.sr.read();
+        match event {
+            Event::AlarmA => sr.alraf().bit_is_set(),
+            Event::AlarmB => sr.alrbf().bit_is_set(),
+            Event::WakeupTimer => sr.wutf().bit_is_set(),
+        }
+    }
+
+    /// Clears the flag of `event`
+    pub fn unpend(&mut self, event: Event) {
+        match event {
+            Event::AlarmA => self.rb.scr.write(|w| w.calraf().set_bit()),
+            Event::AlarmB => self.rb.scr.write(|w| w.calrbf().set_bit()),
+            Event::WakeupTimer => self.rb.scr.write(|w| w.cwutf().set_bit()),
+        }
+    }
 }
 
 pub trait RtcExt {
@@ -134,6 +385,27 @@ fn bcd2_encode(word: u32) -> (u8, u8) {
     (bcd_high, bcd_low)
 }
 
+fn bcd(value: u32) -> u32 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Day of the week of `date`, 1 for Monday to 7 for Sunday
+fn week_day(date: &Date) -> u8 {
+    const OFFSETS: [u32; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+    let year = if date.month < 3 {
+        date.year - 1
+    } else {
+        date.year
+    };
+    let month = date.month.clamp(1, 12) as usize;
+    let day = (year + year / 4 - year / 100 + year / 400 + OFFSETS[month - 1] + date.day) % 7;
+    if day == 0 {
+        7
+    } else {
+        day as u8
+    }
+}
+
 fn bcd2_decode(fst: u8, snd: u8) -> u32 {
     let value = snd | fst << 4;
     let value = (value & 0x0F) + ((value & 0xF0) >> 4) * 10;
@@ -0,0 +1,178 @@
+//! # Class B self-tests
+//!
+//! Building blocks of the IEC 60730 class B startup and periodic self-tests:
+//!
+//! - [`check_flash`]: CRC of the program image, compared with the value stored by the
+//!   build, e.g. over [`FlashRegion::as_slice`](crate::flash::FlashRegion::as_slice)
+//! - [`march_c`]: March C- test of a RAM area reserved for the test
+//! - [`check_clock`]: timer clock measured against the LSI or LSE, independent of the
+//!   clock tree under test
+//! - [`sram_parity_error`] and [`lock_fault_breaks`]: SRAM parity errors, raising an NMI
+//!   when the parity check is enabled in the option bytes, and the hardware paths turning
+//!   a fault into a timer break, so that the outputs are shut down even if the CPU is
+//!   locked up
+//!
+//! The power supply is checked by [`check_power`](crate::diagnostics::check_power). The
+//! tests return a [`SafetyFault`] describing the failure, the reaction (safe state,
+//! reset, ...) is left to the application.
+use crate::crc::Crc;
+use crate::rcc::{next_capture, CaptureClock, Rcc, CAPTURE_PERIODS};
+use crate::stm32::TIM16;
+use crate::syscfg;
+use crate::time::Hertz;
+
+/// Failure of a self-test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafetyFault {
+    /// The CRC of the flash image is not the expected one
+    FlashCrc { computed: u32 },
+    /// The RAM word at `address` did not read back as written
+    Ram { address: usize },
+    /// The reference clock gave no capture
+    ReferenceStopped,
+    /// The timer clock is off by more than the tolerance
+    ClockDeviation { measured: Hertz },
+}
+
+/// Clock captured by TIM16 channel 1 for [`check_clock`] (TIM16_TISEL)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockReference {
+    /// LSI, started by the test, only accurate to about 5 %
+    Lsi,
+    /// LSE, which has to be running
+    Lse,
+}
+
+impl ClockReference {
+    fn clock(&self) -> CaptureClock {
+        match self {
+            ClockReference::Lsi => CaptureClock::Lsi,
+            ClockReference::Lse => CaptureClock::Lse,
+        }
+    }
+}
+
+/// Computes the CRC of `image` with `crc`, which has to be configured like the tool
+/// that computed `expected`
+pub fn check_flash(crc: &mut Crc, image: &[u8], expected: u32) -> Result<(), SafetyFault> {
+    crc.reset();
+    crc.feed(image);
+    let computed = crc.result();
+    if computed != expected {
+        return Err(SafetyFault::FlashCrc { computed });
+    }
+    Ok(())
+}
+
+/// Runs a March C- test over `area`, which is left zeroed
+///
+/// Finds the stuck-at, transition, address decoder and coupling faults between the words
+/// of the area, with all-zeros and all-ones words. The contents are destroyed, so the
+/// area has to be reserved for the test, e.g. a section left out of the stack and of the
+/// statics in the linker script. It is written before being read, so that unwritten words
+/// do not raise SRAM parity errors.
+pub fn march_c(area: &mut [u32]) -> Result<(), SafetyFault> {
+    let ptr = area.as_mut_ptr();
+    let len = area.len();
+    let write = |i: usize, word: u32| unsafe { ptr.add(i).write_volatile(word) };
+    let check = |i: usize, word: u32| {
+        let addr = unsafe { ptr.add(i) };
+        if unsafe { addr.read_volatile() } != word {
+            return Err(SafetyFault::Ram {
+                address: addr as usize,
+            });
+        }
+        Ok(())
+    };
+
+    for i in 0..len {
+        write(i, 0);
+    }
+    for i in 0..len {
+        check(i, 0)?;
+        write(i, !0);
+    }
+    for i in 0..len {
+        check(i, !0)?;
+        write(i, 0);
+    }
+    for i in (0..len).rev() {
+        check(i, 0)?;
+        write(i, !0);
+    }
+    for i in (0..len).rev() {
+        check(i, !0)?;
+        write(i, 0);
+    }
+    for i in 0..len {
+        check(i, 0)?;
+    }
+    Ok(())
+}
+
+/// Measures the timer clock with TIM16 against `reference`, and checks that it is within
+/// `tolerance` per thousand of the configured one
+///
+/// TIM16 counts the timer clock cycles over 8 periods of the reference captured on its
+/// channel 1, and is reset afterwards. A wrong HSE crystal or PLL setting is caught with
+/// the LSI, finer deviations need the LSE against the tolerance of the LSI.
+pub fn check_clock(
+    tim: &mut TIM16,
+    reference: ClockReference,
+    tolerance: u32,
+    rcc: &mut Rcc,
+) -> Result<Hertz, SafetyFault> {
+    match reference {
+        ClockReference::Lsi => rcc.enable_lsi(),
+        ClockReference::Lse => {
+            if rcc.bdcr.read().lserdy().bit_is_clear() {
+                return Err(SafetyFault::ReferenceStopped);
+            }
+        }
+    }
+    let clk = rcc.clocks.apb_tim_clk.raw();
+
+    rcc.start_capture(tim, reference.clock());
+    let captures = next_capture(tim).zip(next_capture(tim));
+    rcc.stop_capture();
+    let (first, second) = captures.ok_or(SafetyFault::ReferenceStopped)?;
+
+    let ticks = second.wrapping_sub(first);
+    let measured = (ticks as u64 * reference.clock().freq() as u64 / CAPTURE_PERIODS as u64) as u32;
+    if measured.abs_diff(clk) as u64 * 1000 > clk as u64 * tolerance as u64 {
+        return Err(SafetyFault::ClockDeviation {
+            measured: Hertz::from_raw(measured),
+        });
+    }
+    Ok(Hertz::from_raw(measured))
+}
+
+/// Returns true if an SRAM parity error has been detected (SYSCFG_CFGR2 SPF)
+///
+/// Meant for the NMI handler, which the parity errors raise.
+pub fn sram_parity_error() -> bool {
+    syscfg::read_cfgr2() & syscfg::CFGR2_SPF != 0
+}
+
+/// Clears the SRAM parity error flag
+pub fn clear_sram_parity_error() {
+    syscfg::set_cfgr2(syscfg::CFGR2_SPF);
+}
+
+/// Routes the Cortex-M0+ lockup, the SRAM parity errors, the flash ECC errors and on the
+/// G0x1 the PVD output to the break inputs of TIM1, TIM15, TIM16 and TIM17
+/// (SYSCFG_CFGR2 CLL, SPL, ECCL, PVDL)
+///
+/// A hard fault in the HardFault handler locks the core up, the break then sets the PWM
+/// outputs to their idle state without any software. The routing can only be undone by
+/// a reset.
+pub fn lock_fault_breaks() {
+    const CLL: u32 = 1 << 0;
+    const SPL: u32 = 1 << 1;
+    #[cfg(feature = "stm32g0x1")]
+    const PVDL: u32 = 1 << 2;
+    #[cfg(feature = "stm32g0x0")]
+    const PVDL: u32 = 0;
+    const ECCL: u32 = 1 << 3;
+    syscfg::set_cfgr2(CLL | SPL | PVDL | ECCL);
+}
@@ -1,5 +1,31 @@
 use crate::prelude::*;
-use crate::time::Bps;
+use crate::rcc::{Clocks, HSI_FREQ, LSE_FREQ};
+use crate::time::{Bps, Hertz};
+use hal::spi::{Mode, Phase, Polarity};
+
+/// USART kernel clock source (RCC_CCIPR USARTxSEL)
+///
+/// Only USART1, USART2 on STM32G07x/G08x and LPUART have a kernel clock mux. A fixed kernel
+/// clock like HSI16 keeps the baud rate correct when SYSCLK or the APB prescaler change.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum ClockSource {
+    Pclk = 0b00,
+    SysClk = 0b01,
+    HSI16 = 0b10,
+    LSE = 0b11,
+}
+
+impl ClockSource {
+    /// Kernel clock frequency for the given clock configuration
+    pub fn freq(&self, clocks: &Clocks) -> Hertz {
+        match self {
+            ClockSource::Pclk => clocks.apb_clk,
+            ClockSource::SysClk => clocks.sys_clk,
+            ClockSource::HSI16 => HSI_FREQ.Hz(),
+            ClockSource::LSE => LSE_FREQ.Hz(),
+        }
+    }
+}
 
 #[derive(PartialEq, PartialOrd, Clone, Copy)]
 pub enum WordLength {
@@ -54,6 +80,126 @@ impl FifoThreshold {
         self as u8
     }
 }
+/// Active level of the RS-485 driver enable output (DEP)
+#[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
+pub enum DePolarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// RS-485 driver enable settings
+#[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
+pub(crate) struct DriverEnable {
+    pub(crate) polarity: DePolarity,
+    pub(crate) assertion_time: u8,
+    pub(crate) deassertion_time: u8,
+}
+
+impl DriverEnable {
+    fn new(polarity: DePolarity, assertion_time: u8, deassertion_time: u8) -> Self {
+        hal_assert!(
+            assertion_time <= 31 && deassertion_time <= 31,
+            "invalid driver enable timing"
+        );
+        DriverEnable {
+            polarity,
+            assertion_time,
+            deassertion_time,
+        }
+    }
+}
+
+/// Length of the LIN break detected by the receiver (LBDL)
+#[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
+pub enum LinBreakLength {
+    #[doc = "10 bit break detection"]
+    Bits10 = 0,
+    #[doc = "11 bit break detection"]
+    Bits11 = 1,
+}
+
+/// Smartcard (ISO 7816-3) mode settings
+#[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
+pub struct SmartcardConfig {
+    pub(crate) prescaler: u8,
+    pub(crate) guard_time: u8,
+    pub(crate) nack: bool,
+    pub(crate) retries: u8,
+    pub(crate) clock_output: bool,
+}
+
+impl SmartcardConfig {
+    /// Card clock output on the CK pin, at the kernel clock divided by `2 * prescaler`,
+    /// the pin is set up with `Serial::clock_pin`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prescaler` is not in the 1..=31 range.
+    pub fn clock_prescaler(mut self, prescaler: u8) -> Self {
+        hal_assert!((1..=31).contains(&prescaler), "invalid smartcard prescaler");
+        self.prescaler = prescaler;
+        self.clock_output = true;
+        self
+    }
+
+    /// Keep the CK pin idle, for cards with their own clock
+    pub fn no_clock_output(mut self) -> Self {
+        self.clock_output = false;
+        self
+    }
+
+    /// Guard time after each character, in baud clock periods
+    pub fn guard_time(mut self, bits: u8) -> Self {
+        self.guard_time = bits;
+        self
+    }
+
+    /// Signal parity errors to the card with a NACK
+    pub fn nack(mut self, enable: bool) -> Self {
+        self.nack = enable;
+        self
+    }
+
+    /// Number of retransmissions of a character NACKed by the card, and of receptions
+    /// NACKed by the USART before a parity error is raised, from 0 to 7
+    ///
+    /// # Panics
+    ///
+    /// Panics if `retries` is above 7.
+    pub fn retries(mut self, retries: u8) -> Self {
+        hal_assert!(retries <= 7, "invalid smartcard retry count");
+        self.retries = retries;
+        self
+    }
+}
+
+impl Default for SmartcardConfig {
+    /// 2 ETU guard time, NACK and 3 retries as required by the T=0 protocol, card clock
+    /// at a tenth of the kernel clock
+    fn default() -> SmartcardConfig {
+        SmartcardConfig {
+            prescaler: 5,
+            guard_time: 2,
+            nack: true,
+            retries: 3,
+            clock_output: true,
+        }
+    }
+}
+
+/// Operating mode of a full featured USART
+#[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
+pub(crate) enum UsartMode {
+    Asynchronous,
+    Synchronous {
+        cpol: bool,
+        cpha: bool,
+        last_bit_clock: bool,
+    },
+    Lin(LinBreakLength),
+    Smartcard(SmartcardConfig),
+}
+
 #[derive(PartialEq, PartialOrd, Clone, Copy)]
 pub struct BasicConfig {
     pub(crate) baudrate: Bps,
@@ -61,6 +207,8 @@ pub struct BasicConfig {
     pub(crate) parity: Parity,
     pub(crate) stopbits: StopBits,
     pub(crate) swap: bool,
+    pub(crate) clock_source: ClockSource,
+    pub(crate) driver_enable: Option<DriverEnable>,
 }
 
 #[derive(PartialEq, PartialOrd, Clone, Copy)]
@@ -70,6 +218,8 @@ pub struct FullConfig {
     pub(crate) parity: Parity,
     pub(crate) stopbits: StopBits,
     pub(crate) swap: bool,
+    pub(crate) clock_source: ClockSource,
+    pub(crate) driver_enable: Option<DriverEnable>,
     pub(crate) fifo_enable: bool,
     pub(crate) tx_fifo_threshold: FifoThreshold,
     pub(crate) rx_fifo_threshold: FifoThreshold,
@@ -77,6 +227,7 @@ pub struct FullConfig {
     pub(crate) rx_fifo_interrupt: bool,
     #[doc = "Number of bits no activity on rx line"]
     pub(crate) receiver_timeout: Option<u32>,
+    pub(crate) mode: UsartMode,
 }
 
 impl BasicConfig {
@@ -122,6 +273,36 @@ impl BasicConfig {
         self.swap = true;
         self
     }
+
+    /// Select the kernel clock used for the baud rate generation
+    pub fn clock_source(mut self, clock_source: ClockSource) -> Self {
+        self.clock_source = clock_source;
+        self
+    }
+
+    /// Drive the enable input of an RS-485 transceiver on the DE pin
+    ///
+    /// The DE output is asserted `assertion_time` before the start bit and released
+    /// `deassertion_time` after the stop bit of the last byte, both in sample time units
+    /// (1/16 of a bit, or a kernel clock cycle on the LPUART) from 0 to 31. The DE pin
+    /// is set up with `Serial::de_pin`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the times is above 31.
+    pub fn rs485_de(
+        mut self,
+        polarity: DePolarity,
+        assertion_time: u8,
+        deassertion_time: u8,
+    ) -> Self {
+        self.driver_enable = Some(DriverEnable::new(
+            polarity,
+            assertion_time,
+            deassertion_time,
+        ));
+        self
+    }
 }
 
 impl FullConfig {
@@ -168,6 +349,36 @@ impl FullConfig {
         self
     }
 
+    /// Select the kernel clock used for the baud rate generation
+    pub fn clock_source(mut self, clock_source: ClockSource) -> Self {
+        self.clock_source = clock_source;
+        self
+    }
+
+    /// Drive the enable input of an RS-485 transceiver on the DE pin
+    ///
+    /// The DE output is asserted `assertion_time` before the start bit and released
+    /// `deassertion_time` after the stop bit of the last byte, both in sample time units
+    /// (1/16 of a bit, or a kernel clock cycle on the LPUART) from 0 to 31. The DE pin
+    /// is set up with `Serial::de_pin`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the times is above 31.
+    pub fn rs485_de(
+        mut self,
+        polarity: DePolarity,
+        assertion_time: u8,
+        deassertion_time: u8,
+    ) -> Self {
+        self.driver_enable = Some(DriverEnable::new(
+            polarity,
+            assertion_time,
+            deassertion_time,
+        ));
+        self
+    }
+
     pub fn fifo_enable(mut self) -> Self {
         self.fifo_enable = true;
         self
@@ -199,11 +410,68 @@ impl FullConfig {
         self.receiver_timeout = Some(t as u32);
         self
     }
+
+    /// Synchronous master mode, the clock is output on the CK pin
+    ///
+    /// `mode` gives the clock polarity and the sampling edge like for SPI. Without
+    /// `last_bit_clock` no clock pulse is output for the last data bit, as needed by the
+    /// devices that only take whole bytes. The CK pin is set up with `Serial::clock_pin`.
+    pub fn synchronous(mut self, mode: Mode, last_bit_clock: bool) -> Self {
+        self.mode = UsartMode::Synchronous {
+            cpol: mode.polarity == Polarity::IdleHigh,
+            cpha: mode.phase == Phase::CaptureOnSecondTransition,
+            last_bit_clock,
+        };
+        self
+    }
+
+    /// LIN mode, with 8 data bits, no parity and 1 stop bit
+    pub fn lin(mut self, break_length: LinBreakLength) -> Self {
+        self.mode = UsartMode::Lin(break_length);
+        self.wordlength = WordLength::DataBits8;
+        self.parity = Parity::ParityNone;
+        self.stopbits = StopBits::STOP1;
+        self
+    }
+
+    /// Smartcard mode, with 8 data bits, even parity and 1.5 stop bits
+    ///
+    /// The baud rate is usually the card clock divided by 372 at the start of a session.
+    /// The IO line of the card is the TX pin, the serial has to be created with
+    /// `usart_half_duplex`.
+    pub fn smartcard(mut self, smartcard: SmartcardConfig) -> Self {
+        self.mode = UsartMode::Smartcard(smartcard);
+        self.wordlength = WordLength::DataBits9;
+        self.parity = Parity::ParityEven;
+        self.stopbits = StopBits::STOP1P5;
+        self
+    }
 }
 
 #[derive(Debug)]
 pub struct InvalidConfig;
 
+/// Computes the USART_BRR value for `baudrate` from the kernel clock `clk`
+///
+/// The LPUART divides 256 times the kernel clock by the baud rate and needs a baud rate
+/// between the kernel clock / 4096 and / 3. The USARTs divide the kernel clock by the baud
+/// rate, and need a divider of at least 16 with 16 times oversampling.
+pub fn baud_rate_divider(clk: Hertz, baudrate: Bps, lpuart: bool) -> Result<u32, InvalidConfig> {
+    let clk_mul = if lpuart { 256 } else { 1 };
+    let div = (clk_mul * clk.raw() as u64)
+        .checked_div(baudrate.0 as u64)
+        .ok_or(InvalidConfig)?;
+    let range = if lpuart {
+        0x300..=0xf_ffff
+    } else {
+        0x10..=0xffff
+    };
+    if !range.contains(&div) {
+        return Err(InvalidConfig);
+    }
+    Ok(div as u32)
+}
+
 impl Default for BasicConfig {
     fn default() -> BasicConfig {
         let baudrate = 19_200.bps();
@@ -213,6 +481,8 @@ impl Default for BasicConfig {
             parity: Parity::ParityNone,
             stopbits: StopBits::STOP1,
             swap: false,
+            clock_source: ClockSource::Pclk,
+            driver_enable: None,
         }
     }
 }
@@ -226,12 +496,50 @@ impl Default for FullConfig {
             parity: Parity::ParityNone,
             stopbits: StopBits::STOP1,
             swap: false,
+            clock_source: ClockSource::Pclk,
+            driver_enable: None,
             fifo_enable: false,
             tx_fifo_threshold: FifoThreshold::FIFO_8_BYTES,
             rx_fifo_threshold: FifoThreshold::FIFO_8_BYTES,
             tx_fifo_interrupt: false,
             rx_fifo_interrupt: false,
             receiver_timeout: None,
+            mode: UsartMode::Asynchronous,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::baud_rate_divider;
+    use crate::prelude::*;
+    use crate::time::Bps;
+
+    #[test]
+    fn usart_divider() {
+        assert_eq!(
+            baud_rate_divider(16.MHz(), 115_200.bps(), false).ok(),
+            Some(138)
+        );
+        assert_eq!(
+            baud_rate_divider(64.MHz(), 4_000_000.bps(), false).ok(),
+            Some(16)
+        );
+        assert!(baud_rate_divider(16.MHz(), 2_000_000.bps(), false).is_err());
+        assert!(baud_rate_divider(16.MHz(), Bps(0), false).is_err());
+    }
+
+    #[test]
+    fn lpuart_divider() {
+        assert_eq!(
+            baud_rate_divider(16.MHz(), 9_600.bps(), true).ok(),
+            Some(426_666)
+        );
+        assert_eq!(
+            baud_rate_divider(32_768.Hz(), 9_600.bps(), true).ok(),
+            Some(873)
+        );
+        assert!(baud_rate_divider(32_768.Hz(), 19_200.bps(), true).is_err());
+        assert!(baud_rate_divider(16.MHz(), 300.bps(), true).is_err());
+    }
+}
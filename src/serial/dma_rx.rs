@@ -0,0 +1,181 @@
+//! Circular DMA reception with idle-line chunk delivery
+//!
+//! The DMA channel continuously fills a ring buffer, on every idle line, half transfer
+//! and transfer complete event the newly received bytes are copied into [`Chunk`]s of up
+//! to `N` bytes and pushed into a `heapless` SPSC queue, so they can be processed from
+//! thread context while the DMA goes on.
+//!
+//! Without interrupts, [`RxDma::read_available`] copies out the bytes received since the
+//! last read by polling the DMA position.
+//!
+//! The DMA lapping the reader, i.e. overwriting bytes before they were read, is detected
+//! from the half and full transfer flags and reported as [`Error::Overrun`]. Only laps
+//! ending more than half a buffer ahead of the reader can go unnoticed.
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use crate::dma::{self, Channel, Direction, Event, WordSize, WriteBuffer};
+use crate::serial::Error;
+use heapless::spsc::Producer;
+use heapless::Vec;
+
+/// Received bytes, copied out of the DMA ring buffer
+pub type Chunk<const N: usize> = Vec<u8, N>;
+
+/// Serial receiver with circular DMA
+pub struct RxDma<RX, CH, BUF = &'static mut [u8]> {
+    pub(crate) rx: RX,
+    pub(crate) ch: CH,
+    buf: BUF,
+    ptr: *const u8,
+    len: usize,
+    read_pos: usize,
+    /// DMA position when the flags were last cleared
+    dma_pos: usize,
+}
+
+impl<RX, CH, BUF> RxDma<RX, CH, BUF>
+where
+    RX: dma::Target,
+    CH: Channel,
+    BUF: WriteBuffer<Word = u8>,
+{
+    pub(crate) fn new(mut rx: RX, mut ch: CH, mut buf: BUF, rdr: u32) -> Self {
+        let (ptr, len) = unsafe { buf.write_buffer() };
+        hal_assert!(len > 0 && len <= u16::MAX as usize);
+
+        ch.disable();
+        ch.set_direction(Direction::FromPeripheral);
+        ch.set_word_size(WordSize::BITS8);
+        ch.set_peripheral_address(rdr, false);
+        ch.set_memory_address(ptr as u32, true);
+        ch.set_transfer_length(len as u16);
+        ch.set_circular_mode(true);
+        ch.select_peripheral(rx.dmamux());
+        ch.clear_event(Event::Any);
+        ch.listen(Event::HalfTransfer);
+        ch.listen(Event::TransferComplete);
+
+        rx.enable_dma();
+        ch.enable();
+
+        RxDma {
+            rx,
+            ch,
+            buf,
+            ptr,
+            len,
+            read_pos: 0,
+            dma_pos: 0,
+        }
+    }
+
+    /// Returns the number of received bytes that have not been read yet
+    pub fn available(&self) -> usize {
+        (self.write_pos() + self.len - self.read_pos) % self.len
+    }
+
+    /// Copies the bytes received since the last read into `out` and returns their number
+    ///
+    /// Returns `Overrun` if the DMA overwrote bytes that were not read yet, these are
+    /// dropped. Calling it at least once per half buffer makes sure overruns are seen.
+    pub fn read_available(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        let write_pos = self.progress()?;
+        // Make sure the buffer is read after the DMA position
+        compiler_fence(Ordering::Acquire);
+
+        let mut count = 0;
+        while count < out.len() && self.read_pos != write_pos {
+            let end = if write_pos > self.read_pos {
+                write_pos
+            } else {
+                self.len
+            };
+            let chunk = self.slice(self.read_pos, end);
+            let n = chunk.len().min(out.len() - count);
+            out[count..count + n].copy_from_slice(&chunk[..n]);
+            count += n;
+            self.read_pos = (self.read_pos + n) % self.len;
+        }
+        Ok(count)
+    }
+
+    fn write_pos(&self) -> usize {
+        let remaining = self.ch.get_remaining_transfers() as usize;
+        (self.len - remaining) % self.len
+    }
+
+    /// Returns the DMA position, or `Overrun` if the DMA overwrote unread bytes since the
+    /// last call, in which case all the unread bytes are dropped
+    fn progress(&mut self) -> Result<usize, Error> {
+        let (mut half, mut full) = (false, false);
+        let write_pos = loop {
+            let pos = self.write_pos();
+            half |= self.ch.event_occurred(Event::HalfTransfer);
+            full |= self.ch.event_occurred(Event::TransferComplete);
+            self.ch.clear_event(Event::HalfTransfer);
+            self.ch.clear_event(Event::TransferComplete);
+            // A byte received meanwhile may have set a flag the position does not include
+            if self.write_pos() == pos {
+                break pos;
+            }
+        };
+
+        let moved = (write_pos + self.len - self.dma_pos) % self.len;
+        let unread = (self.dma_pos + self.len - self.read_pos) % self.len;
+        // A flag set by a boundary the DMA did not just cross means it went around again,
+        // the half transfer boundary is one of the middle bytes for odd lengths
+        let crossed = |boundary: usize| (boundary + self.len - self.dma_pos - 1) % self.len < moved;
+        let lapped = (half && !crossed(self.len / 2) && !crossed(self.len.div_ceil(2)))
+            || (full && !crossed(0))
+            || unread + moved >= self.len;
+        self.dma_pos = write_pos;
+        if lapped {
+            self.read_pos = write_pos;
+            return Err(Error::Overrun);
+        }
+        Ok(write_pos)
+    }
+
+    /// Push the bytes received since the last call into the queue
+    pub(crate) fn collect<const N: usize, const Q: usize>(
+        &mut self,
+        producer: &mut Producer<'_, Chunk<N>, Q>,
+    ) -> Result<(), Error> {
+        let write_pos = self.progress()?;
+        // Make sure the buffer is read after the DMA position
+        compiler_fence(Ordering::Acquire);
+
+        while N > 0 && self.read_pos != write_pos && producer.ready() {
+            let end = if write_pos > self.read_pos {
+                write_pos
+            } else {
+                self.len
+            };
+            let n = (end - self.read_pos).min(N);
+            let chunk = Chunk::from_slice(self.slice(self.read_pos, self.read_pos + n));
+            if let Ok(chunk) = chunk {
+                producer.enqueue(chunk).ok();
+            }
+            self.read_pos = (self.read_pos + n) % self.len;
+        }
+        Ok(())
+    }
+
+    fn slice(&self, start: usize, end: usize) -> &[u8] {
+        // NOTE(unsafe) the buffer is owned by the receiver, the DMA does not write to the
+        // bytes between the read and the write positions
+        unsafe { core::slice::from_raw_parts(self.ptr.add(start), end - start) }
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.ch.disable();
+        self.ch.unlisten(Event::Any);
+        self.rx.disable_dma();
+        // The DMA writes are done before the buffer is handed back
+        compiler_fence(Ordering::Acquire);
+    }
+
+    pub(crate) fn into_parts(self) -> (RX, CH, BUF) {
+        (self.rx, self.ch, self.buf)
+    }
+}
@@ -0,0 +1,113 @@
+//! DMA transmission from a staging buffer
+//!
+//! Bytes written to a [`TxDma`] are copied into a static buffer and sent by a DMA
+//! [`Transfer`], so the caller only blocks when the previous chunk is still going out.
+use core::convert::Infallible;
+
+use crate::dma::{self, Channel, ReadBuffer, Transfer};
+
+/// Serial transmitter fed by DMA
+///
+/// The staging buffer is written by the CPU, so it has to be a
+/// [`WriteBuffer`](dma::WriteBuffer) even though the DMA only reads it.
+pub struct TxDma<TX, CH, BUF = &'static mut [u8]> {
+    /// Always set, only taken while switching between the states
+    state: Option<State<TX, CH, BUF>>,
+    ptr: *mut u8,
+    len: usize,
+}
+
+enum State<TX, CH, BUF> {
+    Idle(CH, BUF, TX),
+    Sending(Transfer<CH, Staged<BUF>, TX>),
+}
+
+/// Start of the staging buffer, holding the chunk being sent
+struct Staged<BUF> {
+    buf: BUF,
+    ptr: *const u8,
+    len: usize,
+}
+
+// NOTE(unsafe) the pointer comes from the `WriteBuffer` implementation of `buf`, which
+// stays valid as long as the buffer, and the length is at most the buffer length
+unsafe impl<BUF> ReadBuffer for Staged<BUF> {
+    type Word = u8;
+
+    unsafe fn read_buffer(&self) -> (*const u8, usize) {
+        (self.ptr, self.len)
+    }
+}
+
+impl<TX, CH, BUF> TxDma<TX, CH, BUF>
+where
+    TX: dma::DataTarget<Word = u8>,
+    CH: Channel,
+    BUF: dma::WriteBuffer<Word = u8>,
+{
+    pub(crate) fn new(tx: TX, ch: CH, mut buf: BUF) -> Self {
+        let (ptr, len) = unsafe { buf.write_buffer() };
+        hal_assert!(len > 0 && len <= u16::MAX as usize);
+
+        TxDma {
+            state: Some(State::Idle(ch, buf, tx)),
+            ptr,
+            len,
+        }
+    }
+
+    /// Returns `true` while the DMA is still feeding the previous chunk to the USART
+    pub fn is_busy(&self) -> bool {
+        matches!(&self.state, Some(State::Sending(transfer)) if !transfer.is_done())
+    }
+
+    /// Starts sending as many bytes of `data` as fit in the staging buffer
+    ///
+    /// Returns the number of bytes queued, or `WouldBlock` until the previous chunk has been
+    /// handed over to the USART.
+    pub fn write(&mut self, data: &[u8]) -> nb::Result<usize, Infallible> {
+        if self.is_busy() {
+            return Err(nb::Error::WouldBlock);
+        }
+        let Some((ch, buf, tx)) = self.state.take().map(State::into_parts) else {
+            return Ok(0);
+        };
+        let len = data.len().min(self.len);
+        if len == 0 {
+            self.state = Some(State::Idle(ch, buf, tx));
+            return Ok(0);
+        }
+        // NOTE(unsafe) the buffer is owned by the transmitter and the DMA is stopped
+        let staging = unsafe { core::slice::from_raw_parts_mut(self.ptr, len) };
+        staging.copy_from_slice(&data[..len]);
+        let staged = Staged {
+            buf,
+            ptr: self.ptr,
+            len,
+        };
+        let transfer = Transfer::memory_to_peripheral(ch, staged, tx);
+        self.state = Some(State::Sending(transfer));
+        Ok(len)
+    }
+
+    /// Stops the transmission and gives back the sender, DMA channel and buffer
+    pub(crate) fn into_parts(mut self) -> (TX, CH, BUF) {
+        let Some((ch, buf, mut tx)) = self.state.take().map(State::into_parts) else {
+            unreachable!()
+        };
+        tx.disable_dma();
+        (tx, ch, buf)
+    }
+}
+
+impl<TX, CH: Channel, BUF> State<TX, CH, BUF> {
+    fn into_parts(self) -> (CH, BUF, TX) {
+        match self {
+            State::Idle(ch, buf, tx) => (ch, buf, tx),
+            State::Sending(transfer) => {
+                let (ch, staged, tx) = transfer.abort();
+                (ch, staged.buf, tx)
+            }
+        }
+    }
+}
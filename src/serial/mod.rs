@@ -1,5 +1,11 @@
 pub mod config;
+pub mod dma_rx;
+pub mod dma_tx;
+#[cfg(feature = "soft-uart")]
+pub mod soft;
 pub mod usart;
 
 pub use config::*;
+pub use dma_rx::*;
+pub use dma_tx::*;
 pub use usart::*;
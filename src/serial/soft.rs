@@ -0,0 +1,249 @@
+//! # Software UART
+//!
+//! Full-duplex 8N1 serial port on any two GPIO pins, for boards where all the USARTs are
+//! taken. Each direction is paced by its own timer: the TX timer shifts one bit out per
+//! update event, and the RX timer is started by the EXTI interrupt of the start bit and
+//! samples the line in the middle of each bit.
+//!
+//! The driver is interrupt driven, the application calls [`SoftSerial::on_tx_timer`],
+//! [`SoftSerial::on_start_bit`] and [`SoftSerial::on_rx_timer`] from the matching
+//! interrupt handlers, and the timer interrupts must be unmasked in the NVIC. Each bit
+//! costs one interrupt of roughly a hundred CPU cycles, so a full-duplex link at
+//! 38400 baud takes about 77k interrupts per second, close to 12% of the CPU at 64 MHz.
+//! The RX sampling point is delayed by the EXTI interrupt latency, which has to stay
+//! well below half a bit time: the RX handlers should have the highest priority.
+use core::convert::Infallible;
+
+use hal::digital::v2::{InputPin, OutputPin};
+
+use crate::exti::{Event, ExtiExt};
+use crate::gpio::SignalEdge;
+use crate::rcc::Rcc;
+use crate::serial::Error;
+use crate::stm32::EXTI;
+use crate::time::Bps;
+use crate::timer::{Instance, Period, UpdateSource};
+
+/// Software UART, see the [module documentation](self)
+///
+/// `rx_line` is the EXTI line of the RX pin, set up beforehand with `listen` on the
+/// falling edge.
+pub struct SoftSerial<TX, RX, TXTIM, RXTIM> {
+    tx: TX,
+    rx: RX,
+    rx_line: Event,
+    tx_tim: TXTIM,
+    rx_tim: RXTIM,
+    bit_arr: u32,
+    half_bit_arr: u32,
+    tx_frame: u16,
+    tx_bits: u8,
+    tx_busy: bool,
+    rx_shift: u8,
+    rx_bits: u8,
+    rx_data: Option<u8>,
+    rx_error: Option<Error>,
+}
+
+impl<TX, RX, TXTIM, RXTIM> SoftSerial<TX, RX, TXTIM, RXTIM>
+where
+    TX: OutputPin<Error = Infallible>,
+    RX: InputPin<Error = Infallible>,
+    TXTIM: Instance,
+    RXTIM: Instance,
+{
+    /// Creates the port, the TX line is driven idle high
+    ///
+    /// # Panics
+    ///
+    /// Panics if `baud` is 0.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        mut tx: TX,
+        rx: RX,
+        rx_line: Event,
+        tx_tim: TXTIM,
+        rx_tim: RXTIM,
+        baud: Bps,
+        exti: &EXTI,
+        rcc: &mut Rcc,
+    ) -> Self {
+        hal_assert!(baud.0 > 0);
+        tx.set_high().ok();
+        TXTIM::enable(rcc);
+        TXTIM::reset(rcc);
+        RXTIM::enable(rcc);
+        RXTIM::reset(rcc);
+
+        // Both timers share the prescaler of a full bit, which also fits half a bit
+        let period = Period::from_ratio(
+            rcc.clocks.apb_tim_clk.raw() as u64,
+            baud.0 as u64,
+            TXTIM::max_auto_reload().min(RXTIM::max_auto_reload()),
+        );
+        let bit_arr = period.arr;
+        period.apply::<TXTIM>();
+        period.apply::<RXTIM>();
+        TXTIM::apply_settings_silently();
+        TXTIM::set_update_source(UpdateSource::CounterOnly);
+        RXTIM::set_update_source(UpdateSource::CounterOnly);
+        TXTIM::listen_update(true);
+        RXTIM::listen_update(true);
+
+        exti.unpend(rx_line);
+        exti.listen(rx_line, SignalEdge::Falling);
+
+        SoftSerial {
+            tx,
+            rx,
+            rx_line,
+            tx_tim,
+            rx_tim,
+            bit_arr,
+            half_bit_arr: bit_arr.div_ceil(2).saturating_sub(1),
+            tx_frame: 0,
+            tx_bits: 0,
+            tx_busy: false,
+            rx_shift: 0,
+            rx_bits: 0,
+            rx_data: None,
+            rx_error: None,
+        }
+    }
+
+    /// Returns `true` while a byte is being sent
+    pub fn is_tx_busy(&self) -> bool {
+        self.tx_busy
+    }
+
+    /// To be called from the TX timer interrupt handler
+    pub fn on_tx_timer(&mut self) {
+        TXTIM::clear_update();
+        if self.tx_bits == 0 {
+            // The stop bit has lasted a full bit time
+            TXTIM::stop();
+            self.tx_busy = false;
+            return;
+        }
+        self.shift_out();
+    }
+
+    /// To be called from the EXTI interrupt handler of the RX line, starts the reception
+    /// of a byte on a falling edge
+    pub fn on_start_bit(&mut self, exti: &EXTI) {
+        if !exti.is_pending(self.rx_line, SignalEdge::Falling) {
+            return;
+        }
+        // The edges of the data bits are ignored until the stop bit
+        exti.unlisten(self.rx_line);
+        self.rx_shift = 0;
+        self.rx_bits = 0;
+        // First sample in the middle of the start bit
+        RXTIM::set_auto_reload(self.half_bit_arr);
+        RXTIM::apply_settings_silently();
+        RXTIM::start(false);
+    }
+
+    /// To be called from the RX timer interrupt handler
+    ///
+    /// `exti` is used to re-arm the start bit detection once the stop bit is sampled.
+    pub fn on_rx_timer(&mut self, exti: &EXTI) {
+        RXTIM::clear_update();
+        let high = self.rx.is_high().unwrap_or(true);
+        match self.rx_bits {
+            0 if high => {
+                // Glitch, not a start bit
+                self.end_rx(exti);
+                return;
+            }
+            // The next samples are one bit apart, the counter restarted from 0
+            0 => RXTIM::set_auto_reload(self.bit_arr),
+            1..=8 => self.rx_shift = self.rx_shift >> 1 | (high as u8) << 7,
+            _ => {
+                if !high {
+                    self.rx_error = Some(Error::Framing);
+                } else if self.rx_data.replace(self.rx_shift).is_some() {
+                    self.rx_error = Some(Error::Overrun);
+                }
+                self.end_rx(exti);
+                return;
+            }
+        }
+        self.rx_bits += 1;
+    }
+
+    /// Stops the timers and releases the pins and the timers
+    pub fn release(self, exti: &EXTI) -> (TX, RX, TXTIM, RXTIM) {
+        TXTIM::stop();
+        RXTIM::stop();
+        TXTIM::listen_update(false);
+        RXTIM::listen_update(false);
+        exti.unlisten(self.rx_line);
+        (self.tx, self.rx, self.tx_tim, self.rx_tim)
+    }
+
+    fn shift_out(&mut self) {
+        if self.tx_frame & 1 != 0 {
+            self.tx.set_high().ok();
+        } else {
+            self.tx.set_low().ok();
+        }
+        self.tx_frame >>= 1;
+        self.tx_bits -= 1;
+    }
+
+    fn end_rx(&mut self, exti: &EXTI) {
+        RXTIM::stop();
+        exti.unpend(self.rx_line);
+        exti.listen(self.rx_line, SignalEdge::Falling);
+    }
+}
+
+impl<TX, RX, TXTIM, RXTIM> hal::serial::Read<u8> for SoftSerial<TX, RX, TXTIM, RXTIM>
+where
+    TX: OutputPin<Error = Infallible>,
+    RX: InputPin<Error = Infallible>,
+    TXTIM: Instance,
+    RXTIM: Instance,
+{
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        if let Some(err) = self.rx_error.take() {
+            return Err(nb::Error::Other(err));
+        }
+        self.rx_data.take().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl<TX, RX, TXTIM, RXTIM> hal::serial::Write<u8> for SoftSerial<TX, RX, TXTIM, RXTIM>
+where
+    TX: OutputPin<Error = Infallible>,
+    RX: InputPin<Error = Infallible>,
+    TXTIM: Instance,
+    RXTIM: Instance,
+{
+    type Error = Error;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Error> {
+        if self.tx_busy {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.tx_busy = true;
+        // Start bit, 8 data bits LSB first, stop bit
+        self.tx_frame = 1 << 9 | (byte as u16) << 1;
+        self.tx_bits = 10;
+        TXTIM::apply_settings_silently();
+        self.shift_out();
+        TXTIM::start(false);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        if self.tx_busy {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+}
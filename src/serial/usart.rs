@@ -1,10 +1,13 @@
+use core::convert::Infallible;
 use core::fmt;
 use core::marker::PhantomData;
 
+use crate::debug::{Dump, SerialRegisters};
 use crate::dma;
 use crate::dmamux::DmaMuxIndex;
-use crate::gpio::AltFunction;
 use crate::gpio::{gpioa::*, gpiob::*, gpioc::*, gpiod::*};
+use crate::gpio::{AltFunction, OpenDrain, Output};
+use crate::power::WaitIdle;
 use crate::prelude::*;
 use crate::rcc::*;
 use crate::stm32::*;
@@ -13,6 +16,10 @@ use cortex_m::interrupt;
 use nb::block;
 
 use crate::serial::config::*;
+use crate::serial::dma_rx::{Chunk, RxDma};
+use crate::serial::dma_tx::TxDma;
+use heapless::spsc::Producer;
+
 /// Serial error
 #[derive(Debug)]
 pub enum Error {
@@ -26,6 +33,24 @@ pub enum Error {
     Parity,
 }
 
+/// Serial self-test error
+#[derive(Debug)]
+pub enum SelfTestError {
+    /// Reception error
+    Serial(Error),
+    /// A byte was not received back in time
+    Timeout,
+    /// A byte was received back corrupted
+    Mismatch {
+        index: usize,
+        sent: u8,
+        received: u8,
+    },
+}
+
+/// Number of status polls before a self-test byte is considered lost
+const SELF_TEST_POLLS: u32 = 1_000_000;
+
 /// Interrupt event
 pub enum Event {
     /// TXFIFO reaches the threshold
@@ -38,9 +63,15 @@ pub enum Event {
     /// TXFIFO empty
     TXFE = 1 << 23,
 
+    /// Wakeup from Stop mode
+    WUF = 1 << 20,
+
     /// Active when a communication is ongoing on the RX line
     BUSY = 1 << 16,
 
+    /// LIN break detected, only for the USARTs in LIN mode
+    LBD = 1 << 8,
+
     /// Receiver timeout.This bit is set by hardware when the timeout value,
     /// programmed in the RTOR register has lapsed, without any communication.
     RTOF = 1 << 11,
@@ -72,6 +103,15 @@ impl Event {
     }
 }
 
+/// Event waking the device up from Stop mode (USART_CR3 WUS)
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum WakeupEvent {
+    /// Start bit of the next frame
+    StartBit = 0b10,
+    /// A whole frame has been received
+    RxNotEmpty = 0b11,
+}
+
 /// Serial receiver
 pub struct Rx<USART, Config> {
     _usart: PhantomData<USART>,
@@ -92,6 +132,23 @@ pub struct Serial<USART, Config> {
     _config: PhantomData<Config>,
 }
 
+/// Thread side of a serial port split with [`Serial::split_isr`]
+///
+/// Owns the peripheral and controls which interrupts are enabled.
+pub struct SerialControl<USART, Config> {
+    usart: USART,
+    _config: PhantomData<Config>,
+}
+
+/// Interrupt side of a serial port split with [`Serial::split_isr`]
+///
+/// The handle is `Send` and only gives access to the data registers and the interrupt
+/// flags, so it can be moved into an interrupt handler.
+pub struct SerialIsr<USART, Config> {
+    tx: Tx<USART, Config>,
+    rx: Rx<USART, Config>,
+}
+
 // Serial TX pin
 pub trait TxPin<USART> {
     fn setup(&self);
@@ -102,6 +159,19 @@ pub trait RxPin<USART> {
     fn setup(&self);
 }
 
+// Serial clock output pin, for the synchronous and smartcard modes
+pub trait CkPin<USART> {
+    fn setup(&self);
+}
+
+// Serial RS-485 driver enable pin
+pub trait DePin<USART> {
+    fn setup(&self);
+}
+
+/// TX pin usable on a single-wire bus, set up as open drain output
+pub trait HalfDuplexPin<USART>: TxPin<USART> {}
+
 pub trait SerialExt<USART, Config> {
     fn usart<TX, RX>(
         self,
@@ -113,6 +183,25 @@ pub trait SerialExt<USART, Config> {
     where
         TX: TxPin<USART>,
         RX: RxPin<USART>;
+
+    /// Single-wire half-duplex serial on the TX pin, which needs a pull-up
+    ///
+    /// The receiver stays connected to the line, so every byte sent is also received.
+    /// Writing through the [`Serial`] drops these echoes: [`write`] drops the ones received
+    /// so far and [`flush`] the one of the last byte, which leaves the receiver empty for
+    /// the reply. After a [`split`](Serial::split), the echoes are read from the
+    /// [`Rx`] half, e.g. to detect collisions.
+    ///
+    /// [`write`]: hal::serial::Write::write
+    /// [`flush`]: hal::serial::Write::flush
+    fn usart_half_duplex<TX>(
+        self,
+        tx: TX,
+        config: Config,
+        rcc: &mut Rcc,
+    ) -> Result<Serial<USART, Config>, InvalidConfig>
+    where
+        TX: HalfDuplexPin<USART>;
 }
 
 impl<USART, Config> fmt::Write for Serial<USART, Config>
@@ -138,7 +227,8 @@ where
 macro_rules! uart_shared {
     ($USARTX:ident, $dmamux_rx:ident, $dmamux_tx:ident,
         tx: [ $(($PTX:ident, $TAF:expr),)+ ],
-        rx: [ $(($PRX:ident, $RAF:expr),)+ ]) => {
+        rx: [ $(($PRX:ident, $RAF:expr),)+ ],
+        de: [ $(($PDE:ident, $DAF:expr),)+ ]) => {
 
         $(
             impl<MODE> TxPin<$USARTX> for $PTX<MODE> {
@@ -146,6 +236,16 @@ macro_rules! uart_shared {
                     self.set_alt_mode($TAF)
                 }
             }
+
+            impl HalfDuplexPin<$USARTX> for $PTX<Output<OpenDrain>> {}
+        )+
+
+        $(
+            impl<MODE> DePin<$USARTX> for $PDE<MODE> {
+                fn setup(&self) {
+                    self.set_alt_mode($DAF)
+                }
+            }
         )+
 
         $(
@@ -174,12 +274,99 @@ macro_rules! uart_shared {
                 usart.isr.read().rxne().bit_is_set()
             }
 
+            /// Starts listening for the idle line interrupt
+            pub fn listen_idle(&mut self) {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                usart.cr1.modify(|_, w| w.idleie().set_bit());
+            }
+
+            /// Stop listening for the idle line interrupt
+            pub fn unlisten_idle(&mut self) {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                usart.cr1.modify(|_, w| w.idleie().clear_bit());
+            }
+
+            /// Return true if an idle line has been detected
+            pub fn is_idle(&self) -> bool {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                usart.isr.read().idle().bit_is_set()
+            }
+
+            /// Clear the idle line flag
+            pub fn clear_idle(&mut self) {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                usart.icr.write(|w| w.idlecf().set_bit());
+            }
+
+            /// Continuously receive into `buf` with a circular DMA transfer
+            ///
+            /// Call [`RxDma::on_interrupt`] from the USART and DMA channel interrupt handlers
+            /// to deliver the received bytes.
+            pub fn read_circular<CH, BUF>(mut self, ch: CH, buf: BUF) -> RxDma<Self, CH, BUF>
+            where
+                CH: dma::Channel,
+                BUF: dma::WriteBuffer<Word = u8>,
+            {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                let rdr = &usart.rdr as *const _ as u32;
+                self.clear_idle();
+                self.listen_idle();
+                RxDma::new(self, ch, buf, rdr)
+            }
         }
 
-        impl<Config> hal::serial::Read<u8> for Rx<$USARTX, Config> {
-            type Error = Error;
+        impl<Config, CH, BUF> RxDma<Rx<$USARTX, Config>, CH, BUF>
+        where
+            CH: dma::Channel,
+            BUF: dma::WriteBuffer<Word = u8>,
+        {
+            /// Pushes the bytes received so far into the queue, in chunks of up to `N` bytes
+            ///
+            /// Has to be called on idle line and DMA half/full transfer interrupts. The bytes
+            /// that do not fit in the queue stay in the ring buffer for the next calls.
+            /// Returns `Overrun` if the DMA overwrote bytes that were not queued yet, these
+            /// are dropped.
+            pub fn on_interrupt<const N: usize, const Q: usize>(
+                &mut self,
+                producer: &mut Producer<'_, Chunk<N>, Q>,
+            ) -> Result<(), Error> {
+                self.rx.clear_idle();
+                self.collect(producer)
+            }
 
-            fn read(&mut self) -> nb::Result<u8, Error> {
+            /// Stops the reception and releases the receiver, DMA channel and buffer
+            pub fn release(mut self) -> (Rx<$USARTX, Config>, CH, BUF) {
+                self.stop();
+                self.rx.unlisten_idle();
+                self.into_parts()
+            }
+        }
+
+        impl<Config, CH, BUF> TxDma<Tx<$USARTX, Config>, CH, BUF>
+        where
+            CH: dma::Channel,
+            BUF: dma::WriteBuffer<Word = u8>,
+        {
+            /// Waits until the last chunk has been transmitted
+            pub fn flush(&mut self) -> nb::Result<(), Infallible> {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                if self.is_busy() || usart.isr.read().tc().bit_is_clear() {
+                    Err(nb::Error::WouldBlock)
+                } else {
+                    Ok(())
+                }
+            }
+
+            /// Stops the transmission and releases the sender, DMA channel and buffer
+            pub fn release(self) -> (Tx<$USARTX, Config>, CH, BUF) {
+                self.into_parts()
+            }
+        }
+
+        impl<Config> Rx<$USARTX, Config> {
+            /// Reads a full frame, including the 9th bit in 9-bit mode (address/data flag of
+            /// multidrop protocols)
+            pub fn read_u16(&mut self) -> nb::Result<u16, Error> {
                 let usart = unsafe { &(*$USARTX::ptr()) };
                 let isr = usart.isr.read();
                 Err(
@@ -196,7 +383,7 @@ macro_rules! uart_shared {
                         usart.icr.write(|w| w.orecf().set_bit());
                         nb::Error::Other(Error::Overrun)
                     } else if isr.rxne().bit_is_set() {
-                        return Ok(usart.rdr.read().bits() as u8)
+                        return Ok(usart.rdr.read().bits() as u16 & 0x1ff)
                     } else {
                         nb::Error::WouldBlock
                     }
@@ -204,6 +391,14 @@ macro_rules! uart_shared {
             }
         }
 
+        impl<Config> hal::serial::Read<u8> for Rx<$USARTX, Config> {
+            type Error = Error;
+
+            fn read(&mut self) -> nb::Result<u8, Error> {
+                self.read_u16().map(|frame| frame as u8)
+            }
+        }
+
         impl<Config> hal::serial::Read<u8> for Serial<$USARTX, Config> {
             type Error = Error;
 
@@ -212,6 +407,23 @@ macro_rules! uart_shared {
             }
         }
 
+        impl<Config> Serial<$USARTX, Config> {
+            /// See [`Rx::read_u16`]
+            pub fn read_u16(&mut self) -> nb::Result<u16, Error> {
+                self.rx.read_u16()
+            }
+
+            /// See [`Tx::write_u16`]
+            pub fn write_u16(&mut self, frame: u16) -> nb::Result<(), Error> {
+                self.tx.write_u16(frame)
+            }
+
+            /// Sets up the RS-485 driver enable pin, see `rs485_de` in the config
+            pub fn de_pin<DE: DePin<$USARTX>>(&mut self, de: &DE) {
+                de.setup();
+            }
+        }
+
         impl<Config> Tx<$USARTX, Config> {
 
             /// Starts listening for an interrupt event
@@ -232,6 +444,17 @@ macro_rules! uart_shared {
                 usart.isr.read().txe().bit_is_set()
             }
 
+            /// Writes a full frame, the 9th bit is only sent in 9-bit mode
+            pub fn write_u16(&mut self, frame: u16) -> nb::Result<(), Error> {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                if usart.isr.read().txe().bit_is_set() {
+                    usart.tdr.write(|w| unsafe { w.bits(frame as u32 & 0x1ff) });
+                    Ok(())
+                } else {
+                    Err(nb::Error::WouldBlock)
+                }
+            }
+
         }
 
         impl<Config> hal::serial::Write<u8> for Tx<$USARTX, Config> {
@@ -247,12 +470,34 @@ macro_rules! uart_shared {
             }
 
             fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+                self.write_u16(byte as u16)
+            }
+        }
+
+        impl<Config> WaitIdle for Tx<$USARTX, Config> {
+            /// Waits until the last frame has been transmitted (TC)
+            fn wait_idle(&mut self) {
                 let usart = unsafe { &(*$USARTX::ptr()) };
-                if usart.isr.read().txe().bit_is_set() {
-                    usart.tdr.write(|w| unsafe { w.bits(byte as u32) });
-                    Ok(())
-                } else {
-                    Err(nb::Error::WouldBlock)
+                while usart.isr.read().tc().bit_is_clear() {}
+            }
+        }
+
+        impl<Config> WaitIdle for Serial<$USARTX, Config> {
+            fn wait_idle(&mut self) {
+                self.tx.wait_idle();
+            }
+        }
+
+        impl<Config> Dump for Serial<$USARTX, Config> {
+            type Registers = SerialRegisters;
+
+            fn dump(&self) -> SerialRegisters {
+                SerialRegisters {
+                    cr1: self.usart.cr1.read().bits(),
+                    cr2: self.usart.cr2.read().bits(),
+                    cr3: self.usart.cr3.read().bits(),
+                    brr: self.usart.brr.read().bits(),
+                    isr: self.usart.isr.read().bits(),
                 }
             }
         }
@@ -261,11 +506,15 @@ macro_rules! uart_shared {
             type Error = Error;
 
             fn flush(&mut self) -> nb::Result<(), Self::Error> {
-                self.tx.flush()
+                self.tx.flush()?;
+                self.drop_echo();
+                Ok(())
             }
 
             fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
-                self.tx.write(byte)
+                self.tx.write(byte)?;
+                self.drop_echo();
+                Ok(())
             }
         }
 
@@ -278,6 +527,186 @@ macro_rules! uart_shared {
                 (self.tx, self.rx)
             }
 
+            /// Splits the serial and receives continuously into `buf` with a circular DMA
+            /// transfer, see [`Rx::read_circular`]
+            pub fn with_rx_dma<CH, BUF>(
+                self,
+                ch: CH,
+                buf: BUF,
+            ) -> (Tx<$USARTX, Config>, RxDma<Rx<$USARTX, Config>, CH, BUF>)
+            where
+                CH: dma::Channel,
+                BUF: dma::WriteBuffer<Word = u8>,
+            {
+                (self.tx, self.rx.read_circular(ch, buf))
+            }
+
+            /// Splits the serial and sends through DMA, `buf` holds the chunk being sent
+            pub fn with_tx_dma<CH, BUF>(
+                self,
+                ch: CH,
+                buf: BUF,
+            ) -> (TxDma<Tx<$USARTX, Config>, CH, BUF>, Rx<$USARTX, Config>)
+            where
+                CH: dma::Channel,
+                BUF: dma::WriteBuffer<Word = u8>,
+            {
+                (TxDma::new(self.tx, ch, buf), self.rx)
+            }
+
+            /// Gives scoped access to the peripheral registers
+            ///
+            /// # Safety
+            ///
+            /// The driver keeps relying on its own setup, `f` must leave the peripheral
+            /// clock, the USART enable and the configuration registers as it found them.
+            pub unsafe fn with_registers<F, R>(&mut self, f: F) -> R
+            where
+                F: FnOnce(&$USARTX) -> R,
+            {
+                f(&self.usart)
+            }
+
+            /// Checks the transmitter and receiver without external wiring
+            ///
+            /// The USART is switched to half-duplex mode during the test, which connects the
+            /// receiver to the TX line internally, so every byte of `pattern` is received
+            /// back. The TX line must not be driven by another device during the test.
+            pub fn self_test(&mut self, pattern: &[u8]) -> Result<(), SelfTestError> {
+                let half_duplex = self.usart.cr3.read().hdsel().bit_is_set();
+                self.set_half_duplex(true);
+
+                // Drop stale data and errors
+                let _ = self.usart.rdr.read();
+                self.usart.icr.write(|w| {
+                    w.pecf().set_bit().fecf().set_bit().ncf().set_bit().orecf().set_bit()
+                });
+
+                let res = pattern.iter().enumerate().try_for_each(|(index, &sent)| {
+                    block!(self.tx.write(sent)).map_err(SelfTestError::Serial)?;
+                    let mut polls = 0;
+                    let received = loop {
+                        match self.rx.read() {
+                            Ok(byte) => break byte,
+                            Err(nb::Error::Other(err)) => return Err(SelfTestError::Serial(err)),
+                            Err(nb::Error::WouldBlock) if polls < SELF_TEST_POLLS => polls += 1,
+                            Err(nb::Error::WouldBlock) => return Err(SelfTestError::Timeout),
+                        }
+                    };
+                    if received != sent {
+                        return Err(SelfTestError::Mismatch { index, sent, received });
+                    }
+                    Ok(())
+                });
+
+                self.set_half_duplex(half_duplex);
+                res
+            }
+
+            /// Drops the bytes sent that were received back in half-duplex mode
+            fn drop_echo(&mut self) {
+                if self.usart.cr3.read().hdsel().bit_is_clear() {
+                    return;
+                }
+                // The line is not driven by another device while sending
+                while self.usart.isr.read().rxne().bit_is_set() {
+                    let _ = self.usart.rdr.read();
+                }
+                // Echoes dropped while two bytes were sent back to back
+                if self.usart.isr.read().ore().bit_is_set() {
+                    self.usart.icr.write(|w| w.orecf().set_bit());
+                }
+            }
+
+            fn set_half_duplex(&mut self, enable: bool) {
+                let _ = block!(self.tx.flush());
+                self.usart.cr1.modify(|_, w| w.ue().clear_bit());
+                self.usart.cr3.modify(|_, w| w.hdsel().bit(enable));
+                self.usart.cr1.modify(|_, w| w.ue().set_bit());
+            }
+
+            /// Separates the serial struct into a thread side handle, controlling the
+            /// interrupts, and an interrupt side handle, accessing the data and flags
+            pub fn split_isr(self) -> (SerialControl<$USARTX, Config>, SerialIsr<$USARTX, Config>) {
+                (
+                    SerialControl {
+                        usart: self.usart,
+                        _config: PhantomData,
+                    },
+                    SerialIsr {
+                        tx: self.tx,
+                        rx: self.rx,
+                    },
+                )
+            }
+        }
+
+        impl<Config> SerialControl<$USARTX, Config> {
+            /// Starts listening for an interrupt event
+            pub fn listen(&mut self, event: Event) {
+                match event {
+                    Event::Rxne => self.usart.cr1.modify(|_, w| w.rxneie().set_bit()),
+                    Event::Txe => self.usart.cr1.modify(|_, w| w.txeie().set_bit()),
+                    Event::Idle => self.usart.cr1.modify(|_, w| w.idleie().set_bit()),
+                    _ => {}
+                }
+            }
+
+            /// Stop listening for an interrupt event
+            pub fn unlisten(&mut self, event: Event) {
+                match event {
+                    Event::Rxne => self.usart.cr1.modify(|_, w| w.rxneie().clear_bit()),
+                    Event::Txe => self.usart.cr1.modify(|_, w| w.txeie().clear_bit()),
+                    Event::Idle => self.usart.cr1.modify(|_, w| w.idleie().clear_bit()),
+                    _ => {}
+                }
+            }
+
+            /// Joins both halves back into the serial struct
+            pub fn join(self, isr: SerialIsr<$USARTX, Config>) -> Serial<$USARTX, Config> {
+                Serial {
+                    tx: isr.tx,
+                    rx: isr.rx,
+                    usart: self.usart,
+                    _config: PhantomData,
+                }
+            }
+        }
+
+        impl<Config> SerialIsr<$USARTX, Config> {
+            /// Check if interrupt event is pending
+            pub fn is_pending(&self, event: Event) -> bool {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                (usart.isr.read().bits() & event.val()) != 0
+            }
+
+            /// Clear pending interrupt
+            pub fn unpend(&mut self, event: Event) {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                // mask the allowed bits
+                let mask: u32 = 0x123BFF;
+                usart.icr.write(|w| unsafe { w.bits(event.val() & mask) });
+            }
+        }
+
+        impl<Config> hal::serial::Read<u8> for SerialIsr<$USARTX, Config> {
+            type Error = Error;
+
+            fn read(&mut self) -> nb::Result<u8, Error> {
+                self.rx.read()
+            }
+        }
+
+        impl<Config> hal::serial::Write<u8> for SerialIsr<$USARTX, Config> {
+            type Error = Error;
+
+            fn flush(&mut self) -> nb::Result<(), Self::Error> {
+                self.tx.flush()
+            }
+
+            fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+                self.tx.write(byte)
+            }
         }
 
         impl<Config> dma::Target for Rx<$USARTX, Config> {
@@ -303,6 +732,14 @@ macro_rules! uart_shared {
             }
         }
 
+        impl<Config> dma::DataTarget for Rx<$USARTX, Config> {
+            type Word = u8;
+
+            fn data_register(&self) -> u32 {
+                unsafe { &(*$USARTX::ptr()).rdr as *const _ as u32 }
+            }
+        }
+
         impl<Config> dma::Target for Tx<$USARTX, Config> {
 
             fn dmamux(&self) -> DmaMuxIndex {
@@ -325,12 +762,20 @@ macro_rules! uart_shared {
                 });
             }
         }
+
+        impl<Config> dma::DataTarget for Tx<$USARTX, Config> {
+            type Word = u8;
+
+            fn data_register(&self) -> u32 {
+                unsafe { &(*$USARTX::ptr()).tdr as *const _ as u32 }
+            }
+        }
     }
 }
 
 macro_rules! uart_basic {
     ($USARTX:ident,
-        $usartX:ident, $clk_mul:expr
+        $usartX:ident, $clk_mul:expr $(, $usartxsel:ident)?
     ) => {
         impl SerialExt<$USARTX, BasicConfig> for $USARTX {
             fn usart<TX, RX>(
@@ -346,6 +791,18 @@ macro_rules! uart_basic {
             {
                 Serial::$usartX(self, tx, rx, config, rcc)
             }
+
+            fn usart_half_duplex<TX>(
+                self,
+                tx: TX,
+                config: BasicConfig,
+                rcc: &mut Rcc,
+            ) -> Result<Serial<$USARTX, BasicConfig>, InvalidConfig>
+            where
+                TX: HalfDuplexPin<$USARTX>,
+            {
+                Serial::<$USARTX, BasicConfig>::half_duplex(self, tx, config, rcc)
+            }
         }
 
         impl Serial<$USARTX, BasicConfig> {
@@ -360,13 +817,56 @@ macro_rules! uart_basic {
                 TX: TxPin<$USARTX>,
                 RX: RxPin<$USARTX>,
             {
+                let serial = Self::init(usart, config, false, rcc)?;
+                tx.setup();
+                rx.setup();
+                Ok(serial)
+            }
+
+            /// See [`SerialExt::usart_half_duplex`]
+            pub fn half_duplex<TX>(
+                usart: $USARTX,
+                tx: TX,
+                config: BasicConfig,
+                rcc: &mut Rcc,
+            ) -> Result<Self, InvalidConfig>
+            where
+                TX: HalfDuplexPin<$USARTX>,
+            {
+                let serial = Self::init(usart, config, true, rcc)?;
+                tx.setup();
+                Ok(serial)
+            }
+
+            fn init(
+                usart: $USARTX,
+                config: BasicConfig,
+                half_duplex: bool,
+                rcc: &mut Rcc,
+            ) -> Result<Self, InvalidConfig> {
                 // Enable clock for USART
                 $USARTX::enable(rcc);
 
-                let clk = rcc.clocks.apb_clk.raw() as u64;
-                let bdr = config.baudrate.0 as u64;
-                let div = ($clk_mul * clk) / bdr;
-                usart.brr.write(|w| unsafe { w.bits(div as u32) });
+                // Select the kernel clock, only the instances with a USARTxSEL mux can
+                // use something else than PCLK
+                let _usart_clk = rcc.clocks.apb_clk;
+                $(
+                    match config.clock_source {
+                        ClockSource::HSI16 => rcc.enable_hsi(),
+                        ClockSource::LSE => rcc.enable_lse(false),
+                        _ => {}
+                    }
+                    rcc.ccipr.modify(|_, w| unsafe {
+                        w.$usartxsel().bits(config.clock_source as u8)
+                    });
+                    let _usart_clk = config.clock_source.freq(&rcc.clocks);
+                )?
+                if _usart_clk != config.clock_source.freq(&rcc.clocks) {
+                    return Err(InvalidConfig);
+                }
+
+                let div = baud_rate_divider(_usart_clk, config.baudrate, $clk_mul == 256)?;
+                usart.brr.write(|w| unsafe { w.bits(div) });
                 // Reset other registers to disable advanced USART features
                 usart.cr2.reset();
                 usart.cr3.reset();
@@ -400,10 +900,27 @@ macro_rules! uart_basic {
                         .swap()
                         .bit(config.swap)
                 });
-
-                // Enable pins
-                tx.setup();
-                rx.setup();
+                usart.cr3.write(|w| {
+                    w.dem()
+                        .bit(config.driver_enable.is_some())
+                        .dep()
+                        .bit(matches!(
+                            config.driver_enable,
+                            Some(DriverEnable { polarity: DePolarity::ActiveLow, .. })
+                        ))
+                        .hdsel()
+                        .bit(half_duplex)
+                });
+                if let Some(de) = config.driver_enable {
+                    // The DEDT field has another name on the LPUART
+                    usart.cr1.modify(|r, w| unsafe {
+                        w.bits(
+                            r.bits() & !(0x3ff << 16)
+                                | (de.assertion_time as u32) << 21
+                                | (de.deassertion_time as u32) << 16,
+                        )
+                    });
+                }
 
                 // Enable USART
                 usart.cr1.modify(|_, w| w.ue().set_bit());
@@ -461,8 +978,18 @@ macro_rules! uart_basic {
 
 macro_rules! uart_full {
     ($USARTX:ident,
-        $usartX:ident, $clk_mul:expr
+        $usartX:ident, $clk_mul:expr,
+        ck: [ $(($PCK:ident, $CAF:expr),)+ ]
+        $(, $usartxsel:ident)?
     ) => {
+        $(
+            impl<MODE> CkPin<$USARTX> for $PCK<MODE> {
+                fn setup(&self) {
+                    self.set_alt_mode($CAF)
+                }
+            }
+        )+
+
         impl SerialExt<$USARTX, FullConfig> for $USARTX {
             fn usart<TX, RX>(
                 self,
@@ -477,6 +1004,18 @@ macro_rules! uart_full {
             {
                 Serial::$usartX(self, tx, rx, config, rcc)
             }
+
+            fn usart_half_duplex<TX>(
+                self,
+                tx: TX,
+                config: FullConfig,
+                rcc: &mut Rcc,
+            ) -> Result<Serial<$USARTX, FullConfig>, InvalidConfig>
+            where
+                TX: HalfDuplexPin<$USARTX>,
+            {
+                Serial::<$USARTX, FullConfig>::half_duplex(self, tx, config, rcc)
+            }
         }
 
         impl Serial<$USARTX, FullConfig> {
@@ -491,14 +1030,96 @@ macro_rules! uart_full {
                 TX: TxPin<$USARTX>,
                 RX: RxPin<$USARTX>,
             {
+                let serial = Self::init(usart, config, false, rcc)?;
+                tx.setup();
+                rx.setup();
+                Ok(serial)
+            }
+
+            /// See [`SerialExt::usart_half_duplex`]
+            pub fn half_duplex<TX>(
+                usart: $USARTX,
+                tx: TX,
+                config: FullConfig,
+                rcc: &mut Rcc,
+            ) -> Result<Self, InvalidConfig>
+            where
+                TX: HalfDuplexPin<$USARTX>,
+            {
+                let serial = Self::init(usart, config, true, rcc)?;
+                tx.setup();
+                Ok(serial)
+            }
+
+            fn init(
+                usart: $USARTX,
+                config: FullConfig,
+                half_duplex: bool,
+                rcc: &mut Rcc,
+            ) -> Result<Self, InvalidConfig> {
                 // Enable clock for USART
                 $USARTX::enable(rcc);
 
-                let clk = rcc.clocks.apb_clk.raw() as u64;
-                let bdr = config.baudrate.0 as u64;
-                let clk_mul = 1;
-                let div = (clk_mul * clk) / bdr;
-                usart.brr.write(|w| unsafe { w.bits(div as u32) });
+                // Select the kernel clock, only the instances with a USARTxSEL mux can
+                // use something else than PCLK
+                let _usart_clk = rcc.clocks.apb_clk;
+                $(
+                    match config.clock_source {
+                        ClockSource::HSI16 => rcc.enable_hsi(),
+                        ClockSource::LSE => rcc.enable_lse(false),
+                        _ => {}
+                    }
+                    rcc.ccipr.modify(|_, w| unsafe {
+                        w.$usartxsel().bits(config.clock_source as u8)
+                    });
+                    let _usart_clk = config.clock_source.freq(&rcc.clocks);
+                )?
+                if _usart_clk != config.clock_source.freq(&rcc.clocks) {
+                    return Err(InvalidConfig);
+                }
+
+                // The single-wire mode only works with asynchronous frames, the smartcard
+                // mode has its own single-wire protocol
+                if half_duplex
+                    && matches!(
+                        config.mode,
+                        UsartMode::Synchronous { .. } | UsartMode::Lin(_)
+                    )
+                {
+                    return Err(InvalidConfig);
+                }
+
+                // LIN and smartcard frames have a fixed format
+                let (cpol, cpha, lbcl, clken) = match config.mode {
+                    UsartMode::Asynchronous => (false, false, false, false),
+                    UsartMode::Synchronous {
+                        cpol,
+                        cpha,
+                        last_bit_clock,
+                    } => (cpol, cpha, last_bit_clock, true),
+                    UsartMode::Lin(_) => {
+                        if config.wordlength != WordLength::DataBits8
+                            || config.parity != Parity::ParityNone
+                            || config.stopbits != StopBits::STOP1
+                        {
+                            return Err(InvalidConfig);
+                        }
+                        (false, false, false, false)
+                    }
+                    UsartMode::Smartcard(smartcard) => {
+                        if config.wordlength != WordLength::DataBits9
+                            || config.parity == Parity::ParityNone
+                            || !matches!(config.stopbits, StopBits::STOP0P5 | StopBits::STOP1P5)
+                        {
+                            return Err(InvalidConfig);
+                        }
+                        // The last bit clock pulse has to be output to the card
+                        (false, false, true, smartcard.clock_output)
+                    }
+                };
+
+                let div = baud_rate_divider(_usart_clk, config.baudrate, false)?;
+                usart.brr.write(|w| unsafe { w.bits(div) });
 
                 usart.cr1.reset();
                 usart.cr2.reset();
@@ -509,7 +1130,23 @@ macro_rules! uart_full {
                         .bits(config.stopbits.bits())
                         .swap()
                         .bit(config.swap)
+                        .clken()
+                        .bit(clken)
+                        .cpol()
+                        .bit(cpol)
+                        .cpha()
+                        .bit(cpha)
+                        .lbcl()
+                        .bit(lbcl)
                 });
+                if let UsartMode::Lin(break_length) = config.mode {
+                    usart.cr2.modify(|_, w| {
+                        w.linen()
+                            .set_bit()
+                            .lbdl()
+                            .bit(break_length == LinBreakLength::Bits11)
+                    });
+                }
 
                 if let Some(timeout) = config.receiver_timeout {
                     usart.cr1.write(|w| w.rtoie().set_bit());
@@ -526,7 +1163,38 @@ macro_rules! uart_full {
                         .bit(config.tx_fifo_interrupt)
                         .rxftie()
                         .bit(config.rx_fifo_interrupt)
+                        .dem()
+                        .bit(config.driver_enable.is_some())
+                        .dep()
+                        .bit(matches!(
+                            config.driver_enable,
+                            Some(DriverEnable { polarity: DePolarity::ActiveLow, .. })
+                        ))
+                        .hdsel()
+                        .bit(half_duplex && config.mode == UsartMode::Asynchronous)
                 });
+                if let Some(de) = config.driver_enable {
+                    usart.cr1.modify(|_, w| unsafe {
+                        w.deat()
+                            .bits(de.assertion_time)
+                            .dedt()
+                            .bits(de.deassertion_time)
+                    });
+                }
+
+                if let UsartMode::Smartcard(smartcard) = config.mode {
+                    usart.gtpr.write(|w| unsafe {
+                        w.gt().bits(smartcard.guard_time).psc().bits(smartcard.prescaler)
+                    });
+                    usart.cr3.modify(|_, w| unsafe {
+                        w.scen()
+                            .set_bit()
+                            .nack()
+                            .bit(smartcard.nack)
+                            .scarcnt()
+                            .bits(smartcard.retries)
+                    });
+                }
 
                 usart.cr1.modify(|_, w| {
                     w.ue()
@@ -536,9 +1204,9 @@ macro_rules! uart_full {
                         .re()
                         .set_bit()
                         .m0()
-                        .bit(config.wordlength == WordLength::DataBits7)
-                        .m1()
                         .bit(config.wordlength == WordLength::DataBits9)
+                        .m1()
+                        .bit(config.wordlength == WordLength::DataBits7)
                         .pce()
                         .bit(config.parity != Parity::ParityNone)
                         .ps()
@@ -547,9 +1215,6 @@ macro_rules! uart_full {
                         .bit(config.fifo_enable)
                 });
 
-                tx.setup();
-                rx.setup();
-
                 Ok(Serial {
                     tx: Tx {
                         _usart: PhantomData,
@@ -570,6 +1235,7 @@ macro_rules! uart_full {
                     Event::Rxne => self.usart.cr1.modify(|_, w| w.rxneie().set_bit()),
                     Event::Txe => self.usart.cr1.modify(|_, w| w.txeie().set_bit()),
                     Event::Idle => self.usart.cr1.modify(|_, w| w.idleie().set_bit()),
+                    Event::LBD => self.usart.cr2.modify(|_, w| w.lbdie().set_bit()),
                     _ => {}
                 }
             }
@@ -580,6 +1246,7 @@ macro_rules! uart_full {
                     Event::Rxne => self.usart.cr1.modify(|_, w| w.rxneie().clear_bit()),
                     Event::Txe => self.usart.cr1.modify(|_, w| w.txeie().clear_bit()),
                     Event::Idle => self.usart.cr1.modify(|_, w| w.idleie().clear_bit()),
+                    Event::LBD => self.usart.cr2.modify(|_, w| w.lbdie().clear_bit()),
                     _ => {}
                 }
             }
@@ -597,8 +1264,38 @@ macro_rules! uart_full {
                     .icr
                     .write(|w| unsafe { w.bits(event.val() & mask) });
             }
+
+            /// Sets up the clock output pin of the synchronous and smartcard modes
+            pub fn clock_pin<CK: CkPin<$USARTX>>(&mut self, ck: &CK) {
+                ck.setup();
+            }
+
+            /// Sends a break, see [`Tx::send_break`]
+            pub fn send_break(&mut self) {
+                self.tx.send_break();
+            }
+
+            /// Returns true if a LIN break has been detected, the flag is cleared with
+            /// `unpend(Event::LBD)`
+            pub fn is_break_detected(&self) -> bool {
+                self.usart.isr.read().lbdf().bit_is_set()
+            }
         }
         impl Tx<$USARTX, FullConfig> {
+            /// Sends a break after the byte being sent, 13 low bits in LIN mode
+            ///
+            /// The bytes written afterwards are sent once the break is over.
+            pub fn send_break(&mut self) {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                usart.rqr.write(|w| w.sbkrq().set_bit());
+            }
+
+            /// Returns true while a requested break has not been sent
+            pub fn is_sending_break(&self) -> bool {
+                let usart = unsafe { &(*$USARTX::ptr()) };
+                usart.isr.read().sbkf().bit_is_set()
+            }
+
             /// Returns true if the tx fifo threshold has been reached.
             pub fn fifo_threshold_reached(&self) -> bool {
                 let usart = unsafe { &(*$USARTX::ptr()) };
@@ -629,6 +1326,48 @@ macro_rules! uart_full {
     };
 }
 
+macro_rules! uart_wakeup {
+    ($USARTX:ident, $usartxsel:ident) => {
+        impl<Config> Serial<$USARTX, Config> {
+            /// Keeps the receiver running in Stop mode and wakes the device up on `event`
+            ///
+            /// The kernel clock has to be HSI16 or LSE, the only clocks that can run in Stop
+            /// mode, an error is returned otherwise. The wakeup interrupt is enabled, the
+            /// EXTI line of the peripheral has to be unmasked with `ExtiExt::wakeup` for
+            /// the interrupt to reach the core. The `Event::WUF` flag is cleared with
+            /// `unpend`.
+            pub fn enable_wakeup_from_stop(
+                &mut self,
+                event: WakeupEvent,
+            ) -> Result<(), InvalidConfig> {
+                let rcc = unsafe { &(*RCC::ptr()) };
+                let clock_source = rcc.ccipr.read().$usartxsel().bits();
+                if clock_source != ClockSource::HSI16 as u8
+                    && clock_source != ClockSource::LSE as u8
+                {
+                    return Err(InvalidConfig);
+                }
+                // WUS can only be written with the peripheral disabled
+                self.usart.cr1.modify(|_, w| w.ue().clear_bit());
+                self.usart
+                    .cr3
+                    .modify(|_, w| unsafe { w.wus().bits(event as u8).wufie().set_bit() });
+                self.usart
+                    .cr1
+                    .modify(|_, w| w.uesm().set_bit().ue().set_bit());
+                Ok(())
+            }
+
+            /// Stops the receiver in Stop mode
+            pub fn disable_wakeup_from_stop(&mut self) {
+                self.usart.cr3.modify(|_, w| w.wufie().clear_bit());
+                self.usart.cr1.modify(|_, w| w.uesm().clear_bit());
+                self.usart.icr.write(|w| w.wucf().set_bit());
+            }
+        }
+    };
+}
+
 uart_shared!(USART1, USART1_RX, USART1_TX,
 tx: [
     (PA9, AltFunction::AF1),
@@ -639,6 +1378,10 @@ rx: [
     (PA10, AltFunction::AF1),
     (PB7, AltFunction::AF0),
     (PC5, AltFunction::AF1),
+],
+de: [
+    (PA12, AltFunction::AF1),
+    (PB3, AltFunction::AF4),
 ]);
 
 uart_shared!(USART2, USART2_RX, USART2_TX,
@@ -651,6 +1394,10 @@ uart_shared!(USART2, USART2_RX, USART2_TX,
         (PA3, AltFunction::AF1),
         (PA15, AltFunction::AF1),
         (PD6, AltFunction::AF0),
+    ],
+    de: [
+        (PA1, AltFunction::AF1),
+        (PD4, AltFunction::AF0),
     ]
 );
 
@@ -672,6 +1419,12 @@ uart_shared!(USART3, USART3_RX, USART3_TX,
         (PC5, AltFunction::AF1),
         (PC11, AltFunction::AF1),
         (PD9, AltFunction::AF1),
+    ],
+    de: [
+        (PA15, AltFunction::AF5),
+        (PB1, AltFunction::AF4),
+        (PB14, AltFunction::AF4),
+        (PD2, AltFunction::AF0),
     ]
 );
 
@@ -684,6 +1437,9 @@ uart_shared!(USART4, USART4_RX, USART4_TX,
     rx: [
         (PC11, AltFunction::AF1),
         (PA1, AltFunction::AF4),
+    ],
+    de: [
+        (PA15, AltFunction::AF4),
     ]
 );
 
@@ -698,13 +1454,32 @@ uart_shared!(LPUART, LPUART_RX, LPUART_TX,
         (PA3, AltFunction::AF6),
         (PB10, AltFunction::AF1),
         (PC0, AltFunction::AF1),
+    ],
+    de: [
+        (PB1, AltFunction::AF6),
+        (PB12, AltFunction::AF1),
     ]
 );
 
-uart_full!(USART1, usart1, 1);
+uart_full!(USART1, usart1, 1,
+    ck: [
+        (PA8, AltFunction::AF1),
+        (PA12, AltFunction::AF1),
+        (PB3, AltFunction::AF4),
+    ],
+    usart1sel
+);
 
 #[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
-uart_full!(USART2, usart2, 1);
+uart_full!(USART2, usart2, 1,
+    ck: [
+        (PA1, AltFunction::AF1),
+        (PA4, AltFunction::AF1),
+        (PD4, AltFunction::AF0),
+        (PD7, AltFunction::AF0),
+    ],
+    usart2sel
+);
 
 #[cfg(any(feature = "stm32g030", feature = "stm32g031", feature = "stm32g041"))]
 uart_basic!(USART2, usart2, 1);
@@ -719,4 +1494,12 @@ uart_basic!(USART4, usart4, 1);
 // the basic feature set such as: Dual clock domain, FIFO or prescaler.
 // Or when Synchronous mode is implemented for the basic feature set, since the LP feature set does not have support.
 #[cfg(feature = "stm32g0x1")]
-uart_basic!(LPUART, lpuart, 256);
+uart_basic!(LPUART, lpuart, 256, lpuart1sel);
+
+uart_wakeup!(USART1, usart1sel);
+
+#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
+uart_wakeup!(USART2, usart2sel);
+
+#[cfg(feature = "stm32g0x1")]
+uart_wakeup!(LPUART, lpuart1sel);
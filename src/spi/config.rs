@@ -0,0 +1,76 @@
+use super::{Mode, MODE_0};
+
+/// Level of the RX FIFO raising RXNE (FRXTH)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RxThreshold {
+    /// One 8-bit frame, needed for frames of up to 8 bits read one by one
+    Quarter,
+    /// 16 bits: one frame of more than 8 bits, or two packed frames of up to 8 bits
+    Half,
+}
+
+/// Filling of a FIFO (FRLVL / FTLVL)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoLevel {
+    Empty,
+    Quarter,
+    Half,
+    Full,
+}
+
+impl From<u8> for FifoLevel {
+    fn from(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => FifoLevel::Empty,
+            0b01 => FifoLevel::Quarter,
+            0b10 => FifoLevel::Half,
+            _ => FifoLevel::Full,
+        }
+    }
+}
+
+pub struct Config {
+    pub mode: Mode,
+    pub data_size: u8,
+    pub rx_threshold: RxThreshold,
+}
+
+impl Config {
+    pub fn new(mode: Mode) -> Self {
+        Config {
+            mode,
+            data_size: 8,
+            rx_threshold: RxThreshold::Quarter,
+        }
+    }
+
+    /// Frame size, from 4 to 16 bits
+    ///
+    /// Frames of up to 8 bits are exchanged as `u8` words, larger frames as `u16` words.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bits` is out of range.
+    pub fn data_size(mut self, bits: u8) -> Self {
+        hal_assert!((4..=16).contains(&bits), "invalid frame size");
+        self.data_size = bits;
+        self
+    }
+
+    pub fn rx_threshold(mut self, threshold: RxThreshold) -> Self {
+        self.rx_threshold = threshold;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new(MODE_0)
+    }
+}
+
+impl From<Mode> for Config {
+    fn from(mode: Mode) -> Self {
+        Config::new(mode)
+    }
+}
@@ -0,0 +1,198 @@
+//! Timer-paced SPI frames
+//!
+//! TIM14 channel 1 drives the chip select as a PWM output, low at the start of each
+//! period for as long as a frame takes. Its compare event is also the DMAMUX
+//! synchronization input that releases the SPI TX requests of one frame, so the frame is
+//! resent at a fixed rate without any CPU involvement, e.g. to refresh a chain of
+//! external DACs or LED drivers.
+use core::ops::Deref;
+use core::ptr;
+
+use crate::dma::{self, Direction, Target, Word};
+use crate::dmamux::{DmaMuxTriggerSync, SyncPolarity};
+use crate::rcc::{Enable, Rcc, Reset};
+use crate::stm32::{spi1, TIM14};
+use crate::time::Hertz;
+use crate::timer::pins::TimerPin;
+use crate::timer::Channel1;
+
+use super::Spi;
+
+/// Maximum number of words in a frame, limited by the DMAMUX request counter
+pub const MAX_FRAME_LEN: usize = 32;
+
+/// SPI data frame sizes, `u8` for frames of up to 8 bits and `u16` for 9 to 16 bits
+pub trait FrameWord: Word {
+    #[doc(hidden)]
+    const BITS: u32;
+
+    /// Reads the data register with an access of the frame size
+    #[doc(hidden)]
+    unsafe fn read(spi: *const spi1::RegisterBlock) -> Self;
+
+    /// Writes the data register with an access of the frame size
+    #[doc(hidden)]
+    unsafe fn write(spi: *const spi1::RegisterBlock, frame: Self);
+}
+
+impl FrameWord for u8 {
+    const BITS: u32 = 8;
+
+    unsafe fn read(spi: *const spi1::RegisterBlock) -> u8 {
+        // NOTE(read_volatile) read only 1 byte (the svd2rust API only allows reading a
+        // half-word)
+        ptr::read_volatile(ptr::addr_of!((*spi).dr) as *const u8)
+    }
+
+    unsafe fn write(spi: *const spi1::RegisterBlock, frame: u8) {
+        // NOTE(write_volatile) see note above, the pointer comes from the register block
+        // address as writes through a reference are not allowed
+        ptr::write_volatile(ptr::addr_of!((*spi).dr) as *mut u8, frame)
+    }
+}
+
+impl FrameWord for u16 {
+    const BITS: u32 = 16;
+
+    unsafe fn read(spi: *const spi1::RegisterBlock) -> u16 {
+        (*spi).dr.read().dr().bits()
+    }
+
+    unsafe fn write(spi: *const spi1::RegisterBlock, frame: u16) {
+        (*spi).dr.write(|w| w.dr().bits(frame))
+    }
+}
+
+/// SPI transmitter sending the same frame at a fixed rate, with a chip select pulse
+/// around each frame
+///
+/// The frame is written by the CPU between the transfers, so it has to be a
+/// [`WriteBuffer`](dma::WriteBuffer) even though the DMA only reads it.
+pub struct FramedTx<SPI, PINS, CH, CS, W: 'static, BUF = &'static mut [W]> {
+    spi: Spi<SPI, PINS>,
+    ch: CH,
+    tim: TIM14,
+    cs: CS,
+    frame: BUF,
+    ptr: *mut W,
+    len: usize,
+}
+
+impl<SPI, PINS, CH, CS, W, BUF> FramedTx<SPI, PINS, CH, CS, W, BUF>
+where
+    SPI: Deref<Target = spi1::RegisterBlock>,
+    Spi<SPI, PINS>: dma::Target,
+    CH: dma::Channel,
+    CS: TimerPin<TIM14, Channel = Channel1>,
+    W: FrameWord,
+    BUF: dma::WriteBuffer<Word = W>,
+{
+    /// Starts sending `frame` `rate` times per second
+    ///
+    /// The chip select is held low for the duration of the frame plus two words, which
+    /// covers the DMA latency. The first frame goes out at the end of the first period.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the frame is empty or longer than [`MAX_FRAME_LEN`] words, or if it does
+    /// not fit in a period at the current SCK frequency.
+    pub fn new(
+        mut spi: Spi<SPI, PINS>,
+        mut ch: CH,
+        tim: TIM14,
+        cs: CS,
+        mut frame: BUF,
+        rate: Hertz,
+        rcc: &mut Rcc,
+    ) -> Self {
+        let (ptr, len) = unsafe { frame.write_buffer() };
+        hal_assert!(len > 0 && len <= MAX_FRAME_LEN);
+
+        // Timer clock cycles per period and per chip select pulse
+        let clk = rcc.clocks.apb_tim_clk.raw();
+        let period = clk / rate.raw();
+        let psc = (period - 1) / 0x1_0000;
+        let arr = period / (psc + 1) - 1;
+        let sck = spi.clk.raw() / (2 << spi.spi.cr1.read().br().bits());
+        let bits = (len as u64 + 2) * W::BITS as u64;
+        let tick = sck as u64 * (psc as u64 + 1);
+        let pulse = (bits * clk as u64).div_ceil(tick);
+        hal_assert!(pulse <= arr as u64, "frame too long for the period");
+
+        spi.spi.cr1.modify(|_, w| w.spe().clear_bit());
+        spi.spi
+            .cr2
+            .modify(|_, w| unsafe { w.ds().bits(W::BITS as u8 - 1) });
+        spi.enable_dma();
+        spi.spi.cr1.modify(|_, w| w.spe().set_bit());
+
+        ch.disable();
+        ch.set_direction(Direction::FromMemory);
+        ch.set_word_size(W::SIZE);
+        ch.set_peripheral_address(&spi.spi.dr as *const _ as u32, false);
+        ch.set_memory_address(ptr as u32, true);
+        ch.set_transfer_length(len as u16);
+        ch.set_circular_mode(true);
+        ch.select_peripheral(spi.dmamux());
+        ch.enable_sync(DmaMuxTriggerSync::TIM14_OC, SyncPolarity::Rising, len as u8);
+        ch.enable();
+
+        TIM14::enable(rcc);
+        TIM14::reset(rcc);
+        cs.setup();
+        tim.psc.write(|w| unsafe { w.psc().bits(psc as u16) });
+        tim.arr.write(|w| unsafe { w.bits(arr) });
+        tim.ccr1.write(|w| unsafe { w.bits(pulse as u32) });
+        // PWM mode 1: OC1REF rises on each update and falls once the frame is out, the
+        // output is inverted to drive an active low chip select
+        tim.ccmr1_output()
+            .write(|w| unsafe { w.oc1m().bits(0b110).oc1pe().set_bit() });
+        tim.ccer.write(|w| w.cc1e().set_bit().cc1p().set_bit());
+        tim.egr.write(|w| w.ug().set_bit());
+        tim.cr1.write(|w| w.arpe().set_bit().cen().set_bit());
+
+        FramedTx {
+            spi,
+            ch,
+            tim,
+            cs,
+            frame,
+            ptr,
+            len,
+        }
+    }
+
+    /// Modifies the frame between two transfers
+    ///
+    /// Waits until the chip select is released, the changes end up in the next frame as
+    /// long as `f` returns before the end of the period.
+    pub fn update<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [W]) -> R,
+    {
+        let pulse = self.tim.ccr1.read().bits();
+        while self.tim.cnt.read().bits() < pulse {}
+        // NOTE(unsafe) the frame is owned by the transmitter, the DMA is idle until the
+        // next period
+        f(unsafe { core::slice::from_raw_parts_mut(self.ptr, self.len) })
+    }
+
+    /// Returns `true` if the DMA failed to feed the SPI, the transfers are stopped
+    pub fn is_error(&self) -> bool {
+        self.ch.event_occurred(dma::Event::TransferError)
+    }
+
+    /// Stops the transfers and releases the resources
+    pub fn release(mut self) -> (Spi<SPI, PINS>, CH, TIM14, CS, BUF) {
+        // Stop after a frame, so that the chip select is left high
+        let pulse = self.tim.ccr1.read().bits();
+        while self.tim.cnt.read().bits() < pulse {}
+        self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+        self.ch.disable();
+        self.ch.disable_sync();
+        while self.spi.spi.sr.read().ftlvl().bits() != 0 {}
+        while self.spi.spi.sr.read().bsy().bit_is_set() {}
+        self.spi.disable_dma();
+        (self.spi, self.ch, self.tim, self.cs.release(), self.frame)
+    }
+}
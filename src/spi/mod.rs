@@ -0,0 +1,952 @@
+use crate::debug::{Dump, SpiRegisters};
+use crate::dma::{self, Word};
+use crate::dmamux::DmaMuxIndex;
+use crate::gpio::{gpioa::*, gpiob::*, gpioc::*, gpiod::*, AltFunction, DefaultMode};
+use crate::power::WaitIdle;
+use crate::rcc::*;
+use crate::stm32::{SPI1, SPI2};
+use crate::time::Hertz;
+use crate::timer::opm::{Strobe, TransferStrobe};
+use core::ptr;
+use core::sync::atomic::{compiler_fence, Ordering};
+pub use hal::spi::{Mode, Phase, Polarity, MODE_0, MODE_1, MODE_2, MODE_3};
+
+mod config;
+mod framed;
+mod regfile;
+mod transfer;
+
+pub use config::{Config, FifoLevel, RxThreshold};
+pub use framed::*;
+pub use regfile::*;
+pub use transfer::*;
+
+/// SPI error
+#[derive(Debug)]
+pub enum Error {
+    /// Overrun occurred
+    Overrun,
+    /// Mode fault occurred
+    ModeFault,
+    /// CRC error
+    Crc,
+}
+
+/// SPI self-test error
+#[derive(Debug)]
+pub enum SelfTestError {
+    /// Bus error
+    Spi(Error),
+    /// A byte was received back corrupted
+    Mismatch {
+        index: usize,
+        sent: u8,
+        received: u8,
+    },
+}
+
+/// A filler type for when the SCK pin is unnecessary
+pub struct NoSck;
+/// A filler type for when the Miso pin is unnecessary
+pub struct NoMiso;
+/// A filler type for when the Mosi pin is unnecessary
+pub struct NoMosi;
+
+pub trait Pins<SPI> {
+    fn setup(&self);
+    fn release(self) -> Self;
+}
+
+pub trait PinSck<SPI> {
+    fn setup(&self);
+    fn release(self) -> Self;
+}
+
+pub trait PinMiso<SPI> {
+    fn setup(&self);
+    fn release(self) -> Self;
+}
+
+pub trait PinMosi<SPI> {
+    fn setup(&self);
+    fn release(self) -> Self;
+}
+
+/// Hardware slave select pin
+pub trait PinNss<SPI> {
+    fn setup(&self);
+    fn release(self) -> Self;
+}
+
+/// Hardware slave select output of a master
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NssOutput {
+    /// NSS is driven low while the peripheral is enabled (SSOE)
+    Continuous,
+    /// NSS is also pulsed high between consecutive frames (NSSP), to delimit the frames of
+    /// slaves latching on the rising edge. Only valid with the CPHA = 0 modes.
+    Pulse,
+}
+
+impl<SPI, SCK, MISO, MOSI> Pins<SPI> for (SCK, MISO, MOSI)
+where
+    SCK: PinSck<SPI>,
+    MISO: PinMiso<SPI>,
+    MOSI: PinMosi<SPI>,
+{
+    fn setup(&self) {
+        self.0.setup();
+        self.1.setup();
+        self.2.setup();
+    }
+
+    fn release(self) -> Self {
+        (self.0.release(), self.1.release(), self.2.release())
+    }
+}
+
+#[derive(Debug)]
+pub struct Spi<SPI, PINS> {
+    spi: SPI,
+    pins: PINS,
+    clk: Hertz,
+}
+
+pub trait SpiExt: Sized {
+    fn spi<PINS>(
+        self,
+        pins: PINS,
+        config: impl Into<Config>,
+        freq: Hertz,
+        rcc: &mut Rcc,
+    ) -> Spi<Self, PINS>
+    where
+        PINS: Pins<Self>;
+
+    /// Creates the bus with the highest frequency not exceeding `max_freq`, see
+    /// [`Spi::max_frequency`] for the upper limit. Returns the achieved frequency.
+    fn spi_up_to<PINS>(
+        self,
+        pins: PINS,
+        config: impl Into<Config>,
+        max_freq: Hertz,
+        rcc: &mut Rcc,
+    ) -> (Spi<Self, PINS>, Hertz)
+    where
+        PINS: Pins<Self>;
+
+    /// Creates a slave, see [`Spi::spi_slave`]
+    fn spi_slave<PINS>(
+        self,
+        pins: PINS,
+        config: impl Into<Config>,
+        rcc: &mut Rcc,
+    ) -> Spi<Self, PINS>
+    where
+        PINS: Pins<Self>;
+}
+
+/// Picks the smallest baud rate divider (2 to 256) whose SCK frequency does not exceed `max_freq`
+fn baud_rate_prescaler(clk: Hertz, max_freq: Hertz) -> (u8, Hertz) {
+    let mut br = 0;
+    while br < 0b111 && clk / (2 << br) > max_freq {
+        br += 1;
+    }
+    (br as u8, clk / (2 << br))
+}
+
+macro_rules! spi {
+    ($SPIX:ident, $spiX:ident, $dmamux_tx:ident, $dmamux_rx:ident,
+        sck: [ $(($SCK:ty, $SCK_AF:expr),)+ ],
+        miso: [ $(($MISO:ty, $MISO_AF:expr),)+ ],
+        mosi: [ $(($MOSI:ty, $MOSI_AF:expr),)+ ],
+        nss: [ $(($NSS:ty, $NSS_AF:expr),)+ ],
+    ) => {
+        impl PinSck<$SPIX> for NoSck {
+            fn setup(&self) {}
+
+            fn release(self) -> Self {
+                self
+            }
+        }
+
+        impl PinMiso<$SPIX> for NoMiso {
+            fn setup(&self) {}
+
+            fn release(self) -> Self {
+                self
+            }
+        }
+
+        impl PinMosi<$SPIX> for NoMosi {
+            fn setup(&self) {}
+
+            fn release(self) -> Self {
+                self
+            }
+        }
+
+        $(
+            impl PinSck<$SPIX> for $SCK {
+                fn setup(&self) {
+                    self.set_alt_mode($SCK_AF);
+                }
+
+                fn release(self) -> Self {
+                    self.into_analog()
+                }
+            }
+        )*
+        $(
+            impl PinMiso<$SPIX> for $MISO {
+                fn setup(&self) {
+                    self.set_alt_mode($MISO_AF);
+                }
+
+                fn release(self) -> Self {
+                    self.into_analog()
+                }
+            }
+        )*
+        $(
+            impl PinMosi<$SPIX> for $MOSI {
+                fn setup(&self) {
+                    self.set_alt_mode($MOSI_AF);
+                }
+
+                fn release(self) -> Self {
+                    self.into_analog()
+                }
+            }
+        )*
+        $(
+            impl PinNss<$SPIX> for $NSS {
+                fn setup(&self) {
+                    self.set_alt_mode($NSS_AF);
+                }
+
+                fn release(self) -> Self {
+                    self.into_analog()
+                }
+            }
+        )*
+
+        impl<PINS: Pins<$SPIX>> Spi<$SPIX, PINS> {
+            pub fn $spiX(
+                spi: $SPIX,
+                pins: PINS,
+                config: impl Into<Config>,
+                speed: Hertz,
+                rcc: &mut Rcc
+            ) -> Self {
+                let br = match rcc.clocks.apb_clk / speed {
+                    // Speeds above PCLK / 2 are clamped to it
+                    0..=2 => 0b000,
+                    3..=5 => 0b001,
+                    6..=11 => 0b010,
+                    12..=23 => 0b011,
+                    24..=47 => 0b100,
+                    48..=95 => 0b101,
+                    96..=191 => 0b110,
+                    _ => 0b111,
+                };
+                Self::init(spi, pins, config.into(), br, true, rcc)
+            }
+
+            /// Creates the bus with the highest frequency that does not exceed `max_freq`
+            ///
+            /// Returns the achieved frequency. If `max_freq` is lower than the slowest possible
+            /// rate, the largest prescaler is used.
+            pub fn with_max_frequency(
+                spi: $SPIX,
+                pins: PINS,
+                config: impl Into<Config>,
+                max_freq: Hertz,
+                rcc: &mut Rcc
+            ) -> (Self, Hertz) {
+                let (br, freq) = baud_rate_prescaler(rcc.clocks.apb_clk, max_freq);
+                (Self::init(spi, pins, config.into(), br, true, rcc), freq)
+            }
+
+            /// Creates a slave, clocked by the SCK of the master
+            ///
+            /// The slave is always selected until a hardware NSS input is enabled with
+            /// [`Self::enable_nss_input`]. The data to send is queued with
+            /// `FullDuplex::send` and goes out when the master clocks the next frame.
+            pub fn spi_slave(spi: $SPIX, pins: PINS, config: impl Into<Config>, rcc: &mut Rcc) -> Self {
+                Self::init(spi, pins, config.into(), 0, false, rcc)
+            }
+
+            fn init(
+                spi: $SPIX,
+                pins: PINS,
+                config: Config,
+                br: u8,
+                master: bool,
+                rcc: &mut Rcc,
+            ) -> Self {
+                $SPIX::enable(rcc);
+                $SPIX::reset(rcc);
+
+                // disable SS output
+                spi.cr2.write(|w| w.ssoe().clear_bit());
+
+                spi.cr2.write(|w| unsafe {
+                    w.frxth()
+                        .bit(config.rx_threshold == RxThreshold::Quarter)
+                        .ds()
+                        .bits(config.data_size - 1)
+                        .ssoe()
+                        .clear_bit()
+                });
+
+                // Enable pins
+                pins.setup();
+
+                let mode = config.mode;
+                spi.cr1.write(|w| unsafe {
+                    w.cpha()
+                        .bit(mode.phase == Phase::CaptureOnSecondTransition)
+                        .cpol()
+                        .bit(mode.polarity == Polarity::IdleHigh)
+                        .mstr()
+                        .bit(master)
+                        .br()
+                        .bits(br)
+                        .lsbfirst()
+                        .clear_bit()
+                        .ssm()
+                        .set_bit()
+                        .ssi()
+                        .bit(master)
+                        .rxonly()
+                        .clear_bit()
+                        .dff()
+                        .clear_bit()
+                        .bidimode()
+                        .clear_bit()
+                        .spe()
+                        .set_bit()
+                });
+
+                Spi { spi, pins, clk: rcc.clocks.apb_clk }
+            }
+
+            /// Highest SCK frequency supported with the current APB clock
+            pub fn max_frequency(&self) -> Hertz {
+                self.clk / 2
+            }
+
+            /// Current SCK frequency
+            pub fn frequency(&self) -> Hertz {
+                self.clk / (2 << self.spi.cr1.read().br().bits())
+            }
+
+            pub fn data_size(&mut self, nr_bits: u8) {
+                self.spi.cr2.modify(|_, w| unsafe {
+                    w.ds().bits(nr_bits-1)
+                });
+            }
+
+            /// Sets the RX FIFO level raising RXNE, see [`RxThreshold`]
+            pub fn set_rx_threshold(&mut self, threshold: RxThreshold) {
+                self.spi.cr2.modify(|_, w| w.frxth().bit(threshold == RxThreshold::Quarter));
+            }
+
+            /// Current filling of the RX FIFO
+            pub fn rx_fifo_level(&self) -> FifoLevel {
+                self.spi.sr.read().frlvl().bits().into()
+            }
+
+            /// Current filling of the TX FIFO
+            pub fn tx_fifo_level(&self) -> FifoLevel {
+                self.spi.sr.read().ftlvl().bits().into()
+            }
+
+            pub fn half_duplex_enable(&mut self, enable: bool) {
+                self.spi.cr1.modify(|_, w|
+                    w.bidimode().bit(enable)
+                );
+            }
+
+            pub fn half_duplex_output_enable(&mut self, enable: bool) {
+                self.spi.cr1.modify(|_, w|
+                    w.bidioe().bit(enable)
+                );
+            }
+
+            /// Master: drives `nss` low while the peripheral is enabled, instead of a
+            /// software chip select
+            pub fn enable_nss_output<NSS: PinNss<$SPIX>>(&mut self, nss: &NSS, output: NssOutput) {
+                nss.setup();
+                self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+                self.spi.cr2.modify(|_, w| {
+                    w.ssoe().set_bit().nssp().bit(output == NssOutput::Pulse)
+                });
+                self.spi.cr1.modify(|_, w| w.ssm().clear_bit().spe().set_bit());
+            }
+
+            /// Slave: only takes part in the transfers while `nss` is held low by the master
+            ///
+            /// MISO is released while the slave is not selected, so several slaves can share
+            /// the bus.
+            pub fn enable_nss_input<NSS: PinNss<$SPIX>>(&mut self, nss: &NSS) {
+                nss.setup();
+                self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+                self.spi.cr2.modify(|_, w| w.ssoe().clear_bit().nssp().clear_bit());
+                self.spi.cr1.modify(|_, w| w.ssm().clear_bit().spe().set_bit());
+            }
+
+            /// Goes back to the software slave select, `NSS` pins can then be released
+            pub fn disable_hardware_nss(&mut self) {
+                let master = self.spi.cr1.read().mstr().bit_is_set();
+                self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+                self.spi.cr2.modify(|_, w| w.ssoe().clear_bit().nssp().clear_bit());
+                self.spi.cr1.modify(|_, w| {
+                    w.ssm().set_bit().ssi().bit(master).spe().set_bit()
+                });
+            }
+
+            pub fn release(self) -> ($SPIX, PINS) {
+                (self.spi, self.pins.release())
+            }
+
+            /// Checks the bus with MOSI looped back to MISO
+            ///
+            /// SPI has no internal loopback, MOSI has to be wired to MISO (on the board or
+            /// with a test fixture) and the slaves deselected. Each byte of `pattern` is sent
+            /// and has to be received back unchanged. Only valid for 8-bit frames.
+            pub fn self_test(&mut self, pattern: &[u8]) -> Result<(), SelfTestError> {
+                // Drop stale data, reading DR then SR also clears an overrun
+                while self.spi.sr.read().rxne().bit_is_set() {
+                    let _ = self.spi.dr.read();
+                }
+                let _ = self.spi.sr.read();
+
+                pattern.iter().enumerate().try_for_each(|(index, &sent)| {
+                    let received = self.exchange(sent).map_err(SelfTestError::Spi)?;
+                    if received != sent {
+                        return Err(SelfTestError::Mismatch { index, sent, received });
+                    }
+                    Ok(())
+                })
+            }
+
+            /// Gives scoped access to the peripheral registers
+            ///
+            /// # Safety
+            ///
+            /// The driver keeps relying on its own setup, `f` must leave the peripheral
+            /// clock, the SPI enable and the configuration registers as it found them.
+            pub unsafe fn with_registers<F, R>(&mut self, f: F) -> R
+            where
+                F: FnOnce(&$SPIX) -> R,
+            {
+                f(&self.spi)
+            }
+        }
+
+        impl<PINS> Spi<$SPIX, PINS> {
+            /// Sends `buf` with the DMA channel `ch`, the received frames are dropped
+            ///
+            /// # Panics
+            ///
+            /// Panics if the buffer is empty, longer than 65535 words, or if its words do
+            /// not match the frame size.
+            pub fn write_dma<CH, BUF>(
+                self,
+                mut ch: CH,
+                buf: BUF,
+            ) -> DmaTransfer<$SPIX, PINS, CH, (), BUF>
+            where
+                CH: dma::Channel,
+                BUF: dma::ReadBuffer,
+                BUF::Word: FrameWord,
+            {
+                let frxth = transfer::prepare::<BUF::Word>(&self.spi);
+                let (ptr, len) = unsafe { buf.read_buffer() };
+                let dr = &self.spi.dr as *const _ as u32;
+                transfer::setup(
+                    &mut ch,
+                    dr,
+                    dma::Direction::FromMemory,
+                    BUF::Word::SIZE,
+                    ptr as u32,
+                    true,
+                    len,
+                    DmaMuxIndex::$dmamux_tx,
+                );
+                // The buffer contents are written before the DMA starts reading them
+                compiler_fence(Ordering::Release);
+                ch.enable();
+                self.spi.cr2.modify(|_, w| w.txdmaen().set_bit());
+                DmaTransfer { spi: self, tx: ch, rx: (), buf, frxth }
+            }
+
+            /// Sends `buf` like [`write_dma`](Self::write_dma), framed by a one-pulse
+            /// `strobe` timed from the SCK frequency, e.g. a chip select or the latch
+            /// enable of shift registers, without any CPU timing
+            ///
+            /// # Panics
+            ///
+            /// Panics like [`write_dma`](Self::write_dma), or if the strobe does not
+            /// fit in the period of its timer.
+            pub fn write_dma_strobed<CH, BUF, STROBE>(
+                self,
+                ch: CH,
+                buf: BUF,
+                pulse: &mut STROBE,
+                strobe: Strobe,
+            ) -> DmaTransfer<$SPIX, PINS, CH, (), BUF>
+            where
+                CH: dma::Channel,
+                BUF: dma::ReadBuffer,
+                BUF::Word: FrameWord,
+                STROBE: TransferStrobe,
+            {
+                let (_, len) = unsafe { buf.read_buffer() };
+                let bits = self.spi.cr2.read().ds().bits() as u32 + 1;
+                let sck = self.clk / (2 << self.spi.cr1.read().br().bits());
+                let duration =
+                    transfer::duration_ticks(len as u32 * bits, sck, pulse.tick());
+                pulse.frame(strobe, duration, || self.write_dma(ch, buf))
+            }
+
+            /// Fills `buf` with the DMA channel `rx_ch`, `tx_ch` clocks the bus by sending
+            /// 0xff words
+            ///
+            /// # Panics
+            ///
+            /// Panics if the buffer is empty, longer than 65535 words, or if its words do
+            /// not match the frame size.
+            pub fn read_dma<RXCH, TXCH, BUF>(
+                mut self,
+                mut rx_ch: RXCH,
+                mut tx_ch: TXCH,
+                mut buf: BUF,
+            ) -> DmaTransfer<$SPIX, PINS, TXCH, RXCH, BUF>
+            where
+                RXCH: dma::Channel,
+                TXCH: dma::Channel,
+                BUF: dma::WriteBuffer,
+                BUF::Word: FrameWord,
+            {
+                let frxth = transfer::prepare::<BUF::Word>(&self.spi);
+                let (ptr, len) = unsafe { buf.write_buffer() };
+                let dr = &self.spi.dr as *const _ as u32;
+                transfer::setup(
+                    &mut rx_ch,
+                    dr,
+                    dma::Direction::FromPeripheral,
+                    BUF::Word::SIZE,
+                    ptr as u32,
+                    true,
+                    len,
+                    DmaMuxIndex::$dmamux_rx,
+                );
+                transfer::setup(
+                    &mut tx_ch,
+                    dr,
+                    dma::Direction::FromMemory,
+                    BUF::Word::SIZE,
+                    &transfer::DUMMY as *const _ as u32,
+                    false,
+                    len,
+                    DmaMuxIndex::$dmamux_tx,
+                );
+                self.start_duplex(&mut rx_ch, &mut tx_ch);
+                DmaTransfer { spi: self, tx: tx_ch, rx: rx_ch, buf, frxth }
+            }
+
+            /// Sends `tx_buf` and receives the answer in `rx_buf` at the same time
+            ///
+            /// # Panics
+            ///
+            /// Panics if the buffers have different lengths, are empty, longer than 65535
+            /// words, or if their words do not match the frame size.
+            #[allow(clippy::type_complexity)]
+            pub fn transfer_dma<RXCH, TXCH, TXBUF, RXBUF>(
+                mut self,
+                mut rx_ch: RXCH,
+                mut tx_ch: TXCH,
+                tx_buf: TXBUF,
+                mut rx_buf: RXBUF,
+            ) -> DmaTransfer<$SPIX, PINS, TXCH, RXCH, (TXBUF, RXBUF)>
+            where
+                RXCH: dma::Channel,
+                TXCH: dma::Channel,
+                TXBUF: dma::ReadBuffer,
+                TXBUF::Word: FrameWord,
+                RXBUF: dma::WriteBuffer<Word = TXBUF::Word>,
+            {
+                let frxth = transfer::prepare::<TXBUF::Word>(&self.spi);
+                let (tx_ptr, len) = unsafe { tx_buf.read_buffer() };
+                let (rx_ptr, rx_len) = unsafe { rx_buf.write_buffer() };
+                assert!(len == rx_len, "buffers of different lengths");
+                let dr = &self.spi.dr as *const _ as u32;
+                transfer::setup(
+                    &mut rx_ch,
+                    dr,
+                    dma::Direction::FromPeripheral,
+                    RXBUF::Word::SIZE,
+                    rx_ptr as u32,
+                    true,
+                    len,
+                    DmaMuxIndex::$dmamux_rx,
+                );
+                transfer::setup(
+                    &mut tx_ch,
+                    dr,
+                    dma::Direction::FromMemory,
+                    TXBUF::Word::SIZE,
+                    tx_ptr as u32,
+                    true,
+                    len,
+                    DmaMuxIndex::$dmamux_tx,
+                );
+                self.start_duplex(&mut rx_ch, &mut tx_ch);
+                DmaTransfer {
+                    spi: self,
+                    tx: tx_ch,
+                    rx: rx_ch,
+                    buf: (tx_buf, rx_buf),
+                    frxth,
+                }
+            }
+
+            /// Turns the slave into a register map slave exposing `table`, see
+            /// [`RegisterFile`]
+            ///
+            /// The bus has to be a slave with 8-bit frames and the hardware NSS input.
+            ///
+            /// # Panics
+            ///
+            /// Panics if the table is empty or longer than 128 registers, or if the frames
+            /// are not 8-bit.
+            pub fn register_file<RXCH, TXCH, BUF>(
+                self,
+                rx_ch: RXCH,
+                tx_ch: TXCH,
+                table: BUF,
+            ) -> RegisterFile<$SPIX, PINS, RXCH, TXCH, BUF>
+            where
+                RXCH: dma::Channel,
+                TXCH: dma::Channel,
+                BUF: dma::WriteBuffer<Word = u8>,
+            {
+                let dr = unsafe { ptr::addr_of!((*$SPIX::ptr()).dr) } as *mut u8;
+                RegisterFile::new(
+                    self,
+                    dr,
+                    rx_ch,
+                    tx_ch,
+                    DmaMuxIndex::$dmamux_rx,
+                    DmaMuxIndex::$dmamux_tx,
+                    table,
+                )
+            }
+
+            fn start_duplex<RXCH: dma::Channel, TXCH: dma::Channel>(
+                &mut self,
+                rx_ch: &mut RXCH,
+                tx_ch: &mut TXCH,
+            ) {
+                // The buffer contents are written before the DMA starts reading them
+                compiler_fence(Ordering::Release);
+                // RX first, so that no frame is missed
+                self.spi.cr2.modify(|_, w| w.rxdmaen().set_bit());
+                rx_ch.enable();
+                tx_ch.enable();
+                self.spi.cr2.modify(|_, w| w.txdmaen().set_bit());
+            }
+        }
+
+        impl SpiExt for $SPIX {
+            fn spi<PINS>(
+                self,
+                pins: PINS,
+                config: impl Into<Config>,
+                freq: Hertz,
+                rcc: &mut Rcc,
+            ) -> Spi<$SPIX, PINS>
+            where
+                PINS: Pins<$SPIX>,
+            {
+                Spi::$spiX(self, pins, config, freq, rcc)
+            }
+
+            fn spi_up_to<PINS>(
+                self,
+                pins: PINS,
+                config: impl Into<Config>,
+                max_freq: Hertz,
+                rcc: &mut Rcc,
+            ) -> (Spi<$SPIX, PINS>, Hertz)
+            where
+                PINS: Pins<$SPIX>,
+            {
+                Spi::<$SPIX, PINS>::with_max_frequency(self, pins, config, max_freq, rcc)
+            }
+
+            fn spi_slave<PINS>(
+                self,
+                pins: PINS,
+                config: impl Into<Config>,
+                rcc: &mut Rcc,
+            ) -> Spi<$SPIX, PINS>
+            where
+                PINS: Pins<$SPIX>,
+            {
+                Spi::<$SPIX, PINS>::spi_slave(self, pins, config, rcc)
+            }
+        }
+
+        /// Frame accesses shared by the embedded-hal 0.2 and 1.0 implementations
+        impl<PINS> Spi<$SPIX, PINS> {
+            fn read_frame<W: FrameWord>(&mut self) -> nb::Result<W, Error> {
+                let sr = self.spi.sr.read();
+
+                Err(if sr.ovr().bit_is_set() {
+                    nb::Error::Other(Error::Overrun)
+                } else if sr.modf().bit_is_set() {
+                    nb::Error::Other(Error::ModeFault)
+                } else if sr.crcerr().bit_is_set() {
+                    nb::Error::Other(Error::Crc)
+                } else if sr.rxne().bit_is_set() {
+                    return Ok(unsafe { W::read($SPIX::ptr()) });
+                } else {
+                    nb::Error::WouldBlock
+                })
+            }
+
+            fn send_frame<W: FrameWord>(&mut self, frame: W) -> nb::Result<(), Error> {
+                let sr = self.spi.sr.read();
+
+                Err(if sr.ovr().bit_is_set() {
+                    nb::Error::Other(Error::Overrun)
+                } else if sr.modf().bit_is_set() {
+                    nb::Error::Other(Error::ModeFault)
+                } else if sr.crcerr().bit_is_set() {
+                    nb::Error::Other(Error::Crc)
+                } else if sr.txe().bit_is_set() {
+                    unsafe { W::write($SPIX::ptr(), frame) };
+                    return Ok(());
+                } else {
+                    nb::Error::WouldBlock
+                })
+            }
+
+            /// Sends a frame and waits for the one received meanwhile
+            fn exchange<W: FrameWord>(&mut self, frame: W) -> Result<W, Error> {
+                nb::block!(self.send_frame(frame))?;
+                nb::block!(self.read_frame())
+            }
+        }
+
+        #[cfg(feature = "legacy")]
+        impl<PINS> hal::spi::FullDuplex<u8> for Spi<$SPIX, PINS> {
+            type Error = Error;
+
+            fn read(&mut self) -> nb::Result<u8, Error> {
+                self.read_frame()
+            }
+
+            fn send(&mut self, byte: u8) -> nb::Result<(), Error> {
+                self.send_frame(byte)
+            }
+        }
+
+        /// Frames of 9 to 16 bits
+        #[cfg(feature = "legacy")]
+        impl<PINS> hal::spi::FullDuplex<u16> for Spi<$SPIX, PINS> {
+            type Error = Error;
+
+            fn read(&mut self) -> nb::Result<u16, Error> {
+                self.read_frame()
+            }
+
+            fn send(&mut self, word: u16) -> nb::Result<(), Error> {
+                self.send_frame(word)
+            }
+        }
+
+        /// embedded-hal 1.0 bus, on the same frame accesses as the 0.2 `FullDuplex`
+        #[cfg(feature = "eh1")]
+        impl<PINS, W: FrameWord + Default + 'static> hal1::spi::SpiBus<W> for Spi<$SPIX, PINS> {
+            fn read(&mut self, words: &mut [W]) -> Result<(), Error> {
+                for word in words {
+                    *word = self.exchange(W::default())?;
+                }
+                Ok(())
+            }
+
+            fn write(&mut self, words: &[W]) -> Result<(), Error> {
+                for word in words {
+                    self.exchange(*word)?;
+                }
+                Ok(())
+            }
+
+            fn transfer(&mut self, read: &mut [W], write: &[W]) -> Result<(), Error> {
+                for i in 0..read.len().max(write.len()) {
+                    let word = self.exchange(write.get(i).copied().unwrap_or_default())?;
+                    if let Some(slot) = read.get_mut(i) {
+                        *slot = word;
+                    }
+                }
+                Ok(())
+            }
+
+            fn transfer_in_place(&mut self, words: &mut [W]) -> Result<(), Error> {
+                for word in words {
+                    *word = self.exchange(*word)?;
+                }
+                Ok(())
+            }
+
+            fn flush(&mut self) -> Result<(), Error> {
+                self.wait_idle();
+                Ok(())
+            }
+        }
+
+        impl<PINS> WaitIdle for Spi<$SPIX, PINS> {
+            /// Waits until the TX FIFO is empty and the last frame has been shifted out
+            fn wait_idle(&mut self) {
+                while self.spi.sr.read().ftlvl().bits() != 0 {}
+                while self.spi.sr.read().bsy().bit_is_set() {}
+            }
+        }
+
+        impl<PINS> dma::Target for Spi<$SPIX, PINS> {
+            fn dmamux(&self) -> DmaMuxIndex {
+                DmaMuxIndex::$dmamux_tx
+            }
+
+            fn enable_dma(&mut self) {
+                self.spi.cr2.modify(|_, w| w.txdmaen().set_bit());
+            }
+
+            fn disable_dma(&mut self) {
+                self.spi.cr2.modify(|_, w| w.txdmaen().clear_bit());
+            }
+        }
+
+        impl<PINS> dma::DataTarget for Spi<$SPIX, PINS> {
+            type Word = u8;
+
+            fn data_register(&self) -> u32 {
+                &self.spi.dr as *const _ as u32
+            }
+        }
+
+        impl<PINS> Dump for Spi<$SPIX, PINS> {
+            type Registers = SpiRegisters;
+
+            fn dump(&self) -> SpiRegisters {
+                SpiRegisters {
+                    cr1: self.spi.cr1.read().bits(),
+                    cr2: self.spi.cr2.read().bits(),
+                    sr: self.spi.sr.read().bits(),
+                }
+            }
+        }
+
+        #[cfg(feature = "legacy")]
+        impl<PINS> ::hal::blocking::spi::transfer::Default<u8> for Spi<$SPIX, PINS> {}
+
+        #[cfg(feature = "legacy")]
+        impl<PINS> ::hal::blocking::spi::write::Default<u8> for Spi<$SPIX, PINS> {}
+
+        #[cfg(feature = "legacy")]
+        impl<PINS> ::hal::blocking::spi::transfer::Default<u16> for Spi<$SPIX, PINS> {}
+
+        #[cfg(feature = "legacy")]
+        impl<PINS> ::hal::blocking::spi::write::Default<u16> for Spi<$SPIX, PINS> {}
+    }
+}
+
+#[cfg(feature = "eh1")]
+impl hal1::spi::Error for Error {
+    fn kind(&self) -> hal1::spi::ErrorKind {
+        match self {
+            Error::Overrun => hal1::spi::ErrorKind::Overrun,
+            Error::ModeFault => hal1::spi::ErrorKind::ModeFault,
+            Error::Crc => hal1::spi::ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "eh1")]
+impl<SPI, PINS> hal1::spi::ErrorType for Spi<SPI, PINS> {
+    type Error = Error;
+}
+
+spi!(
+    SPI1,
+    spi1,
+    SPI1_TX,
+    SPI1_RX,
+    sck: [
+        (PA1<DefaultMode>, AltFunction::AF0),
+        (PA5<DefaultMode>, AltFunction::AF0),
+        (PB3<DefaultMode>, AltFunction::AF0),
+        (PD8<DefaultMode>, AltFunction::AF1),
+    ],
+    miso: [
+        (PA6<DefaultMode>, AltFunction::AF0),
+        (PA11<DefaultMode>, AltFunction::AF0),
+        (PB4<DefaultMode>, AltFunction::AF0),
+        (PD5<DefaultMode>, AltFunction::AF1),
+    ],
+    mosi: [
+        (PA2<DefaultMode>, AltFunction::AF0),
+        (PA7<DefaultMode>, AltFunction::AF0),
+        (PA12<DefaultMode>, AltFunction::AF0),
+        (PB5<DefaultMode>, AltFunction::AF0),
+        (PD6<DefaultMode>, AltFunction::AF1),
+    ],
+    nss: [
+        (PA4<DefaultMode>, AltFunction::AF0),
+        (PA15<DefaultMode>, AltFunction::AF0),
+        (PB0<DefaultMode>, AltFunction::AF0),
+        (PD9<DefaultMode>, AltFunction::AF1),
+    ],
+);
+
+spi!(
+    SPI2,
+    spi2,
+    SPI2_TX,
+    SPI2_RX,
+    sck: [
+        (PA0<DefaultMode>, AltFunction::AF0),
+        (PB8<DefaultMode>, AltFunction::AF1),
+        (PB10<DefaultMode>, AltFunction::AF5),
+        (PB13<DefaultMode>, AltFunction::AF0),
+        (PD1<DefaultMode>, AltFunction::AF1),
+    ],
+    miso: [
+        (PA3<DefaultMode>, AltFunction::AF0),
+        (PA9<DefaultMode>, AltFunction::AF4),
+        (PB2<DefaultMode>, AltFunction::AF1),
+        (PB6<DefaultMode>, AltFunction::AF4),
+        (PB14<DefaultMode>, AltFunction::AF0),
+        (PC2<DefaultMode>, AltFunction::AF1),
+        (PD3<DefaultMode>, AltFunction::AF1),
+    ],
+    mosi: [
+        (PA4<DefaultMode>, AltFunction::AF1),
+        (PA10<DefaultMode>, AltFunction::AF0),
+        (PB7<DefaultMode>, AltFunction::AF1),
+        (PB11<DefaultMode>, AltFunction::AF0),
+        (PB15<DefaultMode>, AltFunction::AF0),
+        (PC3<DefaultMode>, AltFunction::AF1),
+        (PD4<DefaultMode>, AltFunction::AF1),
+    ],
+    nss: [
+        (PB9<DefaultMode>, AltFunction::AF5),
+        (PB12<DefaultMode>, AltFunction::AF0),
+        (PD0<DefaultMode>, AltFunction::AF1),
+    ],
+);
@@ -0,0 +1,245 @@
+//! Register map slave
+//!
+//! Lets the device answer like an SPI sensor: the first byte of each transaction selects a
+//! register of a user table, with the read flag in bit 7, and the following bytes are read
+//! from or written to the table starting at that register. The DMA moves the data, the CPU
+//! only decodes the command byte and ends the transaction when NSS goes high.
+//!
+//! The status byte given with [`RegisterFile::set_status`] is sent while the command byte
+//! is received. The response is set up by the SPI interrupt of the command byte, so the
+//! master has to wait for the interrupt latency, a few microseconds, before clocking the
+//! first data byte, as many sensors already require.
+use core::ops::Deref;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use crate::dma::{Channel, Direction, WordSize, WriteBuffer};
+use crate::dmamux::DmaMuxIndex;
+use crate::rcc::Reset;
+use crate::stm32::spi1;
+
+use super::{transfer, Spi};
+
+/// Read flag of the command byte, the other bits are the register address
+pub const READ_FLAG: u8 = 0x80;
+
+/// Transaction completed by [`RegisterFile::on_deselect`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// The master read the registers from `address`
+    Read { address: u8 },
+    /// The master wrote `len` registers from `address`
+    Write { address: u8, len: usize },
+    /// The transaction ended before the command byte, or the address was out of the table
+    Aborted,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Command,
+    Read(u8),
+    Write(u8, usize),
+    Invalid,
+}
+
+/// SPI slave exposing a register table, see the [module documentation](self)
+pub struct RegisterFile<SPI, PINS, RXCH, TXCH, BUF = &'static mut [u8]> {
+    spi: Spi<SPI, PINS>,
+    dr: *mut u8,
+    rx: RXCH,
+    tx: TXCH,
+    rx_mux: DmaMuxIndex,
+    tx_mux: DmaMuxIndex,
+    table: BUF,
+    ptr: *mut u8,
+    len: usize,
+    status: u8,
+    state: State,
+}
+
+impl<SPI, PINS, RXCH, TXCH, BUF> RegisterFile<SPI, PINS, RXCH, TXCH, BUF>
+where
+    SPI: Deref<Target = spi1::RegisterBlock> + Reset,
+    RXCH: Channel,
+    TXCH: Channel,
+    BUF: WriteBuffer<Word = u8>,
+{
+    pub(super) fn new(
+        spi: Spi<SPI, PINS>,
+        dr: *mut u8,
+        rx: RXCH,
+        tx: TXCH,
+        rx_mux: DmaMuxIndex,
+        tx_mux: DmaMuxIndex,
+        mut table: BUF,
+    ) -> Self {
+        let (ptr, len) = unsafe { table.write_buffer() };
+        hal_assert!(len > 0 && len <= READ_FLAG as usize);
+        hal_assert!(
+            spi.spi.cr2.read().ds().bits() == 7,
+            "register files need 8-bit frames"
+        );
+        let mut regfile = RegisterFile {
+            spi,
+            dr,
+            rx,
+            tx,
+            rx_mux,
+            tx_mux,
+            table,
+            ptr,
+            len,
+            status: 0,
+            state: State::Command,
+        };
+        regfile.restart();
+        regfile
+    }
+
+    /// Sets the byte sent during the command byte of the next transactions
+    pub fn set_status(&mut self, status: u8) {
+        self.status = status;
+    }
+
+    /// Registers, as last written by the master
+    ///
+    /// Returns `None` during a transaction, while the DMA accesses the table.
+    pub fn registers(&self) -> Option<&[u8]> {
+        // NOTE(unsafe) the table is owned by the register file, and the DMA is only
+        // enabled by `on_command`, which takes `&mut self`
+        self.is_idle()
+            .then(|| unsafe { core::slice::from_raw_parts(self.ptr, self.len) })
+    }
+
+    /// Registers to update between transactions
+    ///
+    /// Returns `None` during a transaction, while the DMA accesses the table.
+    pub fn registers_mut(&mut self) -> Option<&mut [u8]> {
+        self.is_idle()
+            .then(|| unsafe { core::slice::from_raw_parts_mut(self.ptr, self.len) })
+    }
+
+    /// True between transactions, when the DMA does not access the table
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, State::Command | State::Invalid)
+    }
+
+    /// To be called from the SPI interrupt handler, decodes the command byte and
+    /// starts the DMA transfer of the data bytes
+    pub fn on_command(&mut self) {
+        let spi = &self.spi.spi;
+        if !matches!(self.state, State::Command) || spi.sr.read().rxne().bit_is_clear() {
+            return;
+        }
+        spi.cr2.modify(|_, w| w.rxneie().clear_bit());
+        let command = unsafe { core::ptr::read_volatile(self.dr) };
+        let address = command & !READ_FLAG;
+        let len = match self.len.checked_sub(address as usize) {
+            Some(len) if len > 0 => len,
+            _ => {
+                self.state = State::Invalid;
+                return;
+            }
+        };
+        let dr = self.dr as u32;
+        let mem = self.ptr as u32 + address as u32;
+        if command & READ_FLAG != 0 {
+            transfer::setup(
+                &mut self.tx,
+                dr,
+                Direction::FromMemory,
+                WordSize::BITS8,
+                mem,
+                true,
+                len,
+                self.tx_mux,
+            );
+            compiler_fence(Ordering::Release);
+            self.tx.enable();
+            spi.cr2.modify(|_, w| w.txdmaen().set_bit());
+            self.state = State::Read(address);
+        } else {
+            transfer::setup(
+                &mut self.rx,
+                dr,
+                Direction::FromPeripheral,
+                WordSize::BITS8,
+                mem,
+                true,
+                len,
+                self.rx_mux,
+            );
+            spi.cr2.modify(|_, w| w.rxdmaen().set_bit());
+            self.rx.enable();
+            self.state = State::Write(address, len);
+        }
+    }
+
+    /// To be called on the rising edge of NSS, from the EXTI interrupt handler of the
+    /// NSS pin, ends the transaction and gets ready for the next one
+    pub fn on_deselect(&mut self) -> Access {
+        let access = match self.state {
+            State::Command | State::Invalid => Access::Aborted,
+            State::Read(address) => Access::Read { address },
+            State::Write(address, len) => {
+                self.rx.disable();
+                let mut len = len - self.rx.get_remaining_transfers() as usize;
+                // Bytes still in the RX FIFO were received too
+                let spi = &self.spi.spi;
+                while spi.sr.read().rxne().bit_is_set() {
+                    let byte = unsafe { core::ptr::read_volatile(self.dr) };
+                    if address as usize + len < self.len {
+                        unsafe { *self.ptr.add(address as usize + len) = byte };
+                        len += 1;
+                    }
+                }
+                Access::Write { address, len }
+            }
+        };
+        self.restart();
+        access
+    }
+
+    /// Stops the slave and releases the resources
+    pub fn release(mut self) -> (Spi<SPI, PINS>, RXCH, TXCH, BUF) {
+        self.rx.disable();
+        self.tx.disable();
+        let spi = &self.spi.spi;
+        spi.cr2.modify(|_, w| {
+            w.rxneie()
+                .clear_bit()
+                .txdmaen()
+                .clear_bit()
+                .rxdmaen()
+                .clear_bit()
+        });
+        compiler_fence(Ordering::Acquire);
+        (self.spi, self.rx, self.tx, self.table)
+    }
+
+    /// Flushes the FIFOs and queues the status byte for the next command byte
+    fn restart(&mut self) {
+        self.rx.disable();
+        self.tx.disable();
+        // The DMA writes to the table are done before the table is used again
+        compiler_fence(Ordering::Acquire);
+        let spi = &self.spi.spi;
+        let cr1 = spi.cr1.read().bits();
+        let cr2 = spi.cr2.read().bits();
+        // Only a reset empties the TX FIFO of a slave, it may still hold response bytes
+        unsafe { SPI::reset_unchecked() };
+        spi.cr2.write(|w| unsafe { w.bits(cr2) });
+        spi.cr2.modify(|_, w| {
+            w.frxth()
+                .set_bit()
+                .txdmaen()
+                .clear_bit()
+                .rxdmaen()
+                .clear_bit()
+                .rxneie()
+                .set_bit()
+        });
+        spi.cr1.write(|w| unsafe { w.bits(cr1) });
+        unsafe { core::ptr::write_volatile(self.dr, self.status) };
+        self.state = State::Command;
+    }
+}
@@ -0,0 +1,222 @@
+//! DMA transfers
+//!
+//! The bus is owned by a [`DmaTransfer`] for the time of the transfer, with the DMA
+//! channels and the buffers, and handed back once the last frame has been shifted out.
+//! The word type of the buffers has to match the frame size: `u8` for frames of up to 8
+//! bits, `u16` above.
+use core::ops::Deref;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use crate::dma::{self, Channel, Direction, Event, WordSize};
+use crate::dmamux::DmaMuxIndex;
+use crate::rcc::Reset;
+use crate::stm32::spi1;
+use crate::time::Hertz;
+
+use super::{FrameWord, Spi};
+
+/// Sent while receiving, SD cards and most sensors need the MOSI line high
+pub(super) static DUMMY: u16 = 0xffff;
+
+/// SPI DMA transfer in progress
+///
+/// `RXCH` is `()` for the transmit only transfers of [`Spi::write_dma`]. Dropping the
+/// transfer does not stop it, use [`DmaTransfer::abort`].
+pub struct DmaTransfer<SPI, PINS, TXCH, RXCH, BUF> {
+    pub(super) spi: Spi<SPI, PINS>,
+    pub(super) tx: TXCH,
+    pub(super) rx: RXCH,
+    pub(super) buf: BUF,
+    /// FRXTH setting to restore at the end
+    pub(super) frxth: bool,
+}
+
+impl<SPI, PINS, TXCH, BUF> DmaTransfer<SPI, PINS, TXCH, (), BUF>
+where
+    SPI: Deref<Target = spi1::RegisterBlock> + Reset,
+    TXCH: Channel,
+{
+    /// Returns `true` once the last frame has been sent, or the DMA failed
+    pub fn is_done(&self) -> bool {
+        let sr = self.spi.spi.sr.read();
+        self.tx.event_occurred(Event::TransferError)
+            || (self.tx.event_occurred(Event::TransferComplete)
+                && sr.ftlvl().bits() == 0
+                && sr.bsy().bit_is_clear())
+    }
+
+    /// Enables the interrupt of the TX channel for the given event
+    ///
+    /// The transfer complete interrupt fires when the last words are loaded in the TX
+    /// FIFO, [`Self::wait`] then blocks for the few frames still being shifted out.
+    pub fn listen(&mut self, event: Event) {
+        self.tx.listen(event);
+    }
+
+    /// Disables the interrupt of the TX channel for the given event
+    pub fn unlisten(&mut self, event: Event) {
+        self.tx.unlisten(event);
+    }
+
+    /// Waits for the end of the transfer and releases the resources
+    ///
+    /// The error is returned with the resources if the DMA hit a bus error.
+    #[allow(clippy::type_complexity)]
+    pub fn wait(self) -> Result<(Spi<SPI, PINS>, TXCH, BUF), (Spi<SPI, PINS>, TXCH, BUF)> {
+        while !self.is_done() {}
+        let failed = self.tx.event_occurred(Event::TransferError);
+        let parts = self.abort();
+        if failed {
+            Err(parts)
+        } else {
+            Ok(parts)
+        }
+    }
+
+    /// Stops the transfer and releases the resources
+    ///
+    /// A slave drops the frames its master did not clock out yet.
+    pub fn abort(mut self) -> (Spi<SPI, PINS>, TXCH, BUF) {
+        self.tx.disable();
+        finish(&self.spi.spi, self.frxth);
+        (self.spi, self.tx, self.buf)
+    }
+}
+
+impl<SPI, PINS, TXCH, RXCH, BUF> DmaTransfer<SPI, PINS, TXCH, RXCH, BUF>
+where
+    SPI: Deref<Target = spi1::RegisterBlock> + Reset,
+    TXCH: Channel,
+    RXCH: Channel,
+{
+    /// Returns `true` once the last frame has been received, or the DMA failed
+    pub fn is_done(&self) -> bool {
+        self.rx.event_occurred(Event::TransferComplete) || self.is_error()
+    }
+
+    /// Enables the interrupt of the RX channel for the given event, the transfer
+    /// complete interrupt fires after the last frame
+    pub fn listen(&mut self, event: Event) {
+        self.rx.listen(event);
+    }
+
+    /// Disables the interrupt of the RX channel for the given event
+    pub fn unlisten(&mut self, event: Event) {
+        self.rx.unlisten(event);
+    }
+
+    /// Number of frames left to receive
+    pub fn remaining(&self) -> u16 {
+        self.rx.get_remaining_transfers()
+    }
+
+    /// Waits for the end of the transfer and releases the resources
+    ///
+    /// The error is returned with the resources if the DMA hit a bus error.
+    #[allow(clippy::type_complexity)]
+    pub fn wait(
+        self,
+    ) -> Result<(Spi<SPI, PINS>, TXCH, RXCH, BUF), (Spi<SPI, PINS>, TXCH, RXCH, BUF)> {
+        while !self.is_done() {}
+        let failed = self.is_error();
+        let parts = self.abort();
+        if failed {
+            Err(parts)
+        } else {
+            Ok(parts)
+        }
+    }
+
+    /// Stops the transfer and releases the resources
+    ///
+    /// A slave drops the frames its master did not clock out yet.
+    pub fn abort(mut self) -> (Spi<SPI, PINS>, TXCH, RXCH, BUF) {
+        self.tx.disable();
+        self.rx.disable();
+        finish(&self.spi.spi, self.frxth);
+        (self.spi, self.tx, self.rx, self.buf)
+    }
+
+    fn is_error(&self) -> bool {
+        self.tx.event_occurred(Event::TransferError) || self.rx.event_occurred(Event::TransferError)
+    }
+}
+
+/// Ticks at the `tick` rate taken by `bits` SCK cycles at `sck`, rounded up
+pub(super) fn duration_ticks(bits: u32, sck: Hertz, tick: Hertz) -> u32 {
+    (bits as u64 * tick.raw() as u64).div_ceil(sck.raw() as u64) as u32
+}
+
+/// Checks the frame size and empties the RX FIFO before a transfer, returns the FRXTH
+/// setting to restore afterwards
+pub(super) fn prepare<W: FrameWord>(spi: &spi1::RegisterBlock) -> bool {
+    let bits = spi.cr2.read().ds().bits() as u32 + 1;
+    hal_assert!(
+        (bits > 8) == (W::BITS > 8),
+        "buffer words do not match the frame size"
+    );
+    while spi.sr.read().rxne().bit_is_set() {
+        let _ = spi.dr.read();
+    }
+    // Reading DR then SR clears an overrun
+    let _ = spi.sr.read();
+    // RXNE, and the RX DMA request, for each frame
+    let frxth = spi.cr2.read().frxth().bit_is_set();
+    spi.cr2.modify(|_, w| w.frxth().bit(W::BITS <= 8));
+    frxth
+}
+
+/// Sets up a channel for `len` words between the data register and `mem`
+#[allow(clippy::too_many_arguments)]
+pub(super) fn setup<CH: Channel>(
+    ch: &mut CH,
+    dr: u32,
+    dir: Direction,
+    size: WordSize,
+    mem: u32,
+    inc: bool,
+    len: usize,
+    mux: DmaMuxIndex,
+) {
+    dma::configure(ch, dir, size, len);
+    ch.set_peripheral_address(dr, false);
+    ch.set_memory_address(mem, inc);
+    ch.select_peripheral(mux);
+}
+
+fn finish<SPI>(spi: &SPI, frxth: bool)
+where
+    SPI: Deref<Target = spi1::RegisterBlock> + Reset,
+{
+    if spi.cr1.read().mstr().bit_is_set() {
+        // The master clocks the frames left in the TX FIFO out by itself
+        while spi.sr.read().ftlvl().bits() != 0 {}
+        while spi.sr.read().bsy().bit_is_set() {}
+    } else if spi.sr.read().ftlvl().bits() != 0 {
+        // The master of a slave may never clock them, only a reset empties the TX FIFO
+        let (cr1, cr2, crcpr) = (
+            spi.cr1.read().bits(),
+            spi.cr2.read().bits(),
+            spi.crcpr.read().bits(),
+        );
+        unsafe { SPI::reset_unchecked() };
+        spi.crcpr.write(|w| unsafe { w.bits(crcpr) });
+        spi.cr2.write(|w| unsafe { w.bits(cr2) });
+        spi.cr1.write(|w| unsafe { w.bits(cr1) });
+    }
+    spi.cr2.modify(|_, w| {
+        w.txdmaen()
+            .clear_bit()
+            .rxdmaen()
+            .clear_bit()
+            .frxth()
+            .bit(frxth)
+    });
+    // Frames received by a transmit only transfer are dropped, with the overrun
+    while spi.sr.read().rxne().bit_is_set() {
+        let _ = spi.dr.read();
+    }
+    let _ = spi.sr.read();
+    // The buffer accesses of the DMA are done before the buffers are handed back
+    compiler_fence(Ordering::Acquire);
+}
@@ -0,0 +1,222 @@
+//! # Shared SYSCFG access
+//!
+//! The SYSCFG registers mix settings of unrelated peripherals (I2C Fast-mode Plus drive,
+//! IR modulation, pin remaps, ...). Instead of handing the whole peripheral to one driver,
+//! every driver updates its own fields with an atomic read-modify-write, so the SYSCFG
+//! peripheral never has to be taken or passed around.
+//!
+//! The read-only ITLINE registers tell which peripherals raised a shared interrupt line,
+//! see [`which_fired`].
+use core::ptr;
+
+use cortex_m::interrupt::InterruptNumber;
+
+use crate::stm32::{Interrupt, RCC};
+
+/// SYSCFG_CFGR1 address, the STM32G070 PAC has no SYSCFG peripheral
+const CFGR1: *mut u32 = 0x4001_0000 as *mut u32;
+
+/// Fast-mode Plus drive capability (SYSCFG_CFGR1 FMP bits)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastModePlus {
+    /// PB6 pad, whatever its alternate function
+    PB6 = 16,
+    /// PB7 pad
+    PB7 = 17,
+    /// PB8 pad
+    PB8 = 18,
+    /// PB9 pad
+    PB9 = 19,
+    /// Every pad used by I2C1
+    I2c1 = 20,
+    /// Every pad used by I2C2
+    I2c2 = 21,
+    /// PA9 pad
+    PA9 = 22,
+    /// PA10 pad
+    PA10 = 23,
+}
+
+/// Enables or disables the Fast-mode Plus drive of a pad or of an I2C instance
+///
+/// The I2C driver sets the bit of its instance when configured above 400 kHz, this is for
+/// pads driven by other means (e.g. a bit-banged bus sinking 20 mA).
+pub fn set_fast_mode_plus(fmp: FastModePlus, enable: bool) {
+    let mask = 1 << fmp as u32;
+    modify_cfgr1(mask, if enable { mask } else { 0 });
+}
+
+/// Replaces the `mask` bits of SYSCFG_CFGR1 with `bits`, enabling the SYSCFG clock first
+pub(crate) fn modify_cfgr1(mask: u32, bits: u32) {
+    cortex_m::interrupt::free(|_| unsafe {
+        (*RCC::ptr()).apbenr2.modify(|_, w| w.syscfgen().set_bit());
+        let cfgr1 = ptr::read_volatile(CFGR1);
+        ptr::write_volatile(CFGR1, cfgr1 & !mask | bits & mask);
+    });
+}
+
+/// SYSCFG_CFGR2 address
+const CFGR2: *mut u32 = 0x4001_0018 as *mut u32;
+
+/// SRAM parity error flag (SYSCFG_CFGR2 SPF), cleared by writing 1
+pub(crate) const CFGR2_SPF: u32 = 1 << 8;
+
+/// Sets the `bits` of SYSCFG_CFGR2, enabling the SYSCFG clock first
+///
+/// The lock bits can only be cleared by a reset, and a pending SRAM parity flag is left
+/// as it is unless `bits` clears it.
+pub(crate) fn set_cfgr2(bits: u32) {
+    cortex_m::interrupt::free(|_| unsafe {
+        (*RCC::ptr()).apbenr2.modify(|_, w| w.syscfgen().set_bit());
+        let cfgr2 = ptr::read_volatile(CFGR2);
+        ptr::write_volatile(CFGR2, cfgr2 & !CFGR2_SPF | bits);
+    });
+}
+
+/// Reads SYSCFG_CFGR2, enabling the SYSCFG clock first
+pub(crate) fn read_cfgr2() -> u32 {
+    cortex_m::interrupt::free(|_| unsafe {
+        (*RCC::ptr()).apbenr2.modify(|_, w| w.syscfgen().set_bit());
+        ptr::read_volatile(CFGR2)
+    })
+}
+
+/// SYSCFG_ITLINE0 address, the status registers of the 32 interrupt lines follow
+const ITLINE0: *const u32 = 0x4001_0080 as *const u32;
+
+/// Interrupt source reported by the SYSCFG_ITLINE status registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Wwdg,
+    Pvd,
+    Tamp,
+    Rtc,
+    FlashItf,
+    FlashEcc,
+    Rcc,
+    /// EXTI line 0 to 15
+    Exti(u8),
+    Ucpd1,
+    Ucpd2,
+    /// DMA channel 1 to 7
+    Dma(u8),
+    Dmamux,
+    Adc,
+    Comp1,
+    Comp2,
+    Tim1Ccu,
+    Tim1Trg,
+    Tim1Upd,
+    Tim1Brk,
+    Tim1Cc,
+    Tim2,
+    Tim3,
+    Tim6,
+    Dac,
+    Lptim1,
+    Tim7,
+    Lptim2,
+    Tim14,
+    Tim15,
+    Tim16,
+    Tim17,
+    I2c1,
+    I2c2,
+    Spi1,
+    Spi2,
+    Usart1,
+    Usart2,
+    Usart3,
+    Usart4,
+    Lpuart1,
+    Cec,
+    Rng,
+    Aes,
+}
+
+/// Sources of each interrupt line, with their bit in the ITLINE register
+///
+/// The bits of the peripherals missing on a device read as 0, so the table is shared by
+/// all the variants.
+#[rustfmt::skip]
+const LINES: [&[(u8, Source)]; 32] = {
+    use Source::*;
+    [
+        &[(0, Wwdg)],
+        &[(0, Pvd)],
+        &[(0, Tamp), (1, Rtc)],
+        &[(0, FlashItf), (1, FlashEcc)],
+        &[(0, Rcc)],
+        &[(0, Exti(0)), (1, Exti(1))],
+        &[(0, Exti(2)), (1, Exti(3))],
+        &[
+            (0, Exti(4)), (1, Exti(5)), (2, Exti(6)), (3, Exti(7)),
+            (4, Exti(8)), (5, Exti(9)), (6, Exti(10)), (7, Exti(11)),
+            (8, Exti(12)), (9, Exti(13)), (10, Exti(14)), (11, Exti(15)),
+        ],
+        &[(0, Ucpd1), (1, Ucpd2)],
+        &[(0, Dma(1))],
+        &[(0, Dma(2)), (1, Dma(3))],
+        &[(0, Dmamux), (1, Dma(4)), (2, Dma(5)), (3, Dma(6)), (4, Dma(7))],
+        &[(0, Adc), (1, Comp1), (2, Comp2)],
+        &[(0, Tim1Ccu), (1, Tim1Trg), (2, Tim1Upd), (3, Tim1Brk)],
+        &[(0, Tim1Cc)],
+        &[(0, Tim2)],
+        &[(0, Tim3)],
+        &[(0, Tim6), (1, Dac), (2, Lptim1)],
+        &[(0, Tim7), (1, Lptim2)],
+        &[(0, Tim14)],
+        &[(0, Tim15)],
+        &[(0, Tim16)],
+        &[(0, Tim17)],
+        &[(0, I2c1)],
+        &[(0, I2c2)],
+        &[(0, Spi1)],
+        &[(0, Spi2)],
+        &[(0, Usart1)],
+        &[(0, Usart2)],
+        &[(0, Usart3), (1, Usart4), (2, Lpuart1)],
+        &[(0, Cec)],
+        &[(0, Rng), (1, Aes)],
+    ]
+};
+
+/// Pending sources of an interrupt line, see [`which_fired`]
+pub struct Sources {
+    status: u32,
+    sources: &'static [(u8, Source)],
+}
+
+impl Iterator for Sources {
+    type Item = Source;
+
+    fn next(&mut self) -> Option<Source> {
+        while let Some((&(bit, source), rest)) = self.sources.split_first() {
+            self.sources = rest;
+            if self.status & (1 << bit) != 0 {
+                return Some(source);
+            }
+        }
+        None
+    }
+}
+
+/// Returns the sources with a pending request on the line of `irq`
+///
+/// Meant for the handlers of shared vectors (e.g. `DMA_CHANNEL4_5_6_7` or `USART3_4`): a
+/// single register read tells which peripherals to service. The status is sampled once,
+/// requests raised while iterating show up on the next call.
+pub fn which_fired(irq: Interrupt) -> Sources {
+    let line = irq.number() as usize;
+    let sources = LINES.get(line).copied().unwrap_or(&[]);
+    let status = if sources.is_empty() {
+        0
+    } else {
+        let rcc = unsafe { &*RCC::ptr() };
+        if rcc.apbenr2.read().syscfgen().bit_is_clear() {
+            cortex_m::interrupt::free(|_| rcc.apbenr2.modify(|_, w| w.syscfgen().set_bit()));
+        }
+        unsafe { ptr::read_volatile(ITLINE0.add(line)) }
+    };
+    Sources { status, sources }
+}
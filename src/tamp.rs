@@ -0,0 +1,186 @@
+//! # Tamper detection and backup registers
+//!
+//! The TAMP peripheral holds five backup registers, which survive resets, Standby and
+//! Shutdown for as long as VDD or VBAT is present, e.g. to keep boot flags or the state of
+//! an application across a Standby wakeup. The two tamper inputs, TAMP_IN1 on PC13 and
+//! TAMP_IN2 on PA0, detect an enclosure being opened even in the low power modes, and
+//! erase the backup registers unless told otherwise.
+//!
+//! The tamper interrupt is routed to the EXTI line 21, enable it with
+//! `exti.wakeup(Event::TAMP)` to wake the device up from Stop mode.
+use crate::gpio::{gpioa::PA0, gpioc::PC13};
+use crate::rcc::Rcc;
+use crate::stm32::TAMP;
+
+/// Number of backup registers
+pub const BACKUP_REGISTERS: usize = 5;
+
+/// Tamper input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TamperInput {
+    In1 = 0,
+    In2 = 1,
+}
+
+/// Pin of a tamper input, the tamper function overrides the GPIO configuration
+pub trait TamperPin {
+    const INPUT: TamperInput;
+}
+
+impl<MODE> TamperPin for PC13<MODE> {
+    const INPUT: TamperInput = TamperInput::In1;
+}
+
+impl<MODE> TamperPin for PA0<MODE> {
+    const INPUT: TamperInput = TamperInput::In2;
+}
+
+/// Level of the input on a tamper event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TamperLevel {
+    High,
+    Low,
+}
+
+/// Tamper detection (TAMP_FLTCR TAMPFLT)
+///
+/// The level detections sample the inputs at 1 Hz and raise the event after 2, 4 or 8
+/// consecutive samples at the active level. The inputs are precharged through their
+/// pull-up before each sample, so a switch to ground works without an external resistor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TamperFilter {
+    /// The event is raised on the edge to the active level, the inputs are not sampled
+    Edge = 0b00,
+    Level2 = 0b01,
+    Level4 = 0b10,
+    Level8 = 0b11,
+}
+
+/// Tamper detection and backup registers
+pub struct Tamper {
+    rb: TAMP,
+    filter: TamperFilter,
+}
+
+impl Tamper {
+    pub fn new(tamp: TAMP, rcc: &mut Rcc) -> Self {
+        // The TAMP registers are clocked by the RTC APB clock
+        rcc.apbenr1.modify(|_, w| w.rtcapben().set_bit());
+        rcc.unlock_rtc();
+        Tamper {
+            rb: tamp,
+            filter: TamperFilter::Edge,
+        }
+    }
+
+    /// Returns the value of the backup register `index`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not below [`BACKUP_REGISTERS`].
+    pub fn read_backup(&self, index: usize) -> u32 {
+        hal_assert!(index < BACKUP_REGISTERS);
+        match index {
+            0 => self.rb.bkp0r.read().bits(),
+            1 => self.rb.bkp1r.read().bits(),
+            2 => self.rb.bkp2r.read().bits(),
+            3 => self.rb.bkp3r.read().bits(),
+            _ => self.rb.bkp4r.read().bits(),
+        }
+    }
+
+    /// Writes `value` to the backup register `index`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not below [`BACKUP_REGISTERS`].
+    pub fn write_backup(&mut self, index: usize, value: u32) {
+        hal_assert!(index < BACKUP_REGISTERS);
+        match index {
+            0 => self.rb.bkp0r.write(|w| unsafe { w.bits(value) }),
+            1 => self.rb.bkp1r.write(|w| unsafe { w.bits(value) }),
+            2 => self.rb.bkp2r.write(|w| unsafe { w.bits(value) }),
+            3 => self.rb.bkp3r.write(|w| unsafe { w.bits(value) }),
+            _ => self.rb.bkp4r.write(|w| unsafe { w.bits(value) }),
+        }
+    }
+
+    /// Sets the detection of both tamper inputs, to be called before enabling them
+    pub fn set_filter(&mut self, filter: TamperFilter) {
+        self.rb
+            .fltcr
+            .modify(|_, w| unsafe { w.tampflt().bits(filter as u8) });
+        self.filter = filter;
+    }
+
+    /// Enables the tamper input of `pin`, raising an event when it goes to `active`
+    ///
+    /// The backup registers are erased on the event if `erase_backup` is set.
+    pub fn enable<PIN: TamperPin>(&mut self, _pin: &PIN, active: TamperLevel, erase_backup: bool) {
+        // TAMPxTRG selects the rising edge or the low level when cleared
+        let trg = match self.filter {
+            TamperFilter::Edge => active == TamperLevel::Low,
+            _ => active == TamperLevel::High,
+        };
+        let bit = PIN::INPUT as u32;
+        self.rb.cr2.modify(|r, w| unsafe {
+            w.bits(
+                r.bits() & !(1 << (24 + bit) | 1 << bit)
+                    | (trg as u32) << (24 + bit)
+                    | (!erase_backup as u32) << bit,
+            )
+        });
+        self.clear(PIN::INPUT);
+        self.rb
+            .cr1
+            .modify(|r, w| unsafe { w.bits(r.bits() | 1 << bit) });
+    }
+
+    /// Disables the tamper input of `pin`
+    pub fn disable<PIN: TamperPin>(&mut self, _pin: &PIN) {
+        let bit = PIN::INPUT as u32;
+        self.rb
+            .cr1
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << bit)) });
+    }
+
+    /// Returns true if a tamper event occurred on `input`
+    pub fn is_tampered(&self, input: TamperInput) -> bool {
+        self.rb.sr.read().bits() & 1 << input as u32 != 0
+    }
+
+    /// Clears the tamper event flag of `input`
+    pub fn clear(&mut self, input: TamperInput) {
+        self.rb.scr.write(|w| unsafe { w.bits(1 << input as u32) });
+    }
+
+    /// Enables the tamper interrupt of `input`
+    pub fn listen(&mut self, input: TamperInput) {
+        self.rb
+            .ier
+            .modify(|r, w| unsafe { w.bits(r.bits() | 1 << input as u32) });
+    }
+
+    /// Disables the tamper interrupt of `input`
+    pub fn unlisten(&mut self, input: TamperInput) {
+        self.rb
+            .ier
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << input as u32)) });
+    }
+
+    /// Releases the TAMP peripheral, the tamper inputs stay enabled
+    pub fn release(self) -> TAMP {
+        self.rb
+    }
+}
+
+/// Extension trait that sets up the tamper detection
+pub trait TamperExt {
+    fn constrain(self, rcc: &mut Rcc) -> Tamper;
+}
+
+impl TamperExt for TAMP {
+    fn constrain(self, rcc: &mut Rcc) -> Tamper {
+        Tamper::new(self, rcc)
+    }
+}
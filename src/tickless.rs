@@ -0,0 +1,65 @@
+//! Event based idle
+//!
+//! Superloop firmware can sleep with `WFE` between the events it polls for. With
+//! SEVONPEND set, any interrupt becoming pending generates an event, even when it is masked
+//! in the NVIC, so timer and peripheral flags wake up the core without running a handler.
+//! EXTI lines can also generate events directly, see [`ExtiExt::listen_event`].
+//!
+//! [`ExtiExt::listen_event`]: crate::exti::ExtiExt::listen_event
+use cortex_m::asm;
+use cortex_m::interrupt::InterruptNumber;
+use cortex_m::peripheral::{NVIC, SCB};
+
+/// Signals an event to the core, the next `WFE` returns immediately
+pub fn signal() {
+    asm::sev();
+}
+
+/// Sleeps until the next event
+pub fn wait_for_event() {
+    asm::wfe();
+}
+
+pub struct Tickless {
+    scb: SCB,
+}
+
+impl Tickless {
+    /// Enables the events on pending interrupts
+    pub fn new(mut scb: SCB) -> Self {
+        scb.set_sevonpend();
+        Tickless { scb }
+    }
+
+    /// Enters Stop mode instead of Sleep mode while idle, the Stop mode is selected with
+    /// [`Power::set_mode`](crate::power::Power::set_mode)
+    pub fn deep_sleep(&mut self, deep: bool) {
+        if deep {
+            self.scb.set_sleepdeep();
+        } else {
+            self.scb.clear_sleepdeep();
+        }
+    }
+
+    /// Sleeps until the next event or pending interrupt
+    pub fn idle(&mut self) {
+        asm::wfe();
+    }
+
+    /// Sleeps until the given interrupt is pending, then clears its NVIC pending bit
+    ///
+    /// The interrupt should be masked in the NVIC, the peripheral flag that raised it still
+    /// has to be cleared to get the next one.
+    pub fn idle_until<I: InterruptNumber>(&mut self, irq: I) {
+        while !NVIC::is_pending(irq) {
+            asm::wfe();
+        }
+        NVIC::unpend(irq);
+    }
+
+    /// Disables the events on pending interrupts and releases the SCB
+    pub fn release(mut self) -> SCB {
+        self.scb.clear_sevonpend();
+        self.scb
+    }
+}
@@ -1,12 +1,19 @@
 pub use fugit::{
     ExtU32, HertzU32 as Hertz, HoursDurationU32 as Hour, MicrosDurationU32 as MicroSecond,
-    MinutesDurationU32 as Minute, RateExtU32, SecsDurationU32 as Second,
+    MillisDurationU32 as MilliSecond, MinutesDurationU32 as Minute, NanosDurationU32 as NanoSecond,
+    RateExtU32, SecsDurationU32 as Second,
 };
 
 /// Baudrate
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub struct Bps(pub u32);
 
+impl core::fmt::Display for Bps {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} bps", self.0)
+    }
+}
+
 /// A measurement of a monotonically nondecreasing clock
 pub type Instant = fugit::TimerInstantU32<1_000_000>;
 
@@ -82,7 +89,7 @@ pub trait U32Ext {
 
 impl U32Ext for u32 {
     fn bps(self) -> Bps {
-        assert!(self > 0);
+        hal_assert!(self > 0);
         Bps(self)
     }
     fn day(self) -> MonthDay {
@@ -106,9 +113,57 @@ pub fn duration(hz: Hertz, cycles: u32) -> MicroSecond {
 }
 
 pub fn cycles(ms: MicroSecond, clk: Hertz) -> u32 {
-    assert!(ms.ticks() > 0);
+    hal_assert!(ms.ticks() > 0);
     let clk = clk.raw() as u64;
     let period = ms.ticks() as u64;
     let cycles = clk.saturating_mul(period) / 1_000_000_u64;
     cycles as u32
 }
+
+/// Checked arithmetic missing from `fugit` durations and rates
+pub trait CheckedExt: Sized {
+    /// Multiplies by `rhs`, returns `None` on overflow
+    fn checked_mul(self, rhs: u32) -> Option<Self>;
+
+    /// Divides by `rhs`, returns `None` if `rhs` is zero
+    fn checked_div(self, rhs: u32) -> Option<Self>;
+}
+
+impl<const NOM: u32, const DENOM: u32> CheckedExt for fugit::Duration<u32, NOM, DENOM> {
+    fn checked_mul(self, rhs: u32) -> Option<Self> {
+        self.ticks().checked_mul(rhs).map(Self::from_ticks)
+    }
+
+    fn checked_div(self, rhs: u32) -> Option<Self> {
+        self.ticks().checked_div(rhs).map(Self::from_ticks)
+    }
+}
+
+impl<const NOM: u32, const DENOM: u32> CheckedExt for fugit::Rate<u32, NOM, DENOM> {
+    fn checked_mul(self, rhs: u32) -> Option<Self> {
+        self.raw().checked_mul(rhs).map(Self::from_raw)
+    }
+
+    fn checked_div(self, rhs: u32) -> Option<Self> {
+        self.raw().checked_div(rhs).map(Self::from_raw)
+    }
+}
+
+/// Converts nanoseconds to microseconds, rounding up so minimum timings are kept
+pub fn micros_from_nanos(ns: NanoSecond) -> MicroSecond {
+    MicroSecond::from_ticks((ns.ticks() / 1_000) + (ns.ticks() % 1_000 != 0) as u32)
+}
+
+/// Period of one cycle at `freq`
+pub fn period(freq: Hertz) -> NanoSecond {
+    hal_assert!(freq.raw() > 0);
+    let ns = 1_000_000_000_u64 / freq.raw() as u64;
+    NanoSecond::from_ticks(ns as u32)
+}
+
+/// Frequency of a signal with the given period, rounded to the nearest Hertz
+pub fn frequency(period: NanoSecond) -> Hertz {
+    hal_assert!(period.ticks() > 0);
+    let ns = period.ticks() as u64;
+    Hertz::from_raw(((1_000_000_000_u64 + ns / 2) / ns) as u32)
+}
@@ -0,0 +1,192 @@
+//! # Alarms on a free-running timer
+//!
+//! The counter runs over its whole range at a fixed tick rate and is never reloaded, so it
+//! can be used as the time base of an async executor or of a soft-timer wheel. The
+//! overflows are counted by [`Alarm::on_interrupt`] to extend the counter to 64-bit ticks.
+//! The compare channel 1 generates the interrupt at an absolute tick, alarms beyond the
+//! current counter period are armed when the counter reaches their period.
+//!
+//! Both the update and the compare interrupts are used by the alarm, the timer interrupt
+//! has to be unmasked in the NVIC and its handler has to call [`Alarm::on_interrupt`].
+use cortex_m::interrupt;
+
+use crate::rcc::*;
+use crate::stm32::*;
+use crate::time::Hertz;
+use crate::timer::Instance;
+
+pub trait AlarmExt<TIM> {
+    fn alarm(self, tick: Hertz, rcc: &mut Rcc) -> Alarm<TIM>;
+}
+
+/// Free-running timer with an alarm, see the [module documentation](self)
+pub struct Alarm<TIM> {
+    tim: TIM,
+    tick: Hertz,
+    periods: u64,
+    alarm: Option<u64>,
+}
+
+macro_rules! alarms {
+    ($($TIM:ident: $tim:ident,)+) => {
+        $(
+            impl Alarm<$TIM> {
+                /// Starts the counter at the `tick` rate, from tick 0
+                ///
+                /// # Panics
+                ///
+                /// Panics if the timer clock is not a multiple of `tick`, or `tick` is below
+                /// the timer clock divided by 65536.
+                pub fn $tim(tim: $TIM, tick: Hertz, rcc: &mut Rcc) -> Self {
+                    let clk = rcc.clocks.apb_tim_clk.raw();
+                    hal_assert!(tick.raw() > 0 && clk.is_multiple_of(tick.raw()));
+                    let psc = clk / tick.raw() - 1;
+                    hal_assert!(psc <= u16::MAX as u32);
+
+                    $TIM::enable(rcc);
+                    $TIM::reset(rcc);
+                    $TIM::set_prescaler(psc as u16);
+                    $TIM::set_auto_reload($TIM::max_auto_reload());
+                    $TIM::set_update_source(crate::timer::UpdateSource::CounterOnly);
+                    $TIM::apply_settings_silently();
+                    $TIM::clear_update();
+                    $TIM::listen_update(true);
+                    $TIM::start(false);
+
+                    Alarm {
+                        tim,
+                        tick,
+                        periods: 0,
+                        alarm: None,
+                    }
+                }
+
+                /// Tick rate of the counter
+                pub fn tick_rate(&self) -> Hertz {
+                    self.tick
+                }
+
+                /// Ticks since the timer was started
+                pub fn now(&self) -> u64 {
+                    interrupt::free(|_| {
+                        let mut periods = self.periods;
+                        let mut counter = $TIM::counter();
+                        // Overflow not handled by `on_interrupt` yet, the counter is read
+                        // again as it may have been read before the overflow
+                        if self.tim.sr.read().uif().bit_is_set() {
+                            periods += 1;
+                            counter = $TIM::counter();
+                        }
+                        periods << $TIM::WIDTH | counter as u64
+                    })
+                }
+
+                /// Sets the alarm at the absolute tick `at`, replacing the previous one
+                ///
+                /// Returns `false` without setting the alarm if `at` has already been
+                /// reached, the caller then runs the expired work straight away.
+                pub fn set_alarm(&mut self, at: u64) -> bool {
+                    self.cancel_alarm();
+                    if at <= self.now() {
+                        return false;
+                    }
+                    self.alarm = Some(at);
+                    self.arm();
+                    // The counter may have gone past the compare value while it was written
+                    if at <= self.now() {
+                        self.cancel_alarm();
+                        return false;
+                    }
+                    true
+                }
+
+                /// Returns the tick of the pending alarm
+                pub fn alarm(&self) -> Option<u64> {
+                    self.alarm
+                }
+
+                /// Cancels the pending alarm
+                pub fn cancel_alarm(&mut self) {
+                    self.alarm = None;
+                    self.tim.dier.modify(|_, w| w.cc1ie().clear_bit());
+                    Self::clear_compare();
+                }
+
+                /// To be called from the timer interrupt handler
+                ///
+                /// Counts the counter overflows and returns `true` when the alarm has
+                /// fired, the alarm is then cleared.
+                pub fn on_interrupt(&mut self) -> bool {
+                    interrupt::free(|_| {
+                        if self.tim.sr.read().uif().bit_is_set() {
+                            $TIM::clear_update();
+                            self.periods += 1;
+                            self.arm();
+                        }
+                    });
+                    // The compare flag only raises the interrupt, an alarm armed at the
+                    // start of a period may already have been passed
+                    Self::clear_compare();
+                    match self.alarm {
+                        Some(at) if at <= self.now() => {
+                            self.cancel_alarm();
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+
+                /// Stops the timer and releases it
+                pub fn release(mut self) -> $TIM {
+                    self.cancel_alarm();
+                    $TIM::listen_update(false);
+                    $TIM::stop();
+                    self.tim
+                }
+
+                /// Enables the compare interrupt if the alarm falls in the current
+                /// counter period
+                fn arm(&mut self) {
+                    if let Some(at) = self.alarm {
+                        if at >> $TIM::WIDTH == self.periods {
+                            let compare = (at & $TIM::max_auto_reload() as u64) as u32;
+                            unsafe { (*$TIM::ptr()).ccr1.write(|w| w.bits(compare)) };
+                            Self::clear_compare();
+                            self.tim.dier.modify(|_, w| w.cc1ie().set_bit());
+                        }
+                    }
+                }
+
+                /// Clears CC1IF, without the read-modify-write that could clear an update
+                /// flag set meanwhile
+                fn clear_compare() {
+                    unsafe { (*$TIM::ptr()).sr.write(|w| w.bits(!(1 << 1))) };
+                }
+            }
+
+            impl AlarmExt<$TIM> for $TIM {
+                fn alarm(self, tick: Hertz, rcc: &mut Rcc) -> Alarm<$TIM> {
+                    Alarm::$tim(self, tick, rcc)
+                }
+            }
+        )+
+    }
+}
+
+alarms! {
+    TIM1: tim1,
+    TIM3: tim3,
+    TIM14: tim14,
+    TIM16: tim16,
+    TIM17: tim17,
+}
+
+#[cfg(feature = "stm32g0x1")]
+alarms! {
+    TIM2: tim2,
+}
+
+#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
+alarms! {
+    TIM15: tim15,
+}
@@ -0,0 +1,327 @@
+//! # PWM input
+//!
+//! Measures the period and the high time of a signal on the channel 1 input: IC1 captures
+//! the rising edges and resets the counter through the slave mode controller, IC2
+//! captures the falling edges. The input digital filter and the capture prescaler clean
+//! up and decimate noisy signals (fan tachometers, hall sensors) in hardware.
+//!
+//! [`PwmInput::measure_average`] measures the average period over many periods instead,
+//! with the counter running freely and its overflows counted, down to a fraction of ppm
+//! for reference clocks and crystal calibration.
+use core::convert::Infallible;
+
+use crate::rcc::*;
+use crate::stm32::*;
+use crate::time::Hertz;
+use crate::timer::pins::TimerPin;
+use crate::timer::{Channel1, Instance};
+
+/// Input digital filter (ICxF): sampling clock and number of consecutive equal samples
+/// needed to validate a transition
+///
+/// `CkIntNx` sample at the timer clock, `DtsDivyNx` at the timer clock divided by `y`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFilter {
+    NoFilter = 0b0000,
+    CkIntN2 = 0b0001,
+    CkIntN4 = 0b0010,
+    CkIntN8 = 0b0011,
+    DtsDiv2N6 = 0b0100,
+    DtsDiv2N8 = 0b0101,
+    DtsDiv4N6 = 0b0110,
+    DtsDiv4N8 = 0b0111,
+    DtsDiv8N6 = 0b1000,
+    DtsDiv8N8 = 0b1001,
+    DtsDiv16N5 = 0b1010,
+    DtsDiv16N6 = 0b1011,
+    DtsDiv16N8 = 0b1100,
+    DtsDiv32N5 = 0b1101,
+    DtsDiv32N6 = 0b1110,
+    DtsDiv32N8 = 0b1111,
+}
+
+/// Capture prescaler (ICxPSC): a capture is done every 1, 2, 4 or 8 valid edges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapturePrescaler {
+    Div1 = 0b00,
+    Div2 = 0b01,
+    Div4 = 0b10,
+    Div8 = 0b11,
+}
+
+/// Period and high time of the input signal, in counter ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PwmReading {
+    pub period: u32,
+    pub high: u32,
+}
+
+impl PwmReading {
+    /// Frequency of the signal, for a counter running at `tick`
+    pub fn frequency(&self, tick: Hertz) -> Hertz {
+        Hertz::from_raw(tick.raw() / self.period.max(1))
+    }
+
+    /// High time of the signal, in parts per thousand of the period
+    pub fn duty_permille(&self) -> u32 {
+        (self.high as u64 * 1000 / self.period.max(1) as u64) as u32
+    }
+}
+
+/// Average period measured by [`PwmInput::measure_average`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeriodAverage {
+    /// Counter ticks elapsed over the periods
+    pub ticks: u64,
+    /// Number of periods measured
+    pub periods: u32,
+}
+
+impl PeriodAverage {
+    /// Average period, in counter ticks rounded down
+    pub fn period(&self) -> u64 {
+        self.ticks / self.periods as u64
+    }
+
+    /// Frequency of the signal in millihertz, for a counter running at `tick`
+    pub fn frequency_millihertz(&self, tick: Hertz) -> u64 {
+        (tick.raw() as u128 * self.periods as u128 * 1000 / self.ticks.max(1) as u128) as u64
+    }
+
+    /// Deviation of the frequency of the signal from `nominal`, in parts per million
+    pub fn deviation_ppm(&self, tick: Hertz, nominal: Hertz) -> i32 {
+        let measured = tick.raw() as i128 * self.periods as i128 * 1_000_000;
+        let expected = nominal.raw() as i128 * self.ticks.max(1) as i128;
+        ((measured - expected) / expected.max(1)) as i32
+    }
+}
+
+/// Error of [`PwmInput::measure_average`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeasureError {
+    /// A capture was lost, the signal is too fast to be measured
+    Overcapture,
+    /// No edge for a longer time than the minimum frequency allows
+    NoSignal,
+}
+
+/// PWM input on the channel 1 of a timer
+pub struct PwmInput<TIM, PIN> {
+    tim: TIM,
+    pin: PIN,
+    tick: Hertz,
+}
+
+pub trait PwmInputExt<PIN>: Sized {
+    /// Measures the signal on `pin`, down to `min_freq` before the counter overflows
+    fn pwm_input(self, pin: PIN, min_freq: Hertz, rcc: &mut Rcc) -> PwmInput<Self, PIN>;
+}
+
+macro_rules! pwm_input {
+    ($($TIMX:ident,)+) => {
+        $(
+            impl<PIN> PwmInputExt<PIN> for $TIMX
+            where
+                PIN: TimerPin<$TIMX, Channel = Channel1>,
+            {
+                fn pwm_input(
+                    self,
+                    pin: PIN,
+                    min_freq: Hertz,
+                    rcc: &mut Rcc,
+                ) -> PwmInput<Self, PIN> {
+                    $TIMX::enable(rcc);
+                    $TIMX::reset(rcc);
+                    pin.setup();
+
+                    let clk = rcc.clocks.apb_tim_clk.raw();
+                    let max_ticks = $TIMX::max_auto_reload() as u64 + 1;
+                    let psc = (clk as u64)
+                        .div_ceil(min_freq.raw() as u64 * max_ticks)
+                        .clamp(1, 0x1_0000);
+                    $TIMX::set_prescaler((psc - 1) as u16);
+                    $TIMX::set_auto_reload($TIMX::max_auto_reload());
+                    self.egr.write(|w| w.ug().set_bit());
+
+                    // IC1 mapped on TI1 (CC1S = 01), IC2 mapped on TI1 (CC2S = 10)
+                    self.ccmr1_input().write(|w| unsafe { w.bits(0b10 << 8 | 0b01) });
+                    // IC1 captures the rising edges, IC2 the falling edges
+                    self.ccer.write(|w| {
+                        w.cc1p()
+                            .clear_bit()
+                            .cc1e()
+                            .set_bit()
+                            .cc2p()
+                            .set_bit()
+                            .cc2e()
+                            .set_bit()
+                    });
+                    // Reset mode (SMS = 100) triggered by TI1FP1 (TS = 101)
+                    self.smcr.write(|w| unsafe { w.bits(0b101 << 4 | 0b100) });
+                    $TIMX::start(false);
+
+                    PwmInput {
+                        tim: self,
+                        pin,
+                        tick: Hertz::from_raw(clk / psc as u32),
+                    }
+                }
+            }
+
+            impl<PIN> PwmInput<$TIMX, PIN>
+            where
+                PIN: TimerPin<$TIMX, Channel = Channel1>,
+            {
+                /// Counter clock, the unit of the readings
+                pub fn tick(&self) -> Hertz {
+                    self.tick
+                }
+
+                /// Sets the digital filter of the input
+                pub fn set_filter(&mut self, filter: InputFilter) {
+                    // TI1 feeds both captures, its filter is IC1F
+                    self.tim.ccmr1_input().modify(|r, w| unsafe {
+                        w.bits(r.bits() & !(0xf << 4) | (filter as u32) << 4)
+                    });
+                }
+
+                /// Only captures every 2, 4 or 8 periods, to reduce the capture rate
+                ///
+                /// The counter is still reset on every rising edge, so the readings keep
+                /// measuring a single period.
+                pub fn set_prescaler(&mut self, prescaler: CapturePrescaler) {
+                    let psc = prescaler as u32;
+                    self.tim.ccmr1_input().modify(|r, w| unsafe {
+                        w.bits(r.bits() & !(0b11 << 2 | 0b11 << 10) | psc << 2 | psc << 10)
+                    });
+                }
+
+                /// Returns the last period and high time captured since the previous reading
+                pub fn read(&mut self) -> nb::Result<PwmReading, Infallible> {
+                    let sr = self.tim.sr.read();
+                    if sr.cc1if().bit_is_clear() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    // Reading CCR1 clears CC1IF
+                    let period = self.tim.ccr1.read().bits() + 1;
+                    let high = self.tim.ccr2.read().bits() + 1;
+                    // Clear the overcapture flags, the other flags are not affected by 1s
+                    self.tim.sr.write(|w| unsafe { w.bits(!(1 << 9 | 1 << 10)) });
+                    Ok(PwmReading { period, high })
+                }
+
+                /// Measures the average period of the signal over `n_periods` periods
+                ///
+                /// The counter runs freely during the measurement, the first and last
+                /// edges are captured and the overflows in between are counted, so the
+                /// result is accurate to one tick over the whole measurement. The capture
+                /// prescaler is set to the largest of 8, 4 or 2 periods dividing
+                /// `n_periods`, which allows signals up to a few MHz. The capture interrupt
+                /// has to be disabled, the measurement blocks until it is done or no edge
+                /// comes for about 8 counter overflows. The PWM input is restored after it.
+                ///
+                /// # Panics
+                ///
+                /// Panics if `n_periods` is 0.
+                pub fn measure_average(
+                    &mut self,
+                    n_periods: u32,
+                ) -> Result<PeriodAverage, MeasureError> {
+                    hal_assert!(n_periods > 0);
+                    let (prescaler, psc) = match n_periods.trailing_zeros() {
+                        0 => (CapturePrescaler::Div1, 1),
+                        1 => (CapturePrescaler::Div2, 2),
+                        2 => (CapturePrescaler::Div4, 4),
+                        _ => (CapturePrescaler::Div8, 8),
+                    };
+                    let captures = n_periods / psc;
+                    let wrap = $TIMX::max_auto_reload() as u64 + 1;
+                    let ccmr1 = self.tim.ccmr1_input().read().bits();
+                    let smcr = self.tim.smcr.read().bits();
+
+                    // Free running counter, with the capture prescaler on IC1
+                    self.tim.smcr.write(|w| unsafe { w.bits(0) });
+                    self.tim.ccmr1_input().modify(|r, w| unsafe {
+                        w.bits(r.bits() & !(0b11 << 2) | (prescaler as u32) << 2)
+                    });
+                    let _ = self.tim.ccr1.read();
+                    $TIMX::clear_update();
+                    self.tim.sr.write(|w| unsafe { w.bits(!(1 << 9)) });
+
+                    let mut overflows = 0u64;
+                    let mut idle = 0;
+                    let mut start = None;
+                    let mut count = 0;
+                    let result = loop {
+                        let sr = self.tim.sr.read();
+                        if sr.cc1of().bit_is_set() {
+                            break Err(MeasureError::Overcapture);
+                        }
+                        if sr.cc1if().bit_is_set() {
+                            let capture = self.tim.ccr1.read().bits() as u64;
+                            // A pending overflow happened before the capture if the counter
+                            // was captured in the lower half of its range
+                            if self.tim.sr.read().uif().bit_is_set() && capture < wrap / 2 {
+                                $TIMX::clear_update();
+                                overflows += 1;
+                            }
+                            let at = overflows * wrap + capture;
+                            idle = 0;
+                            match start {
+                                None => start = Some(at),
+                                Some(start) => {
+                                    count += 1;
+                                    if count == captures {
+                                        break Ok(PeriodAverage {
+                                            ticks: at - start,
+                                            periods: n_periods,
+                                        });
+                                    }
+                                }
+                            }
+                        } else if sr.uif().bit_is_set() {
+                            $TIMX::clear_update();
+                            overflows += 1;
+                            idle += 1;
+                            if idle > 8 {
+                                break Err(MeasureError::NoSignal);
+                            }
+                        }
+                    };
+
+                    self.tim.ccmr1_input().write(|w| unsafe { w.bits(ccmr1) });
+                    self.tim.smcr.write(|w| unsafe { w.bits(smcr) });
+                    let _ = self.tim.ccr1.read();
+                    self.tim.sr.write(|w| unsafe { w.bits(!(1 << 9 | 1 << 10)) });
+                    result
+                }
+
+                /// Enables raising the timer interrupt on each period capture
+                pub fn listen(&mut self) {
+                    self.tim.dier.modify(|_, w| w.cc1ie().set_bit());
+                }
+
+                /// Disables the capture interrupt
+                pub fn unlisten(&mut self) {
+                    self.tim.dier.modify(|_, w| w.cc1ie().clear_bit());
+                }
+
+                /// Stops the timer and releases it with the input pin
+                pub fn release(self) -> ($TIMX, PIN) {
+                    $TIMX::stop();
+                    (self.tim, self.pin.release())
+                }
+            }
+        )+
+    }
+}
+
+pwm_input! {
+    TIM1,
+    TIM3,
+}
+
+#[cfg(feature = "stm32g0x1")]
+pwm_input! {
+    TIM2,
+}
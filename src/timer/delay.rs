@@ -1,7 +1,9 @@
 //! Delays
 use core::cmp;
 use cortex_m::peripheral::SYST;
+#[cfg(any(feature = "legacy", feature = "eh1"))]
 use fugit::ExtU32;
+#[cfg(feature = "legacy")]
 use hal::blocking::delay::{DelayMs, DelayUs};
 
 use crate::rcc::*;
@@ -46,36 +48,42 @@ impl Delay<SYST> {
     }
 }
 
+#[cfg(feature = "legacy")]
 impl DelayUs<u32> for Delay<SYST> {
     fn delay_us(&mut self, us: u32) {
         self.delay(us.micros())
     }
 }
 
+#[cfg(feature = "legacy")]
 impl DelayUs<u16> for Delay<SYST> {
     fn delay_us(&mut self, us: u16) {
         self.delay_us(us as u32)
     }
 }
 
+#[cfg(feature = "legacy")]
 impl DelayUs<u8> for Delay<SYST> {
     fn delay_us(&mut self, us: u8) {
         self.delay_us(us as u32)
     }
 }
 
+#[cfg(feature = "legacy")]
 impl DelayMs<u32> for Delay<SYST> {
     fn delay_ms(&mut self, ms: u32) {
         self.delay_us(ms.saturating_mul(1_000));
     }
 }
 
+#[cfg(feature = "legacy")]
 impl DelayMs<u16> for Delay<SYST> {
     fn delay_ms(&mut self, ms: u16) {
         self.delay_ms(ms as u32);
     }
 }
 
+#[cfg(feature = "legacy")]
 impl DelayMs<u8> for Delay<SYST> {
     fn delay_ms(&mut self, ms: u8) {
         self.delay_ms(ms as u32);
@@ -122,36 +130,42 @@ macro_rules! delays {
                 }
             }
 
+            #[cfg(feature = "legacy")]
             impl DelayUs<u32> for Delay<$TIM> {
                 fn delay_us(&mut self, us: u32) {
                     self.delay(us.micros())
                 }
             }
 
+            #[cfg(feature = "legacy")]
             impl DelayUs<u16> for Delay<$TIM> {
                 fn delay_us(&mut self, us: u16) {
                     self.delay_us(us as u32)
                 }
             }
 
+            #[cfg(feature = "legacy")]
             impl DelayUs<u8> for Delay<$TIM> {
                 fn delay_us(&mut self, us: u8) {
                     self.delay_us(us as u32)
                 }
             }
 
+            #[cfg(feature = "legacy")]
             impl DelayMs<u32> for Delay<$TIM> {
                 fn delay_ms(&mut self, ms: u32) {
                     self.delay_us(ms.saturating_mul(1_000));
                 }
             }
 
+            #[cfg(feature = "legacy")]
             impl DelayMs<u16> for Delay<$TIM> {
                 fn delay_ms(&mut self, ms: u16) {
                     self.delay_ms(ms as u32);
                 }
             }
 
+            #[cfg(feature = "legacy")]
             impl DelayMs<u8> for Delay<$TIM> {
                 fn delay_ms(&mut self, ms: u8) {
                     self.delay_ms(ms as u32);
@@ -186,3 +200,50 @@ delays! {
     TIM7: tim7,
     TIM15: tim15,
 }
+
+/// embedded-hal 1.0 delays, built on the same `delay` as the 0.2 ones
+#[cfg(feature = "eh1")]
+macro_rules! eh1_delays {
+    ($($TIM:ident,)+) => {
+        $(
+            impl hal1::delay::DelayNs for Delay<$TIM> {
+                fn delay_ns(&mut self, ns: u32) {
+                    self.delay(crate::time::micros_from_nanos(ns.nanos()));
+                }
+
+                fn delay_us(&mut self, us: u32) {
+                    self.delay(us.micros());
+                }
+
+                fn delay_ms(&mut self, ms: u32) {
+                    self.delay(ms.saturating_mul(1_000).micros());
+                }
+            }
+        )+
+    }
+}
+
+#[cfg(feature = "eh1")]
+eh1_delays! {
+    SYST,
+    TIM1,
+    TIM3,
+    TIM14,
+    TIM16,
+    TIM17,
+}
+
+#[cfg(all(feature = "eh1", feature = "stm32g0x1"))]
+eh1_delays! {
+    TIM2,
+}
+
+#[cfg(all(
+    feature = "eh1",
+    any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081")
+))]
+eh1_delays! {
+    TIM6,
+    TIM7,
+    TIM15,
+}
@@ -0,0 +1,186 @@
+//! # Gated PWM
+//!
+//! The slave gated mode lets the counter run only while an input is at its active level,
+//! e.g. to strobe an illumination PWM in sync with the exposure signal of a camera. The
+//! gate is handled by the timer, without any software latency.
+//!
+//! The counter stops where it is when the gate closes, so the outputs hold their current
+//! level until it opens again: a PWM much faster than the gate, or a pulse at the start of
+//! the period, keeps the illumination within the gate. TIM1, TIM2, TIM3 and TIM15 have a
+//! slave mode controller, TIM14, TIM16 and TIM17 cannot be gated.
+use crate::stm32::*;
+use crate::timer::pins::TimerPin;
+use crate::timer::pwm::Pwm;
+use crate::timer::{Channel1, Channel2, Instance, Polarity};
+
+/// Input gating the counter (SMCR TS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateInput {
+    /// Internal trigger from another timer, see the reference manual for the connections
+    Itr0 = 0b000,
+    Itr1 = 0b001,
+    Itr2 = 0b010,
+    Itr3 = 0b011,
+    /// Filtered channel 1 input, the channel can not be used as an output
+    Ti1 = 0b101,
+    /// Filtered channel 2 input, the channel can not be used as an output
+    Ti2 = 0b110,
+    /// External trigger input, TIM15 has none
+    Etr = 0b111,
+}
+
+/// Channel whose input can gate the counter
+pub trait GateChannel {
+    const INPUT: GateInput;
+}
+
+impl GateChannel for Channel1 {
+    const INPUT: GateInput = GateInput::Ti1;
+}
+
+impl GateChannel for Channel2 {
+    const INPUT: GateInput = GateInput::Ti2;
+}
+
+/// Timer with a slave mode controller
+pub trait GateInstance: Instance {
+    /// Runs the counter only while `input` is at the `active` level (SMS = 0101)
+    fn set_gate(input: GateInput, active: Polarity);
+
+    /// Lets the counter run freely (SMS = 0000)
+    fn clear_gate();
+}
+
+macro_rules! gate_instances {
+    ($($TIM:ident,)+) => {
+        $(
+            impl GateInstance for $TIM {
+                fn set_gate(input: GateInput, active: Polarity) {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    let low = (active == Polarity::ActiveLow) as u32;
+                    match input {
+                        // Unfiltered and unscaled TIx input (CCxS = 01, ICxPSC = ICxF = 0), CCxP
+                        // selects its active level, CCxNP is cleared
+                        GateInput::Ti1 => {
+                            tim.ccmr1_input().modify(|r, w| unsafe { w.bits(r.bits() & !0xff | 0b01) });
+                            tim.ccer.modify(|r, w| unsafe { w.bits(r.bits() & !0b1010 | low << 1) });
+                        }
+                        GateInput::Ti2 => {
+                            tim.ccmr1_input().modify(|r, w| unsafe { w.bits(r.bits() & !0xff00 | 0b01 << 8) });
+                            tim.ccer.modify(|r, w| unsafe { w.bits(r.bits() & !0b1010_0000 | low << 5) });
+                        }
+                        GateInput::Etr => {
+                            tim.smcr.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 15) | low << 15) });
+                        }
+                        _ => {
+                            hal_assert!(low == 0, "internal triggers gate while high");
+                        }
+                    }
+                    // SMS[3:0] and TS[4:0], with TS[4:3] cleared
+                    let bits = (input as u32) << 4 | 0b101;
+                    tim.smcr.modify(|r, w| unsafe { w.bits(r.bits() & !0x0031_0077 | bits) });
+                }
+
+                fn clear_gate() {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.smcr.modify(|r, w| unsafe { w.bits(r.bits() & !0x0031_0077) });
+                }
+            }
+        )+
+    }
+}
+
+gate_instances! {
+    TIM1,
+    TIM3,
+}
+
+#[cfg(feature = "stm32g0x1")]
+gate_instances! {
+    TIM2,
+}
+
+/// Some PACs share the TIM16 register block with TIM15, which lacks SMCR
+#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
+mod tim15_gate {
+    use super::*;
+
+    const SMCR_OFFSET: usize = 0x08;
+
+    fn smcr() -> *mut u32 {
+        (TIM15::ptr() as usize + SMCR_OFFSET) as *mut u32
+    }
+
+    impl GateInstance for TIM15 {
+        fn set_gate(input: GateInput, active: Polarity) {
+            let tim = unsafe { &*TIM15::ptr() };
+            let low = (active == Polarity::ActiveLow) as u32;
+            match input {
+                // Same channel input setup as the other timers
+                GateInput::Ti1 => {
+                    tim.ccmr1_input()
+                        .modify(|r, w| unsafe { w.bits(r.bits() & !0xff | 0b01) });
+                    tim.ccer
+                        .modify(|r, w| unsafe { w.bits(r.bits() & !0b1010 | low << 1) });
+                }
+                GateInput::Ti2 => {
+                    tim.ccmr1_input()
+                        .modify(|r, w| unsafe { w.bits(r.bits() & !0xff00 | 0b01 << 8) });
+                    tim.ccer
+                        .modify(|r, w| unsafe { w.bits(r.bits() & !0b1010_0000 | low << 5) });
+                }
+                GateInput::Etr => {
+                    hal_assert!(false, "TIM15 has no external trigger input");
+                }
+                _ => {
+                    hal_assert!(low == 0, "internal triggers gate while high");
+                }
+            }
+            // SMS[3:0] and TS[2:0]
+            let bits = (input as u32) << 4 | 0b101;
+            unsafe {
+                let smcr = smcr();
+                smcr.write_volatile(smcr.read_volatile() & !0x0001_0077 | bits);
+            }
+        }
+
+        fn clear_gate() {
+            unsafe {
+                let smcr = smcr();
+                smcr.write_volatile(smcr.read_volatile() & !0x0001_0077);
+            }
+        }
+    }
+}
+
+impl<TIM: GateInstance> Pwm<TIM> {
+    /// Runs the counter only while `input` is at the `active` level
+    ///
+    /// The internal triggers have no polarity setting, they gate the counter while high.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an internal trigger is given with an active low level, or the external
+    /// trigger for TIM15.
+    pub fn gate(&mut self, input: GateInput, active: Polarity) {
+        TIM::set_gate(input, active);
+    }
+
+    /// Runs the counter only while the channel 1 or channel 2 input on `pin` is at the
+    /// `active` level
+    ///
+    /// The complementary output pins of TIM1 have no input, they can not gate the counter.
+    pub fn gate_with_pin<PIN>(&mut self, pin: PIN, active: Polarity)
+    where
+        PIN: TimerPin<TIM>,
+        PIN::Channel: GateChannel,
+    {
+        pin.setup();
+        TIM::set_gate(PIN::Channel::INPUT, active);
+    }
+
+    /// Lets the counter run regardless of the gate input
+    pub fn ungate(&mut self) {
+        TIM::clear_gate();
+    }
+}
@@ -0,0 +1,237 @@
+//! # Keypad matrix
+//!
+//! [`Keypad`] scans a key matrix from a timer interrupt: one row is driven low at a time,
+//! and the columns, inputs with pull-ups, read low where a key of that row is pressed. The
+//! row is selected one interrupt before its columns are read, which leaves a whole tick for
+//! the lines to settle. The rows have to be open-drain outputs, so that two keys of a
+//! column pressed together do not short two rows. Rows and columns can share a port, with
+//! [`Port::input`](crate::gpio::gpioa::Port::input) for the columns.
+//!
+//! A key changes state after the same level has been read on `debounce` consecutive scans,
+//! the changes are queued as [`KeyEvent`]s for the application.
+//!
+//! Without a diode per key, three keys pressed at three corners of a rectangle also pull
+//! the fourth corner low: the ghost can not be told apart from a real key. A press that
+//! would complete such a rectangle is held back, and only reported once it is no longer
+//! ambiguous, [`Keypad::is_ghosting`] tells the application meanwhile.
+//!
+//! The timer interrupt has to be unmasked in the NVIC and its handler has to call
+//! [`Keypad::on_interrupt`].
+use heapless::Deque;
+
+use crate::gpio::{InputPort, OutputPort};
+use crate::rcc::Rcc;
+use crate::time::Hertz;
+use crate::timer::{setup_ticker, Instance};
+
+/// Scan settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Rows scanned per second
+    pub scan_rate: Hertz,
+    /// Consecutive scans of the same level for a key to change state
+    pub debounce: u8,
+}
+
+impl Config {
+    pub fn new(scan_rate: Hertz) -> Self {
+        Config {
+            scan_rate,
+            debounce: 4,
+        }
+    }
+
+    pub fn debounce(mut self, scans: u8) -> Self {
+        self.debounce = scans;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        // 5 ms per scan of a 4-row keypad, a change is reported after 20 ms
+        Config::new(Hertz::from_raw(800))
+    }
+}
+
+/// Key position in the matrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub row: u8,
+    pub col: u8,
+}
+
+/// Debounced key change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Pressed(Key),
+    Released(Key),
+}
+
+/// Timer driven scan of `R` rows and `C` columns with a queue of `Q` events, see the
+/// [module documentation](self)
+pub struct Keypad<ROWS, COLS, TIM, const R: usize, const C: usize, const Q: usize> {
+    rows: ROWS,
+    cols: COLS,
+    tim: TIM,
+    row_pins: [u16; R],
+    col_pins: [u16; C],
+    row_mask: u16,
+    debounce: u8,
+    /// Debounced pressed keys, one bit per column
+    pressed: [u16; R],
+    /// Consecutive scans reading the other state than `pressed`
+    counts: [[u8; C]; R],
+    events: Deque<KeyEvent, Q>,
+    row: usize,
+    ghosting: bool,
+    ghost_seen: bool,
+    overrun: bool,
+}
+
+impl<ROWS, COLS, TIM, const R: usize, const C: usize, const Q: usize>
+    Keypad<ROWS, COLS, TIM, R, C, Q>
+where
+    ROWS: OutputPort,
+    COLS: InputPort,
+    TIM: Instance,
+{
+    /// Starts the scan
+    ///
+    /// `row_pins` are the masks of the row pins of `rows` and `col_pins` the masks of the
+    /// column pins of `cols`, each entry has one pin. The pins have to be configured
+    /// beforehand, the other pins of the ports are left untouched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no row, no column or more than 16 columns, or if the scan rate or
+    /// `debounce` is 0.
+    pub fn new(
+        rows: ROWS,
+        row_pins: [u16; R],
+        cols: COLS,
+        col_pins: [u16; C],
+        tim: TIM,
+        config: Config,
+        rcc: &mut Rcc,
+    ) -> Self {
+        hal_assert!(R > 0 && C > 0 && C <= 16);
+        hal_assert!(config.scan_rate.raw() > 0 && config.debounce > 0);
+        setup_ticker::<TIM>(config.scan_rate, rcc);
+
+        let mut keypad = Keypad {
+            rows,
+            cols,
+            tim,
+            row_pins,
+            col_pins,
+            row_mask: row_pins.iter().fold(0, |mask, pins| mask | pins),
+            debounce: config.debounce,
+            pressed: [0; R],
+            counts: [[0; C]; R],
+            events: Deque::new(),
+            row: 0,
+            ghosting: false,
+            ghost_seen: false,
+            overrun: false,
+        };
+        keypad.select(0);
+        TIM::listen_update(true);
+        TIM::start(false);
+        keypad
+    }
+
+    /// Returns the oldest queued key change
+    pub fn next_event(&mut self) -> Option<KeyEvent> {
+        self.events.pop_front()
+    }
+
+    /// Returns true if the debounced state of `key` is pressed
+    pub fn is_pressed(&self, key: Key) -> bool {
+        self.pressed
+            .get(key.row as usize)
+            .is_some_and(|cols| key.col < 16 && cols & 1 << key.col != 0)
+    }
+
+    /// Returns true while a press is held back because it may be a ghost key
+    pub fn is_ghosting(&self) -> bool {
+        self.ghosting
+    }
+
+    /// Returns true if events have been dropped because the queue was full since the last
+    /// call
+    pub fn take_overrun(&mut self) -> bool {
+        core::mem::replace(&mut self.overrun, false)
+    }
+
+    /// To be called from the timer interrupt handler, reads the selected row and selects
+    /// the next one
+    pub fn on_interrupt(&mut self) {
+        TIM::clear_update();
+        let levels = self.cols.read_pins();
+        let row = self.row;
+        for col in 0..C {
+            let down = levels & self.col_pins[col] == 0;
+            let bit = 1 << col;
+            if down == (self.pressed[row] & bit != 0) {
+                self.counts[row][col] = 0;
+                continue;
+            }
+            if self.counts[row][col] < self.debounce {
+                self.counts[row][col] += 1;
+            }
+            if self.counts[row][col] < self.debounce {
+                continue;
+            }
+            if down && self.is_ghost(row, bit) {
+                // Checked again on the next scan, until a key of the rectangle is released
+                self.ghost_seen = true;
+                continue;
+            }
+            self.counts[row][col] = 0;
+            self.pressed[row] ^= bit;
+            let key = Key {
+                row: row as u8,
+                col: col as u8,
+            };
+            let event = if down {
+                KeyEvent::Pressed(key)
+            } else {
+                KeyEvent::Released(key)
+            };
+            if self.events.push_back(event).is_err() {
+                self.overrun = true;
+            }
+        }
+
+        self.row = (row + 1) % R;
+        if self.row == 0 {
+            self.ghosting = self.ghost_seen;
+            self.ghost_seen = false;
+        }
+        self.select(self.row);
+    }
+
+    /// Stops the scan and releases the ports and the timer, the rows are released high
+    pub fn release(mut self) -> (ROWS, COLS, TIM) {
+        TIM::stop();
+        TIM::listen_update(false);
+        TIM::clear_update();
+        self.rows.write_pins(self.row_mask, 0);
+        (self.rows, self.cols, self.tim)
+    }
+
+    /// Returns true if pressing `bit` in `row` makes two rows share two pressed columns
+    fn is_ghost(&self, row: usize, bit: u16) -> bool {
+        let cols = self.pressed[row] | bit;
+        self.pressed
+            .iter()
+            .enumerate()
+            .any(|(other, pressed)| other != row && (cols & pressed).count_ones() >= 2)
+    }
+
+    fn select(&mut self, row: usize) {
+        let on = self.row_pins[row];
+        self.rows.write_pins(self.row_mask & !on, on);
+    }
+}
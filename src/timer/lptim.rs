@@ -0,0 +1,472 @@
+//! # Low-power timer
+//!
+//! One-pulse and timeout generation on LPTIM1/LPTIM2. Clocked from LSE or LSI with the
+//! largest prescaler the counter wraps every 256 seconds, longer timeouts are split into
+//! several identical periods counted in software.
+//!
+//! A periodic tick can also be derived from the LSE, e.g. a 1 kHz OS tick that does not
+//! drift when the system clock is a trimmed HSI.
+//!
+//! The input multiplexer also lets the counter follow internal signals: counting the
+//! toggles of a comparator while in Stop mode, or counting the kernel clock from an RTC
+//! or comparator event, e.g. to check the LSI against the LSE clocked RTC.
+use crate::gpio::gpioa::PA4;
+use crate::gpio::gpiob::PB2;
+use crate::gpio::{AltFunction, DefaultMode};
+use crate::rcc::*;
+use crate::stm32::{LPTIM1, LPTIM2};
+use crate::time::{Hertz, MicroSecond, MilliSecond};
+use fugit::RateExtU32;
+use hal::blocking::delay::DelayMs;
+use hal::timer::CountDown;
+use void::Void;
+
+/// LPTIM kernel clock source (RCC_CCIPR LPTIMxSEL)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSource {
+    Pclk = 0b00,
+    LSI = 0b01,
+    HSI16 = 0b10,
+    LSE = 0b11,
+}
+
+impl ClockSource {
+    /// Kernel clock frequency for the given clock configuration
+    pub fn freq(&self, clocks: &Clocks) -> Hertz {
+        match self {
+            ClockSource::Pclk => clocks.apb_clk,
+            ClockSource::LSI => LSI_FREQ.Hz(),
+            ClockSource::HSI16 => HSI_FREQ.Hz(),
+            ClockSource::LSE => LSE_FREQ.Hz(),
+        }
+    }
+}
+
+/// Output waveform
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    /// Output active from the end of the delay to the end of the period
+    Pulse,
+    /// Output stays active once the delay has elapsed (set-once mode)
+    SetOnce,
+}
+
+/// Signal counted by [`LowPowerTimer::start_counting`] (CFGR2 IN1SEL)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    /// LPTIMx_IN1 pin, configured in its alternate function by the application
+    Pin = 0b00,
+    #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+    Comp1 = 0b01,
+    #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+    Comp2 = 0b10,
+}
+
+/// Edges of the input counted (CFGR CKPOL)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CountEdge {
+    Rising = 0b00,
+    Falling = 0b01,
+    /// Both edges, the kernel clock has to be at least four times faster than the input
+    Both = 0b10,
+}
+
+/// External trigger starting [`LowPowerTimer::start_on_trigger`] (CFGR TRIGSEL)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// LPTIMx_ETR pin, configured in its alternate function by the application
+    Pin = 0b000,
+    RtcAlarmA = 0b001,
+    RtcAlarmB = 0b010,
+    Tamp1 = 0b011,
+    Tamp2 = 0b100,
+    #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+    Comp1 = 0b110,
+    #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+    Comp2 = 0b111,
+}
+
+/// Active edge of the external trigger (CFGR TRIGEN)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerEdge {
+    Rising = 0b01,
+    Falling = 0b10,
+    Both = 0b11,
+}
+
+/// LPTIM output pin
+pub trait OutputPin<LPTIM> {
+    fn setup(&self);
+    fn release(self) -> Self;
+}
+
+pub struct LowPowerTimer<LPTIM> {
+    tim: LPTIM,
+    clk: Hertz,
+    periods: u32,
+    elapsed: u32,
+    tick: Option<Tick>,
+}
+
+/// Periodic tick state, the fractional part of the period is spread over the ticks
+struct Tick {
+    rate: u32,
+    cycles: u32,
+    remainder: u32,
+    acc: u32,
+}
+
+impl Tick {
+    /// Length of the next period, in kernel clock cycles
+    fn next_period(&mut self) -> u32 {
+        self.acc += self.remainder;
+        if self.acc >= self.rate {
+            self.acc -= self.rate;
+            self.cycles + 1
+        } else {
+            self.cycles
+        }
+    }
+}
+
+pub trait LowPowerTimerExt: Sized {
+    fn low_power_timer(self, source: ClockSource, rcc: &mut Rcc) -> LowPowerTimer<Self>;
+}
+
+/// Computes the prescaler (as a power of two) and the number of ticks
+fn prescale(cycles: u64) -> (u8, u64) {
+    let mut presc = 0;
+    while presc < 7 && cycles >> presc > 0x1_0000 {
+        presc += 1;
+    }
+    (presc, cycles >> presc)
+}
+
+macro_rules! lptim {
+    ($($LPTIMX:ident: ($lptimx:ident, $lptimxsel:ident, $pin:ty),)+) => {
+        $(
+            impl OutputPin<$LPTIMX> for $pin {
+                fn setup(&self) {
+                    self.set_alt_mode(AltFunction::AF5);
+                }
+
+                fn release(self) -> Self {
+                    self.into_analog()
+                }
+            }
+
+            impl LowPowerTimerExt for $LPTIMX {
+                fn low_power_timer(self, source: ClockSource, rcc: &mut Rcc) -> LowPowerTimer<Self> {
+                    LowPowerTimer::$lptimx(self, source, rcc)
+                }
+            }
+
+            impl LowPowerTimer<$LPTIMX> {
+                /// Configures the LPTIM peripheral with the given kernel clock
+                pub fn $lptimx(tim: $LPTIMX, source: ClockSource, rcc: &mut Rcc) -> Self {
+                    match source {
+                        ClockSource::LSE => rcc.enable_lse(false),
+                        ClockSource::LSI => rcc.enable_lsi(),
+                        ClockSource::HSI16 => rcc.enable_hsi(),
+                        ClockSource::Pclk => {}
+                    }
+                    rcc.ccipr.modify(|_, w| unsafe { w.$lptimxsel().bits(source as u8) });
+                    $LPTIMX::enable(rcc);
+                    $LPTIMX::reset(rcc);
+
+                    LowPowerTimer {
+                        tim,
+                        clk: source.freq(&rcc.clocks),
+                        periods: 1,
+                        elapsed: 0,
+                        tick: None,
+                    }
+                }
+
+                /// Kernel clock of the timer
+                pub fn clock(&self) -> Hertz {
+                    self.clk
+                }
+
+                /// Binds the output pin
+                pub fn bind_pin<PIN: OutputPin<$LPTIMX>>(&mut self, pin: PIN) -> PIN {
+                    pin.setup();
+                    pin
+                }
+
+                /// Outputs a single pulse once `delay` has elapsed
+                ///
+                /// With [`Waveform::SetOnce`] the output stays active after the delay and the
+                /// width only sets the end of the counting period. The whole waveform has to
+                /// fit in one counter period, i.e. 65536 * 128 kernel clock cycles.
+                pub fn pulse(&mut self, delay: MicroSecond, width: MicroSecond, waveform: Waveform) {
+                    let clk = self.clk.raw() as u64;
+                    let cycles = |us: MicroSecond| us.ticks() as u64 * clk / 1_000_000;
+                    let delay = cycles(delay);
+                    let (presc, total) = prescale(delay + cycles(width));
+                    let cmp = delay >> presc;
+                    hal_assert!(total <= 0x1_0000 && cmp < total);
+
+                    self.configure(presc, waveform == Waveform::SetOnce, false);
+                    self.set_compare(cmp as u16);
+                    self.set_auto_reload((total - 1) as u16);
+                    self.periods = 1;
+                    self.elapsed = 0;
+                    self.tick = None;
+                    self.tim.icr.write(|w| w.arrmcf().set_bit());
+                    self.tim.cr.modify(|_, w| w.sngstrt().set_bit());
+                }
+
+                /// Starts a timeout, up to several days with a low-speed kernel clock
+                ///
+                /// Timeouts longer than one counter period are split into equal periods, so
+                /// the actual timeout may be a few kernel clock cycles shorter.
+                pub fn start_timeout(&mut self, timeout: MilliSecond) {
+                    let cycles = timeout.ticks() as u64 * self.clk.raw() as u64 / 1_000;
+                    let (presc, ticks) = prescale(cycles);
+                    let periods = ticks.div_ceil(0x1_0000).max(1);
+                    let arr = (ticks / periods).max(2) - 1;
+
+                    self.configure(presc, false, false);
+                    self.set_compare(0);
+                    self.set_auto_reload(arr as u16);
+                    self.periods = periods as u32;
+                    self.elapsed = 0;
+                    self.tick = None;
+                    self.tim.icr.write(|w| w.arrmcf().set_bit());
+                    self.tim.cr.modify(|_, w| w.cntstrt().set_bit());
+                }
+
+                /// Starts a periodic tick at `rate`, [`wait`](Self::wait) returns once per tick
+                ///
+                /// Periods of one more kernel clock cycle are inserted when needed, so the
+                /// average rate is exact: with the LSE a 1 kHz tick alternates periods of 32 and
+                /// 33 cycles. [`wait`](Self::wait) has to be called (or the interrupt serviced)
+                /// once per tick to keep the average rate.
+                pub fn start_tick(&mut self, rate: Hertz) {
+                    let clk = self.clk.raw();
+                    let rate = rate.raw();
+                    hal_assert!(rate > 0 && clk / rate >= 2 && clk / rate <= 0xffff);
+
+                    let mut tick = Tick {
+                        rate,
+                        cycles: clk / rate,
+                        remainder: clk % rate,
+                        acc: 0,
+                    };
+                    self.configure(0, false, true);
+                    self.set_compare(0);
+                    self.set_auto_reload((tick.next_period() - 1) as u16);
+                    self.tick = Some(tick);
+                    self.tim.icr.write(|w| w.arrmcf().set_bit());
+                    self.tim.cr.modify(|_, w| w.cntstrt().set_bit());
+                }
+
+                /// Waits for the end of the pulse or of the timeout
+                ///
+                /// Has to be polled (or called from the LPTIM interrupt) at least once per
+                /// counter period for timeouts longer than one period.
+                pub fn wait(&mut self) -> nb::Result<(), Void> {
+                    if let Some(tick) = self.tick.as_mut() {
+                        if self.tim.isr.read().arrm().bit_is_clear() {
+                            return Err(nb::Error::WouldBlock);
+                        }
+                        self.tim.icr.write(|w| w.arrmcf().set_bit().arrokcf().set_bit());
+                        // Preloaded, takes effect at the end of the current period
+                        let arr = (tick.next_period() - 1) as u16;
+                        self.tim.arr.write(|w| unsafe { w.arr().bits(arr) });
+                        return Ok(());
+                    }
+                    if self.elapsed >= self.periods {
+                        return Ok(());
+                    }
+                    if self.tim.isr.read().arrm().bit_is_clear() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    self.tim.icr.write(|w| w.arrmcf().set_bit());
+                    self.elapsed += 1;
+                    if self.elapsed < self.periods {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    self.tim.cr.modify(|_, w| w.enable().clear_bit());
+                    Ok(())
+                }
+
+                /// Counts the edges of `input`, the counter wraps after 65536 edges
+                ///
+                /// The edges are sampled by the kernel clock, which keeps running in Stop mode
+                /// with the LSI or the LSE. Read the count with [`count`](Self::count).
+                pub fn start_counting(&mut self, input: Input, edge: CountEdge) {
+                    self.tim.cr.modify(|_, w| w.enable().clear_bit());
+                    self.tim.cfgr2.modify(|_, w| unsafe { w.in1sel().bits(input as u8) });
+                    self.tim.cfgr.write(|w| unsafe {
+                        w.ckpol().bits(edge as u8).countmode().set_bit()
+                    });
+                    self.start_free_running();
+                    self.tim.cr.modify(|_, w| w.cntstrt().set_bit());
+                }
+
+                /// Counts the kernel clock from the first `edge` of `trigger`, the counter wraps
+                /// after 65536 cycles
+                ///
+                /// Reading [`count`](Self::count) when the next event of the same source is
+                /// seen measures the kernel clock against it: with the LSI as kernel clock and
+                /// a periodic RTC alarm, the count is the number of LSI cycles per alarm period.
+                pub fn start_on_trigger(&mut self, trigger: Trigger, edge: TriggerEdge) {
+                    self.tim.cr.modify(|_, w| w.enable().clear_bit());
+                    self.tim.cfgr.write(|w| unsafe {
+                        w.trigsel().bits(trigger as u8).trigen().bits(edge as u8)
+                    });
+                    self.start_free_running();
+                    self.tim.icr.write(|w| w.exttrigcf().set_bit());
+                    self.tim.cr.modify(|_, w| w.cntstrt().set_bit());
+                }
+
+                /// Returns true once the trigger of [`start_on_trigger`](Self::start_on_trigger)
+                /// has started the counter
+                pub fn is_triggered(&self) -> bool {
+                    self.tim.isr.read().exttrig().bit_is_set()
+                }
+
+                /// Current counter value
+                pub fn count(&self) -> u16 {
+                    // The counter runs asynchronously to the APB clock, two identical
+                    // consecutive reads are needed for a reliable value
+                    loop {
+                        let cnt = self.tim.cnt.read().cnt().bits();
+                        if cnt == self.tim.cnt.read().cnt().bits() {
+                            return cnt;
+                        }
+                    }
+                }
+
+                /// Returns true once the pulse or the timeout has been completed
+                pub fn is_done(&self) -> bool {
+                    self.tick.is_none() && self.elapsed >= self.periods
+                }
+
+                /// Stops the counter
+                pub fn cancel(&mut self) {
+                    self.tim.cr.modify(|_, w| w.enable().clear_bit());
+                    self.elapsed = self.periods;
+                    self.tick = None;
+                }
+
+                /// Starts listening for the auto-reload match interrupt
+                ///
+                /// IER can only be written while the timer is disabled, which resets the
+                /// counter: a running count restarts from 0, and a single pulse is started
+                /// again. Call it before starting the timer to keep the current period.
+                pub fn listen(&mut self) {
+                    self.while_disabled(|tim| tim.ier.modify(|_, w| w.arrmie().set_bit()));
+                }
+
+                /// Stops listening for the auto-reload match interrupt
+                ///
+                /// Restarts the count like [`listen`](Self::listen).
+                pub fn unlisten(&mut self) {
+                    self.while_disabled(|tim| tim.ier.modify(|_, w| w.arrmie().clear_bit()));
+                }
+
+                /// Runs `f` with the timer disabled, as needed to write IER, then restores
+                /// the control register
+                ///
+                /// Disabling the timer resets the counter, restoring the start bits restarts
+                /// the count from 0, or a new single pulse.
+                fn while_disabled<F: FnOnce(&$LPTIMX)>(&mut self, f: F) {
+                    // COUNTRST is not written back, it would reset the counter
+                    let cr = self.tim.cr.read().bits() & !(1 << 3);
+                    self.tim.cr.write(|w| unsafe { w.bits(0) });
+                    f(&self.tim);
+                    // The start bits are only taken into account once the timer is enabled
+                    self.tim.cr.write(|w| unsafe { w.bits(cr & 1) });
+                    self.tim.cr.write(|w| unsafe { w.bits(cr) });
+                }
+
+                /// Releases the LPTIM peripheral
+                pub fn release(self) -> $LPTIMX {
+                    self.tim.cr.reset();
+                    self.tim
+                }
+
+                fn configure(&mut self, presc: u8, set_once: bool, preload: bool) {
+                    // CFGR can only be written while the timer is disabled
+                    self.tim.cr.modify(|_, w| w.enable().clear_bit());
+                    self.tim.cfgr.modify(|_, w| unsafe {
+                        w.presc()
+                            .bits(presc)
+                            .wave()
+                            .bit(set_once)
+                            .wavpol()
+                            .clear_bit()
+                            .preload()
+                            .bit(preload)
+                    });
+                    self.tim.cr.modify(|_, w| w.enable().set_bit());
+                }
+
+                /// Enables the timer with the full counter range, CFGR has to be written first
+                fn start_free_running(&mut self) {
+                    self.tim.cr.modify(|_, w| w.enable().set_bit());
+                    self.set_compare(0);
+                    self.set_auto_reload(0xffff);
+                    self.periods = 1;
+                    self.elapsed = 1;
+                    self.tick = None;
+                    self.tim.icr.write(|w| w.arrmcf().set_bit());
+                }
+
+                fn set_compare(&mut self, cmp: u16) {
+                    self.tim.cmp.write(|w| unsafe { w.cmp().bits(cmp) });
+                    while self.tim.isr.read().cmpok().bit_is_clear() {}
+                    self.tim.icr.write(|w| w.cmpokcf().set_bit());
+                }
+
+                fn set_auto_reload(&mut self, arr: u16) {
+                    self.tim.arr.write(|w| unsafe { w.arr().bits(arr) });
+                    while self.tim.isr.read().arrok().bit_is_clear() {}
+                    self.tim.icr.write(|w| w.arrokcf().set_bit());
+                }
+            }
+
+            impl CountDown for LowPowerTimer<$LPTIMX> {
+                type Time = MilliSecond;
+
+                fn start<T>(&mut self, timeout: T)
+                where
+                    T: Into<MilliSecond>,
+                {
+                    self.start_timeout(timeout.into())
+                }
+
+                fn wait(&mut self) -> nb::Result<(), Void> {
+                    self.wait()
+                }
+            }
+
+            impl DelayMs<u32> for LowPowerTimer<$LPTIMX> {
+                fn delay_ms(&mut self, ms: u32) {
+                    self.start_timeout(MilliSecond::from_ticks(ms));
+                    nb::block!(self.wait()).ok();
+                }
+            }
+
+            impl DelayMs<u16> for LowPowerTimer<$LPTIMX> {
+                fn delay_ms(&mut self, ms: u16) {
+                    self.delay_ms(ms as u32);
+                }
+            }
+
+            impl DelayMs<u8> for LowPowerTimer<$LPTIMX> {
+                fn delay_ms(&mut self, ms: u8) {
+                    self.delay_ms(ms as u32);
+                }
+            }
+        )+
+    }
+}
+
+lptim! {
+    LPTIM1: (lptim1, lptim1sel, PB2<DefaultMode>),
+    LPTIM2: (lptim2, lptim2sel, PA4<DefaultMode>),
+}
@@ -0,0 +1,213 @@
+//! # Multiplexed LED matrix
+//!
+//! [`MatrixScan`] lights an LED matrix, or a multi-digit 7-segment display, one row at a
+//! time from a timer interrupt: the row pins select the row, or the common of a digit, and
+//! the column pins, on another port, carry its pattern. Above a refresh rate of about
+//! 100 Hz the eye sees the whole picture, each row being lit for 1 / rows of the time.
+//!
+//! The patterns are double-banked: the application draws in the back bank, and
+//! [`MatrixScan::present`] swaps it with the displayed one at the start of the next frame,
+//! so that a half-drawn picture is never shown. All the lines are turned off between two
+//! rows, so that the pattern of a row does not ghost on the next one.
+//!
+//! The timer interrupt has to be unmasked in the NVIC and its handler has to call
+//! [`MatrixScan::on_interrupt`], which costs a few dozen CPU cycles per row.
+use crate::gpio::OutputPort;
+use crate::rcc::Rcc;
+use crate::time::Hertz;
+use crate::timer::{setup_ticker, Instance};
+
+/// Scan settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Complete frames per second
+    pub refresh: Hertz,
+    /// The row pins are low when the row is lit, e.g. PNP high-side drivers
+    pub rows_active_low: bool,
+    /// The column pins are low when the LED is lit, e.g. a common anode display
+    pub cols_active_low: bool,
+}
+
+impl Config {
+    pub fn new(refresh: Hertz) -> Self {
+        Config {
+            refresh,
+            rows_active_low: false,
+            cols_active_low: false,
+        }
+    }
+
+    pub fn rows_active_low(mut self) -> Self {
+        self.rows_active_low = true;
+        self
+    }
+
+    pub fn cols_active_low(mut self) -> Self {
+        self.cols_active_low = true;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new(Hertz::from_raw(200))
+    }
+}
+
+/// Timer driven scan of `R` rows, see the [module documentation](self)
+///
+/// A pattern is the set of the lit column pins of a row, as a mask of the column port.
+pub struct MatrixScan<ROWS, COLS, TIM, const R: usize> {
+    rows: ROWS,
+    cols: COLS,
+    tim: TIM,
+    config: Config,
+    row_pins: [u16; R],
+    row_mask: u16,
+    col_mask: u16,
+    banks: [[u16; R]; 2],
+    front: usize,
+    swap: bool,
+    row: usize,
+}
+
+impl<ROWS, COLS, TIM, const R: usize> MatrixScan<ROWS, COLS, TIM, R>
+where
+    ROWS: OutputPort,
+    COLS: OutputPort,
+    TIM: Instance,
+{
+    /// Starts the scan, with all the LEDs off
+    ///
+    /// `row_pins` are the masks of the row pins of `rows`, in scan order, and `col_pins` the
+    /// mask of the column pins of `cols`. The pins have to be configured as outputs
+    /// beforehand, the other pins of the ports are left untouched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no row or the refresh rate is 0.
+    pub fn new(
+        rows: ROWS,
+        row_pins: [u16; R],
+        cols: COLS,
+        col_pins: u16,
+        tim: TIM,
+        config: Config,
+        rcc: &mut Rcc,
+    ) -> Self {
+        hal_assert!(R > 0 && config.refresh.raw() > 0);
+        setup_ticker::<TIM>(Hertz::from_raw(config.refresh.raw() * R as u32), rcc);
+
+        let mut scan = MatrixScan {
+            rows,
+            cols,
+            tim,
+            config,
+            row_pins,
+            row_mask: row_pins.iter().fold(0, |mask, pins| mask | pins),
+            col_mask: col_pins,
+            banks: [[0; R]; 2],
+            front: 0,
+            swap: false,
+            row: 0,
+        };
+        scan.blank();
+        TIM::listen_update(true);
+        TIM::start(false);
+        scan
+    }
+
+    /// Patterns being displayed
+    pub fn front(&self) -> &[u16; R] {
+        &self.banks[self.front]
+    }
+
+    /// Patterns of the back bank, to draw the next picture
+    pub fn back_mut(&mut self) -> &mut [u16; R] {
+        &mut self.banks[self.front ^ 1]
+    }
+
+    /// Displays the back bank from the start of the next frame
+    ///
+    /// The back bank then holds the previous picture. It should not be drawn again until
+    /// [`is_presenting`](Self::is_presenting) returns false, the new picture could
+    /// otherwise be changed while it is shown.
+    pub fn present(&mut self) {
+        self.swap = true;
+    }
+
+    /// Returns true until the bank given to [`present`](Self::present) is displayed
+    pub fn is_presenting(&self) -> bool {
+        self.swap
+    }
+
+    /// To be called from the timer interrupt handler, lights the next row
+    pub fn on_interrupt(&mut self) {
+        TIM::clear_update();
+        // The interrupt may have been pending when the scan was paused
+        if !TIM::is_running() {
+            return;
+        }
+        if self.row == 0 && self.swap {
+            self.front ^= 1;
+            self.swap = false;
+        }
+        let (row_mask, rows_low) = (self.row_mask, self.config.rows_active_low);
+        drive(&mut self.rows, 0, row_mask, rows_low);
+        let pattern = self.banks[self.front][self.row];
+        drive(
+            &mut self.cols,
+            pattern,
+            self.col_mask,
+            self.config.cols_active_low,
+        );
+        drive(&mut self.rows, self.row_pins[self.row], row_mask, rows_low);
+        self.row = (self.row + 1) % R;
+    }
+
+    /// Stops the scan and turns all the LEDs off, the patterns are kept
+    pub fn pause(&mut self) {
+        TIM::stop();
+        TIM::clear_update();
+        self.blank();
+    }
+
+    /// Restarts the scan from the first row
+    pub fn resume(&mut self) {
+        self.row = 0;
+        TIM::start(false);
+    }
+
+    /// Stops the scan, turns all the LEDs off and releases the ports and the timer
+    pub fn release(mut self) -> (ROWS, COLS, TIM) {
+        self.pause();
+        TIM::listen_update(false);
+        (self.rows, self.cols, self.tim)
+    }
+
+    fn blank(&mut self) {
+        drive(
+            &mut self.rows,
+            0,
+            self.row_mask,
+            self.config.rows_active_low,
+        );
+        drive(
+            &mut self.cols,
+            0,
+            self.col_mask,
+            self.config.cols_active_low,
+        );
+    }
+}
+
+/// Drives the pins of `on` to their active level and the other pins of `mask` inactive
+#[inline(always)]
+fn drive<PORT: OutputPort>(port: &mut PORT, on: u16, mask: u16, active_low: bool) {
+    let (on, off) = (on & mask, !on & mask);
+    if active_low {
+        port.write_pins(off, on);
+    } else {
+        port.write_pins(on, off);
+    }
+}
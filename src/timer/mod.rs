@@ -1,4 +1,7 @@
 //! Timers
+use core::marker::PhantomData;
+
+use crate::debug::{Dump, TimerRegisters};
 use crate::rcc::*;
 use crate::stm32::*;
 use crate::time::{Hertz, MicroSecond};
@@ -7,12 +10,323 @@ use cortex_m::peripheral::SYST;
 use hal::timer::{CountDown, Periodic};
 use void::Void;
 
+pub mod alarm;
+pub mod capture;
 pub mod delay;
+pub mod gate;
+pub mod keypad;
+#[cfg(feature = "stm32g0x1")]
+pub mod lptim;
+pub mod matrix;
 pub mod opm;
+pub mod phase;
 pub mod pins;
 pub mod pwm;
 pub mod qei;
 pub mod stopwatch;
+pub mod ultrasonic;
+#[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+pub mod zerocross;
+
+/// The requested timing is out of the reach of the timer
+#[derive(Debug)]
+pub struct InvalidConfig;
+
+/// Output channel polarity (CCxP)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Events setting the update flag (URS)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpdateSource {
+    /// Counter overflow/underflow, software (UG) and slave mode controller updates
+    Any,
+    /// Counter overflow/underflow only
+    CounterOnly,
+}
+
+/// Timer peripheral
+///
+/// Hides the register differences between the timer instances, so drivers can be written
+/// once for all of them.
+pub trait Instance: Enable + Reset + crate::Sealed {
+    /// Counter width in bits
+    const WIDTH: u8;
+    /// Number of capture/compare channels
+    const CHANNELS: u8;
+    /// Has complementary outputs and a break/dead-time unit with a main output enable
+    const ADVANCED: bool;
+
+    /// Sets the prescaler, the counter clock is the timer clock divided by `psc + 1`
+    fn set_prescaler(psc: u16);
+    /// Returns the prescaler
+    fn prescaler() -> u16;
+    /// Sets the auto-reload value
+    fn set_auto_reload(arr: u32);
+    /// Returns the auto-reload value
+    fn auto_reload() -> u32;
+    /// Returns the counter value
+    fn counter() -> u32;
+    /// Starts the counter, stopping at the next update event if `one_pulse` is set
+    fn start(one_pulse: bool);
+    /// Stops the counter
+    fn stop();
+    /// Returns true while the counter is enabled
+    fn is_running() -> bool;
+    /// Enables or disables the update interrupt
+    fn listen_update(enable: bool);
+    /// Clears the update interrupt flag
+    fn clear_update();
+    /// Selects which events set the update flag and request the update interrupt (URS)
+    fn set_update_source(source: UpdateSource);
+    /// Disables the update events (UDIS), the preloaded registers keep their values
+    fn set_update_disabled(disabled: bool);
+    /// Generates an update event, copying the preloaded registers into their shadow
+    /// registers, without setting the update flag nor firing the update interrupt
+    fn apply_settings_silently();
+
+    /// Largest auto-reload value, and counter value
+    fn max_auto_reload() -> u32 {
+        if Self::WIDTH == 32 {
+            u32::MAX
+        } else {
+            0xffff
+        }
+    }
+
+    /// Number of counter ticks from `from` to `to`, accounting for the counter wrapping
+    fn ticks_between(from: u32, to: u32) -> u32 {
+        to.wrapping_sub(from) & Self::max_auto_reload()
+    }
+}
+
+macro_rules! instances {
+    ($($TIM:ident: ($width:expr, $channels:expr, $advanced:expr),)+) => {
+        $(
+            impl Instance for $TIM {
+                const WIDTH: u8 = $width;
+                const CHANNELS: u8 = $channels;
+                const ADVANCED: bool = $advanced;
+
+                fn set_prescaler(psc: u16) {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.psc.write(|w| unsafe { w.psc().bits(psc) });
+                }
+
+                fn prescaler() -> u16 {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.psc.read().psc().bits()
+                }
+
+                fn set_auto_reload(arr: u32) {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.arr.write(|w| unsafe { w.bits(arr) });
+                }
+
+                fn auto_reload() -> u32 {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.arr.read().bits()
+                }
+
+                fn counter() -> u32 {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    let cnt = tim.cnt.read().bits();
+                    if $width == 32 {
+                        cnt
+                    } else {
+                        cnt & 0xffff
+                    }
+                }
+
+                fn start(one_pulse: bool) {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.cr1.modify(|_, w| w.opm().bit(one_pulse).cen().set_bit());
+                }
+
+                fn stop() {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.cr1.modify(|_, w| w.cen().clear_bit());
+                }
+
+                fn is_running() -> bool {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.cr1.read().cen().bit_is_set()
+                }
+
+                fn listen_update(enable: bool) {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.dier.modify(|_, w| w.uie().bit(enable));
+                }
+
+                fn clear_update() {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.sr.modify(|_, w| w.uif().clear_bit());
+                }
+
+                fn set_update_source(source: UpdateSource) {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.cr1
+                        .modify(|_, w| w.urs().bit(source == UpdateSource::CounterOnly));
+                }
+
+                fn set_update_disabled(disabled: bool) {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.cr1.modify(|_, w| w.udis().bit(disabled));
+                }
+
+                fn apply_settings_silently() {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    let cr1 = tim.cr1.read();
+                    tim.cr1.modify(|_, w| w.urs().set_bit().udis().clear_bit());
+                    tim.egr.write(|w| w.ug().set_bit());
+                    tim.cr1
+                        .modify(|_, w| w.urs().bit(cr1.urs().bit()).udis().bit(cr1.udis().bit()));
+                }
+            }
+        )+
+    }
+}
+
+instances! {
+    TIM1: (16, 4, true),
+    TIM3: (16, 4, false),
+    TIM14: (16, 1, false),
+    TIM16: (16, 1, true),
+    TIM17: (16, 1, true),
+}
+
+#[cfg(feature = "stm32g0x1")]
+instances! {
+    TIM2: (32, 4, false),
+}
+
+#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
+instances! {
+    TIM6: (16, 0, false),
+    TIM7: (16, 0, false),
+    TIM15: (16, 2, true),
+}
+
+/// Counter period settings
+///
+/// The counter period is `(psc + 1) * (arr + 1)` timer clock cycles. The smallest prescaler
+/// is used, so the auto-reload value (and the PWM duty resolution) is as large as possible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Period {
+    /// Prescaler value
+    pub psc: u16,
+    /// Auto-reload value
+    pub arr: u32,
+    /// Difference between the achieved and the requested period, in parts per million
+    pub error_ppm: i32,
+}
+
+impl Period {
+    /// Settings for a period of `num / den` timer clock cycles, saturated to what the
+    /// counter can achieve
+    pub fn from_ratio(num: u64, den: u64, max_arr: u32) -> Self {
+        hal_assert!(num > 0 && den > 0);
+        let (num, den) = (num.max(1), den.max(1));
+        let max_reload = max_arr as u64 + 1;
+        let cycles = ((num + den / 2) / den).clamp(1, 0x1_0000 * max_reload);
+        let div = (cycles - 1) / max_reload + 1;
+        let reload = ((cycles + div / 2) / div).clamp(1, max_reload);
+        let actual = div * reload;
+        let error_ppm = (actual as i128 * den as i128 - num as i128) * 1_000_000 / num as i128;
+
+        Period {
+            psc: (div - 1) as u16,
+            arr: (reload - 1) as u32,
+            error_ppm: error_ppm.clamp(i32::MIN as i128, i32::MAX as i128) as i32,
+        }
+    }
+
+    /// Settings for an update rate of `freq`
+    pub fn from_rate(clk: Hertz, freq: Hertz, max_arr: u32) -> Self {
+        Self::from_ratio(clk.raw() as u64, freq.raw() as u64, max_arr)
+    }
+
+    /// Settings for an update period of `period`
+    pub fn from_duration(clk: Hertz, period: MicroSecond, max_arr: u32) -> Self {
+        Self::from_ratio(clk.raw() as u64 * period.ticks() as u64, 1_000_000, max_arr)
+    }
+
+    /// Settings for an update rate of `freq` on the timer `TIM`
+    pub fn for_rate<TIM: Instance>(clk: Hertz, freq: Hertz) -> Self {
+        Self::from_rate(clk, freq, TIM::max_auto_reload())
+    }
+
+    /// Settings for an update period of `period` on the timer `TIM`
+    pub fn for_duration<TIM: Instance>(clk: Hertz, period: MicroSecond) -> Self {
+        Self::from_duration(clk, period, TIM::max_auto_reload())
+    }
+
+    /// Settings for an update rate of `freq`, written to `regs`
+    pub fn program<REGS: PeriodRegisters>(regs: &mut REGS, clk: Hertz, freq: Hertz) -> Self {
+        let period = Self::from_rate(clk, freq, regs.max_auto_reload());
+        period.write(regs);
+        period
+    }
+
+    /// Writes the prescaler and auto-reload registers of `TIM`
+    pub fn apply<TIM: Instance>(&self) {
+        self.write(&mut Registers::<TIM>(PhantomData));
+    }
+
+    /// Writes the prescaler and auto-reload registers
+    ///
+    /// # Panics
+    ///
+    /// Panics if the auto-reload value does not fit the counter.
+    pub fn write<REGS: PeriodRegisters>(&self, regs: &mut REGS) {
+        hal_assert!(self.arr <= regs.max_auto_reload());
+        regs.write_prescaler(self.psc);
+        regs.write_auto_reload(self.arr);
+    }
+}
+
+/// Prescaler and auto-reload registers of a timer
+///
+/// The period programming goes through this trait instead of the PAC, so that it can be
+/// checked against a mock register block in the host tests.
+pub trait PeriodRegisters {
+    /// Largest auto-reload value
+    fn max_auto_reload(&self) -> u32;
+    /// Writes the prescaler register
+    fn write_prescaler(&mut self, psc: u16);
+    /// Writes the auto-reload register
+    fn write_auto_reload(&mut self, arr: u32);
+}
+
+/// Period registers of the timer `TIM`
+pub(crate) struct Registers<TIM>(PhantomData<TIM>);
+
+impl<TIM: Instance> PeriodRegisters for Registers<TIM> {
+    fn max_auto_reload(&self) -> u32 {
+        TIM::max_auto_reload()
+    }
+
+    fn write_prescaler(&mut self, psc: u16) {
+        TIM::set_prescaler(psc);
+    }
+
+    fn write_auto_reload(&mut self, arr: u32) {
+        TIM::set_auto_reload(arr);
+    }
+}
+
+/// Resets `TIM` to raise an update `rate` times per second, for the scans driven by the
+/// timer interrupt
+pub(crate) fn setup_ticker<TIM: Instance>(rate: Hertz, rcc: &mut Rcc) {
+    TIM::enable(rcc);
+    TIM::reset(rcc);
+    Period::for_rate::<TIM>(rcc.clocks.apb_tim_clk, rate).apply::<TIM>();
+    TIM::apply_settings_silently();
+    TIM::set_update_source(UpdateSource::CounterOnly);
+}
 
 /// Hardware timers
 pub struct Timer<TIM> {
@@ -54,12 +368,21 @@ impl Timer<SYST> {
 impl Timer<SYST> {
     pub fn start(&mut self, timeout: MicroSecond) {
         let cycles = crate::time::cycles(timeout, self.clk);
-        assert!(cycles < 0x00ff_ffff);
+        hal_assert!(cycles < 0x00ff_ffff);
         self.tim.set_reload(cycles);
         self.tim.clear_current();
         self.tim.enable_counter();
     }
 
+    /// Starts the timer, failing if the timeout does not fit in the 24-bit counter
+    pub fn try_start(&mut self, timeout: MicroSecond) -> Result<(), InvalidConfig> {
+        if crate::time::cycles(timeout, self.clk) >= 0x00ff_ffff {
+            return Err(InvalidConfig);
+        }
+        self.start(timeout);
+        Ok(())
+    }
+
     pub fn wait(&mut self) -> nb::Result<(), Void> {
         if self.tim.has_wrapped() {
             Ok(())
@@ -97,8 +420,23 @@ impl TimerExt<SYST> for SYST {
 impl Periodic for Timer<SYST> {}
 
 macro_rules! timers {
-    ($($TIM:ident: ($tim:ident, $cnt:ident $(,$cnt_h:ident)*),)+) => {
+    ($($TIM:ident: $tim:ident,)+) => {
         $(
+            impl Dump for Timer<$TIM> {
+                type Registers = TimerRegisters;
+
+                fn dump(&self) -> TimerRegisters {
+                    TimerRegisters {
+                        cr1: self.tim.cr1.read().bits(),
+                        dier: self.tim.dier.read().bits(),
+                        sr: self.tim.sr.read().bits(),
+                        psc: self.tim.psc.read().bits(),
+                        arr: self.tim.arr.read().bits(),
+                        cnt: self.tim.cnt.read().bits(),
+                    }
+                }
+            }
+
             impl Timer<$TIM> {
                 /// Configures a TIM peripheral as a periodic count down timer
                 pub fn $tim(tim: $TIM, rcc: &mut Rcc) -> Self {
@@ -141,20 +479,47 @@ macro_rules! timers {
                     self.tim.cnt.reset();
                 }
 
+                /// Selects which events set the update flag and fire the interrupt
+                pub fn set_update_source(&mut self, source: UpdateSource) {
+                    $TIM::set_update_source(source);
+                }
+
+                /// Disables or re-enables the update events
+                ///
+                /// While disabled, the counter still wraps but the prescaler and auto-reload
+                /// changes are not loaded, so several settings can be changed atomically.
+                pub fn set_update_disabled(&mut self, disabled: bool) {
+                    $TIM::set_update_disabled(disabled);
+                }
+
+                /// Loads the prescaler and auto-reload settings immediately, without a
+                /// spurious update interrupt
+                pub fn apply_settings_silently(&mut self) {
+                    $TIM::apply_settings_silently();
+                }
+
                 /// Gets timer counter current value
                 pub fn get_current(&self) -> u32 {
-                    let _high = 0;
-                    $(
-                        let _high = self.tim.cnt.read().$cnt_h().bits() as u32;
-                    )*
-                    let low = self.tim.cnt.read().$cnt().bits() as u32;
-                    low | (_high << 16)
+                    $TIM::counter()
                 }
 
                 /// Releases the TIM peripheral
                 pub fn release(self) -> $TIM {
                     self.tim
                 }
+
+                /// Gives scoped access to the peripheral registers
+                ///
+                /// # Safety
+                ///
+                /// The driver keeps relying on its own setup, `f` must leave the peripheral clock
+                /// and the configuration registers as it found them.
+                pub unsafe fn with_registers<F, R>(&mut self, f: F) -> R
+                where
+                    F: FnOnce(&$TIM) -> R,
+                {
+                    f(&self.tim)
+                }
             }
 
             impl TimerExt<$TIM> for $TIM {
@@ -165,8 +530,8 @@ macro_rules! timers {
 
             impl Timer<$TIM> {
                 pub fn start(&mut self, timeout: MicroSecond) {
-                    // Pause the counter. Also set URS so that when we set UG below, it will
-                    // generate an update event *without* triggering an interrupt.
+                    // Pause the counter. Also set URS so that the update events are only
+                    // generated by the counter, and not by the settings update below.
                     self.tim.cr1.modify(|_, w| w.cen().clear_bit().urs().set_bit());
                     // reset counter
                     self.tim.cnt.reset();
@@ -174,16 +539,10 @@ macro_rules! timers {
                     self.tim.sr.modify(|_, w| w.uif().clear_bit());
 
                     // Calculate counter configuration
-                    let cycles = crate::time::cycles(timeout, self.clk);
-                    let psc = cycles / 0xffff;
-                    let arr = cycles / (psc + 1);
-
-                    self.tim.psc.write(|w| unsafe { w.psc().bits(psc as u16) });
-                    self.tim.arr.write(|w| unsafe { w.bits(arr) });
+                    Period::for_duration::<$TIM>(self.clk, timeout).apply::<$TIM>();
 
-                    // Generate an update event so that PSC and ARR values are copied into their
-                    // shadow registers.
-                    self.tim.egr.write(|w| w.ug().set_bit());
+                    // Copy the PSC and ARR values into their shadow registers
+                    $TIM::apply_settings_silently();
 
                     self.tim.cr1.modify(|_, w| w.cen().set_bit());
                 }
@@ -219,21 +578,112 @@ macro_rules! timers {
 }
 
 timers! {
-    TIM1: (tim1, cnt),
-    TIM3: (tim3, cnt_l, cnt_h),
-    TIM14: (tim14, cnt),
-    TIM16: (tim16, cnt),
-    TIM17: (tim17, cnt),
+    TIM1: tim1,
+    TIM3: tim3,
+    TIM14: tim14,
+    TIM16: tim16,
+    TIM17: tim17,
 }
 
 #[cfg(feature = "stm32g0x1")]
 timers! {
-    TIM2: (tim2, cnt_l, cnt_h),
+    TIM2: tim2,
 }
 
 #[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
 timers! {
-    TIM6: (tim6, cnt),
-    TIM7: (tim7, cnt),
-    TIM15: (tim15, cnt),
+    TIM6: tim6,
+    TIM7: tim7,
+    TIM15: tim15,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Period, PeriodRegisters};
+    use crate::prelude::*;
+
+    /// Register block of a timer, recording the writes
+    struct MockTimer {
+        width: u8,
+        psc: Option<u16>,
+        arr: Option<u32>,
+    }
+
+    impl MockTimer {
+        fn new(width: u8) -> Self {
+            MockTimer {
+                width,
+                psc: None,
+                arr: None,
+            }
+        }
+    }
+
+    impl PeriodRegisters for MockTimer {
+        fn max_auto_reload(&self) -> u32 {
+            ((1u64 << self.width) - 1) as u32
+        }
+
+        fn write_prescaler(&mut self, psc: u16) {
+            self.psc = Some(psc);
+        }
+
+        fn write_auto_reload(&mut self, arr: u32) {
+            self.arr = Some(arr);
+        }
+    }
+
+    #[test]
+    fn period_exact() {
+        let period = Period::from_rate(16.MHz(), 1.kHz(), 0xffff);
+        assert_eq!((period.psc, period.arr, period.error_ppm), (0, 15_999, 0));
+        let period = Period::from_duration(16.MHz(), 1.millis(), 0xffff);
+        assert_eq!((period.psc, period.arr, period.error_ppm), (0, 15_999, 0));
+    }
+
+    #[test]
+    fn period_prescaled() {
+        let period = Period::from_rate(64.MHz(), 1.Hz(), 0xffff);
+        assert_eq!((period.psc, period.arr, period.error_ppm), (976, 65_506, 5));
+        let period = Period::from_ratio(64_000_000, 7, 0xffff);
+        assert_eq!(
+            (period.psc, period.arr, period.error_ppm),
+            (139, 65_305, -1)
+        );
+    }
+
+    #[test]
+    fn period_32bit_counter() {
+        let period = Period::from_rate(64.MHz(), 1.Hz(), 0xffff_ffff);
+        assert_eq!(
+            (period.psc, period.arr, period.error_ppm),
+            (0, 63_999_999, 0)
+        );
+    }
+
+    #[test]
+    fn period_programmed() {
+        let mut tim = MockTimer::new(16);
+        Period::program(&mut tim, 64.MHz(), 1.Hz());
+        assert_eq!((tim.psc, tim.arr), (Some(976), Some(65_506)));
+
+        let mut tim = MockTimer::new(32);
+        Period::program(&mut tim, 64.MHz(), 1.Hz());
+        assert_eq!((tim.psc, tim.arr), (Some(0), Some(63_999_999)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn period_too_long_for_counter() {
+        let period = Period::from_rate(64.MHz(), 1.Hz(), 0xffff_ffff);
+        period.write(&mut MockTimer::new(16));
+    }
+
+    #[test]
+    fn period_saturated() {
+        let period = Period::from_ratio(1, 2, 0xffff);
+        assert_eq!((period.psc, period.arr), (0, 0));
+        let period = Period::from_ratio(1 << 40, 1, 0xffff);
+        assert_eq!((period.psc, period.arr), (0xffff, 0xffff));
+    }
 }
@@ -1,25 +1,301 @@
 //! # One-pulse Mode
 use crate::rcc::*;
 use crate::stm32::*;
-use crate::time::{Hertz, MicroSecond};
+use crate::time::{Hertz, MicroSecond, NanoSecond};
 use crate::timer::pins::TimerPin;
 use crate::timer::*;
 use core::marker::PhantomData;
-use fugit::RateExtU32;
+use cortex_m::interrupt;
 
 pub trait OpmExt: Sized {
     fn opm(self, period: MicroSecond, rcc: &mut Rcc) -> Opm<Self>;
 }
 
+/// One-pulse source, lets drivers be generic over the timer and channel generating the pulse
+///
+/// Delay and width are expressed in timer ticks. The pulse starts `delay` ticks after
+/// [`OneShotPulse::generate`] and lasts `width` ticks.
+pub trait OneShotPulse {
+    /// Starts the pulse
+    fn generate(&mut self);
+
+    /// Sets the delay before the pulse
+    fn set_delay(&mut self, delay: u32);
+
+    /// Sets the pulse width
+    ///
+    /// All the channels of a timer end their pulse at the same time, so this also changes
+    /// the width of the other channels.
+    fn set_width(&mut self, width: u32);
+
+    /// Returns true once the pulse has been output
+    fn is_done(&self) -> bool;
+}
+
+/// Strobe framing a transfer, generated by a [`TransferStrobe`]
+///
+/// Durations are in timer ticks. The transfer is assumed to shift its frames back to back,
+/// as the SPI does from its TX FIFO, and starts a few bus cycles after being enabled, which
+/// the margins have to cover.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strobe {
+    /// Chip select active from `lead` ticks before the transfer to `lag` ticks after it
+    ChipSelect { lead: u32, lag: u32 },
+    /// Latch enable pulse of `width` ticks, `gap` ticks after the end of the transfer, to
+    /// load the outputs of shift registers
+    Latch { gap: u32, width: u32 },
+}
+
+impl Strobe {
+    /// Returns the delay and the width of the pulse, and the tick at which the transfer
+    /// starts, for a transfer of `duration` ticks
+    fn timing(&self, duration: u32) -> (u32, u32, u32) {
+        match *self {
+            // The output is stuck active with a zero delay, the pulse starts at tick 1
+            Strobe::ChipSelect { lead, lag } => (1, lead + duration + lag, 1 + lead),
+            Strobe::Latch { gap, width } => ((duration + gap).max(1), width, 0),
+        }
+    }
+}
+
+/// One-pulse output started together with a transfer, see [`Strobe`]
+pub trait TransferStrobe: OneShotPulse {
+    /// Rate of the timer ticks
+    fn tick(&self) -> Hertz;
+
+    /// Programs `strobe` around a transfer lasting `duration` ticks, then generates it
+    /// with the transfer started by `start`
+    ///
+    /// The pulse and the transfer are started in a critical section, a chip select
+    /// waits for the pulse to be active before starting the transfer, a latch counts its
+    /// gap from just after the start of the transfer. The pulse has to be enabled with a
+    /// software trigger.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the strobe does not fit in the timer period.
+    fn frame<R>(&mut self, strobe: Strobe, duration: u32, start: impl FnOnce() -> R) -> R;
+}
+
+/// Output compare mode PWM mode 2: inactive until CCR, active until the update event
+const MODE_PWM2: u8 = 0b0111;
+/// Output compare mode retriggerable OPM mode 2: active from the trigger until the update event
+const MODE_RETRIGGERABLE: u8 = 0b1001;
+
+pub use crate::timer::Polarity;
+
+/// Timer input starting the pulse (SMCR TS)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// Pulse started by [`Opm::generate`]
+    Software,
+    /// Internal trigger from another timer
+    Itr0,
+    Itr1,
+    Itr2,
+    Itr3,
+    /// Any edge on channel 1 input
+    Ti1Edge,
+    /// Filtered channel 1 input, the channel can not be used as an output
+    Ti1,
+    /// Filtered channel 2 input, the channel can not be used as an output
+    Ti2,
+    /// External trigger input
+    Etr,
+}
+
+impl Trigger {
+    fn bits(&self) -> u32 {
+        match self {
+            Trigger::Software | Trigger::Itr0 => 0b000,
+            Trigger::Itr1 => 0b001,
+            Trigger::Itr2 => 0b010,
+            Trigger::Itr3 => 0b011,
+            Trigger::Ti1Edge => 0b100,
+            Trigger::Ti1 => 0b101,
+            Trigger::Ti2 => 0b110,
+            Trigger::Etr => 0b111,
+        }
+    }
+}
+
+/// One-pulse configuration
+///
+/// Only TIM1, TIM2, TIM3 and TIM15 have a slave mode controller for hardware triggers,
+/// TIM15 without the external trigger input, and only TIM1, TIM15, TIM16 and TIM17 have a
+/// repetition counter.
+#[derive(Debug, Clone, Copy)]
+pub struct OpmConfig {
+    /// Duration of the whole pulse period, delay included
+    pub period: MicroSecond,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+    /// A trigger during the pulse restarts it, extending the pulse. Needs a hardware trigger,
+    /// the pulse then starts on the trigger and lasts the whole period.
+    pub retriggerable: bool,
+    /// Number of pulses output after the first one, before the counter stops
+    pub repetitions: u8,
+}
+
+impl OpmConfig {
+    pub fn new(period: MicroSecond) -> Self {
+        OpmConfig {
+            period,
+            polarity: Polarity::ActiveHigh,
+            trigger: Trigger::Software,
+            retriggerable: false,
+            repetitions: 0,
+        }
+    }
+
+    pub fn polarity(mut self, polarity: Polarity) -> Self {
+        self.polarity = polarity;
+        self
+    }
+
+    pub fn trigger(mut self, trigger: Trigger) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    pub fn retriggerable(mut self, retriggerable: bool) -> Self {
+        self.retriggerable = retriggerable;
+        self
+    }
+
+    pub fn repetitions(mut self, repetitions: u8) -> Self {
+        self.repetitions = repetitions;
+        self
+    }
+}
+
+impl From<MicroSecond> for OpmConfig {
+    fn from(period: MicroSecond) -> Self {
+        OpmConfig::new(period)
+    }
+}
+
+pub use crate::timer::InvalidConfig;
+
+/// Timer features used by the one-pulse configuration
+pub trait OpmInstance: Instance {
+    /// Timer has a slave mode controller
+    const SLAVE_MODE: bool = false;
+    /// Timer has a repetition counter
+    const REPETITION: bool = false;
+    /// Timer has an external trigger input
+    const EXTERNAL_TRIGGER: bool = true;
+
+    /// Sets CR1 OPM, the counter stops at the next update event
+    fn set_one_pulse();
+
+    /// Programs the slave mode controller
+    fn set_slave_mode(_sms: u8, _trigger: Trigger) {}
+
+    /// Programs the repetition counter
+    fn set_repetitions(_repetitions: u8) {}
+}
+
+macro_rules! opm_instances {
+    ($($TIMX:ident: { $(smcr: $smcr:ident,)? $(rcr: $rcr:ident,)? },)+) => {
+        $(
+            impl OpmInstance for $TIMX {
+                fn set_one_pulse() {
+                    let tim = unsafe { &*$TIMX::ptr() };
+                    tim.cr1.modify(|_, w| w.opm().set_bit());
+                }
+
+                $( opm_instances!(@slave_mode $TIMX, $smcr); )?
+                $( opm_instances!(@repetition $TIMX, $rcr); )?
+            }
+        )+
+    };
+    // Some PACs share the TIM16 register block with TIM15, which lacks SMCR and the channel
+    // 2 input fields
+    (@slave_mode $TIMX:ident, raw) => {
+        const SLAVE_MODE: bool = true;
+        const EXTERNAL_TRIGGER: bool = false;
+
+        fn set_slave_mode(sms: u8, trigger: Trigger) {
+            const SMCR_OFFSET: usize = 0x08;
+            const CCMR1_OFFSET: usize = 0x18;
+            let smcr = ($TIMX::ptr() as usize + SMCR_OFFSET) as *mut u32;
+            let ccmr1 = ($TIMX::ptr() as usize + CCMR1_OFFSET) as *mut u32;
+            unsafe {
+                // Same setup as the other timers, TS[4:3] and ETR do not exist
+                let ccxs = match trigger {
+                    Trigger::Ti1 => Some(0),
+                    Trigger::Ti2 => Some(8),
+                    _ => None,
+                };
+                if let Some(shift) = ccxs {
+                    ccmr1.write_volatile(ccmr1.read_volatile() & !(0b11 << shift) | 0b01 << shift);
+                }
+                let sms = sms as u32;
+                let bits = (sms & 0b111) | (sms >> 3) << 16 | trigger.bits() << 4;
+                smcr.write_volatile(smcr.read_volatile() & !0x0001_0077 | bits);
+            }
+        }
+    };
+    (@slave_mode $TIMX:ident, $smcr:ident) => {
+        const SLAVE_MODE: bool = true;
+
+        fn set_slave_mode(sms: u8, trigger: Trigger) {
+            let tim = unsafe { &*$TIMX::ptr() };
+            // Filtered channel inputs need the channel mapped on its own input
+            match trigger {
+                Trigger::Ti1 => tim.ccmr1_input().modify(|_, w| unsafe { w.cc1s().bits(0b01) }),
+                Trigger::Ti2 => tim.ccmr1_input().modify(|_, w| unsafe { w.cc2s().bits(0b01) }),
+                _ => {}
+            }
+            // SMS[2:0] at bit 0, TS[2:0] at bit 4, SMS[3] at bit 16, TS[4:3] at bit 20
+            let sms = sms as u32;
+            let bits = (sms & 0b111) | (sms >> 3) << 16 | trigger.bits() << 4;
+            tim.$smcr.modify(|r, w| unsafe { w.bits(r.bits() & !0x0031_0077 | bits) });
+        }
+    };
+    (@repetition $TIMX:ident, $rcr:ident) => {
+        const REPETITION: bool = true;
+
+        fn set_repetitions(repetitions: u8) {
+            let tim = unsafe { &*$TIMX::ptr() };
+            tim.$rcr.write(|w| unsafe { w.bits(repetitions as u32) });
+        }
+    };
+}
+
+opm_instances! {
+    TIM1: { smcr: smcr, rcr: rcr, },
+    TIM3: { smcr: smcr, },
+    TIM14: { },
+    TIM16: { rcr: rcr, },
+    TIM17: { rcr: rcr, },
+}
+
+#[cfg(feature = "stm32g0x1")]
+opm_instances! {
+    TIM2: { smcr: smcr, },
+}
+
+#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
+opm_instances! {
+    TIM15: { smcr: raw, rcr: rcr, },
+}
+
 pub struct OpmPin<TIM, CH> {
     tim: PhantomData<TIM>,
     channel: PhantomData<CH>,
+    clk: Hertz,
     delay: u32,
+    mode: u8,
+    polarity: Polarity,
 }
 
 pub struct Opm<TIM> {
     tim: PhantomData<TIM>,
     clk: Hertz,
+    mode: u8,
+    polarity: Polarity,
 }
 
 impl<TIM> Opm<TIM> {
@@ -31,67 +307,117 @@ impl<TIM> Opm<TIM> {
         OpmPin {
             tim: PhantomData,
             channel: PhantomData,
-            delay: 1,
+            clk: self.clk,
+            delay: if self.mode == MODE_RETRIGGERABLE {
+                0
+            } else {
+                1
+            },
+            mode: self.mode,
+            polarity: self.polarity,
         }
     }
 }
 
-macro_rules! opm {
-    ($($TIMX:ident: ($timX:ident, $arr:ident $(,$arr_h:ident)*),)+) => {
-        $(
-            impl OpmExt for $TIMX {
-                fn opm(self, pulse: MicroSecond, rcc: &mut Rcc) -> Opm<Self> {
-                    $timX(self, pulse, rcc)
-                }
-            }
+impl<TIM: OpmInstance> OpmExt for TIM {
+    fn opm(self, pulse: MicroSecond, rcc: &mut Rcc) -> Opm<Self> {
+        // A software triggered pulse without repetitions is supported by all the timers
+        Opm::configure(OpmConfig::new(pulse), rcc)
+    }
+}
 
-            fn $timX(_tim: $TIMX, pulse: MicroSecond, rcc: &mut Rcc) -> Opm<$TIMX> {
-                $TIMX::enable(rcc);
-                $TIMX::reset(rcc);
+impl<TIM: OpmInstance> Opm<TIM> {
+    /// Configures the timer for one-pulse mode
+    ///
+    /// Fails if the timer lacks the slave mode controller, the external trigger input or the
+    /// repetition counter needed by the configuration, or if a retriggerable pulse has no
+    /// hardware trigger.
+    pub fn new(_tim: TIM, config: OpmConfig, rcc: &mut Rcc) -> Result<Self, InvalidConfig> {
+        let hw_trigger = config.trigger != Trigger::Software;
+        if (hw_trigger && !TIM::SLAVE_MODE)
+            || (config.trigger == Trigger::Etr && !TIM::EXTERNAL_TRIGGER)
+            || (config.retriggerable && !hw_trigger)
+            || (config.repetitions > 0 && !TIM::REPETITION)
+        {
+            return Err(InvalidConfig);
+        }
+        Ok(Self::configure(config, rcc))
+    }
 
-                let mut opm = Opm::<$TIMX> {
-                    clk: rcc.clocks.apb_tim_clk,
-                    tim: PhantomData,
-                };
-                opm.set_pulse(pulse);
-                opm
-            }
+    fn configure(config: OpmConfig, rcc: &mut Rcc) -> Self {
+        let hw_trigger = config.trigger != Trigger::Software;
+        TIM::enable(rcc);
+        TIM::reset(rcc);
 
-            impl Opm<$TIMX> {
-                pub fn set_pulse(&mut self, pulse: MicroSecond) {
-                    let pulsehz: Hertz = pulse.into_rate();
-                    let cycles_per_period = self.clk / pulsehz;
-                    let psc = (cycles_per_period - 1) / 0xffff;
-                    let freq = (self.clk.raw() / (psc + 1)).Hz();
-                    let reload = crate::time::cycles(pulse, freq);
-                    unsafe {
-                        let tim = &*$TIMX::ptr();
-                        tim.psc.write(|w| w.psc().bits(psc as u16));
-                        tim.arr.write(|w| w.$arr().bits(reload as u16));
-                        $(
-                            tim.arr.modify(|_, w| w.$arr_h().bits((reload >> 16) as u16));
-                        )*
-                    }
-                }
+        let mut opm = Opm::<TIM> {
+            clk: rcc.clocks.apb_tim_clk,
+            tim: PhantomData,
+            mode: if config.retriggerable {
+                MODE_RETRIGGERABLE
+            } else {
+                MODE_PWM2
+            },
+            polarity: config.polarity,
+        };
+        opm.set_pulse(config.period);
+        TIM::set_repetitions(config.repetitions);
+        // RCR is preloaded as well, latched before the first pulse
+        TIM::apply_settings_silently();
+        TIM::set_one_pulse();
+        if hw_trigger {
+            // Trigger mode starts the counter, combined reset + trigger mode also restarts it
+            let sms = if config.retriggerable { 0b1000 } else { 0b0110 };
+            TIM::set_slave_mode(sms, config.trigger);
+        }
+        opm
+    }
 
-                pub fn generate(&mut self) {
-                    let tim =  unsafe {&*$TIMX::ptr()};
-                    tim.cr1.write(|w| w.opm().set_bit().cen().set_bit());
-                }
-            }
-        )+
+    /// Sets the duration of the pulse period
+    ///
+    /// The preloaded prescaler is latched right away, so it should not be called while a
+    /// pulse is being generated.
+    pub fn set_pulse(&mut self, pulse: MicroSecond) {
+        Period::for_duration::<TIM>(self.clk, pulse).apply::<TIM>();
+        TIM::apply_settings_silently();
+    }
+
+    pub fn generate(&mut self) {
+        TIM::start(true);
+    }
+
+    /// Returns true while the pulse is being generated
+    pub fn is_pulse_active(&self) -> bool {
+        TIM::is_running()
+    }
+
+    /// Starts listening for the pulse completion (update) interrupt
+    pub fn listen(&mut self) {
+        TIM::listen_update(true);
+    }
+
+    /// Stops listening for the pulse completion interrupt
+    pub fn unlisten(&mut self) {
+        TIM::listen_update(false);
+    }
+
+    /// Clears the pulse completion interrupt flag
+    pub fn clear_irq(&mut self) {
+        TIM::clear_update();
     }
 }
 
 macro_rules! opm_hal {
     ($($TIMX:ident:
-        ($CH:ty, $ccxe:ident, $ccmrx_output:ident, $ocxm:ident, $ocxfe:ident, $ccrx:ident),)+
+        ($CH:ty, $ccxe:ident, $ccxp:ident, $ccmrx_output:ident, $ocxm:ident, $ocxm_3:expr, $ocxfe:ident, $ccrx:ident $(, $moe:ident)?),)+
     ) => {
         $(
             impl OpmPin<$TIMX, $CH> {
                 pub fn enable(&mut self) {
                     let tim =  unsafe {&*$TIMX::ptr()};
-                    tim.ccer.modify(|_, w| w.$ccxe().set_bit());
+                    let active_low = self.polarity == Polarity::ActiveLow;
+                    tim.ccer.modify(|_, w| w.$ccxp().bit(active_low).$ccxe().set_bit());
+                    // The outputs of the timers with a break unit also need the main output enable
+                    $( tim.bdtr.modify(|_, w| w.$moe().set_bit()); )?
                     self.setup();
                 }
 
@@ -101,7 +427,7 @@ macro_rules! opm_hal {
                 }
 
                 pub fn get_max_delay(&mut self) -> u32 {
-                    unsafe { (*$TIMX::ptr()).arr.read().bits() }
+                    $TIMX::auto_reload()
                 }
 
                 pub fn set_delay(&mut self, delay: u32) {
@@ -109,54 +435,227 @@ macro_rules! opm_hal {
                     self.setup();
                 }
 
+                /// Sets the delay of this channel, checking that the pulse fits in the period
+                ///
+                /// Each channel of the timer has its own delay, and all the pulses end at the
+                /// end of the period. Staggered pulses can be generated from a single shot by
+                /// giving the channels different delays.
+                pub fn try_set_delay(&mut self, delay: u32) -> Result<(), InvalidConfig> {
+                    if delay >= $TIMX::auto_reload() {
+                        return Err(InvalidConfig);
+                    }
+                    self.set_delay(delay);
+                    Ok(())
+                }
+
+                /// Delay of this channel, in timer ticks
+                pub fn delay(&self) -> u32 {
+                    self.delay
+                }
+
+                /// Width of the pulse of this channel, in timer ticks
+                ///
+                /// The pulse lasts from the end of the delay to the end of the period.
+                pub fn width(&self) -> u32 {
+                    ($TIMX::auto_reload() + 1).saturating_sub(self.delay)
+                }
+
                 fn setup(&mut self) {
                     unsafe {
                         let tim = &*$TIMX::ptr();
                         tim.$ccrx.write(|w| w.bits(self.delay));
-                        tim.$ccmrx_output().modify(|_, w| w.$ocxm().bits(7).$ocxfe().set_bit());
+                        // OCxM[3] is not contiguous with OCxM[2:0]
+                        let m3 = ((self.mode >> 3) as u32) << $ocxm_3;
+                        tim.$ccmrx_output().modify(|r, w| {
+                            w.bits(r.bits() & !(1 << $ocxm_3) | m3)
+                                .$ocxm()
+                                .bits(self.mode & 0b111)
+                                .$ocxfe()
+                                .set_bit()
+                        });
                     }
                 }
             }
+
+            impl OneShotPulse for OpmPin<$TIMX, $CH> {
+                fn generate(&mut self) {
+                    $TIMX::start(true);
+                }
+
+                fn set_delay(&mut self, delay: u32) {
+                    OpmPin::<$TIMX, $CH>::set_delay(self, delay);
+                }
+
+                fn set_width(&mut self, width: u32) {
+                    hal_assert!(width > 0);
+                    $TIMX::set_auto_reload(self.delay + width - 1);
+                }
+
+                fn is_done(&self) -> bool {
+                    !$TIMX::is_running()
+                }
+            }
+
+            impl TransferStrobe for OpmPin<$TIMX, $CH> {
+                fn tick(&self) -> Hertz {
+                    self.clk / ($TIMX::prescaler() as u32 + 1)
+                }
+
+                fn frame<R>(&mut self, strobe: Strobe, duration: u32, start: impl FnOnce() -> R) -> R {
+                    let (delay, width, start_at) = strobe.timing(duration);
+                    hal_assert!(
+                        width > 0 && delay + width - 1 <= $TIMX::max_auto_reload(),
+                        "strobe longer than the timer period"
+                    );
+                    OpmPin::<$TIMX, $CH>::set_delay(self, delay);
+                    OneShotPulse::set_width(self, width);
+                    interrupt::free(|_| {
+                        if start_at == 0 {
+                            let res = start();
+                            $TIMX::start(true);
+                            res
+                        } else {
+                            $TIMX::start(true);
+                            while $TIMX::counter() < start_at && $TIMX::is_running() {}
+                            start()
+                        }
+                    })
+                }
+            }
         )+
     };
 }
 
 opm_hal! {
-    TIM1: (Channel1, cc1e, ccmr1_output, oc1m, oc1fe, ccr1),
-    TIM1: (Channel2, cc2e, ccmr1_output, oc2m, oc2fe, ccr2),
-    TIM1: (Channel3, cc3e, ccmr2_output, oc3m, oc3fe, ccr3),
-    TIM1: (Channel4, cc4e, ccmr2_output, oc4m, oc4fe, ccr4),
-    TIM3: (Channel1, cc1e, ccmr1_output, oc1m, oc1fe, ccr1),
-    TIM3: (Channel2, cc2e, ccmr1_output, oc2m, oc2fe, ccr2),
-    TIM3: (Channel3, cc3e, ccmr2_output, oc3m, oc3fe, ccr3),
-    TIM3: (Channel4, cc4e, ccmr2_output, oc4m, oc4fe, ccr4),
-    TIM14: (Channel1, cc1e, ccmr1_output, oc1m, oc1fe, ccr1),
-    TIM16: (Channel1, cc1e, ccmr1_output, oc1m, oc1fe, ccr1),
-    TIM17: (Channel1, cc1e, ccmr1_output, oc1m, oc1fe, ccr1),
+    TIM1: (Channel1, cc1e, cc1p, ccmr1_output, oc1m, 16, oc1fe, ccr1, moe),
+    TIM1: (Channel2, cc2e, cc2p, ccmr1_output, oc2m, 24, oc2fe, ccr2, moe),
+    TIM1: (Channel3, cc3e, cc3p, ccmr2_output, oc3m, 16, oc3fe, ccr3, moe),
+    TIM1: (Channel4, cc4e, cc4p, ccmr2_output, oc4m, 24, oc4fe, ccr4, moe),
+    TIM3: (Channel1, cc1e, cc1p, ccmr1_output, oc1m, 16, oc1fe, ccr1),
+    TIM3: (Channel2, cc2e, cc2p, ccmr1_output, oc2m, 24, oc2fe, ccr2),
+    TIM3: (Channel3, cc3e, cc3p, ccmr2_output, oc3m, 16, oc3fe, ccr3),
+    TIM3: (Channel4, cc4e, cc4p, ccmr2_output, oc4m, 24, oc4fe, ccr4),
+    TIM14: (Channel1, cc1e, cc1p, ccmr1_output, oc1m, 16, oc1fe, ccr1),
+    TIM16: (Channel1, cc1e, cc1p, ccmr1_output, oc1m, 16, oc1fe, ccr1, moe),
+    TIM17: (Channel1, cc1e, cc1p, ccmr1_output, oc1m, 16, oc1fe, ccr1, moe),
+}
+
+#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
+opm_hal! {
+    TIM15: (Channel1, cc1e, cc1p, ccmr1_output, oc1m, 16, oc1fe, ccr1, moe),
 }
 
 #[cfg(feature = "stm32g0x1")]
 opm_hal! {
-    TIM2: (Channel1, cc1e, ccmr1_output, oc1m, oc1fe, ccr1),
-    TIM2: (Channel2, cc2e, ccmr1_output, oc2m, oc2fe, ccr2),
-    TIM2: (Channel3, cc3e, ccmr2_output, oc3m, oc3fe, ccr3),
-    TIM2: (Channel4, cc4e, ccmr2_output, oc4m, oc4fe, ccr4),
+    TIM2: (Channel1, cc1e, cc1p, ccmr1_output, oc1m, 16, oc1fe, ccr1),
+    TIM2: (Channel2, cc2e, cc2p, ccmr1_output, oc2m, 24, oc2fe, ccr2),
+    TIM2: (Channel3, cc3e, cc3p, ccmr2_output, oc3m, 16, oc3fe, ccr3),
+    TIM2: (Channel4, cc4e, cc4p, ccmr2_output, oc4m, 24, oc4fe, ccr4),
 }
 
-opm! {
-    TIM1: (tim1, arr),
-    TIM3: (tim3, arr_l, arr_h),
-    TIM14: (tim14, arr),
-    TIM16: (tim16, arr),
-    TIM17: (tim17, arr),
+/// Double-pulse generator built from TIM16 and TIM17 in one-pulse mode
+///
+/// The first pulse is output on TIM16_CH1 and the second one on TIM17_CH1, combine both
+/// outputs (or use a single gate driver input per timer) to get the classic double-pulse
+/// test waveform. TIM16 and TIM17 have no slave mode controller, so both counters are
+/// started back to back inside a critical section. The second timer starts a constant
+/// number of timer clock cycles after the first one, which can be compensated with
+/// [`DoublePulse::set_start_skew`].
+pub struct DoublePulse {
+    first: Opm<TIM16>,
+    second: Opm<TIM17>,
+    skew: u32,
 }
 
-#[cfg(feature = "stm32g0x1")]
-opm! {
-    TIM2: (tim2, arr_l, arr_h),
+impl Opm<TIM16> {
+    /// Pairs the TIM16 and TIM17 one-pulse timers into a double-pulse generator
+    ///
+    /// The channel 1 pins of both timers have to be bound and enabled, the waveform
+    /// overrides their delays.
+    pub fn double_pulse(self, second: Opm<TIM17>) -> DoublePulse {
+        DoublePulse {
+            first: self,
+            second,
+            skew: 0,
+        }
+    }
 }
 
-#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
-opm! {
-    TIM15: (tim15, arr),
+impl DoublePulse {
+    /// Clock of the timers, one cycle is the resolution of the waveform
+    pub fn clock(&self) -> Hertz {
+        self.first.clk
+    }
+
+    /// Number of timer clock cycles between the start of TIM16 and TIM17
+    pub fn set_start_skew(&mut self, cycles: u32) {
+        self.skew = cycles;
+    }
+
+    /// Programs the pulse widths and the gap between both pulses, in timer clock cycles
+    ///
+    /// The whole waveform has to fit in 16 bits.
+    pub fn set_waveform_cycles(&mut self, first: u32, gap: u32, second: u32) {
+        // In PWM mode 2 the output is active while CCR <= CNT <= ARR, the pulses end one
+        // cycle after their ARR
+        let first_end = 1 + first;
+        let second_start = (first_end + gap).saturating_sub(self.skew);
+        let second_end = second_start + second;
+        hal_assert!(first > 0 && second > 0 && second_end - 1 <= 0xffff);
+
+        unsafe {
+            let tim16 = &*TIM16::ptr();
+            tim16.psc.write(|w| w.psc().bits(0));
+            tim16.arr.write(|w| w.arr().bits((first_end - 1) as u16));
+            tim16.ccr1.write(|w| w.bits(1));
+            tim16
+                .ccmr1_output()
+                .modify(|_, w| w.oc1m().bits(7).oc1fe().set_bit());
+
+            let tim17 = &*TIM17::ptr();
+            tim17.psc.write(|w| w.psc().bits(0));
+            tim17.arr.write(|w| w.arr().bits((second_end - 1) as u16));
+            tim17.ccr1.write(|w| w.bits(second_start));
+            tim17
+                .ccmr1_output()
+                .modify(|_, w| w.oc1m().bits(7).oc1fe().set_bit());
+        }
+        // The prescaler is preloaded, an earlier one-pulse setup may have left another one
+        TIM16::apply_settings_silently();
+        TIM17::apply_settings_silently();
+    }
+
+    /// Programs the pulse widths and the gap between both pulses
+    ///
+    /// The durations are rounded to the nearest timer clock cycle.
+    pub fn set_waveform(&mut self, first: NanoSecond, gap: NanoSecond, second: NanoSecond) {
+        let clk = self.clock().raw() as u64;
+        let cycles =
+            |ns: NanoSecond| ((ns.ticks() as u64 * clk + 500_000_000) / 1_000_000_000) as u32;
+        self.set_waveform_cycles(cycles(first), cycles(gap), cycles(second));
+    }
+
+    /// Outputs the double pulse
+    pub fn generate(&mut self) {
+        let tim16 = unsafe { &*TIM16::ptr() };
+        let tim17 = unsafe { &*TIM17::ptr() };
+        tim16.bdtr.modify(|_, w| w.moe().set_bit());
+        tim17.bdtr.modify(|_, w| w.moe().set_bit());
+        interrupt::free(|_| {
+            tim16.cr1.write(|w| w.opm().set_bit().cen().set_bit());
+            tim17.cr1.write(|w| w.opm().set_bit().cen().set_bit());
+        });
+    }
+
+    /// Returns true while one of the pulses is still being generated
+    pub fn is_busy(&self) -> bool {
+        let tim16 = unsafe { &*TIM16::ptr() };
+        let tim17 = unsafe { &*TIM17::ptr() };
+        tim16.cr1.read().cen().bit_is_set() || tim17.cr1.read().cen().bit_is_set()
+    }
+
+    /// Splits the generator back into both one-pulse timers
+    pub fn release(self) -> (Opm<TIM16>, Opm<TIM17>) {
+        (self.first, self.second)
+    }
 }
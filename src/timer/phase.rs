@@ -0,0 +1,147 @@
+//! # Phase-shifted PWM
+//!
+//! A slave timer is held stopped with its counter preloaded, and started by the update
+//! event of a master timer through the slave trigger mode. Both counters then run from
+//! the same clock with a constant offset, without any software latency, which gives the
+//! interleaved PWM pairs of multiphase converters.
+//!
+//! TIM1, TIM2 and TIM3 can be chained with each other, and TIM15 can follow TIM2 or TIM3.
+//! TIM14, TIM16 and TIM17 have no slave mode controller, they cannot be phase shifted
+//! this way.
+use crate::stm32::*;
+use crate::timer::pwm::Pwm;
+use crate::timer::Instance;
+
+/// Timer driving the phase of a [`PhaseSlave`]
+pub trait PhaseMaster: Instance {
+    /// Routes the update event to the trigger output (MMS = 010)
+    fn trigger_on_update();
+}
+
+/// Timer which can start on the trigger output of `MASTER`
+pub trait PhaseSlave<MASTER>: Instance {
+    /// Internal trigger (ITRx) connected to `MASTER`
+    const ITR: u8;
+
+    /// Preloads the counter and waits for the trigger (SMS = 0110)
+    fn arm(counter: u32);
+}
+
+macro_rules! phase_master {
+    ($($TIM:ident,)+) => {
+        $(
+            impl PhaseMaster for $TIM {
+                fn trigger_on_update() {
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.cr2.modify(|_, w| unsafe { w.mms().bits(0b010) });
+                }
+            }
+        )+
+    }
+}
+
+macro_rules! phase_slave {
+    ($($TIM:ident: [$($MASTER:ident: $itr:expr,)+],)+) => {
+        $(
+            $(
+                impl PhaseSlave<$MASTER> for $TIM {
+                    const ITR: u8 = $itr;
+
+                    fn arm(counter: u32) {
+                        let tim = unsafe { &*$TIM::ptr() };
+                        tim.cnt.write(|w| unsafe { w.bits(counter) });
+                        tim.smcr.write(|w| unsafe { w.bits(($itr as u32) << 4 | 0b110) });
+                    }
+                }
+            )+
+        )+
+    }
+}
+
+phase_master! {
+    TIM1,
+    TIM3,
+}
+
+phase_slave! {
+    TIM1: [TIM3: 2,],
+    TIM3: [TIM1: 0,],
+}
+
+#[cfg(feature = "stm32g0x1")]
+phase_master! {
+    TIM2,
+}
+
+#[cfg(feature = "stm32g0x1")]
+phase_slave! {
+    TIM1: [TIM2: 1,],
+    TIM2: [TIM1: 0, TIM3: 2,],
+    TIM3: [TIM2: 1,],
+}
+
+/// Some PACs share the TIM16 register block with TIM15, which lacks SMCR
+#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
+mod tim15_slave {
+    use super::*;
+
+    const SMCR_OFFSET: usize = 0x08;
+
+    macro_rules! tim15_phase_slave {
+        ($($MASTER:ident: $itr:expr,)+) => {
+            $(
+                impl PhaseSlave<$MASTER> for TIM15 {
+                    const ITR: u8 = $itr;
+
+                    fn arm(counter: u32) {
+                        let tim = unsafe { &*TIM15::ptr() };
+                        let smcr = (TIM15::ptr() as usize + SMCR_OFFSET) as *mut u32;
+                        tim.cnt.write(|w| unsafe { w.bits(counter) });
+                        unsafe { smcr.write_volatile(($itr as u32) << 4 | 0b110) };
+                    }
+                }
+            )+
+        }
+    }
+
+    tim15_phase_slave! {
+        TIM3: 1,
+    }
+
+    #[cfg(any(feature = "stm32g071", feature = "stm32g081"))]
+    tim15_phase_slave! {
+        TIM2: 0,
+    }
+}
+
+impl<TIM: Instance> Pwm<TIM> {
+    /// Restarts both counters so that this timer lags `master` by `lag` counter ticks
+    ///
+    /// The two timers must run with the same period settings. `lag` is taken modulo the
+    /// period: a lag of half the period gives two-phase interleaving. The outputs restart
+    /// on the next update event of `master`, the trigger adds a small constant delay of a
+    /// few timer clock cycles to the lag.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the prescalers or the auto-reload values of the timers differ.
+    pub fn sync_to<MASTER>(&mut self, _master: &mut Pwm<MASTER>, lag: u32)
+    where
+        MASTER: PhaseMaster,
+        TIM: PhaseSlave<MASTER>,
+    {
+        let arr = TIM::auto_reload();
+        hal_assert!(
+            TIM::prescaler() == MASTER::prescaler() && arr == MASTER::auto_reload(),
+            "timers with different periods"
+        );
+        let period = arr as u64 + 1;
+        let start = (period - lag as u64 % period) % period;
+
+        MASTER::stop();
+        TIM::stop();
+        MASTER::trigger_on_update();
+        TIM::arm(start as u32);
+        MASTER::start(false);
+    }
+}
@@ -1,9 +1,10 @@
 //! # Pulse Width Modulation
+use core::convert::TryFrom;
 use core::marker::PhantomData;
 
 use crate::rcc::*;
 use crate::stm32::*;
-use crate::time::Hertz;
+use crate::time::{Hertz, NanoSecond};
 use crate::timer::pins::TimerPin;
 use crate::timer::*;
 
@@ -56,7 +57,7 @@ impl<TIM> Pwm<TIM> {
 }
 
 macro_rules! pwm {
-    ($($TIMX:ident: ($timX:ident, $arr:ident $(,$arr_h:ident)*),)+) => {
+    ($($TIMX:ident: $timX:ident,)+) => {
         $(
             impl PwmExt for $TIMX {
                 fn pwm(self, freq: Hertz, rcc: &mut Rcc) -> Pwm<Self> {
@@ -78,18 +79,8 @@ macro_rules! pwm {
 
             impl Pwm<$TIMX> {
                 pub fn set_freq(&mut self, freq: Hertz) {
-                    let ratio = self.clk / freq;
-                    let psc = (ratio - 1) / 0xffff;
-                    let arr = ratio / (psc + 1) - 1;
-
-                    unsafe {
-                        self.tim.psc.write(|w| w.psc().bits(psc as u16));
-                        self.tim.arr.write(|w| w.$arr().bits(arr as u16));
-                        $(
-                            self.tim.arr.modify(|_, w| w.$arr_h().bits((arr >> 16) as u16));
-                        )*
-                        self.tim.cr1.write(|w| w.cen().set_bit())
-                    }
+                    Period::for_rate::<$TIMX>(self.clk, freq).apply::<$TIMX>();
+                    self.tim.cr1.write(|w| w.cen().set_bit());
                 }
                 /// Starts listening
                 pub fn listen(&mut self) {
@@ -114,12 +105,142 @@ macro_rules! pwm {
     }
 }
 
+/// Converts a dead time in timer clock cycles to the DTG encoding, rounding up
+fn dead_time_bits(cycles: u32) -> u8 {
+    if cycles < 128 {
+        cycles as u8
+    } else if cycles.div_ceil(2) < 128 {
+        0x80 | (cycles.div_ceil(2) - 64) as u8
+    } else if cycles.div_ceil(8) < 64 {
+        0xc0 | (cycles.div_ceil(8) - 32) as u8
+    } else if cycles.div_ceil(16) < 64 {
+        0xe0 | (cycles.div_ceil(16) - 32) as u8
+    } else {
+        0xff
+    }
+}
+
+macro_rules! pwm_break_dead_time {
+    ($($TIMX:ident,)+) => {
+        $(
+            impl Pwm<$TIMX> {
+                /// Sets the delay inserted between the deactivation of an output and the
+                /// activation of its complementary output
+                ///
+                /// The dead time is rounded up to what the dead-time generator can achieve,
+                /// and saturates at 1008 timer clock cycles.
+                pub fn set_dead_time(&mut self, dead_time: NanoSecond) {
+                    let clk = self.clk.raw() as u64;
+                    let cycles = (dead_time.ticks() as u64 * clk).div_ceil(1_000_000_000);
+                    let dtg = dead_time_bits(cycles.min(u32::MAX as u64) as u32);
+                    self.tim.bdtr.modify(|_, w| unsafe { w.dtg().bits(dtg) });
+                }
+
+                /// Enables the break input: while it is active the outputs are disabled and
+                /// driven to their idle states
+                ///
+                /// With `auto_restart`, the outputs are enabled again at the next update
+                /// event once the break input is released. Otherwise they stay disabled
+                /// until [`resume_outputs`](Self::resume_outputs) is called. The break pin
+                /// (BKIN) has to be configured by the application.
+                pub fn enable_break(&mut self, polarity: Polarity, auto_restart: bool) {
+                    self.tim.bdtr.modify(|_, w| {
+                        w.bkp()
+                            .bit(polarity == Polarity::ActiveHigh)
+                            .aoe()
+                            .bit(auto_restart)
+                            .bke()
+                            .set_bit()
+                    });
+                }
+
+                /// Disables the break input
+                pub fn disable_break(&mut self) {
+                    self.tim.bdtr.modify(|_, w| w.bke().clear_bit());
+                }
+
+                /// Returns true if a break event disabled the outputs
+                pub fn is_break_active(&self) -> bool {
+                    self.tim.sr.read().bif().bit_is_set()
+                }
+
+                /// Clears the break flag and enables the outputs again
+                pub fn resume_outputs(&mut self) {
+                    self.tim.sr.modify(|_, w| w.bif().clear_bit());
+                    self.tim.bdtr.modify(|_, w| w.moe().set_bit());
+                }
+            }
+        )+
+    };
+}
+
+pwm_break_dead_time! {
+    TIM1,
+    TIM16,
+    TIM17,
+}
+
+#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
+pwm_break_dead_time! {
+    TIM15,
+}
+
+macro_rules! pwm_output_control {
+    ($TIMX:ident, $CH:ty, $ccxp:ident, $ccmrx_output:ident, $ocxm:ident) => {
+        impl PwmPin<$TIMX, $CH> {
+            /// Sets the polarity of the output, the duty cycle is the active time
+            pub fn set_polarity(&mut self, polarity: Polarity) {
+                let tim = unsafe { &*$TIMX::ptr() };
+                tim.ccer
+                    .modify(|_, w| w.$ccxp().bit(polarity == Polarity::ActiveLow));
+            }
+
+            /// Drives the output high, regardless of the counter and the duty cycle
+            ///
+            /// The timer keeps running, so the other channels are not affected.
+            pub fn force_high(&mut self) {
+                self.force(true);
+            }
+
+            /// Drives the output low, regardless of the counter and the duty cycle
+            pub fn force_low(&mut self) {
+                self.force(false);
+            }
+
+            /// Resumes PWM output after [`force_high`](Self::force_high) or
+            /// [`force_low`](Self::force_low)
+            pub fn resume(&mut self) {
+                self.set_mode(OutputCompareMode::PwmMode1);
+            }
+
+            fn force(&mut self, high: bool) {
+                let tim = unsafe { &*$TIMX::ptr() };
+                // The pin level is the reference level inverted by CCxP
+                let inverted = tim.ccer.read().$ccxp().bit_is_set();
+                self.set_mode(if high != inverted {
+                    OutputCompareMode::ForceHigh
+                } else {
+                    OutputCompareMode::ForceLow
+                });
+            }
+
+            fn set_mode(&mut self, mode: OutputCompareMode) {
+                let tim = unsafe { &*$TIMX::ptr() };
+                tim.$ccmrx_output()
+                    .modify(|_, w| unsafe { w.$ocxm().bits(mode as u8) });
+            }
+        }
+    };
+}
+
 #[cfg(any(feature = "stm32g0x1", feature = "stm32g070"))]
 macro_rules! pwm_hal {
     ($($TIMX:ident:
-        ($CH:ty, $ccxe:ident, $ccmrx_output:ident, $ocxpe:ident, $ocxm:ident, $ccrx:ident, $ccrx_l:ident, $ccrx_h:ident),)+
+        ($CH:ty, $ccxe:ident, $ccxp:ident, $ccmrx_output:ident, $ocxpe:ident, $ocxm:ident, $ccrx:ident, $ccrx_l:ident, $ccrx_h:ident),)+
     ) => {
         $(
+            pwm_output_control!($TIMX, $CH, $ccxp, $ccmrx_output, $ocxm);
+
             impl hal::PwmPin for PwmPin<$TIMX, $CH> {
                 type Duty = u32;
 
@@ -157,6 +278,7 @@ macro_rules! pwm_advanced_hal {
     ($($TIMX:ident: (
         $CH:ty,
         $ccxe:ident $(: $ccxne:ident)*,
+        $ccxp:ident,
         $ccmrx_output:ident,
         $ocxpe:ident,
         $ocxm:ident,
@@ -165,6 +287,8 @@ macro_rules! pwm_advanced_hal {
     ) ,)+
     ) => {
         $(
+            pwm_output_control!($TIMX, $CH, $ccxp, $ccmrx_output, $ocxm);
+
             impl hal::PwmPin for PwmPin<$TIMX, $CH> {
                 type Duty = u16;
 
@@ -214,54 +338,230 @@ macro_rules! pwm_advanced_hal {
 }
 
 pwm_advanced_hal! {
-    TIM1:  (Channel1, cc1e: cc1ne, ccmr1_output, oc1pe, oc1m, ccr1, moe),
-    TIM1:  (Channel2, cc2e: cc2ne, ccmr1_output, oc2pe, oc2m, ccr2, moe),
-    TIM1:  (Channel3, cc3e: cc3ne, ccmr2_output, oc3pe, oc3m, ccr3, moe),
-    TIM1:  (Channel4, cc4e, ccmr2_output, oc4pe, oc4m, ccr4, moe),
-    TIM14: (Channel1, cc1e, ccmr1_output, oc1pe, oc1m, ccr1),
-    TIM16: (Channel1, cc1e: cc1ne, ccmr1_output, oc1pe, oc1m, ccr1, moe),
-    TIM17: (Channel1, cc1e: cc1ne, ccmr1_output, oc1pe, oc1m, ccr1, moe),
+    TIM1:  (Channel1, cc1e: cc1ne, cc1p, ccmr1_output, oc1pe, oc1m, ccr1, moe),
+    TIM1:  (Channel2, cc2e: cc2ne, cc2p, ccmr1_output, oc2pe, oc2m, ccr2, moe),
+    TIM1:  (Channel3, cc3e: cc3ne, cc3p, ccmr2_output, oc3pe, oc3m, ccr3, moe),
+    TIM1:  (Channel4, cc4e, cc4p, ccmr2_output, oc4pe, oc4m, ccr4, moe),
+    TIM14: (Channel1, cc1e, cc1p, ccmr1_output, oc1pe, oc1m, ccr1),
+    TIM16: (Channel1, cc1e: cc1ne, cc1p, ccmr1_output, oc1pe, oc1m, ccr1, moe),
+    TIM17: (Channel1, cc1e: cc1ne, cc1p, ccmr1_output, oc1pe, oc1m, ccr1, moe),
 }
 
 #[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
 pwm_advanced_hal! {
-    TIM15: (Channel1, cc1e: cc1ne, ccmr1_output, oc1pe, oc1m, ccr1, moe),
+    TIM15: (Channel1, cc1e: cc1ne, cc1p, ccmr1_output, oc1pe, oc1m, ccr1, moe),
+}
+
+/// The PAC shares the TIM16 register block with TIM15, which lacks the channel 2 fields
+#[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
+mod tim15_ch2 {
+    use super::*;
+
+    const CC2E: u32 = 1 << 4;
+    const CC2P: u32 = 1 << 5;
+    const OC2PE: u32 = 1 << 11;
+    const OC2M_OFFSET: u32 = 12;
+    const OC2M_3: u32 = 1 << 24;
+    const CCR2_OFFSET: usize = 0x38;
+
+    fn ccr2() -> *mut u32 {
+        (TIM15::ptr() as usize + CCR2_OFFSET) as *mut u32
+    }
+
+    fn set_mode(mode: OutputCompareMode) {
+        let mode = mode as u32;
+        let tim = unsafe { &*TIM15::ptr() };
+        tim.ccmr1_output().modify(|r, w| unsafe {
+            let bits = r.bits() & !(0b111 << OC2M_OFFSET) & !OC2M_3;
+            w.bits(bits | ((mode & 0b111) << OC2M_OFFSET) | ((mode >> 3) * OC2M_3))
+        });
+    }
+
+    impl PwmPin<TIM15, Channel2> {
+        /// Sets the polarity of the output, the duty cycle is the active time
+        pub fn set_polarity(&mut self, polarity: Polarity) {
+            let tim = unsafe { &*TIM15::ptr() };
+            tim.ccer.modify(|r, w| unsafe {
+                if polarity == Polarity::ActiveLow {
+                    w.bits(r.bits() | CC2P)
+                } else {
+                    w.bits(r.bits() & !CC2P)
+                }
+            });
+        }
+
+        /// Drives the output high, regardless of the counter and the duty cycle
+        pub fn force_high(&mut self) {
+            self.force(true);
+        }
+
+        /// Drives the output low, regardless of the counter and the duty cycle
+        pub fn force_low(&mut self) {
+            self.force(false);
+        }
+
+        /// Resumes PWM output after [`force_high`](Self::force_high) or
+        /// [`force_low`](Self::force_low)
+        pub fn resume(&mut self) {
+            set_mode(OutputCompareMode::PwmMode1);
+        }
+
+        fn force(&mut self, high: bool) {
+            let tim = unsafe { &*TIM15::ptr() };
+            let inverted = tim.ccer.read().bits() & CC2P != 0;
+            set_mode(if high != inverted {
+                OutputCompareMode::ForceHigh
+            } else {
+                OutputCompareMode::ForceLow
+            });
+        }
+    }
+
+    impl hal::PwmPin for PwmPin<TIM15, Channel2> {
+        type Duty = u16;
+
+        fn disable(&mut self) {
+            let tim = unsafe { &*TIM15::ptr() };
+            tim.ccer.modify(|r, w| unsafe { w.bits(r.bits() & !CC2E) });
+        }
+
+        fn enable(&mut self) {
+            let tim = unsafe { &*TIM15::ptr() };
+            set_mode(OutputCompareMode::PwmMode1);
+            tim.ccmr1_output()
+                .modify(|r, w| unsafe { w.bits(r.bits() | OC2PE) });
+            tim.ccer.modify(|r, w| unsafe { w.bits(r.bits() | CC2E) });
+            tim.bdtr.modify(|_, w| w.moe().set_bit());
+        }
+
+        fn get_duty(&self) -> u16 {
+            unsafe { core::ptr::read_volatile(ccr2()) as u16 }
+        }
+
+        fn get_max_duty(&self) -> u16 {
+            unsafe { (*TIM15::ptr()).arr.read().arr().bits() }
+        }
+
+        fn set_duty(&mut self, duty: u16) {
+            unsafe { core::ptr::write_volatile(ccr2(), duty as u32) }
+        }
+    }
+
+    impl PwmPinMode for PwmPin<TIM15, Channel2> {
+        fn set_compare_mode(&mut self, mode: OutputCompareMode) {
+            set_mode(mode);
+        }
+    }
 }
 
 #[cfg(feature = "stm32g0x1")]
 pwm_hal! {
-    TIM2: (Channel1, cc1e, ccmr1_output, oc1pe, oc1m, ccr1, ccr1_l, ccr1_h),
-    TIM2: (Channel2, cc2e, ccmr1_output, oc2pe, oc2m, ccr2, ccr2_l, ccr2_h),
-    TIM2: (Channel3, cc3e, ccmr2_output, oc3pe, oc3m, ccr3, ccr3_l, ccr3_h),
-    TIM2: (Channel4, cc4e, ccmr2_output, oc4pe, oc4m, ccr4, ccr4_l, ccr4_h),
-    TIM3: (Channel1, cc1e, ccmr1_output, oc1pe, oc1m, ccr1, ccr1_l, ccr1_h),
-    TIM3: (Channel2, cc2e, ccmr1_output, oc2pe, oc2m, ccr2, ccr2_l, ccr2_h),
-    TIM3: (Channel3, cc3e, ccmr2_output, oc3pe, oc3m, ccr3, ccr3_l, ccr3_h),
-    TIM3: (Channel4, cc4e, ccmr2_output, oc4pe, oc4m, ccr4, ccr4_l, ccr4_h),
+    TIM2: (Channel1, cc1e, cc1p, ccmr1_output, oc1pe, oc1m, ccr1, ccr1_l, ccr1_h),
+    TIM2: (Channel2, cc2e, cc2p, ccmr1_output, oc2pe, oc2m, ccr2, ccr2_l, ccr2_h),
+    TIM2: (Channel3, cc3e, cc3p, ccmr2_output, oc3pe, oc3m, ccr3, ccr3_l, ccr3_h),
+    TIM2: (Channel4, cc4e, cc4p, ccmr2_output, oc4pe, oc4m, ccr4, ccr4_l, ccr4_h),
+    TIM3: (Channel1, cc1e, cc1p, ccmr1_output, oc1pe, oc1m, ccr1, ccr1_l, ccr1_h),
+    TIM3: (Channel2, cc2e, cc2p, ccmr1_output, oc2pe, oc2m, ccr2, ccr2_l, ccr2_h),
+    TIM3: (Channel3, cc3e, cc3p, ccmr2_output, oc3pe, oc3m, ccr3, ccr3_l, ccr3_h),
+    TIM3: (Channel4, cc4e, cc4p, ccmr2_output, oc4pe, oc4m, ccr4, ccr4_l, ccr4_h),
 }
 
 #[cfg(feature = "stm32g070")]
 pwm_hal! {
-    TIM3: (Channel1, cc1e, ccmr1_output, oc1pe, oc1m, ccr1, ccr1_l, ccr1_h),
-    TIM3: (Channel2, cc2e, ccmr1_output, oc2pe, oc2m, ccr2, ccr2_l, ccr2_h),
-    TIM3: (Channel3, cc3e, ccmr2_output, oc3pe, oc3m, ccr3, ccr3_l, ccr3_h),
-    TIM3: (Channel4, cc4e, ccmr2_output, oc4pe, oc4m, ccr4, ccr4_l, ccr4_h),
+    TIM3: (Channel1, cc1e, cc1p, ccmr1_output, oc1pe, oc1m, ccr1, ccr1_l, ccr1_h),
+    TIM3: (Channel2, cc2e, cc2p, ccmr1_output, oc2pe, oc2m, ccr2, ccr2_l, ccr2_h),
+    TIM3: (Channel3, cc3e, cc3p, ccmr2_output, oc3pe, oc3m, ccr3, ccr3_l, ccr3_h),
+    TIM3: (Channel4, cc4e, cc4p, ccmr2_output, oc4pe, oc4m, ccr4, ccr4_l, ccr4_h),
 }
 
 pwm! {
-    TIM1: (tim1, arr),
-    TIM3: (tim3, arr_l, arr_h),
-    TIM14: (tim14, arr),
-    TIM16: (tim16, arr),
-    TIM17: (tim17, arr),
+    TIM1: tim1,
+    TIM3: tim3,
+    TIM14: tim14,
+    TIM16: tim16,
+    TIM17: tim17,
 }
 
 #[cfg(feature = "stm32g0x1")]
 pwm! {
-    TIM2: (tim2, arr_l, arr_h),
+    TIM2: tim2,
 }
 
 #[cfg(any(feature = "stm32g070", feature = "stm32g071", feature = "stm32g081"))]
 pwm! {
-    TIM15: (tim15, arr),
+    TIM15: tim15,
+}
+
+/// PWM output with fractional duty cycles, dithered over consecutive periods
+///
+/// The duty cycle has `frac_bits` more bits than the timer: each period the output uses
+/// one of the two nearest duty codes, picked by a first order sigma-delta modulator so the
+/// average is exact. [`update`](HiResPwm::update) has to be called once per period, from
+/// the timer update interrupt. The dithering adds a ripple of one duty code, fine for LED
+/// dimming or heaters that filter it out.
+pub struct HiResPwm<PIN> {
+    pin: PIN,
+    frac_bits: u8,
+    duty: u32,
+    acc: u32,
+}
+
+impl<PIN> HiResPwm<PIN>
+where
+    PIN: hal::PwmPin,
+    PIN::Duty: Into<u32> + TryFrom<u32>,
+{
+    /// Wraps a PWM pin, adding `frac_bits` (1 to 16) bits of resolution
+    pub fn new(pin: PIN, frac_bits: u8) -> Self {
+        hal_assert!(frac_bits > 0 && frac_bits <= 16);
+        HiResPwm {
+            pin,
+            frac_bits,
+            duty: 0,
+            acc: 0,
+        }
+    }
+
+    /// Largest duty cycle, the maximum duty of the pin shifted by the fractional bits
+    pub fn get_max_duty(&self) -> u32 {
+        let max: u32 = self.pin.get_max_duty().into();
+        max.saturating_mul(1 << self.frac_bits)
+    }
+
+    /// Duty cycle currently requested
+    pub fn get_duty(&self) -> u32 {
+        self.duty
+    }
+
+    /// Sets the duty cycle, saturated to [`get_max_duty`](HiResPwm::get_max_duty)
+    pub fn set_duty(&mut self, duty: u32) {
+        self.duty = duty.min(self.get_max_duty());
+        self.update();
+    }
+
+    /// Programs the duty code of the next period
+    pub fn update(&mut self) {
+        let mask = (1 << self.frac_bits) - 1;
+        self.acc += self.duty & mask;
+        let code = (self.duty >> self.frac_bits) + (self.acc >> self.frac_bits);
+        self.acc &= mask;
+        if let Ok(code) = PIN::Duty::try_from(code) {
+            self.pin.set_duty(code);
+        }
+    }
+
+    /// Enables the output
+    pub fn enable(&mut self) {
+        self.pin.enable();
+    }
+
+    /// Disables the output
+    pub fn disable(&mut self) {
+        self.pin.disable();
+    }
+
+    /// Releases the PWM pin
+    pub fn release(self) -> PIN {
+        self.pin
+    }
 }
@@ -1,6 +1,7 @@
 use crate::rcc::*;
 use crate::stm32::*;
 use crate::time::{duration, Hertz, Instant, MicroSecond};
+use crate::timer::Instance;
 
 pub trait StopwatchExt<TIM> {
     fn stopwatch(self, rcc: &mut Rcc) -> Stopwatch<TIM>;
@@ -16,7 +17,7 @@ macro_rules! stopwatches {
         $(
             impl Stopwatch<$TIM> {
                 pub fn $tim(tim: $TIM, rcc: &mut Rcc) -> Self {
-                    assert!(rcc.clocks.apb_tim_clk.raw() > 1_000_000);
+                    hal_assert!(rcc.clocks.apb_tim_clk.raw() > 1_000_000);
                     $TIM::enable(rcc);
                     $TIM::reset(rcc);
 
@@ -32,7 +33,7 @@ macro_rules! stopwatches {
                 /// Useful if the APB Timer Clock changes after the `Stopwatch` is created or
                 /// to deliberately speed up or slow down the `Stopwatch` from actual measured time.
                 pub fn set_clock(&mut self, clk: Hertz) {
-                    assert!(clk.raw() > 1_000_000);
+                    hal_assert!(clk.raw() > 1_000_000);
                     self.clk = clk;
                 }
 
@@ -66,7 +67,7 @@ macro_rules! stopwatches {
 
                 pub fn elapsed(&self, ts: Instant) -> MicroSecond {
                     let now = self.now().ticks();
-                    let cycles = (now as u16).wrapping_sub(ts.ticks() as u16) as u32;
+                    let cycles = $TIM::ticks_between(ts.ticks(), now);
                     duration(self.clk, cycles * (1 + self.tim.psc.read().bits()))
                 }
 
@@ -77,7 +78,8 @@ macro_rules! stopwatches {
                     let started = self.now().ticks();
                     closure();
                     let now = self.now().ticks();
-                    duration(self.clk, now.wrapping_sub(started) * (1 + self.tim.psc.read().bits()))
+                    let cycles = $TIM::ticks_between(started, now);
+                    duration(self.clk, cycles * (1 + self.tim.psc.read().bits()))
                 }
             }
 
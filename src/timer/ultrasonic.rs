@@ -0,0 +1,142 @@
+//! # Ultrasonic ranging (HC-SR04)
+//!
+//! The trigger pulse is generated by any [`OneShotPulse`] source and the echo pulse is
+//! measured by a timer capturing both edges of its channel 1 input.
+use crate::rcc::*;
+use crate::stm32::*;
+use crate::time::Hertz;
+use crate::timer::opm::OneShotPulse;
+use crate::timer::pins::TimerPin;
+use crate::timer::{Channel1, Instance};
+
+/// Ranging error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// No echo received within the timeout
+    Timeout,
+}
+
+/// HC-SR04 style ultrasonic ranger
+pub struct Ultrasonic<TIM, TRIG, ECHO> {
+    tim: TIM,
+    trigger: TRIG,
+    echo: ECHO,
+    timeout: u16,
+    started: u32,
+}
+
+/// Speed of sound in air at 20°C, in mm/s
+const SPEED_OF_SOUND: u32 = 343_000;
+
+/// Default echo timeout: HC-SR04 outputs a ~38 ms pulse when no obstacle is detected
+const DEFAULT_TIMEOUT_US: u16 = 30_000;
+
+macro_rules! ultrasonic {
+    ($($TIMX:ident: $timX:ident,)+) => {
+        $(
+            impl<TRIG, ECHO> Ultrasonic<$TIMX, TRIG, ECHO>
+            where
+                TRIG: OneShotPulse,
+                ECHO: TimerPin<$TIMX, Channel = Channel1>,
+            {
+                /// Creates the ranger, `trigger` has to be configured to output a 10 µs pulse
+                pub fn $timX(tim: $TIMX, trigger: TRIG, echo: ECHO, rcc: &mut Rcc) -> Self {
+                    $TIMX::enable(rcc);
+                    $TIMX::reset(rcc);
+                    echo.setup();
+
+                    // 1 MHz free running counter
+                    let clk: Hertz = rcc.clocks.apb_tim_clk;
+                    let psc = clk.raw() / 1_000_000 - 1;
+                    $TIMX::set_prescaler(psc as u16);
+                    $TIMX::set_auto_reload($TIMX::max_auto_reload());
+                    tim.egr.write(|w| w.ug().set_bit());
+
+                    // IC1 mapped on TI1 (CC1S = 01), IC2 mapped on TI1 (CC2S = 10)
+                    tim.ccmr1_input().write(|w| unsafe { w.bits(0b10 << 8 | 0b01) });
+                    // IC1 captures the rising edge, IC2 the falling edge
+                    tim.ccer.write(|w| {
+                        w.cc1p()
+                            .clear_bit()
+                            .cc1e()
+                            .set_bit()
+                            .cc2p()
+                            .set_bit()
+                            .cc2e()
+                            .set_bit()
+                    });
+                    $TIMX::start(false);
+
+                    Ultrasonic {
+                        tim,
+                        trigger,
+                        echo,
+                        timeout: DEFAULT_TIMEOUT_US,
+                        started: 0,
+                    }
+                }
+
+                /// Sets the maximum echo duration, in microseconds
+                pub fn set_timeout(&mut self, timeout_us: u16) {
+                    self.timeout = timeout_us;
+                }
+
+                /// Sends the trigger pulse and starts waiting for the echo
+                pub fn start(&mut self) {
+                    // Discard stale captures
+                    self.tim.ccr1.read();
+                    self.tim.ccr2.read();
+                    self.tim.sr.write(|w| unsafe { w.bits(0) });
+                    self.started = $TIMX::counter();
+                    self.trigger.generate();
+                }
+
+                /// Returns the echo pulse width in microseconds
+                pub fn read_echo(&mut self) -> nb::Result<u32, Error> {
+                    let sr = self.tim.sr.read();
+                    if sr.cc1if().bit_is_set() || sr.cc1of().bit_is_set() {
+                        if sr.cc2if().bit_is_set() {
+                            let rise = self.tim.ccr1.read().bits();
+                            let fall = self.tim.ccr2.read().bits();
+                            return Ok($TIMX::ticks_between(rise, fall));
+                        }
+                    }
+                    let now = $TIMX::counter();
+                    if $TIMX::ticks_between(self.started, now) > self.timeout as u32 {
+                        Err(nb::Error::Other(Error::Timeout))
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
+
+                /// Returns the distance to the obstacle in millimeters
+                pub fn read_distance(&mut self) -> nb::Result<u32, Error> {
+                    let echo = self.read_echo()?;
+                    Ok(echo * SPEED_OF_SOUND / 2_000_000)
+                }
+
+                /// Triggers a measurement and blocks until the distance is known, in millimeters
+                pub fn measure(&mut self) -> Result<u32, Error> {
+                    self.start();
+                    nb::block!(self.read_distance())
+                }
+
+                /// Releases the timer, trigger source and echo pin
+                pub fn release(self) -> ($TIMX, TRIG, ECHO) {
+                    $TIMX::stop();
+                    (self.tim, self.trigger, self.echo.release())
+                }
+            }
+        )+
+    }
+}
+
+ultrasonic! {
+    TIM1: tim1,
+    TIM3: tim3,
+}
+
+#[cfg(feature = "stm32g0x1")]
+ultrasonic! {
+    TIM2: tim2,
+}
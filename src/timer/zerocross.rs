@@ -0,0 +1,271 @@
+//! # Zero-cross detection
+//!
+//! The output of a comparator watching a divided-down AC voltage toggles at each zero
+//! crossing. Routed to a capture input of TIM2 or TIM3 (TIMx_TISEL), each edge is
+//! timestamped by the timer at the counter tick, without the interrupt latency, which is
+//! what a triac dimmer needs to fire at a constant phase angle.
+//!
+//! COMP1 is routed to the channel 1 input and COMP2 to the channel 2 input. Some comparator
+//! hysteresis keeps the noise around the crossings from giving several edges.
+//!
+//! The overflows are counted by [`ZeroCross::on_interrupt`] to extend the timestamps to
+//! 64-bit ticks, the timer interrupt has to be unmasked in the NVIC and its handler has to
+//! call it.
+use cortex_m::interrupt;
+
+use crate::analog::comparator::{Comparator, Enabled, COMP1, COMP2};
+use crate::analog::ComparatorOutput;
+use crate::rcc::*;
+use crate::stm32::*;
+use crate::time::Hertz;
+use crate::timer::capture::InputFilter;
+use crate::timer::Instance;
+
+/// Comparator whose output can be captured by `TIM`
+pub trait CaptureRoute<TIM> {
+    /// Capture channel, 0 for channel 1
+    const CHANNEL: u8;
+    /// TIxSEL code of the comparator output, the same on both channels
+    const TISEL: u8;
+}
+
+impl CaptureRoute<TIM2> for COMP1 {
+    const CHANNEL: u8 = 0;
+    const TISEL: u8 = 0b0001;
+}
+
+impl CaptureRoute<TIM2> for COMP2 {
+    const CHANNEL: u8 = 1;
+    const TISEL: u8 = 0b0010;
+}
+
+impl CaptureRoute<TIM3> for COMP1 {
+    const CHANNEL: u8 = 0;
+    const TISEL: u8 = 0b0001;
+}
+
+impl CaptureRoute<TIM3> for COMP2 {
+    const CHANNEL: u8 = 1;
+    const TISEL: u8 = 0b0010;
+}
+
+/// Zero crossing timestamped by [`ZeroCross::on_interrupt`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crossing {
+    /// Tick of the comparator edge
+    pub at: u64,
+    /// The comparator output went high
+    pub rising: bool,
+}
+
+pub trait ZeroCrossExt<COMP>: Sized {
+    fn zero_cross(
+        self,
+        comp: Comparator<COMP, Enabled>,
+        tick: Hertz,
+        rcc: &mut Rcc,
+    ) -> ZeroCross<Self, COMP>;
+}
+
+/// Comparator edges timestamped by a timer, see the [module documentation](self)
+pub struct ZeroCross<TIM, COMP> {
+    tim: TIM,
+    comp: Comparator<COMP, Enabled>,
+    tick: Hertz,
+    periods: u64,
+    last: Option<u64>,
+    /// Last two half periods, the most recent first
+    half_periods: [Option<u32>; 2],
+}
+
+impl<TIM, COMP> ZeroCross<TIM, COMP> {
+    /// Tick rate of the timestamps
+    pub fn tick_rate(&self) -> Hertz {
+        self.tick
+    }
+
+    /// Tick of the last crossing
+    pub fn last_crossing(&self) -> Option<u64> {
+        self.last
+    }
+
+    /// Ticks between the last crossing and the previous one
+    pub fn half_period(&self) -> Option<u32> {
+        self.half_periods[0]
+    }
+
+    /// Ticks of the last full period
+    ///
+    /// Made of two consecutive half periods, so that an offset of the comparator
+    /// threshold, which lengthens one half period as much as it shortens the other one,
+    /// cancels out.
+    pub fn period(&self) -> Option<u32> {
+        match self.half_periods {
+            [Some(a), Some(b)] => Some(a.saturating_add(b)),
+            _ => None,
+        }
+    }
+
+    /// Frequency of the last full period, in millihertz
+    pub fn frequency_millihertz(&self) -> Option<u32> {
+        let period = self.period()? as u64;
+        Some((self.tick.raw() as u64 * 1000 / period) as u32)
+    }
+
+    /// Forgets the past crossings, e.g. after the supply was interrupted
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.half_periods = [None, None];
+    }
+
+    fn record(&mut self, at: u64) {
+        if let Some(last) = self.last {
+            let half_period = (at - last).min(u32::MAX as u64) as u32;
+            self.half_periods = [Some(half_period), self.half_periods[0]];
+        }
+        self.last = Some(at);
+    }
+}
+
+macro_rules! zero_cross {
+    ($($TIM:ident: $tim:ident,)+) => {
+        $(
+            impl<COMP> ZeroCross<$TIM, COMP>
+            where
+                COMP: CaptureRoute<$TIM>,
+                Comparator<COMP, Enabled>: ComparatorOutput,
+            {
+                /// Starts the counter at the `tick` rate and captures both edges of `comp`
+                ///
+                /// # Panics
+                ///
+                /// Panics if the timer clock is not a multiple of `tick`, or `tick` is below
+                /// the timer clock divided by 65536.
+                pub fn $tim(tim: $TIM, comp: Comparator<COMP, Enabled>, tick: Hertz, rcc: &mut Rcc) -> Self {
+                    let clk = rcc.clocks.apb_tim_clk.raw();
+                    hal_assert!(tick.raw() > 0 && clk.is_multiple_of(tick.raw()));
+                    let psc = clk / tick.raw() - 1;
+                    hal_assert!(psc <= u16::MAX as u32);
+
+                    $TIM::enable(rcc);
+                    $TIM::reset(rcc);
+                    $TIM::set_prescaler(psc as u16);
+                    $TIM::set_auto_reload($TIM::max_auto_reload());
+                    $TIM::set_update_source(crate::timer::UpdateSource::CounterOnly);
+                    $TIM::apply_settings_silently();
+
+                    let ch = COMP::CHANNEL as u32;
+                    // TIxSEL = 0001 selects COMP1_OUT, 0010 COMP2_OUT
+                    tim.tisel.write(|w| unsafe { w.bits((COMP::TISEL as u32) << (8 * ch)) });
+                    // ICx mapped on TIx (CCxS = 01)
+                    tim.ccmr1_input().write(|w| unsafe { w.bits(0b01 << (8 * ch)) });
+                    // Both edges (CCxP = CCxNP = 1), capture enabled
+                    tim.ccer.write(|w| unsafe { w.bits(0b1011 << (4 * ch)) });
+                    tim.sr.write(|w| unsafe { w.bits(0) });
+                    tim.dier.write(|w| unsafe { w.bits(1 << (1 + ch) | 1) });
+                    $TIM::start(false);
+
+                    ZeroCross {
+                        tim,
+                        comp,
+                        tick,
+                        periods: 0,
+                        last: None,
+                        half_periods: [None, None],
+                    }
+                }
+
+                /// Sets the digital filter of the capture input
+                pub fn set_filter(&mut self, filter: InputFilter) {
+                    let shift = 8 * COMP::CHANNEL as u32 + 4;
+                    self.tim.ccmr1_input().modify(|r, w| unsafe {
+                        w.bits(r.bits() & !(0b1111 << shift) | (filter as u32) << shift)
+                    });
+                }
+
+                /// Ticks since the timer was started
+                pub fn now(&self) -> u64 {
+                    interrupt::free(|_| {
+                        let mut periods = self.periods;
+                        let mut counter = $TIM::counter();
+                        // Overflow not handled by `on_interrupt` yet, the counter is read
+                        // again as it may have been read before the overflow
+                        if self.tim.sr.read().uif().bit_is_set() {
+                            periods += 1;
+                            counter = $TIM::counter();
+                        }
+                        periods << $TIM::WIDTH | counter as u64
+                    })
+                }
+
+                /// To be called from the timer interrupt handler
+                ///
+                /// Counts the counter overflows and returns the crossing captured since
+                /// the last call, if any. A crossing missed while the previous capture was
+                /// not read yet leaves a half period twice as long.
+                pub fn on_interrupt(&mut self) -> Option<Crossing> {
+                    let ch = COMP::CHANNEL as u32;
+                    let at = interrupt::free(|_| {
+                        let sr = self.tim.sr.read().bits();
+                        let mut at = None;
+                        if sr & 1 << (1 + ch) != 0 {
+                            // Reading CCRx clears CCxIF
+                            let captured = match ch {
+                                0 => self.tim.ccr1.read().bits(),
+                                _ => self.tim.ccr2.read().bits(),
+                            };
+                            // With an overflow pending, a capture in the lower half of the
+                            // counter range was taken after the overflow
+                            let mut periods = self.periods;
+                            if sr & 1 != 0 && captured < 1 << ($TIM::WIDTH - 1) {
+                                periods += 1;
+                            }
+                            at = Some(periods << $TIM::WIDTH | captured as u64);
+                        }
+                        if sr & 1 != 0 {
+                            // Clears UIF, without the read-modify-write that could clear a
+                            // capture flag set meanwhile
+                            self.tim.sr.write(|w| unsafe { w.bits(!1) });
+                            self.periods += 1;
+                        }
+                        at
+                    })?;
+                    self.record(at);
+                    Some(Crossing {
+                        at,
+                        rising: self.comp.output(),
+                    })
+                }
+
+                /// Stops the timer and releases it with the comparator
+                pub fn release(self) -> ($TIM, Comparator<COMP, Enabled>) {
+                    $TIM::stop();
+                    self.tim.dier.write(|w| unsafe { w.bits(0) });
+                    self.tim.ccer.write(|w| unsafe { w.bits(0) });
+                    self.tim.tisel.write(|w| unsafe { w.bits(0) });
+                    (self.tim, self.comp)
+                }
+            }
+
+            impl<COMP> ZeroCrossExt<COMP> for $TIM
+            where
+                COMP: CaptureRoute<$TIM>,
+                Comparator<COMP, Enabled>: ComparatorOutput,
+            {
+                fn zero_cross(
+                    self,
+                    comp: Comparator<COMP, Enabled>,
+                    tick: Hertz,
+                    rcc: &mut Rcc,
+                ) -> ZeroCross<$TIM, COMP> {
+                    ZeroCross::$tim(self, comp, tick, rcc)
+                }
+            }
+        )+
+    }
+}
+
+zero_cross! {
+    TIM2: tim2,
+    TIM3: tim3,
+}
@@ -0,0 +1,176 @@
+//! # USB Type-C termination
+//!
+//! After reset the CC pins of the UCPD peripherals are tied to the dead-battery pull-downs
+//! (Rd), so a powerless board still gets VBUS from a source. A device acting as a source
+//! has to configure its own terminations, then hand the pads over to the UCPD, otherwise
+//! the partner sees a sink on both ends and never applies VBUS. This driver only covers
+//! the Type-C terminations and VCONN, not the Power Delivery messaging.
+use crate::rcc::{Enable, Rcc, Reset};
+use crate::stm32::{UCPD1, UCPD2};
+use crate::syscfg;
+
+/// Configuration channel line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CcLine {
+    CC1,
+    CC2,
+}
+
+impl CcLine {
+    fn other(self) -> Self {
+        match self {
+            CcLine::CC1 => CcLine::CC2,
+            CcLine::CC2 => CcLine::CC1,
+        }
+    }
+
+    fn mask(self) -> u8 {
+        match self {
+            CcLine::CC1 => 0b01,
+            CcLine::CC2 => 0b10,
+        }
+    }
+}
+
+/// Current advertised by the Rp pull-up of a source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceCurrent {
+    /// Default USB power (500 mA or 900 mA)
+    Default = 0b01,
+    /// 1.5 A at 5 V
+    A1_5 = 0b10,
+    /// 3.0 A at 5 V
+    A3_0 = 0b11,
+}
+
+/// Termination applied on the CC lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Rp pull-ups
+    Source(SourceCurrent),
+    /// Rd pull-downs
+    Sink,
+}
+
+/// Voltage state of a CC line, as reported by the UCPD comparators
+///
+/// The meaning of the levels depends on the role: for a source 0 is no sink (or a cable
+/// with Ra), 1 an attached sink and 3 nothing connected; for a sink the levels 1 to 3 give
+/// the current advertised by the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CcState(pub u8);
+
+pub struct Ucpd<UCPD> {
+    rb: UCPD,
+    vconn: Option<CcLine>,
+}
+
+pub trait UcpdExt: Sized {
+    fn ucpd(self, rcc: &mut Rcc) -> Ucpd<Self>;
+}
+
+macro_rules! ucpd {
+    ($($UCPDX:ident: ($strobe:expr, $strobe_doc:expr),)+) => {
+        $(
+            impl UcpdExt for $UCPDX {
+                fn ucpd(self, rcc: &mut Rcc) -> Ucpd<Self> {
+                    Ucpd::$UCPDX(self, rcc)
+                }
+            }
+
+            impl Ucpd<$UCPDX> {
+                /// Enables the peripheral, the pads stay on the dead-battery pull-downs
+                /// until [`Self::take_cc_control()`]
+                #[allow(non_snake_case)]
+                pub fn $UCPDX(ucpd: $UCPDX, rcc: &mut Rcc) -> Self {
+                    $UCPDX::enable(rcc);
+                    $UCPDX::reset(rcc);
+                    ucpd.cfg1.modify(|_, w| w.ucpden().set_bit());
+                    Ucpd {
+                        rb: ucpd,
+                        vconn: None,
+                    }
+                }
+
+                /// Sets the terminations of both CC lines
+                ///
+                /// An enabled VCONN line keeps its termination off.
+                pub fn set_role(&mut self, role: Role) {
+                    let (sink, current) = match role {
+                        Role::Source(current) => (false, current as u8),
+                        Role::Sink => (true, 0b00),
+                    };
+                    self.rb.cr.modify(|_, w| unsafe {
+                        w.anamode().bit(sink).anasubmode().bits(current)
+                    });
+                    self.apply_ccenable();
+                }
+
+                /// Replaces the dead-battery pull-downs by the terminations set with
+                /// [`Self::set_role()`]
+                ///
+                #[doc = $strobe_doc]
+                /// The role has to be configured first, so that the lines never float.
+                pub fn take_cc_control(&mut self) {
+                    syscfg::modify_cfgr1(1 << $strobe, 1 << $strobe);
+                }
+
+                /// Supplies VCONN on `line`, through an external power switch driven by
+                /// the application
+                ///
+                /// The termination of `line` is disabled so the switch can drive it, and
+                /// the PHY is moved to the other line which carries the communication.
+                pub fn enable_vconn(&mut self, line: CcLine) {
+                    self.vconn = Some(line);
+                    self.rb
+                        .cr
+                        .modify(|_, w| w.phyccsel().bit(line.other() == CcLine::CC2));
+                    self.apply_ccenable();
+                }
+
+                /// Restores the termination of the VCONN line, once the external switch is
+                /// off
+                pub fn disable_vconn(&mut self) {
+                    self.vconn = None;
+                    self.apply_ccenable();
+                }
+
+                /// Line supplied with VCONN, if any
+                pub fn vconn(&self) -> Option<CcLine> {
+                    self.vconn
+                }
+
+                /// Returns the voltage state of `line`
+                pub fn cc_state(&self, line: CcLine) -> CcState {
+                    let sr = self.rb.sr.read();
+                    CcState(match line {
+                        CcLine::CC1 => sr.typec_vstate_cc1().bits(),
+                        CcLine::CC2 => sr.typec_vstate_cc2().bits(),
+                    })
+                }
+
+                /// Disables the peripheral and releases it, the pads keep their
+                /// terminations until the next reset
+                pub fn release(self) -> $UCPDX {
+                    self.rb.cfg1.modify(|_, w| w.ucpden().clear_bit());
+                    self.rb
+                }
+
+                fn apply_ccenable(&mut self) {
+                    let lines = match self.vconn {
+                        Some(line) => line.other().mask(),
+                        None => 0b11,
+                    };
+                    self.rb
+                        .cr
+                        .modify(|_, w| unsafe { w.ccenable().bits(lines) });
+                }
+            }
+        )+
+    };
+}
+
+ucpd! {
+    UCPD1: (9, "Sets UCPD1_STROBE in SYSCFG_CFGR1."),
+    UCPD2: (10, "Sets UCPD2_STROBE in SYSCFG_CFGR1."),
+}
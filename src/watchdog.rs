@@ -1,30 +1,44 @@
+//! # Watchdogs
+//!
+//! The independent watchdog (IWDG) runs from the LSI, which it starts itself, keeps running
+//! in Stop and Standby, and cannot be stopped once started. The window watchdog (WWDG) runs
+//! from PCLK, so it stops with the clocks in Stop mode, but it also resets the device when
+//! fed too early and can raise an interrupt just before its timeout to save some state.
 use crate::prelude::*;
-use crate::rcc::{Enable, Rcc};
+use crate::rcc::{Enable, Rcc, LSI_FREQ};
 use crate::stm32::{IWDG, WWDG};
 use crate::time::{Hertz, MicroSecond};
 use hal::watchdog;
 
-pub struct IndependedWatchdog {
+/// Largest IWDG_RLR value
+const IWDG_MAX_RELOAD: u32 = 0xfff;
+
+/// Value of the WWDG counter at which the device is reset when the counter decrements
+const WWDG_RESET_COUNTER: u8 = 0x3f;
+
+/// Independent watchdog (IWDG)
+pub struct IndependentWatchdog {
     iwdg: IWDG,
 }
 
-impl IndependedWatchdog {
+#[deprecated(note = "renamed to `IndependentWatchdog`")]
+pub type IndependedWatchdog = IndependentWatchdog;
+
+impl IndependentWatchdog {
     pub fn feed(&mut self) {
         self.iwdg.kr.write(|w| unsafe { w.key().bits(0xaaaa) });
     }
 
+    /// Starts the watchdog, which resets the device if it is not fed within `period`
+    ///
+    /// The prescaler is the smallest one for which `period` fits the 12-bit reload value,
+    /// the LSI is only accurate to a few percent so the actual timeout varies as much.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is above 32.7 s.
     pub fn start(&mut self, period: MicroSecond) {
-        let mut cycles = crate::time::cycles(period.into(), 16_384.Hz());
-        let mut psc = 0;
-        let mut reload = 0;
-        while psc < 6 {
-            reload = cycles;
-            if reload <= 0xfff {
-                break;
-            }
-            psc += 1;
-            cycles /= 2;
-        }
+        let (psc, reload) = Self::prescaler_and_reload(period);
 
         // Enable watchdog
         self.iwdg.kr.write(|w| unsafe { w.key().bits(0xcccc) });
@@ -41,15 +55,32 @@ impl IndependedWatchdog {
 
         self.iwdg.kr.write(|w| unsafe { w.key().bits(0xaaaa) });
     }
+
+    pub fn release(self) -> IWDG {
+        self.iwdg
+    }
+
+    /// Returns the IWDG_PR and IWDG_RLR values of a timeout, the counter runs at the LSI
+    /// frequency divided by 4 << IWDG_PR and resets the device after IWDG_RLR + 1 ticks
+    fn prescaler_and_reload(period: MicroSecond) -> (u8, u32) {
+        let mut psc = 0;
+        let mut ticks = crate::time::cycles(period, (LSI_FREQ / 4).Hz());
+        while psc < 6 && ticks > IWDG_MAX_RELOAD + 1 {
+            psc += 1;
+            ticks /= 2;
+        }
+        hal_assert!(ticks <= IWDG_MAX_RELOAD + 1, "IWDG timeout above 32.7 s");
+        (psc, ticks.clamp(1, IWDG_MAX_RELOAD + 1) - 1)
+    }
 }
 
-impl watchdog::Watchdog for IndependedWatchdog {
+impl watchdog::Watchdog for IndependentWatchdog {
     fn feed(&mut self) {
         self.feed();
     }
 }
 
-impl watchdog::WatchdogEnable for IndependedWatchdog {
+impl watchdog::WatchdogEnable for IndependentWatchdog {
     type Time = MicroSecond;
 
     fn start<T>(&mut self, period: T)
@@ -61,65 +92,139 @@ impl watchdog::WatchdogEnable for IndependedWatchdog {
 }
 
 pub trait IWDGExt {
-    fn constrain(self) -> IndependedWatchdog;
-}
-
-impl IndependedWatchdog {
-    pub fn release(self) -> IWDG {
-        self.iwdg
-    }
+    fn constrain(self) -> IndependentWatchdog;
 }
 
 impl IWDGExt for IWDG {
-    fn constrain(self) -> IndependedWatchdog {
-        IndependedWatchdog { iwdg: self }
+    fn constrain(self) -> IndependentWatchdog {
+        IndependentWatchdog { iwdg: self }
     }
 }
 
+/// Window watchdog (WWDG)
+///
+/// The 7-bit counter decrements at PCLK / 4096 / 2^WDGTB and resets the device when it goes
+/// from 0x40 to 0x3f. The counter is written with the reload value by each feed, and a feed
+/// while the counter is above the window value resets the device too.
 pub struct WindowWatchdog {
     wwdg: WWDG,
     clk: Hertz,
+    psc: u8,
+    reload: u8,
+    window: Option<MicroSecond>,
 }
 
 impl WindowWatchdog {
     pub fn feed(&mut self) {
-        self.wwdg.cr.write(|w| unsafe { w.t().bits(0xff) });
+        self.wwdg.cr.write(|w| unsafe { w.t().bits(self.reload) });
     }
 
+    /// Sets the time that has to elapse after a feed before the next one, feeding sooner
+    /// resets the device
+    ///
+    /// The window applies straight away if the watchdog is running, otherwise on
+    /// [`start`](Self::start).
+    ///
+    /// # Panics
+    ///
+    /// Panics, once the watchdog is started, if the window is not below its timeout.
     pub fn set_window(&mut self, window: MicroSecond) {
-        let mut cycles = crate::time::cycles(window, self.clk);
-        let mut psc = 0u8;
-        let mut window = 0;
-        while psc < 8 {
-            window = cycles;
-            if window <= 0x40 {
-                break;
-            }
-            psc += 1;
-            cycles /= 2;
+        self.window = Some(window);
+        if self.is_running() {
+            self.configure();
+        }
+    }
+
+    /// Lets the watchdog be fed at any time before its timeout
+    pub fn clear_window(&mut self) {
+        self.window = None;
+        if self.is_running() {
+            self.configure();
         }
-        assert!(window <= 0x40);
-        self.wwdg
-            .cfr
-            .write(|w| unsafe { w.wdgtb().bits(psc).w().bits(window as u8) });
     }
 
+    /// Enables the early wakeup interrupt, raised one counter tick before the timeout
+    ///
+    /// The `WWDG` interrupt has to be unmasked in the NVIC, its handler can still feed the
+    /// watchdog or save some state before the reset, and clears the event with
+    /// [`unpend`](Self::unpend). The interrupt can only be disabled again by a reset.
     pub fn listen(&mut self) {
-        self.wwdg.cfr.write(|w| w.ewi().set_bit());
+        self.wwdg.cfr.modify(|_, w| w.ewi().set_bit());
     }
 
-    pub fn unlisten(&mut self) {
-        self.wwdg.cfr.write(|w| w.ewi().clear_bit());
+    #[deprecated(note = "the early wakeup interrupt can only be disabled by a reset")]
+    pub fn unlisten(&mut self) {}
+
+    /// Returns true if the early wakeup interrupt is pending
+    pub fn is_pending(&self) -> bool {
+        self.wwdg.sr.read().ewif().bit_is_set()
+    }
+
+    /// Clears the early wakeup interrupt flag
+    pub fn unpend(&mut self) {
+        self.wwdg.sr.write(|w| w.ewif().clear_bit());
     }
 
     pub fn release(self) -> WWDG {
         self.wwdg
     }
 
+    /// Starts the watchdog, which resets the device if it is not fed within `period`
+    ///
+    /// The prescaler is the smallest one for which `period` fits the 64 counter ticks, the
+    /// timeout is rounded down to a whole number of ticks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is shorter than 4096 PCLK cycles or longer than 64 * 4096 * 128
+    /// PCLK cycles, 2.1 s with a 16 MHz PCLK.
     pub fn start(&mut self, period: MicroSecond) {
-        self.set_window(period.into());
-        self.feed();
-        self.wwdg.cr.write(|w| w.wdga().set_bit());
+        let mut psc = 0;
+        let mut ticks = self.ticks(period, 0);
+        while psc < 7 && ticks > 64 {
+            psc += 1;
+            ticks = self.ticks(period, psc);
+        }
+        hal_assert!((1..=64).contains(&ticks), "WWDG timeout out of range");
+        self.psc = psc;
+        self.reload = WWDG_RESET_COUNTER + ticks.clamp(1, 64) as u8;
+        self.configure();
+        self.wwdg
+            .cr
+            .write(|w| unsafe { w.t().bits(self.reload).wdga().set_bit() });
+    }
+
+    fn is_running(&self) -> bool {
+        self.wwdg.cr.read().wdga().bit_is_set()
+    }
+
+    /// Writes the prescaler and the window value matching the minimum feed interval
+    fn configure(&mut self) {
+        let window = match self.window {
+            None => 0x7f,
+            Some(window) => {
+                let clk = self.clk.raw() as u64;
+                let ticks = (window.ticks() as u64 * clk).div_ceil(4_096_000_000 << self.psc);
+                let ticks = ticks.min(u8::MAX as u64) as u8;
+                hal_assert!(
+                    ticks < self.reload - WWDG_RESET_COUNTER,
+                    "WWDG window not below the timeout"
+                );
+                self.reload
+                    .saturating_sub(ticks)
+                    .max(WWDG_RESET_COUNTER + 1)
+            }
+        };
+        let psc = self.psc;
+        self.wwdg
+            .cfr
+            .modify(|_, w| unsafe { w.wdgtb().bits(psc).w().bits(window) });
+    }
+
+    /// Counter ticks in `period` with the prescaler `psc`
+    fn ticks(&self, period: MicroSecond, psc: u8) -> u32 {
+        let clk = self.clk.raw() as u64;
+        (period.ticks() as u64 * clk / (4_096_000_000 << psc)) as u32
     }
 }
 
@@ -147,10 +252,12 @@ pub trait WWDGExt {
 impl WWDGExt for WWDG {
     fn constrain(self, rcc: &mut Rcc) -> WindowWatchdog {
         WWDG::enable(rcc);
-        let clk = rcc.clocks.apb_clk.raw() / 4096;
         WindowWatchdog {
             wwdg: self,
-            clk: clk.Hz(),
+            clk: rcc.clocks.apb_clk,
+            psc: 0,
+            reload: 0x7f,
+            window: None,
         }
     }
 }